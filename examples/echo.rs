@@ -7,9 +7,10 @@ fn main() {
     cmd.arg("hello");
     cmd.arg("world!");
 
-    match cmd.status().unwrap() {
+    let status = cmd.status().unwrap();
+    match status.signal() {
         // propagate signal
-        unshare::ExitStatus::Exited(x) => exit(x as i32),
-        unshare::ExitStatus::Signaled(x, _) => exit((128 + x as i32) as i32),
+        Some(sig) => exit(128 + sig),
+        None => exit(status.code().unwrap_or(1)),
     }
 }
@@ -0,0 +1,34 @@
+extern crate unshare;
+
+use std::env;
+use std::sync::{Arc, Mutex};
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<_>>();
+    if args.is_empty() {
+        eprintln!("Usage: trace <program> [args...]");
+        std::process::exit(1);
+    }
+    let phases = Arc::new(Mutex::new(Vec::new()));
+    let sink = phases.clone();
+    let mut cmd = unshare::Command::new(&args[0]);
+    cmd.args(&args[1..]);
+    cmd.trace(move |phase, time| sink.lock().unwrap().push((phase, time)));
+    let mut child = cmd.spawn().unwrap();
+
+    let phases = phases.lock().unwrap();
+    let total = phases
+        .iter()
+        .map(|&(_, time)| time)
+        .sum::<std::time::Duration>();
+    for &(phase, time) in phases.iter() {
+        println!(
+            "{:>16} {:>10.3}ms {:>5.1}%",
+            format!("{:?}", phase),
+            time.as_secs_f64() * 1000.,
+            time.as_secs_f64() * 100. / total.as_secs_f64(),
+        );
+    }
+    println!("{:>16} {:>10.3}ms", "Total", total.as_secs_f64() * 1000.);
+    child.wait().unwrap();
+}
@@ -0,0 +1,176 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use tokio::io::unix::AsyncFd;
+
+use crate::wait::waitpid_raw;
+use crate::{Child, Error, ExitStatus};
+
+/// A pidfd, closed on drop
+#[derive(Debug)]
+struct PidFd(RawFd);
+
+impl AsRawFd for PidFd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl Drop for PidFd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+/// The `Child` which can be waited for in async code
+///
+/// Requires `async` feature of the crate, a tokio runtime with IO enabled
+/// and Linux 5.3 (for `pidfd_open`). The `Child` is still accessible via
+/// `child()`/`child_mut()`, i.e. to take `stdout` and read it concurrently
+/// with `wait`.
+#[derive(Debug)]
+pub struct AsyncChild {
+    child: Child,
+    pidfd: Option<AsyncFd<PidFd>>,
+}
+
+impl Child {
+    /// Wrap the child into `AsyncChild` (only with `async` feature)
+    ///
+    /// Must be called within tokio runtime. Returns `Error::WaitError` if
+    /// pidfd can't be opened.
+    pub fn into_async(self) -> Result<AsyncChild, Error> {
+        if self.status.is_some() {
+            return Ok(AsyncChild {
+                child: self,
+                pidfd: None,
+            });
+        }
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, self.pid, 0) };
+        if fd < 0 {
+            return Err(Error::WaitError(nix::errno::errno()));
+        }
+        let pidfd = AsyncFd::new(PidFd(fd as RawFd))
+            .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))?;
+        Ok(AsyncChild {
+            child: self,
+            pidfd: Some(pidfd),
+        })
+    }
+}
+
+impl AsyncChild {
+    /// Returns the wrapped child
+    pub fn child(&self) -> &Child {
+        &self.child
+    }
+
+    /// Returns the wrapped child, i.e. to take its pipes
+    pub fn child_mut(&mut self) -> &mut Child {
+        &mut self.child
+    }
+
+    /// Returns the wrapped child dropping the pidfd
+    pub fn into_inner(self) -> Child {
+        self.child
+    }
+
+    /// Wait for child to complete and return exit status
+    ///
+    /// Same as `Child::wait`, the status is cached, so it can be called
+    /// multiple times, and is also returned by sync methods of the `Child`
+    /// afterwards. Dropping the future before it's ready doesn't lose the
+    /// status.
+    pub async fn wait(&mut self) -> Result<ExitStatus, Error> {
+        let map_err = |e: io::Error| Error::WaitError(e.raw_os_error().unwrap_or(-1));
+        loop {
+            if let Some(status) = self.child.status {
+                return Ok(status);
+            }
+            let pidfd = self
+                .pidfd
+                .as_ref()
+                .expect("pidfd is open while status is unknown");
+            // pidfd is readable when the process exits
+            let mut guard = pidfd.readable().await.map_err(map_err)?;
+            match waitpid_raw(self.child.pid, libc::WNOHANG) {
+                // the stops of a traced child are not remembered
+                Ok(Some((_, status))) if !status.terminated() => return Ok(status),
+                Ok(Some((_, status))) => {
+                    self.child.status = Some(status);
+                    self.pidfd = None;
+                }
+                Ok(None) => guard.clear_ready(),
+                Err(errno) => return Err(Error::WaitError(errno as i32)),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read};
+    use std::os::unix::io::AsRawFd;
+    use std::time::Duration;
+
+    use tokio::io::unix::AsyncFd;
+
+    use crate::{Command, ExitStatus, Stdio};
+
+    #[tokio::test]
+    async fn test_wait() {
+        let child = Command::new("/bin/sh")
+            .args(&["-c", "sleep 0.1; exit 7"])
+            .spawn()
+            .unwrap();
+        let mut child = child.into_async().unwrap();
+        assert_eq!(child.wait().await.unwrap(), ExitStatus::Exited(7));
+        // cached, even though the child is reaped
+        assert_eq!(child.wait().await.unwrap(), ExitStatus::Exited(7));
+        let mut child = child.into_inner();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(7));
+    }
+
+    #[tokio::test]
+    async fn test_wait_cancelled() {
+        let child = Command::new("/bin/sleep").arg("0.2").spawn().unwrap();
+        let mut child = child.into_async().unwrap();
+        let res = tokio::time::timeout(Duration::from_millis(10), child.wait()).await;
+        assert!(res.is_err());
+        assert!(child.wait().await.unwrap().success());
+    }
+
+    #[tokio::test]
+    async fn test_wait_with_pipe() {
+        let child = Command::new("/bin/sh")
+            .args(&["-c", "echo hello; sleep 0.1; echo world"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut child = child.into_async().unwrap();
+        let mut stdout = child.child_mut().stdout.take().unwrap();
+        let fd = stdout.as_raw_fd();
+        unsafe {
+            let flags = libc::fcntl(fd, libc::F_GETFL);
+            libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK);
+        }
+        let reader = async {
+            let stdout_fd = AsyncFd::new(fd).unwrap();
+            let mut output = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                let mut guard = stdout_fd.readable().await.unwrap();
+                match guard.try_io(|_| stdout.read(&mut buf)) {
+                    Ok(Ok(0)) => return output,
+                    Ok(Ok(n)) => output.extend_from_slice(&buf[..n]),
+                    Ok(Err(e)) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Ok(Err(e)) => panic!("read error: {}", e),
+                    Err(_would_block) => {}
+                }
+            }
+        };
+        let (output, status) = tokio::join!(reader, child.wait());
+        assert_eq!(output, b"hello\nworld\n");
+        assert!(status.unwrap().success());
+    }
+}
@@ -1,5 +1,7 @@
 use std::io;
 
+use libc::pid_t;
+
 use crate::{BoxError, Command};
 
 impl Command {
@@ -26,6 +28,23 @@ impl Command {
         self
     }
 
+    /// Set a callback to run in the parent once the child has executed
+    /// the program
+    ///
+    /// The callback receives the pid of the child. It runs in `spawn` right
+    /// after the successful `execve` is confirmed (the descriptor which
+    /// reports errors is closed by the exec), so it's never called if the
+    /// child failed before that. This is the place to register the child
+    /// with external systems which should only know about launched
+    /// processes. For `spawn_fn` it's called when the closure starts.
+    ///
+    /// Each invocation **replaces** callback,
+    /// so there is only one of them can be called.
+    pub fn after_exec(&mut self, f: impl FnMut(pid_t) + 'static) -> &mut Self {
+        self.after_exec = Some(Box::new(f));
+        self
+    }
+
     /// Set a callback to run in the child before calling exec
     ///
     /// The callback is executed right before `execve` system calls.
@@ -51,3 +70,37 @@ impl Command {
         self
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_after_exec() {
+        let pids = Rc::new(RefCell::new(Vec::new()));
+        let mut cmd = Command::new("/bin/true");
+        let seen = pids.clone();
+        cmd.after_exec(move |pid| seen.borrow_mut().push(pid));
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(*pids.borrow(), [child.pid().as_raw()]);
+        assert!(child.wait().unwrap().success());
+
+        // not called when the child fails before or in exec
+        let mut cmd = Command::new("/nonexistent");
+        let seen = pids.clone();
+        cmd.after_exec(move |pid| seen.borrow_mut().push(pid));
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let mut cmd = Command::new("/bin/true");
+        cmd.current_dir("/nonexistent");
+        let seen = pids.clone();
+        cmd.after_exec(move |pid| seen.borrow_mut().push(pid));
+        assert!(cmd.spawn().is_err());
+        assert_eq!(pids.borrow().len(), 1);
+    }
+}
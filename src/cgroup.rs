@@ -0,0 +1,181 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use libc::pid_t;
+
+use crate::{Child, Command, Error};
+
+/// Limits of cgroup v2 controllers, see `Command::cgroup_limits`
+///
+/// Limits which are `None` are not written, so they are left at the value
+/// the kernel (or the previous owner of the cgroup) set.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CgroupLimits {
+    /// `memory.max` in bytes
+    pub memory_max: Option<u64>,
+    /// `memory.swap.max` in bytes
+    pub memory_swap_max: Option<u64>,
+    /// `cpu.max`: the quota and the period in microseconds
+    pub cpu_max: Option<(u64, u64)>,
+    /// `pids.max`: the number of processes (and threads)
+    pub pids_max: Option<u64>,
+}
+
+impl CgroupLimits {
+    /// Returns files to write with their values
+    pub(crate) fn files(&self) -> Vec<(&'static str, String)> {
+        let mut files = Vec::new();
+        if let Some(bytes) = self.memory_max {
+            files.push(("memory.max", bytes.to_string()));
+        }
+        if let Some(bytes) = self.memory_swap_max {
+            files.push(("memory.swap.max", bytes.to_string()));
+        }
+        if let Some((quota, period)) = self.cpu_max {
+            files.push(("cpu.max", format!("{} {}", quota, period)));
+        }
+        if let Some(pids) = self.pids_max {
+            files.push(("pids.max", pids.to_string()));
+        }
+        files
+    }
+}
+
+impl Command {
+    /// Run the child in the cgroup (v2) at `path` with `limits` applied
+    ///
+    /// The parent creates the directory if it doesn't exist, writes the
+    /// limits and moves the child into the cgroup (via `cgroup.procs`)
+    /// while the child is not yet unfrozen, so the program never runs
+    /// unconstrained. The controllers must be enabled in
+    /// `cgroup.subtree_control` of the parent cgroup.
+    ///
+    /// If any of the writes fails, the child is killed and `Error::Cgroup`
+    /// is returned with the file and the value rejected. The cgroup is not
+    /// removed either way, `Child::cgroup_path` may be used to remove it
+    /// after the child is waited for.
+    ///
+    /// # Panics
+    ///
+    /// If path is not absolute
+    pub fn cgroup_limits<P: AsRef<Path>>(&mut self, path: P, limits: CgroupLimits) -> &mut Command {
+        let path = path.as_ref();
+        if !path.is_absolute() {
+            panic!("Cgroup path must be absolute");
+        }
+        self.config.cgroup = Some((path.to_path_buf(), limits));
+        self
+    }
+}
+
+impl Child {
+    /// Returns the path of the cgroup set by `Command::cgroup_limits`
+    pub fn cgroup_path(&self) -> Option<&Path> {
+        self.cgroup.as_deref()
+    }
+}
+
+fn write_file(path: PathBuf, value: String) -> Result<(), Error> {
+    // cgroup files exist, creating a file means it's not a cgroup
+    OpenOptions::new()
+        .write(true)
+        .open(&path)
+        .and_then(|mut f| f.write_all(value.as_bytes()))
+        .map_err(|e| Error::Cgroup {
+            path,
+            value: Some(value),
+            errno: e.raw_os_error().unwrap_or(libc::EIO),
+        })
+}
+
+/// Creates the cgroup, writes the limits and moves the process `pid` into it
+pub fn apply_cgroup(path: &Path, limits: &CgroupLimits, pid: pid_t) -> Result<(), Error> {
+    match fs::create_dir(path) {
+        Ok(()) => {}
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+        Err(e) => {
+            return Err(Error::Cgroup {
+                path: path.to_path_buf(),
+                value: None,
+                errno: e.raw_os_error().unwrap_or(libc::EIO),
+            })
+        }
+    }
+    for (name, value) in limits.files() {
+        write_file(path.join(name), value)?;
+    }
+    write_file(path.join("cgroup.procs"), pid.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+
+    use super::CgroupLimits;
+    use crate::test_util::cgroup_with_controller;
+    use crate::{Command, Error, Stdio};
+
+    #[test]
+    fn test_pids_max() {
+        let dir = match cgroup_with_controller("pids") {
+            Some(dir) => dir.join(format!("unshare-test-{}", std::process::id())),
+            None => return, // cgroup2 with pids controller is not available
+        };
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "for i in 1 2 3 4 5 6 7 8; do sleep 1 & done; wait"]);
+        cmd.cgroup_limits(
+            &dir,
+            CgroupLimits {
+                pids_max: Some(5),
+                ..CgroupLimits::default()
+            },
+        );
+        cmd.stderr(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut errors = String::new();
+        child
+            .stderr
+            .take()
+            .unwrap()
+            .read_to_string(&mut errors)
+            .unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.cgroup_path(), Some(dir.as_path()));
+        fs::remove_dir(&dir).unwrap();
+        assert!(errors.contains("fork"), "{}", errors);
+    }
+
+    #[test]
+    fn test_not_cgroup() {
+        let dir = std::env::temp_dir().join(format!("unshare-cgroup-{}", std::process::id()));
+        let mut cmd = Command::new("/bin/true");
+        cmd.cgroup_limits(
+            &dir,
+            CgroupLimits {
+                pids_max: Some(5),
+                ..CgroupLimits::default()
+            },
+        );
+        let res = cmd.spawn();
+        fs::remove_dir(&dir).unwrap();
+        match res {
+            Err(Error::Cgroup {
+                path,
+                value,
+                errno: libc::ENOENT,
+            }) => {
+                assert_eq!(path, dir.join("pids.max"));
+                assert_eq!(value.as_deref(), Some("5"));
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "must be absolute")]
+    fn test_relative_path() {
+        Command::new("/bin/true").cgroup_limits("cgroup", CgroupLimits::default());
+    }
+}
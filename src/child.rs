@@ -1,6 +1,7 @@
 use std::mem;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::ptr;
+use std::sync::atomic::{AtomicI32, Ordering};
 
 use libc;
 use libc::{c_ulong, c_void, sigset_t, size_t};
@@ -9,8 +10,11 @@ use libc::{FD_CLOEXEC, F_DUPFD_CLOEXEC, F_GETFD, F_SETFD, MNT_DETACH};
 use libc::{SIG_DFL, SIG_SETMASK};
 use nix;
 
-use crate::error::ErrorCode as Err;
-use crate::run::{ChildInfo, MAX_PID_LEN};
+use crate::error::{ErrorCode as Err, SetupStage};
+use crate::interpreter::{find_interpreter, INTERP_MAX};
+use crate::mount::Mount;
+use crate::run::{ChildInfo, LateEnvValue, MAX_INO_LEN, MAX_PID_LEN};
+use crate::trace::{child_phase, TRACE_RECORD};
 
 // And at this point we've reached a special time in the life of the
 // child. The child must now be considered hamstrung and unable to
@@ -22,10 +26,19 @@ use crate::run::{ChildInfo, MAX_PID_LEN};
 // https://github.com/rust-lang/rust/blob/c1e865c/src/libstd/sys/unix/process.rs#L202
 //
 
+extern "C" {
+    static mut environ: *const *const libc::c_char;
+}
+
 // In particular ChildInfo is passed by refernce here to avoid
 // deallocating (parts of) it.
 pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
-    let mut epipe = child.error_pipe;
+    let mut epipe = Output {
+        error_pipe: child.error_pipe,
+        progress: child.progress_fd,
+        pivot_old: ptr::null(),
+        stage: SetupStage::Startup as u8,
+    };
 
     child.cfg.death_sig.as_ref().map(|&sig| {
         if libc::prctl(ffi::PR_SET_PDEATHSIG, sig as c_ulong, 0, 0, 0) != 0 {
@@ -33,13 +46,24 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     });
 
+    if let Some(ref name) = child.cfg.comm {
+        // can't fail, the name is nul-terminated
+        libc::prctl(libc::PR_SET_NAME, name.as_ptr() as c_ulong, 0, 0, 0);
+    }
+
     // Now we must wait until parent set some environment for us. It's mostly
     // for uid_map/gid_map. But also used for attaching debugger and maybe
     // other things
-    let mut wbuf = [0u8];
+    // The message is a single `x` byte followed by our pid as seen by parent
+    let mut wbuf = [0u8; 5];
+    let mut wlen = 0;
     loop {
         // TODO(tailhook) put some timeout on this pipe?
-        let rc = libc::read(child.wakeup_pipe, (&mut wbuf).as_ptr() as *mut c_void, 1);
+        let rc = libc::read(
+            child.wakeup_pipe,
+            wbuf[wlen..].as_mut_ptr() as *mut c_void,
+            wbuf.len() - wlen,
+        );
         if rc == 0 {
             // Parent already dead presumably before we had a chance to
             // set PDEATHSIG, so just send signal ourself in that case
@@ -59,49 +83,214 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
             if errno == libc::EINTR as i32 || errno == libc::EAGAIN as i32 {
                 continue;
             } else {
-                fail(Err::PipeError, errno);
+                fail_errno(Err::PipeError, errno, epipe);
             }
         } else {
-            // Do we need to check that exactly one byte is received?
-            break;
+            wlen += rc as usize;
+            if wlen == wbuf.len() {
+                break;
+            }
         }
     }
+    let host_pid = i32::from_be_bytes([wbuf[1], wbuf[2], wbuf[3], wbuf[4]]);
+    let mut pid_buf = [0u8; MAX_PID_LEN];
+    let pid = format_pid_fixed(&mut pid_buf, host_pid);
+    progress(epipe, &[b"started ", &pid[..pid.len() - 1], b"\n"]);
+    epipe.stage = SetupStage::Namespaces as u8;
+    let mut trace_time: libc::timespec = mem::zeroed();
+    if child.trace {
+        libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut trace_time);
+    }
 
     // Move error pipe file descriptors in case they clobber stdio
-    while epipe < 3 {
-        let nerr = libc::fcntl(epipe, F_DUPFD_CLOEXEC, 3);
+    while epipe.error_pipe < 3 {
+        let nerr = libc::fcntl(epipe.error_pipe, F_DUPFD_CLOEXEC, 3);
         if nerr < 0 {
             fail(Err::CreatePipe, epipe);
         }
-        epipe = nerr;
+        epipe.error_pipe = nerr;
+    }
+
+    if child.cfg.make_session_leader && libc::setsid() < 0 {
+        fail(Err::SetSid, epipe);
     }
 
-    for &(nstype, fd) in child.setns_namespaces {
+    if !child.cfg.make_session_leader && child.cfg.inherit_ctty == Some(false) {
+        let tty = libc::open(
+            b"/dev/tty\0".as_ptr() as *const libc::c_char,
+            libc::O_RDWR | libc::O_NOCTTY | libc::O_CLOEXEC,
+        );
+        if tty >= 0 {
+            if libc::ioctl(tty, libc::TIOCNOTTY) != 0 {
+                fail(Err::ControllingTty, epipe);
+            }
+            libc::close(tty);
+        } else if nix::errno::errno() != libc::ENXIO {
+            // ENXIO means there is no controlling terminal already
+            fail(Err::ControllingTty, epipe);
+        }
+    }
+
+    if let Some(pgid) = child.cfg.process_group {
+        // parent does the same before waking us up, repeat it like shells do
+        if libc::setpgid(0, pgid) != 0 {
+            fail(Err::SetPGid, epipe);
+        }
+    }
+
+    if let Some(ref tty) = child.cfg.foreground_tty {
+        // we are not in the foreground group yet, so tcsetpgrp would
+        // send us SIGTTOU unless it's blocked
+        let mut ttou: libc::sigset_t = mem::zeroed();
+        let mut old: libc::sigset_t = mem::zeroed();
+        libc::sigemptyset(&mut ttou);
+        libc::sigaddset(&mut ttou, libc::SIGTTOU);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &ttou, &mut old);
+        if libc::tcsetpgrp(tty.as_raw_fd(), libc::getpgrp()) != 0 {
+            fail(Err::ControllingTty, epipe);
+        }
+        libc::pthread_sigmask(libc::SIG_SETMASK, &old, ptr::null_mut());
+    }
+
+    for (index, &(nstype, fd)) in child.setns_namespaces.iter().enumerate() {
         if libc::setns(fd, nstype.bits()) != 0 {
-            fail(Err::SetNs, epipe);
+            let errno = nix::errno::errno();
+            // the parent finds the namespace by index
+            fail_record(Err::SetNs, errno, (index as i32) << 16 | errno, epipe);
         }
     }
 
-    if !child.pid_env_vars.is_empty() {
-        let mut buf = [0u8; MAX_PID_LEN + 1];
-        let data = format_pid_fixed(&mut buf, libc::getpid());
-        for &(index, offset) in child.pid_env_vars {
-            // we know that there are at least MAX_PID_LEN+1 bytes in buffer
-            child.environ[index]
-                .offset(offset as isize)
-                .copy_from(data.as_ptr() as *const libc::c_char, data.len());
+    for &(index, offset, ref value) in child.metadata_env_vars {
+        let mut buf = [0u8; MAX_INO_LEN + 1];
+        let data = match *value {
+            LateEnvValue::Pid => format_pid_fixed(&mut buf, libc::getpid()),
+            // the init doesn't run the program itself
+            LateEnvValue::InnerPid => continue,
+            LateEnvValue::HostPid => format_pid_fixed(&mut buf, host_pid),
+            LateEnvValue::NamespaceId(ref path) => {
+                let mut stat: libc::stat = mem::zeroed();
+                if libc::stat(path.as_ptr(), &mut stat) != 0 {
+                    fail(Err::EnvMetadata, epipe);
+                }
+                format_u64_fixed(&mut buf, stat.st_ino)
+            }
+        };
+        // we know that there are enough bytes in buffer for any value kind
+        child.environ[index]
+            .add(offset)
+            .copy_from(data.as_ptr() as *const libc::c_char, data.len());
+    }
+
+    if let Some(uid) = child.cfg.loginuid {
+        // before the root is changed, as `/proc` may be not there
+        let fd = libc::open(
+            b"/proc/self/loginuid\0".as_ptr() as *const libc::c_char,
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            fail(Err::LoginUid, epipe);
+        }
+        let mut buf = [0u8; MAX_INO_LEN + 1];
+        let data = format_u64_fixed(&mut buf, uid as u64);
+        // without the trailing nul
+        if libc::write(fd, data.as_ptr() as *const c_void, data.len() - 1) < 0 {
+            fail(Err::LoginUid, epipe);
         }
+        libc::close(fd);
     }
+    trace(child, child_phase::NAMESPACES, &mut trace_time, &mut epipe);
 
-    child.pivot.as_ref().map(|piv| {
-        if ffi::pivot_root(piv.new_root.as_ptr(), piv.put_old.as_ptr()) != 0 {
+    // don't propagate our mounts to the parent namespace
+    if !child.cfg.mounts.is_empty()
+        && libc::mount(
+            ptr::null(),
+            b"/\0".as_ptr() as *const libc::c_char,
+            ptr::null(),
+            libc::MS_REC | libc::MS_SLAVE,
+            ptr::null(),
+        ) != 0
+    {
+        fail(Err::Mount, epipe);
+    }
+
+    // opened before the root is changed, as `/proc` may be not there
+    let mut audit_dir = -1;
+    if child.cfg.audit_fds && (child.chroot.is_some() || child.pivot.is_some()) {
+        audit_dir = libc::open(
+            b"/proc/self/fd\0".as_ptr() as *const libc::c_char,
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        );
+        if audit_dir < 0 {
             fail(Err::ChangeRoot, epipe);
         }
-        if libc::chdir(piv.workdir.as_ptr()) != 0 {
+        // move it out of the way of `dup2` of the passed descriptors
+        let above = child
+            .fds
+            .iter()
+            .map(|&(dest, _)| dest + 1)
+            .max()
+            .unwrap_or(0);
+        let moved = libc::fcntl(audit_dir, F_DUPFD_CLOEXEC, above);
+        if moved < 0 {
             fail(Err::ChangeRoot, epipe);
         }
-        if piv.unmount_old_root {
-            if libc::umount2(piv.old_inside.as_ptr(), MNT_DETACH) != 0 {
+        libc::close(audit_dir);
+        audit_dir = moved;
+    }
+
+    // with `current_dir_fd_before_root` the root change keeps the working
+    // directory, instead of moving it to the `workdir`
+    let early_fd = match child.cfg.work_dir_fd {
+        Some(ref fd) if child.cfg.work_dir_fd_before_root => fd.as_raw_fd(),
+        _ => -1,
+    };
+    if early_fd >= 0 && libc::fchdir(early_fd) != 0 {
+        fail(Err::Chdir, epipe);
+    }
+
+    // the name of `put_old` created for `pivot_root_auto`, absolute after
+    // the pivot, the leading slash is skipped before
+    let mut auto_old = *b"/.pivot_old.XXXXXX\0";
+    child.pivot.as_ref().map(|piv| match piv.put_old {
+        Some((ref put_old, ref old_inside)) => {
+            if ffi::pivot_root(piv.new_root.as_ptr(), put_old.as_ptr()) != 0 {
+                fail(Err::ChangeRoot, epipe);
+            }
+            if early_fd < 0 && libc::chdir(piv.workdir.as_ptr()) != 0 {
+                fail(Err::ChangeRoot, epipe);
+            }
+            if piv.unmount_old_root {
+                if libc::umount2(old_inside.as_ptr(), MNT_DETACH) != 0 {
+                    fail(Err::ChangeRoot, epipe);
+                }
+            }
+        }
+        None => {
+            if libc::chdir(piv.new_root.as_ptr()) != 0 {
+                fail(Err::ChangeRoot, epipe);
+            }
+            make_auto_old(&mut auto_old, epipe);
+            let relative = auto_old[1..].as_ptr() as *const libc::c_char;
+            let dot = b".\0".as_ptr() as *const libc::c_char;
+            if ffi::pivot_root(dot, relative) != 0 {
+                let errno = nix::errno::errno();
+                libc::rmdir(relative);
+                fail_errno(Err::ChangeRoot, errno, epipe);
+            }
+            let absolute = auto_old.as_ptr() as *const libc::c_char;
+            if piv.unmount_old_root {
+                if libc::umount2(absolute, MNT_DETACH) != 0 || libc::rmdir(absolute) != 0 {
+                    fail(Err::ChangeRoot, epipe);
+                }
+            } else {
+                epipe.pivot_old = absolute;
+            }
+            // the working directory was changed to do the pivot
+            if early_fd >= 0 {
+                if libc::fchdir(early_fd) != 0 {
+                    fail(Err::Chdir, epipe);
+                }
+            } else if libc::chdir(piv.workdir.as_ptr()) != 0 {
                 fail(Err::ChangeRoot, epipe);
             }
         }
@@ -111,11 +300,112 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         if libc::chroot(chroot.root.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
+        if early_fd >= 0 {
+            // checked below
+            return;
+        }
+        // `chroot` doesn't change the working directory, if it was left
+        // outside, `..` would lead out of the new root
+        if libc::chdir(b"/\0".as_ptr() as *const libc::c_char) != 0 {
+            fail(Err::ChangeRoot, epipe);
+        }
+        let mut cur: libc::stat = mem::zeroed();
+        let mut up: libc::stat = mem::zeroed();
+        if libc::stat(b".\0".as_ptr() as *const libc::c_char, &mut cur) != 0
+            || libc::stat(b"..\0".as_ptr() as *const libc::c_char, &mut up) != 0
+        {
+            fail(Err::ChangeRoot, epipe);
+        }
+        if cur.st_dev != up.st_dev || cur.st_ino != up.st_ino {
+            fail_errno(Err::ChangeRoot, libc::EXDEV, epipe);
+        }
         if libc::chdir(chroot.workdir.as_ptr()) != 0 {
             fail(Err::ChangeRoot, epipe);
         }
     });
 
+    if early_fd >= 0 {
+        if child.chroot.is_some() || child.pivot.is_some() {
+            check_cwd_in_root(epipe);
+        }
+        libc::close(early_fd);
+    }
+
+    for (index, mount) in child.cfg.mounts.iter().enumerate() {
+        let secure = if child.cfg.secure_mounts {
+            mount.secure_flags().bits()
+        } else {
+            0
+        };
+        match *mount {
+            Mount::Sysfs {
+                ref target,
+                readonly,
+            } => {
+                if libc::mount(
+                    b"sysfs\0".as_ptr() as *const libc::c_char,
+                    target.as_ptr(),
+                    b"sysfs\0".as_ptr() as *const libc::c_char,
+                    secure,
+                    ptr::null(),
+                ) != 0
+                {
+                    fail(Err::Mount, epipe);
+                }
+                if readonly
+                    && libc::mount(
+                        ptr::null(),
+                        target.as_ptr(),
+                        ptr::null(),
+                        libc::MS_REMOUNT | libc::MS_BIND | libc::MS_RDONLY | secure,
+                        ptr::null(),
+                    ) != 0
+                {
+                    fail(Err::Mount, epipe);
+                }
+            }
+            Mount::Shm {
+                ref target,
+                ref data,
+            } => {
+                if libc::mount(
+                    b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    target.as_ptr(),
+                    b"tmpfs\0".as_ptr() as *const libc::c_char,
+                    secure,
+                    data.as_ptr() as *const c_void,
+                ) != 0
+                {
+                    let errno = nix::errno::errno();
+                    fail_record(Err::MountAt, errno, (index as i32) << 16 | errno, epipe);
+                }
+            }
+            Mount::Raw {
+                ref source,
+                ref target,
+                ref fstype,
+                flags,
+                ref data,
+            } => {
+                if libc::mount(
+                    source.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                    target.as_ptr(),
+                    fstype.as_ref().map_or(ptr::null(), |s| s.as_ptr()),
+                    flags.bits(),
+                    data.as_ref()
+                        .map_or(ptr::null(), |s| s.as_ptr() as *const c_void),
+                ) != 0
+                {
+                    let errno = nix::errno::errno();
+                    // the parent finds the target by index
+                    fail_record(Err::MountAt, errno, (index as i32) << 16 | errno, epipe);
+                }
+            }
+        }
+    }
+
+    trace(child, child_phase::CHANGE_ROOT, &mut trace_time, &mut epipe);
+
     child.keep_caps.as_ref().map(|_| {
         // Don't use securebits because on older systems it doesn't work
         if libc::prctl(libc::PR_SET_KEEPCAPS, 1, 0, 0, 0) != 0 {
@@ -129,7 +419,7 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     });
 
-    child.cfg.supplementary_gids.as_ref().map(|groups| {
+    child.supplementary_gids.as_ref().map(|groups| {
         if libc::setgroups(groups.len() as size_t, groups.as_ptr()) != 0 {
             fail(Err::SetUser, epipe);
         }
@@ -168,12 +458,27 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     });
 
+    trace(child, child_phase::SET_USER, &mut trace_time, &mut epipe);
+
     child.cfg.work_dir.as_ref().map(|dir| {
         if libc::chdir(dir.as_ptr()) != 0 {
             fail(Err::Chdir, epipe);
         }
     });
 
+    if let Some(ref fd) = child.cfg.work_dir_fd {
+        if early_fd < 0 {
+            if libc::fchdir(fd.as_raw_fd()) != 0 {
+                fail(Err::Chdir, epipe);
+            }
+            if child.chroot.is_some() || child.pivot.is_some() {
+                check_cwd_in_root(epipe);
+            }
+            // it's not inherited, whether the caller set `CLOEXEC` or not
+            libc::close(fd.as_raw_fd());
+        }
+    }
+
     for &(dest_fd, src_fd) in child.fds {
         if src_fd == dest_fd {
             let flags = libc::fcntl(src_fd, F_GETFD);
@@ -187,10 +492,50 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     }
 
+    // after the passed descriptors are in place, so the new ones don't
+    // clobber them
+    for &(dest_fd, path, flags) in child.open_fds {
+        let fd = libc::open(path, flags);
+        if fd < 0 {
+            fail(Err::OpenInRoot, epipe);
+        }
+        if fd != dest_fd {
+            if libc::dup2(fd, dest_fd) < 0 {
+                fail(Err::StdioError, epipe);
+            }
+            libc::close(fd);
+        }
+    }
+
+    // `dup2` clears the flag, so it's set back for `file_descriptor_cloexec`
+    for &fd in &child.cfg.cloexec_fds {
+        let flags = libc::fcntl(fd, F_GETFD);
+        if flags < 0 || libc::fcntl(fd, F_SETFD, flags | FD_CLOEXEC) < 0 {
+            fail(Err::StdioError, epipe);
+        }
+    }
+
+    if child.cfg.make_session_leader
+        && child.cfg.inherit_ctty == Some(true)
+        && libc::ioctl(0, libc::TIOCSCTTY, child.cfg.steal_ctty as libc::c_int) != 0
+    {
+        fail(Err::ControllingTty, epipe);
+    }
+
     for &(start, end) in child.close_fds {
         if start < end {
             for fd in start..end {
-                if child.fds.iter().find(|&&(cfd, _)| cfd == fd).is_none() {
+                if fd != epipe.error_pipe
+                    && fd != epipe.progress
+                    && fd != child.program_fd
+                    && fd != audit_dir
+                    && child.fds.iter().find(|&&(cfd, _)| cfd == fd).is_none()
+                    && child
+                        .open_fds
+                        .iter()
+                        .find(|&&(cfd, _, _)| cfd == fd)
+                        .is_none()
+                {
                     // Close may fail with ebadf, and it's okay
                     libc::close(fd);
                 }
@@ -198,56 +543,505 @@ pub unsafe fn child_after_clone(child: &ChildInfo) -> ! {
         }
     }
 
+    trace(child, child_phase::FDS, &mut trace_time, &mut epipe);
+
     if child.cfg.restore_sigmask {
         let mut sigmask: sigset_t = mem::zeroed();
         libc::sigemptyset(&mut sigmask);
         libc::pthread_sigmask(SIG_SETMASK, &sigmask, ptr::null_mut());
+    }
+    if !child
+        .cfg
+        .inherit_handlers
+        .unwrap_or(!child.cfg.restore_sigmask)
+    {
         for sig in 1..32 {
             signal(sig, SIG_DFL);
         }
     }
 
+    if let Some(secs) = child.cfg.cpu_time_limit {
+        let mut limit = libc::rlimit {
+            rlim_cur: 0,
+            rlim_max: 0,
+        };
+        if libc::getrlimit(libc::RLIMIT_CPU, &mut limit) != 0 {
+            fail(Err::ResourceLimit, epipe);
+        }
+        // raising the hard limit needs CAP_SYS_RESOURCE, so it's only lowered
+        let hard = limit.rlim_max;
+        limit.rlim_cur = secs.min(hard);
+        limit.rlim_max = secs.saturating_add(1).min(hard);
+        if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 {
+            fail(Err::ResourceLimit, epipe);
+        }
+    }
+
+    if let Some(node) = child.cfg.numa_node {
+        // enough for the maximum of 1024 nodes the kernel supports
+        let bits = c_ulong::BITS as usize;
+        let mut mask = [0 as c_ulong; 1024 / c_ulong::BITS as usize];
+        let node = node as usize;
+        if node >= mask.len() * bits {
+            fail_errno(Err::NumaBind, libc::EINVAL, epipe);
+        }
+        mask[node / bits] |= 1 << (node % bits);
+        // the kernel ignores the last bit of `maxnode`
+        let rc = libc::syscall(
+            libc::SYS_set_mempolicy,
+            ffi::MPOL_BIND,
+            mask.as_ptr(),
+            mask.len() * bits + 1,
+        );
+        if rc != 0 {
+            fail(Err::NumaBind, epipe);
+        }
+    }
+
+    for &(option, arg2, arg3) in child.cfg.mitigations.prctls().iter().flatten() {
+        if libc::prctl(option, arg2 as c_ulong, arg3 as c_ulong, 0, 0) != 0 {
+            match nix::errno::errno() {
+                libc::EINVAL | libc::ENXIO => fail(Err::MitigationUnsupported, epipe),
+                _ => fail(Err::Mitigation, epipe),
+            }
+        }
+    }
+
     if let Some(callback) = child.pre_exec {
         if let Err(e) = callback() {
             fail_errno(Err::PreExec, e.raw_os_error().unwrap_or(10873289), epipe);
         }
     }
 
+    trace(child, child_phase::PRE_EXEC, &mut trace_time, &mut epipe);
+
+    if audit_dir >= 0 {
+        audit_fds(audit_dir, epipe);
+        libc::close(audit_dir);
+    }
+
+    if child.cfg.reap_orphans && libc::getpid() == 1 {
+        // the raw syscall, as `fork()` of libc runs `pthread_atfork`
+        // handlers and updates its own state, which is not safe after
+        // `clone`; with zero stack pointer the child continues on a copy
+        // of this stack, like after `fork` (the order of the other
+        // arguments differs between architectures, but all are zero)
+        match libc::syscall(libc::SYS_clone, libc::SIGCHLD, 0, 0, 0, 0) {
+            -1 => fail(Err::Fork, epipe),
+            0 => {}
+            pid => run_init(child, pid as libc::pid_t, epipe),
+        }
+    }
+
+    for &(index, offset, ref value) in child.metadata_env_vars {
+        if let LateEnvValue::InnerPid = *value {
+            let mut buf = [0u8; MAX_PID_LEN];
+            let data = format_pid_fixed(&mut buf, libc::getpid());
+            child.environ[index]
+                .add(offset)
+                .copy_from(data.as_ptr() as *const libc::c_char, data.len());
+        }
+    }
+
+    if let Some(payload) = child.payload {
+        // same as on successful `execve`: nothing is reported anymore
+        libc::close(epipe.error_pipe);
+        if epipe.progress >= 0 {
+            libc::close(epipe.progress);
+        }
+        environ = child.environ.as_ptr() as *const *const libc::c_char;
+        libc::_exit(payload());
+    }
+
+    if child.cfg.trace_me
+        && libc::ptrace(
+            libc::PTRACE_TRACEME,
+            0,
+            ptr::null_mut::<c_void>(),
+            ptr::null_mut::<c_void>(),
+        ) != 0
+    {
+        fail(Err::TraceMe, epipe);
+    }
+
+    if child.program_fd >= 0 {
+        if libc::fcntl(child.program_fd, F_SETFD, 0) < 0 {
+            fail(Err::Exec, epipe);
+        }
+        libc::fexecve(
+            child.program_fd,
+            child.args.as_ptr(),
+            child.environ.as_ptr() as *const *const libc::c_char,
+        );
+        fail(Err::Exec, epipe);
+    }
+
     libc::execvpe(
         child.filename,
         child.args.as_ptr(),
         // cancelling mutability, it should be fine
         child.environ.as_ptr() as *const *const libc::c_char,
     );
-    fail(Err::Exec, epipe);
+    let errno = nix::errno::errno();
+    if errno == libc::ENOENT
+        && (child.chroot.is_some() || child.pivot.is_some())
+        && interpreter_missing(child.filename)
+    {
+        fail_errno(Err::ExecInterpreter, errno, epipe);
+    }
+    fail_errno(Err::Exec, errno, epipe);
 }
 
-unsafe fn fail(code: Err, output: RawFd) -> ! {
-    fail_errno(code, nix::errno::errno(), output)
+/// Reports every directory descriptor which is outside of the root and is
+/// kept open by `execve` with `EscapeFd` record, then fails if there were
+/// any
+///
+/// `dir` is `/proc/self/fd` opened before changing root.
+unsafe fn audit_fds(dir: RawFd, output: Output) {
+    let mut root: libc::stat = mem::zeroed();
+    if libc::stat(b"/\0".as_ptr() as *const libc::c_char, &mut root) != 0 {
+        fail(Err::ChangeRoot, output);
+    }
+    let mut found = false;
+    let mut buf = [0u8; 1024];
+    loop {
+        let len = libc::syscall(libc::SYS_getdents64, dir, buf.as_mut_ptr(), buf.len());
+        if len < 0 {
+            fail(Err::ChangeRoot, output);
+        }
+        if len == 0 {
+            break;
+        }
+        let mut pos = 0;
+        while pos < len as usize {
+            // struct linux_dirent64: u64 ino, i64 off, u16 reclen,
+            // u8 type, then nul-terminated name
+            let reclen = u16::from_ne_bytes([buf[pos + 16], buf[pos + 17]]) as usize;
+            // names are numbers, except `.` and `..`
+            let mut fd: RawFd = -1;
+            for &c in buf[pos + 19..pos + reclen].iter().take_while(|&&c| c != 0) {
+                if !c.is_ascii_digit() {
+                    fd = -1;
+                    break;
+                }
+                fd = fd.max(0) * 10 + (c - b'0') as RawFd;
+            }
+            pos += reclen;
+            // descriptors closed by `execve` can't be used by the program
+            if fd < 0 || libc::fcntl(fd, F_GETFD) & FD_CLOEXEC != 0 {
+                continue;
+            }
+            if outside_root(fd, &root) {
+                found = true;
+                if !write_record(output, &record(Err::EscapeFd, output.stage, fd)) {
+                    fail(Err::PipeError, output);
+                }
+            }
+        }
+    }
+    if found {
+        libc::_exit(127);
+    }
+}
+
+/// Fails with `EXDEV` if the working directory is outside of the root
+unsafe fn check_cwd_in_root(output: Output) {
+    let mut root: libc::stat = mem::zeroed();
+    if libc::stat(b"/\0".as_ptr() as *const libc::c_char, &mut root) != 0 {
+        fail(Err::ChangeRoot, output);
+    }
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let cwd = libc::open(b".\0".as_ptr() as *const libc::c_char, flags);
+    if cwd < 0 {
+        fail(Err::ChangeRoot, output);
+    }
+    if outside_root(cwd, &root) {
+        fail_errno(Err::ChangeRoot, libc::EXDEV, output);
+    }
+    libc::close(cwd);
+}
+
+/// Returns `true` if `fd` is a directory not under the `root`
+///
+/// Walks `..` up from the directory: the root of the real file system is
+/// reached if it's outside, as `..` resolution only stops at the root of
+/// the process when walking from inside of it.
+unsafe fn outside_root(fd: RawFd, root: &libc::stat) -> bool {
+    let mut st: libc::stat = mem::zeroed();
+    if libc::fstat(fd, &mut st) != 0 || st.st_mode & libc::S_IFMT != libc::S_IFDIR {
+        return false;
+    }
+    let flags = libc::O_PATH | libc::O_DIRECTORY | libc::O_CLOEXEC;
+    let dotdot = b"..\0".as_ptr() as *const libc::c_char;
+    let mut cur = libc::fcntl(fd, F_DUPFD_CLOEXEC, 0);
+    // the depth is limited in case we are fooled by concurrent renames
+    for _ in 0..4096 {
+        if cur < 0 {
+            return true;
+        }
+        if st.st_dev == root.st_dev && st.st_ino == root.st_ino {
+            libc::close(cur);
+            return false;
+        }
+        let parent = libc::openat(cur, dotdot, flags);
+        libc::close(cur);
+        if parent < 0 {
+            return true;
+        }
+        let mut up: libc::stat = mem::zeroed();
+        if libc::fstat(parent, &mut up) != 0 || (up.st_dev == st.st_dev && up.st_ino == st.st_ino) {
+            libc::close(parent);
+            return true;
+        }
+        st = up;
+        cur = parent;
+    }
+    libc::close(cur);
+    true
+}
+
+/// The main process of the namespace, signals are forwarded to it
+static INIT_MAIN_PID: AtomicI32 = AtomicI32::new(0);
+
+extern "C" fn forward_signal(sig: libc::c_int) {
+    unsafe { kill(INIT_MAIN_PID.load(Ordering::SeqCst), sig) };
+}
+
+/// Reaps processes until `main` exits, then exits with the same status
+///
+/// The error pipe is left to `main`, which reports errors of `execve`.
+unsafe fn run_init(child: &ChildInfo, main: libc::pid_t, output: Output) -> ! {
+    libc::close(output.error_pipe);
+    if output.progress >= 0 {
+        libc::close(output.progress);
+    }
+    // so that pipes are closed when the program closes them
+    for &(fd, _) in child.fds {
+        libc::close(fd);
+    }
+    INIT_MAIN_PID.store(main, Ordering::SeqCst);
+    for &sig in &[
+        libc::SIGHUP,
+        libc::SIGINT,
+        libc::SIGQUIT,
+        libc::SIGTERM,
+        libc::SIGUSR1,
+        libc::SIGUSR2,
+    ] {
+        signal(sig, forward_signal as *const () as libc::sighandler_t);
+    }
+    loop {
+        let mut status = 0;
+        let pid = libc::waitpid(-1, &mut status, 0);
+        if pid == main {
+            if libc::WIFSIGNALED(status) {
+                libc::_exit(128 + libc::WTERMSIG(status));
+            }
+            libc::_exit(libc::WEXITSTATUS(status));
+        }
+        if pid < 0 && nix::errno::errno() == libc::ECHILD {
+            libc::_exit(127);
+        }
+    }
 }
-unsafe fn fail_errno(code: Err, errno: i32, output: RawFd) -> ! {
+
+/// Checks whether the program exists but its interpreter doesn't
+///
+/// Only paths containing a slash are checked, as we don't know which
+/// directory of `PATH` was tried.
+unsafe fn interpreter_missing(filename: *const libc::c_char) -> bool {
+    if libc::strchr(filename, b'/' as libc::c_int).is_null() {
+        return false;
+    }
+    let fd = libc::open(filename, libc::O_RDONLY | libc::O_CLOEXEC);
+    if fd < 0 {
+        return false;
+    }
+    let mut buf = [0u8; INTERP_MAX];
+    let read = |offset: u64, dest: &mut [u8]| {
+        let n = libc::pread(
+            fd,
+            dest.as_mut_ptr() as *mut c_void,
+            dest.len(),
+            offset as libc::off_t,
+        );
+        n.max(0) as usize
+    };
+    let len = find_interpreter(read, &mut buf);
+    libc::close(fd);
+    match len {
+        Some(len) => {
+            buf[len] = 0;
+            libc::access(buf.as_ptr() as *const libc::c_char, libc::F_OK) != 0
+        }
+        None => false,
+    }
+}
+
+/// Where the child reports errors and progress
+#[derive(Clone, Copy)]
+struct Output {
+    error_pipe: RawFd,
+    /// Set by `capture_child_errors`, or `-1`
+    progress: RawFd,
+    /// The old root mounted by `pivot_root_auto` which is removed on
+    /// failure, or null
+    pivot_old: *const libc::c_char,
+    /// The `SetupStage` sent with the errors
+    stage: u8,
+}
+
+/// Writes a line to the progress stream, if it's enabled
+///
+/// Parts are concatenated into a single write, so lines from different
+/// processes are not interleaved.
+unsafe fn progress(output: Output, parts: &[&[u8]]) {
+    if output.progress < 0 {
+        return;
+    }
+    let mut buf = [0u8; 64];
+    let mut len = 0;
+    for part in parts {
+        let n = part.len().min(buf.len() - len);
+        buf[len..len + n].copy_from_slice(&part[..n]);
+        len += n;
+    }
+    // errors are ignored, it's just a debugging aid
+    while libc::write(output.progress, buf.as_ptr() as *const c_void, len) < 0
+        && nix::errno::errno() == libc::EINTR
+    {}
+}
+
+/// Marks the end of the phase: sends the time since `last` as a trace
+/// record, if tracing is enabled, and writes progress line
+///
+/// The errors reported afterwards belong to the next stage.
+unsafe fn trace(child: &ChildInfo, phase: u8, last: &mut libc::timespec, output: &mut Output) {
+    output.stage = match phase {
+        child_phase::NAMESPACES => SetupStage::ChangeRoot,
+        child_phase::CHANGE_ROOT => SetupStage::SetUser,
+        child_phase::SET_USER => SetupStage::Fds,
+        child_phase::FDS => SetupStage::PreExec,
+        _ => SetupStage::Exec,
+    } as u8;
+    let output = *output;
+    progress(output, &[child_phase::name(phase), b" done\n"]);
+    if !child.trace {
+        return;
+    }
+    let mut now: libc::timespec = mem::zeroed();
+    libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
+    let micros =
+        (now.tv_sec - last.tv_sec) as i64 * 1_000_000 + (now.tv_nsec - last.tv_nsec) as i64 / 1000;
+    *last = now;
+    let value = (micros.max(0).min(u32::MAX as i64) as u32).to_be_bytes();
     let bytes = [
+        TRACE_RECORD | phase,
+        output.stage,
+        value[0],
+        value[1],
+        value[2],
+        value[3],
+    ];
+    if !write_record(output, &bytes) {
+        fail(Err::PipeError, output);
+    }
+}
+
+/// Writes a record into the error pipe, retrying on `EINTR`
+///
+/// Writes less than PIPE_BUF are atomic, so the record is never split.
+unsafe fn write_record(output: Output, bytes: &[u8; 6]) -> bool {
+    loop {
+        let rc = libc::write(output.error_pipe, bytes.as_ptr() as *const c_void, 6);
+        if rc < 0 && nix::errno::errno() == libc::EINTR {
+            continue;
+        }
+        return rc == 6;
+    }
+}
+
+/// Creates the directory for `pivot_root_auto` in the working directory,
+/// filling in the random suffix of `name` (which starts with `/`)
+unsafe fn make_auto_old(name: &mut [u8; 19], output: Output) {
+    const CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+    let mut random = [0u8; 6];
+    for _ in 0..16 {
+        let len = libc::getrandom(random.as_mut_ptr() as *mut c_void, random.len(), 0);
+        if len != random.len() as isize {
+            if len < 0 && nix::errno::errno() == libc::EINTR {
+                continue;
+            }
+            fail(Err::ChangeRoot, output);
+        }
+        for (dst, &byte) in name[12..18].iter_mut().zip(random.iter()) {
+            *dst = CHARS[byte as usize % CHARS.len()];
+        }
+        if libc::mkdir(name[1..].as_ptr() as *const libc::c_char, 0o700) == 0 {
+            return;
+        }
+        if nix::errno::errno() != libc::EEXIST {
+            fail(Err::ChangeRoot, output);
+        }
+    }
+    fail(Err::ChangeRoot, output);
+}
+
+unsafe fn fail(code: Err, output: Output) -> ! {
+    fail_errno(code, nix::errno::errno(), output)
+}
+unsafe fn fail_errno(code: Err, errno: i32, output: Output) -> ! {
+    fail_record(code, errno, errno, output)
+}
+/// Same as `fail_errno`, but the record sent to the parent has `value`
+/// instead of errno
+unsafe fn fail_record(code: Err, errno: i32, value: i32, output: Output) -> ! {
+    // before reporting, so the directory is gone when `spawn` returns
+    if !output.pivot_old.is_null() {
+        libc::umount2(output.pivot_old, MNT_DETACH);
+        libc::rmdir(output.pivot_old);
+    }
+    let mut errno_buf = [0u8; 12];
+    let errno_str = format_u64_fixed(&mut errno_buf, errno as u32 as u64);
+    progress(
+        output,
+        &[
+            b"error ",
+            code.name().as_bytes(),
+            b" ",
+            &errno_str[..errno_str.len() - 1],
+            b"\n",
+        ],
+    );
+    // It's unclear what to do if error happened anyway
+    write_record(output, &record(code, output.stage, value));
+    libc::_exit(127);
+}
+
+fn record(code: Err, stage: u8, errno: i32) -> [u8; 6] {
+    [
         code as u8,
+        stage,
         (errno >> 24) as u8,
         (errno >> 16) as u8,
         (errno >> 8) as u8,
         (errno >> 0) as u8,
         // TODO(tailhook) rustc adds a special sentinel at the end of error
         // code. Do we really need it? Assuming our pipes are always cloexec'd.
-    ];
-    // Writes less than PIPE_BUF should be atomic. It's also unclear what
-    // to do if error happened anyway
-    libc::write(output, bytes.as_ptr() as *const c_void, 5);
-    libc::_exit(127);
+    ]
 }
 
 fn format_pid_fixed<'a>(buf: &'a mut [u8], pid: libc::pid_t) -> &'a [u8] {
+    format_u64_fixed(buf, pid as u64)
+}
+
+fn format_u64_fixed(buf: &mut [u8], value: u64) -> &[u8] {
     buf[buf.len() - 1] = 0;
-    if pid == 0 {
+    if value == 0 {
         buf[buf.len() - 2] = b'0';
         return &buf[buf.len() - 2..];
     } else {
-        let mut tmp = pid;
+        let mut tmp = value;
         // can't use stdlib function because that can allocate
         for n in (0..buf.len() - 1).rev() {
             buf[n] = (tmp % 10) as u8 + b'0';
@@ -265,6 +1059,7 @@ mod ffi {
     use libc::{c_char, c_int};
 
     pub const PR_SET_PDEATHSIG: c_int = 1;
+    pub const MPOL_BIND: c_int = 2;
     pub const CAPS_V3: u32 = 0x20080522;
 
     #[repr(C)]
@@ -2,8 +2,9 @@ use std::ffi::CString;
 
 pub struct Pivot {
     pub new_root: CString,
-    pub put_old: CString,
-    pub old_inside: CString,
+    /// `put_old` and the same path inside the new root, `None` if the
+    /// directory is created by the child
+    pub put_old: Option<(CString, CString)>,
     pub workdir: CString,
     pub unmount_old_root: bool,
 }
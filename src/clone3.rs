@@ -0,0 +1,66 @@
+use std::mem;
+
+use libc::pid_t;
+use nix::sched::CloneFlags;
+use nix::sys::signal::SIGCHLD;
+use nix::unistd::Pid;
+
+use crate::Error;
+
+/// The `struct clone_args` up to (including) the `set_tid_size` field,
+/// i.e. `CLONE_ARGS_SIZE_VER1`, the version supported since Linux 5.5
+#[repr(C)]
+struct CloneArgs {
+    flags: u64,
+    pidfd: u64,
+    child_tid: u64,
+    parent_tid: u64,
+    exit_signal: u64,
+    stack: u64,
+    stack_size: u64,
+    tls: u64,
+    set_tid: u64,
+    set_tid_size: u64,
+}
+
+/// Clones the process via `clone3` requesting specific pids
+///
+/// Unlike `nix::sched::clone` this one runs `callback` on the (copied)
+/// stack of the parent, like `fork` does. The callback is not expected to
+/// return (but if it does, the child exits with its return value).
+pub unsafe fn clone_with_tids(
+    callback: &mut dyn FnMut() -> isize,
+    flags: CloneFlags,
+    tids: &[pid_t],
+) -> Result<Pid, Error> {
+    let args = CloneArgs {
+        flags: flags.bits() as u64,
+        pidfd: 0,
+        child_tid: 0,
+        parent_tid: 0,
+        exit_signal: SIGCHLD as u64,
+        stack: 0,
+        stack_size: 0,
+        tls: 0,
+        set_tid: tids.as_ptr() as u64,
+        set_tid_size: tids.len() as u64,
+    };
+    let rc = libc::syscall(
+        libc::SYS_clone3,
+        &args as *const CloneArgs,
+        mem::size_of::<CloneArgs>(),
+    );
+    if rc < 0 {
+        let errno = nix::errno::errno();
+        // ENOSYS: no clone3 at all (before Linux 5.3),
+        // E2BIG: clone3 without `set_tid` field (before Linux 5.5)
+        if errno == libc::ENOSYS || errno == libc::E2BIG {
+            return Err(Error::SetTidUnsupported);
+        }
+        return Err(Error::Fork(errno));
+    }
+    if rc == 0 {
+        libc::_exit(callback() as i32);
+    }
+    Ok(Pid::from_raw(rc as pid_t))
+}
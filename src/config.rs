@@ -1,27 +1,79 @@
 use std::collections::HashMap;
 use std::default::Default;
-use std::ffi::CString;
+use std::ffi::{CString, OsString};
+use std::os::unix::io::{OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
-use libc::{gid_t, uid_t};
+use libc::{gid_t, pid_t, uid_t};
 use nix::sched::CloneFlags;
 use nix::sys::signal::{Signal, SIGKILL};
 
+use crate::cgroup::CgroupLimits;
 use crate::idmap::{GidMap, UidMap};
+use crate::mitigations::SpecMitigations;
+use crate::mount::Mount;
 use crate::namespace::Namespace;
+use crate::program::ProgramBase;
+use crate::reaper::Registry;
+use crate::retry::RetryPolicy;
 use crate::stdio::Closing;
 
+/// Descriptor of a namespace to join and the description of where it came
+/// from, for `Error::SetNs`
+pub type NsFile = (Closing, String);
+
 pub struct Config {
     pub death_sig: Option<Signal>,
     pub work_dir: Option<CString>,
+    pub work_dir_fd: Option<OwnedFd>,
+    pub work_dir_fd_before_root: bool,
     pub uid: Option<uid_t>,
     pub gid: Option<gid_t>,
     pub supplementary_gids: Option<Vec<gid_t>>,
     pub id_maps: Option<(Vec<UidMap>, Vec<GidMap>)>,
     pub namespaces: CloneFlags,
-    pub setns_namespaces: HashMap<Namespace, Closing>,
+    pub setns_namespaces: HashMap<Namespace, NsFile>,
     pub restore_sigmask: bool,
+    pub inherit_handlers: Option<bool>,
     pub make_group_leader: bool,
-    // TODO(tailhook) session leader
+    pub make_session_leader: bool,
+    pub inherit_ctty: Option<bool>,
+    pub steal_ctty: bool,
+    pub process_group: Option<pid_t>,
+    pub foreground_tty: Option<Closing>,
+    pub set_tid: Option<Vec<pid_t>>,
+    pub retry: Option<RetryPolicy>,
+    pub spawn_timeout: Option<Duration>,
+    pub mounts: Vec<Mount>,
+    pub output_limits: Option<(usize, usize)>,
+    pub child_stack_size: usize,
+    pub move_interfaces: Vec<String>,
+    pub persist_namespaces: Vec<(Namespace, PathBuf)>,
+    pub program_base: ProgramBase,
+    pub trace_me: bool,
+    pub cgroup: Option<(PathBuf, CgroupLimits)>,
+    pub ready_env: Option<OsString>,
+    pub program_fd: Option<OwnedFd>,
+    pub reap_orphans: bool,
+    pub keep_siblings: bool,
+    pub audit_fds: bool,
+    pub captured_namespaces: Option<Vec<(Namespace, NsFile)>>,
+    pub mitigations: SpecMitigations,
+    pub id_map_timeout: Duration,
+    pub cpu_time_limit: Option<u64>,
+    pub auto_reap: Option<Arc<Registry>>,
+    pub cloexec_fds: Vec<RawFd>,
+    pub loginuid: Option<uid_t>,
+    pub env_sorted: bool,
+    pub require_single_thread: bool,
+    pub init_groups_user: Option<String>,
+    pub unfreezer: Option<OwnedFd>,
+    pub min_internal_fd: Option<RawFd>,
+    pub comm: Option<[u8; 16]>,
+    pub secure_mounts: bool,
+    pub numa_node: Option<u32>,
 }
 
 impl Default for Config {
@@ -29,6 +81,8 @@ impl Default for Config {
         Config {
             death_sig: Some(SIGKILL),
             work_dir: None,
+            work_dir_fd: None,
+            work_dir_fd_before_root: false,
             uid: None,
             gid: None,
             supplementary_gids: None,
@@ -36,7 +90,44 @@ impl Default for Config {
             namespaces: CloneFlags::empty(),
             setns_namespaces: HashMap::new(),
             restore_sigmask: true,
+            inherit_handlers: None,
             make_group_leader: false,
+            make_session_leader: false,
+            inherit_ctty: None,
+            steal_ctty: false,
+            process_group: None,
+            foreground_tty: None,
+            set_tid: None,
+            retry: None,
+            spawn_timeout: None,
+            mounts: Vec::new(),
+            output_limits: None,
+            child_stack_size: crate::run::MIN_CHILD_STACK,
+            move_interfaces: Vec::new(),
+            persist_namespaces: Vec::new(),
+            program_base: ProgramBase::ChildCwd,
+            trace_me: false,
+            cgroup: None,
+            ready_env: None,
+            program_fd: None,
+            reap_orphans: false,
+            keep_siblings: false,
+            audit_fds: false,
+            captured_namespaces: None,
+            mitigations: SpecMitigations::default(),
+            id_map_timeout: Duration::from_secs(5),
+            cpu_time_limit: None,
+            auto_reap: None,
+            cloexec_fds: Vec::new(),
+            loginuid: None,
+            env_sorted: false,
+            require_single_thread: false,
+            init_groups_user: None,
+            unfreezer: None,
+            min_internal_fd: None,
+            comm: None,
+            secure_mounts: true,
+            numa_node: None,
         }
     }
 }
@@ -1,7 +1,9 @@
 use std::fmt::{self, Display};
+use std::os::unix::io::AsRawFd;
 
 use nix::sched::CloneFlags;
 
+use crate::environ::EnvBase;
 use crate::Command;
 
 /// This is a builder for various settings of how command may be printed
@@ -104,24 +106,39 @@ impl<'a> fmt::Display for Printer<'a> {
             for arg in cmd.args[1..].iter() {
                 write!(fmt, " {:?}", arg)?;
             }
-            if opt.print_env {
-                if let Some(ref env) = cmd.environ {
-                    write!(fmt, "; environ: {{")?;
-                    for (ref k, ref v) in env.iter() {
-                        write!(fmt, "{:?}={:?},", k, v)?;
+            let base = match cmd.env_base {
+                EnvBase::Inherit if cmd.environ.is_empty() => None,
+                EnvBase::Inherit => Some("inherited+"),
+                EnvBase::Keep(..) => Some("kept+"),
+                EnvBase::Clear => Some(""),
+                EnvBase::Raw(ref entries) => {
+                    write!(fmt, "; environ[raw {}]", entries.len())?;
+                    None
+                }
+            };
+            if let Some(base) = base {
+                if opt.print_env {
+                    write!(fmt, "; environ: {}{{", base)?;
+                    for (k, v) in cmd.environ.iter() {
+                        match *v {
+                            Some(ref v) => write!(fmt, "{:?}={:?},", k, v)?,
+                            None => write!(fmt, "-{:?},", k)?,
+                        }
                     }
                     write!(fmt, "}}")?;
-                }
-            } else {
-                if let Some(ref env) = cmd.environ {
-                    write!(fmt, "; environ[{}]", env.len())?;
+                } else {
+                    write!(fmt, "; environ[{}{}]", base, cmd.environ.len())?;
                 }
             }
             if let Some(ref dir) = cmd.chroot_dir {
                 write!(fmt, "; chroot={:?}", dir)?;
             }
-            if let Some((ref new, ref old, unmount)) = cmd.pivot_root {
-                write!(fmt, "; pivot_root=({:?};{:?};{})", new, old, unmount)?;
+            if let Some((ref new, ref old)) = cmd.pivot_root {
+                let unmount = cmd.unmount_old_root;
+                match *old {
+                    Some(ref old) => write!(fmt, "; pivot_root=({:?};{:?};{})", new, old, unmount)?,
+                    None => write!(fmt, "; pivot_root=({:?};<auto>;{})", new, unmount)?,
+                }
             }
             if cmd.config.namespaces != CloneFlags::empty() {
                 // TODO(tailhook)
@@ -129,6 +146,9 @@ impl<'a> fmt::Display for Printer<'a> {
             if let Some(ref dir) = cmd.config.work_dir {
                 write!(fmt, "; work-dir={:?}", dir)?;
             }
+            if let Some(ref fd) = cmd.config.work_dir_fd {
+                write!(fmt, "; work-dir-fd={}", fd.as_raw_fd())?;
+            }
             if let Some((ref uidm, ref gidm)) = cmd.config.id_maps {
                 write!(fmt, "; uid_map={:?}", uidm)?;
                 write!(fmt, "; gid_map={:?}", gidm)?;
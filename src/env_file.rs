@@ -0,0 +1,367 @@
+use std::error::Error as StdError;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+use crate::Command;
+
+/// Error reading environment file with `Command::env_file`
+#[derive(Debug)]
+pub enum EnvFileError {
+    /// Error reading the file
+    Io(io::Error),
+    /// Syntax error in the file
+    Syntax {
+        /// Line number (starting from one) where the error is found
+        line: usize,
+        /// Description of the error
+        message: &'static str,
+    },
+}
+
+impl fmt::Display for EnvFileError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            EnvFileError::Io(ref e) => write!(fmt, "error reading env file: {}", e),
+            EnvFileError::Syntax { line, message } => {
+                write!(
+                    fmt,
+                    "syntax error in env file at line {}: {}",
+                    line, message
+                )
+            }
+        }
+    }
+}
+
+impl StdError for EnvFileError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match *self {
+            EnvFileError::Io(ref e) => Some(e),
+            EnvFileError::Syntax { .. } => None,
+        }
+    }
+}
+
+struct Parser<'a> {
+    data: &'a [u8],
+    pos: usize,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).cloned()
+    }
+    fn bump(&mut self) -> Option<u8> {
+        let c = self.peek();
+        if let Some(c) = c {
+            self.pos += 1;
+            if c == b'\n' {
+                self.line += 1;
+            }
+        }
+        c
+    }
+    fn skip_spaces(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') = self.peek() {
+            self.bump();
+        }
+    }
+    fn skip_line(&mut self) {
+        while let Some(c) = self.bump() {
+            if c == b'\n' {
+                break;
+            }
+        }
+    }
+    fn error(&self, line: usize, message: &'static str) -> EnvFileError {
+        EnvFileError::Syntax { line, message }
+    }
+    fn end_of_entry(&mut self) -> Result<(), EnvFileError> {
+        self.skip_spaces();
+        match self.peek() {
+            None => Ok(()),
+            Some(b'\n') | Some(b'#') => {
+                self.skip_line();
+                Ok(())
+            }
+            Some(_) => Err(self.error(self.line, "unexpected characters after value")),
+        }
+    }
+    fn key(&mut self) -> Result<&'a [u8], EnvFileError> {
+        let start = self.pos;
+        while let Some(c) = self.peek() {
+            let valid = c == b'_'
+                || c.is_ascii_alphabetic()
+                || (self.pos > start && (c.is_ascii_digit() || c == b'.'));
+            if !valid {
+                break;
+            }
+            self.bump();
+        }
+        if self.pos == start {
+            return Err(self.error(self.line, "invalid variable name"));
+        }
+        Ok(&self.data[start..self.pos])
+    }
+    fn single_quoted(&mut self) -> Result<Vec<u8>, EnvFileError> {
+        let line = self.line;
+        let mut value = Vec::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error(line, "unterminated single quote")),
+                Some(b'\'') => return Ok(value),
+                Some(c) => value.push(c),
+            }
+        }
+    }
+    fn double_quoted(&mut self) -> Result<Vec<u8>, EnvFileError> {
+        let line = self.line;
+        let mut value = Vec::new();
+        loop {
+            match self.bump() {
+                None => return Err(self.error(line, "unterminated double quote")),
+                Some(b'"') => return Ok(value),
+                Some(b'\\') => match self.bump() {
+                    None => return Err(self.error(line, "unterminated double quote")),
+                    Some(b'n') => value.push(b'\n'),
+                    Some(b't') => value.push(b'\t'),
+                    Some(b'r') => value.push(b'\r'),
+                    Some(c @ b'"') | Some(c @ b'\\') | Some(c @ b'$') => value.push(c),
+                    Some(b'\n') => {} // line continuation
+                    Some(c) => {
+                        value.push(b'\\');
+                        value.push(c);
+                    }
+                },
+                Some(c) => value.push(c),
+            }
+        }
+    }
+    fn unquoted(&mut self) -> Vec<u8> {
+        let mut value = Vec::new();
+        while let Some(c) = self.peek() {
+            let comment = c == b'#' && matches!(value.last(), None | Some(b' ') | Some(b'\t'));
+            if c == b'\n' || comment {
+                break;
+            }
+            value.push(c);
+            self.bump();
+        }
+        while let Some(b' ') | Some(b'\t') | Some(b'\r') = value.last() {
+            value.pop();
+        }
+        value
+    }
+}
+
+/// Parses the contents of environment file
+///
+/// The format is `KEY=VALUE` lines, blank lines and `#` comments are
+/// ignored as well as optional `export ` prefix. Values may be single quoted
+/// (literally, no escapes) or double quoted (`\n`, `\t`, `\r`, `\"`, `\\`
+/// and `\$` escapes are supported). No variable substitution is done.
+pub fn parse(data: &[u8]) -> Result<Vec<(OsString, OsString)>, EnvFileError> {
+    let mut parser = Parser {
+        data,
+        pos: 0,
+        line: 1,
+    };
+    let mut result = Vec::new();
+    loop {
+        parser.skip_spaces();
+        match parser.peek() {
+            None => break,
+            Some(b'\n') | Some(b'#') => {
+                parser.skip_line();
+                continue;
+            }
+            Some(_) => {}
+        }
+        let mut key = parser.key()?;
+        if key == b"export" {
+            if let Some(b' ') | Some(b'\t') = parser.peek() {
+                parser.skip_spaces();
+                key = parser.key()?;
+            }
+        }
+        parser.skip_spaces();
+        if parser.peek() != Some(b'=') {
+            return Err(parser.error(parser.line, "expected `=` after variable name"));
+        }
+        parser.bump();
+        parser.skip_spaces();
+        let value = match parser.peek() {
+            Some(b'\'') => {
+                parser.bump();
+                let value = parser.single_quoted()?;
+                parser.end_of_entry()?;
+                value
+            }
+            Some(b'"') => {
+                parser.bump();
+                let value = parser.double_quoted()?;
+                parser.end_of_entry()?;
+                value
+            }
+            _ => {
+                let value = parser.unquoted();
+                parser.skip_line();
+                value
+            }
+        };
+        result.push((
+            OsStr::from_bytes(key).to_os_string(),
+            OsStr::from_bytes(&value).to_os_string(),
+        ));
+    }
+    Ok(result)
+}
+
+impl Command {
+    /// Reads environment variables from a dotenv-style file
+    ///
+    /// The file is read and parsed right away. The format is `KEY=VALUE`
+    /// lines, where:
+    ///
+    /// * blank lines and lines starting with `#` are ignored
+    /// * `export ` prefix is allowed (and ignored)
+    /// * values may be single-quoted, taken literally
+    /// * or double-quoted, with `\n`, `\t`, `\r`, `\"`, `\\`, `\$` escapes
+    /// * unquoted values end at the end of line or at ` #` comment
+    /// * no variable substitution is done, `$` is just a character
+    ///
+    /// Values may contain arbitrary (non-UTF-8) bytes. Variables are set
+    /// just like `env` does, so later calls to `env` (and later
+    /// `env_file` calls) override the values.
+    ///
+    /// Syntax errors are reported as `EnvFileError::Syntax` with the line
+    /// number, before anything from the file is set.
+    #[doc(alias = "envs_from_file")]
+    pub fn env_file<P: AsRef<Path>>(&mut self, path: P) -> Result<&mut Command, EnvFileError> {
+        let data = fs::read(path).map_err(EnvFileError::Io)?;
+        for (key, value) in parse(&data)? {
+            self.env(key, value);
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::ffi::OsStrExt;
+
+    use super::{parse, EnvFileError};
+    use crate::Command;
+
+    fn vars(data: &str) -> Vec<(String, String)> {
+        parse(data.as_bytes())
+            .unwrap()
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.into_string().unwrap()))
+            .collect()
+    }
+
+    fn one(data: &str) -> String {
+        let mut vars = vars(data);
+        assert_eq!(vars.len(), 1);
+        vars.pop().unwrap().1
+    }
+
+    fn error_line(data: &str) -> usize {
+        match parse(data.as_bytes()) {
+            Err(EnvFileError::Syntax { line, .. }) => line,
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_simple() {
+        assert_eq!(
+            vars("# comment\n\nA=1\n  export B=two words  \nC=\n"),
+            vec![
+                ("A".into(), "1".into()),
+                ("B".into(), "two words".into()),
+                ("C".into(), "".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(one("A=value # comment"), "value");
+        assert_eq!(one("A=value#not-comment"), "value#not-comment");
+        assert_eq!(
+            one("A='quoted # not comment' # comment"),
+            "quoted # not comment"
+        );
+    }
+
+    #[test]
+    fn test_single_quotes() {
+        assert_eq!(one(r#"A='a "b" \n $HOME'"#), r#"a "b" \n $HOME"#);
+        assert_eq!(one("A='multi\nline'"), "multi\nline");
+    }
+
+    #[test]
+    fn test_double_quotes() {
+        assert_eq!(one(r#"A="a \"b\" \\ \$HOME \n""#), "a \"b\" \\ $HOME \n");
+        assert_eq!(one(r#"A="it's""#), "it's");
+        assert_eq!(one(r#"A="unknown \q""#), r"unknown \q");
+        assert_eq!(one("A=\"line\\\ncontinued\""), "linecontinued");
+    }
+
+    #[test]
+    fn test_export_name() {
+        assert_eq!(vars("export=1"), vec![("export".into(), "1".into())]);
+    }
+
+    #[test]
+    fn test_non_utf8() {
+        let vars = parse(b"A=\xff\xfe\n").unwrap();
+        assert_eq!(vars[0].1.as_bytes(), b"\xff\xfe");
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(error_line("A=1\nB\n"), 2);
+        assert_eq!(error_line("A=1\n\n1A=2\n"), 3);
+        assert_eq!(error_line("A=1\nB=\"unterminated\n\n"), 2);
+        assert_eq!(error_line("A='x' y\n"), 1);
+        assert_eq!(error_line("A=1\nexport\n"), 2);
+    }
+
+    #[test]
+    fn test_layering() {
+        let dir = std::env::temp_dir().join(format!("unshare-env-file-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let first = dir.join("first.env");
+        let second = dir.join("second.env");
+        std::fs::write(&first, "A=1\nB=1\nC=1\n").unwrap();
+        std::fs::write(&second, "B=2\nC=2\n").unwrap();
+        let mut cmd = Command::new("/bin/true");
+        cmd.env_clear();
+        cmd.env_file(&first).unwrap();
+        cmd.env_file(&second).unwrap();
+        cmd.env("C", "3");
+        std::fs::remove_dir_all(&dir).unwrap();
+        let mut env = cmd.resolve_env(Vec::new());
+        env.sort();
+        assert_eq!(
+            env,
+            vec![
+                ("A".into(), "1".into()),
+                ("B".into(), "2".into()),
+                ("C".into(), "3".into()),
+            ]
+        );
+        match Command::new("/bin/true").env_file(dir.join("missing")) {
+            Err(EnvFileError::Io(_)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,546 @@
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::os::unix::ffi::OsStrExt;
+use std::slice;
+
+use crate::{Command, Error};
+
+type EnvFilter = Box<dyn Fn(&OsStr) -> bool>;
+pub(crate) type EnvRewrite = Box<dyn Fn(&OsStr, &OsStr) -> EnvAction>;
+
+/// What to do with inherited environment variable, returned by the
+/// closure passed to `Command::env_filter`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvAction {
+    /// Pass the variable unchanged
+    Keep,
+    /// Don't pass the variable to the child
+    Remove,
+    /// Pass the variable with the new value
+    Replace(OsString),
+}
+
+/// A map of variables which iterates in the order they were first inserted
+///
+/// Updating the value keeps the position of the variable, removing it
+/// shifts the ones inserted after it.
+#[derive(Clone)]
+pub(crate) struct VarMap<V> {
+    entries: Vec<(OsString, V)>,
+    index: HashMap<OsString, usize>,
+}
+
+impl<V> VarMap<V> {
+    pub fn new() -> VarMap<V> {
+        VarMap {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+    pub fn insert(&mut self, key: OsString, value: V) {
+        match self.index.get(&key) {
+            Some(&i) => self.entries[i].1 = value,
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+            }
+        }
+    }
+    pub fn remove(&mut self, key: &OsStr) -> Option<V> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.remove(i);
+        for &mut (ref key, _) in &mut self.entries[i..] {
+            *self.index.get_mut(key).expect("indexed") -= 1;
+        }
+        Some(value)
+    }
+    pub fn contains_key(&self, key: &OsStr) -> bool {
+        self.index.contains_key(key)
+    }
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+    pub fn iter(&self) -> VarIter<'_, V> {
+        VarIter(self.entries.iter())
+    }
+}
+
+pub(crate) struct VarIter<'a, V>(slice::Iter<'a, (OsString, V)>);
+
+impl<'a, V> Iterator for VarIter<'a, V> {
+    type Item = (&'a OsString, &'a V);
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(|(k, v)| (k, v))
+    }
+}
+
+impl<'a, V> IntoIterator for &'a VarMap<V> {
+    type Item = (&'a OsString, &'a V);
+    type IntoIter = VarIter<'a, V>;
+    fn into_iter(self) -> VarIter<'a, V> {
+        self.iter()
+    }
+}
+
+/// Describes which variables of the parent environment are inherited
+pub enum EnvBase {
+    /// Inherit whole environment (default)
+    Inherit,
+    /// Don't inherit anything, set by `env_clear`
+    Clear,
+    /// Inherit only variables matching any of the filters, set by `env_keep`
+    Keep(Vec<EnvFilter>),
+    /// Exactly these entries (with the trailing nuls), set by `raw_env`
+    Raw(Vec<Vec<u8>>),
+}
+
+impl EnvBase {
+    fn inherits(&self, key: &OsStr) -> bool {
+        match *self {
+            EnvBase::Inherit => true,
+            EnvBase::Clear | EnvBase::Raw(..) => false,
+            EnvBase::Keep(ref filters) => filters.iter().any(|f| f(key)),
+        }
+    }
+}
+
+impl Command {
+    /// Inherit only the specified variables of the parent environment
+    ///
+    /// This is a shortcut for "clear everything but `PATH`, `TERM` and
+    /// `LANG`" which doesn't need to read the environment of the current
+    /// process with `std::env::var_os` in advance. The parent environment is
+    /// read once, when process is spawned.
+    ///
+    /// The environment is composed in the following order:
+    ///
+    /// 1. The variables of the parent environment are filtered: the ones
+    ///    passed to any of the `env_keep` or `env_keep_matching` calls are
+    ///    kept (so multiple calls are cumulative)
+    /// 2. The closures passed to `env_filter` are applied to the variables
+    ///    which are left
+    /// 3. Variables set by `env`/`envs` and removed by `env_remove` are
+    ///    applied on top, so these always win, regardless of whether they
+    ///    were called before or after `env_keep`
+    ///
+    /// Calling `env_keep` after `env_clear` works the same as without
+    /// `env_clear`: only variables listed are inherited. But `env_clear`
+    /// called after `env_keep` discards the filters (as well as any
+    /// variables set by `env`), so nothing is inherited.
+    pub fn env_keep<I, K>(&mut self, keys: I) -> &mut Command
+    where
+        I: IntoIterator<Item = K>,
+        K: AsRef<OsStr>,
+    {
+        let keys = keys
+            .into_iter()
+            .map(|k| k.as_ref().to_os_string())
+            .collect::<HashSet<_>>();
+        self.env_keep_matching(move |key| keys.contains(key))
+    }
+
+    /// Inherit only the variables of the parent environment for which the
+    /// filter returns `true`
+    ///
+    /// The filter is called for the name of each variable of the parent
+    /// environment when process is spawned. See `env_keep` for more info.
+    pub fn env_keep_matching(&mut self, filter: impl Fn(&OsStr) -> bool + 'static) -> &mut Command {
+        match self.env_base {
+            EnvBase::Keep(ref mut filters) => filters.push(Box::new(filter)),
+            _ => self.env_base = EnvBase::Keep(vec![Box::new(filter)]),
+        }
+        self.prepared_env = None;
+        self
+    }
+
+    /// Rewrite or remove inherited environment variables
+    ///
+    /// The filter is called with the name and value of each variable
+    /// inherited from the parent environment (i.e. after `env_clear` and
+    /// `env_keep` are taken into account). It's called in the parent process
+    /// when building environment for the child, before the child is cloned,
+    /// so it's free to allocate or lock.
+    ///
+    /// Multiple filters are applied in order they are added, each one
+    /// receives the value returned by the previous one. Variables set
+    /// explicitly by `env`/`envs` are not passed to the filter, as they
+    /// override inherited ones anyway. See `env_keep` for the whole
+    /// composition order.
+    pub fn env_filter(
+        &mut self,
+        filter: impl Fn(&OsStr, &OsStr) -> EnvAction + 'static,
+    ) -> &mut Command {
+        self.env_filters.push(Box::new(filter));
+        self.prepared_env = None;
+        self
+    }
+
+    /// Pass exactly these entries as the environment of the child
+    ///
+    /// The strings are handed to `execve` as they are, without converting
+    /// them to and from `OsString`, so entries without `=` are kept too.
+    /// Nothing is inherited from the parent environment. The variables of
+    /// `ready_notification` and `env_var_with_metadata` are still added
+    /// after these entries, and `env_sorted` sorts them all.
+    ///
+    /// This replaces the variables set by `env`/`envs`/`env_remove` before
+    /// this call, and setting them afterwards makes `spawn` fail with
+    /// `Error::ConflictingOptions`. Calling `env_clear` or `env_keep`
+    /// discards the raw environment.
+    pub fn raw_env(&mut self, env: Vec<CString>) -> &mut Command {
+        self.env_base = EnvBase::Raw(env.into_iter().map(CString::into_bytes_with_nul).collect());
+        self.environ = VarMap::new();
+        self.prepared_env = None;
+        self
+    }
+
+    /// Pass the environment to the child sorted by the name of variables
+    ///
+    /// By default the order of the environment is deterministic but depends
+    /// on where the variables come from: the inherited ones are in the order
+    /// of the parent environment, then the ones set by `env` follow in the
+    /// order they were first set (setting the variable again doesn't move
+    /// it), then the `ready_notification` variable and the variables of
+    /// `env_var_with_metadata` in the order they were set. When `true` is
+    /// passed, the whole environment is sorted by name (as bytes), so it
+    /// doesn't depend on the parent environment either.
+    pub fn env_sorted(&mut self, sorted: bool) -> &mut Command {
+        self.config.env_sorted = sorted;
+        self
+    }
+
+    /// The environment the child would get if spawned now
+    ///
+    /// This is exactly the list of variables passed to `execve`, in the same
+    /// order: the parent environment (read right now, or when `prepare` was
+    /// called) filtered by `env_clear`/`env_keep`/`env_filter`, the
+    /// variables set by `env`, the `ready_notification` variable and the
+    /// ones of `env_var_with_metadata`. The metadata values which only the
+    /// child knows (`Pid`, `HostPid`, `NamespaceId`) are empty here, and
+    /// `SpawnTimestamp` is the current time.
+    ///
+    /// Returns an error when the environment can't be passed to the child,
+    /// i.e. a variable contains a nul byte.
+    pub fn resolved_env(&self) -> Result<Vec<(OsString, OsString)>, Error> {
+        let (environ, _) = self.build_environ(self.ready_fd())?;
+        Ok(environ
+            .iter()
+            .map(|pair| {
+                // without the trailing nul (or the zeros of a late value)
+                let pair = pair.split(|&b| b == 0).next().unwrap_or(&[]);
+                let eq = pair.iter().position(|&b| b == b'=').unwrap_or(pair.len());
+                let value = pair.get(eq + 1..).unwrap_or(&[]);
+                (
+                    OsStr::from_bytes(&pair[..eq]).to_os_string(),
+                    OsStr::from_bytes(value).to_os_string(),
+                )
+            })
+            .collect())
+    }
+
+    fn filter_inherited(&self, key: OsString, mut value: OsString) -> Option<(OsString, OsString)> {
+        if !self.env_base.inherits(&key)
+            || self.environ.contains_key(&key)
+            || self.metadata_env_vars.contains_key(&key)
+        {
+            return None;
+        }
+        for filter in &self.env_filters {
+            match filter(&key, &value) {
+                EnvAction::Keep => {}
+                EnvAction::Remove => return None,
+                EnvAction::Replace(new) => value = new,
+            }
+        }
+        Some((key, value))
+    }
+
+    /// Computes the environment for the child from the parent environment
+    pub(crate) fn resolve_env<I>(&self, parent: I) -> Vec<(OsString, OsString)>
+    where
+        I: IntoIterator<Item = (OsString, OsString)>,
+    {
+        let mut result = parent
+            .into_iter()
+            .filter_map(|(k, v)| self.filter_inherited(k, v))
+            .collect::<Vec<_>>();
+        for (k, v) in &self.environ {
+            if let Some(ref v) = *v {
+                result.push((k.clone(), v.clone()));
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::ffi::{CString, OsStr, OsString};
+    use std::os::unix::ffi::OsStrExt;
+
+    use crate::{Command, EnvAction, Error, MetadataVar, Stdio};
+
+    fn parent() -> Vec<(OsString, OsString)> {
+        vec![
+            ("PATH".into(), "/bin".into()),
+            ("TERM".into(), "xterm".into()),
+            ("LANG".into(), "C".into()),
+            ("HOME".into(), "/root".into()),
+        ]
+    }
+
+    fn resolve(cmd: &Command) -> Vec<(String, String)> {
+        let mut env = cmd
+            .resolve_env(parent())
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.into_string().unwrap()))
+            .collect::<Vec<_>>();
+        env.sort();
+        env
+    }
+
+    fn pairs(items: &[(&str, &str)]) -> Vec<(String, String)> {
+        items
+            .iter()
+            .map(|&(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_inherit() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env("HOME", "/home").env_remove("LANG");
+        assert_eq!(
+            resolve(&cmd),
+            pairs(&[("HOME", "/home"), ("PATH", "/bin"), ("TERM", "xterm")])
+        );
+    }
+
+    #[test]
+    fn test_keep() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env_keep(&["PATH", "TERM"]);
+        assert_eq!(resolve(&cmd), pairs(&[("PATH", "/bin"), ("TERM", "xterm")]));
+    }
+
+    #[test]
+    fn test_keep_cumulative() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env_keep(&["PATH"]);
+        cmd.env_keep_matching(|k| k == "LANG");
+        assert_eq!(resolve(&cmd), pairs(&[("LANG", "C"), ("PATH", "/bin")]));
+    }
+
+    #[test]
+    fn test_explicit_wins() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env("TERM", "dumb");
+        cmd.env("EXTRA", "1");
+        cmd.env_keep(&["PATH", "TERM", "LANG"]);
+        cmd.env_remove("LANG");
+        assert_eq!(
+            resolve(&cmd),
+            pairs(&[("EXTRA", "1"), ("PATH", "/bin"), ("TERM", "dumb")])
+        );
+    }
+
+    #[test]
+    fn test_keep_after_clear() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env_clear();
+        cmd.env("A", "B");
+        cmd.env_keep(&["PATH"]);
+        assert_eq!(resolve(&cmd), pairs(&[("A", "B"), ("PATH", "/bin")]));
+    }
+
+    #[test]
+    fn test_clear_after_keep() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env("A", "B");
+        cmd.env_keep(&["PATH"]);
+        cmd.env_clear();
+        assert_eq!(resolve(&cmd), pairs(&[]));
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut parent = parent();
+        parent.push(("LD_PRELOAD".into(), "/lib/evil.so".into()));
+        parent.push(("LD_LIBRARY_PATH".into(), "/lib".into()));
+        let mut cmd = Command::new("/bin/true");
+        cmd.env_filter(|k, _| {
+            if k.as_bytes().starts_with(b"LD_") {
+                EnvAction::Remove
+            } else {
+                EnvAction::Keep
+            }
+        });
+        cmd.env_filter(|k, v| {
+            if k == "HOME" {
+                let mut new = OsString::from("/chroot");
+                new.push(v);
+                EnvAction::Replace(new)
+            } else {
+                EnvAction::Keep
+            }
+        });
+        cmd.env("LD_BIND_NOW", "1");
+        let mut env = cmd
+            .resolve_env(parent)
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.into_string().unwrap()))
+            .collect::<Vec<_>>();
+        env.sort();
+        assert_eq!(
+            env,
+            pairs(&[
+                ("HOME", "/chroot/root"),
+                ("LANG", "C"),
+                ("LD_BIND_NOW", "1"),
+                ("PATH", "/bin"),
+                ("TERM", "xterm"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_order() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env("B", "1").env("A", "2").env("C", "3").env("B", "4");
+        cmd.env_remove("A").env("A", "5").env("TERM", "dumb");
+        let env = cmd
+            .resolve_env(parent())
+            .into_iter()
+            .map(|(k, v)| (k.into_string().unwrap(), v.into_string().unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            env,
+            pairs(&[
+                ("PATH", "/bin"),
+                ("LANG", "C"),
+                ("HOME", "/root"),
+                ("B", "4"),
+                ("A", "5"),
+                ("C", "3"),
+                ("TERM", "dumb"),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_spawned_order() {
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.stdout(Stdio::piped());
+        for i in 0..20 {
+            cmd.env(format!("VAR{}", 20 - i), i.to_string());
+        }
+        let first = cmd.output().unwrap().stdout;
+        assert_eq!(cmd.output().unwrap().stdout, first);
+        let tail = (0..20)
+            .map(|i| format!("VAR{}={}\n", 20 - i, i))
+            .collect::<String>();
+        assert!(String::from_utf8(first).unwrap().ends_with(&tail));
+
+        cmd.env_clear().env("D", "1").env("B", "2").env("C", "3");
+        assert_eq!(cmd.output().unwrap().stdout, b"D=1\nB=2\nC=3\n");
+        cmd.env_var_with_metadata("A_PID", MetadataVar::Pid)
+            .env("A", "4");
+        cmd.env_sorted(true);
+        let child = cmd.spawn().unwrap();
+        let expected = format!("A=4\nA_PID={}\nB=2\nC=3\nD=1\n", child.id());
+        let output = child.wait_with_output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    fn spawned_env(cmd: &mut Command) -> Vec<(OsString, OsString)> {
+        let output = cmd.stdout(Stdio::piped()).output().unwrap();
+        output
+            .stdout
+            .split(|&b| b == 0)
+            .filter(|line| !line.is_empty())
+            .map(|line| {
+                let eq = line.iter().position(|&b| b == b'=').unwrap();
+                (
+                    OsStr::from_bytes(&line[..eq]).into(),
+                    OsStr::from_bytes(&line[eq + 1..]).into(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_resolved_env() {
+        let mut cmd = Command::new("/usr/bin/env");
+        // values may contain newlines
+        cmd.arg("-0");
+        assert_eq!(
+            cmd.resolved_env().unwrap(),
+            env::vars_os().collect::<Vec<_>>()
+        );
+        assert_eq!(cmd.resolved_env().unwrap(), spawned_env(&mut cmd));
+
+        // the override takes the place after the inherited variables
+        cmd.env("PATH", "/nowhere").env("UNSHARE_RESOLVED", "a=b");
+        let env = cmd.resolved_env().unwrap();
+        assert_eq!(env.len(), env::vars_os().count() + 1);
+        assert_eq!(
+            env[env.len() - 2..],
+            [
+                ("PATH".into(), "/nowhere".into()),
+                ("UNSHARE_RESOLVED".into(), "a=b".into())
+            ]
+        );
+        assert_eq!(env, spawned_env(&mut cmd));
+
+        cmd.env_clear().env("B", "1").env("A", "2");
+        cmd.env_var_with_metadata("C", MetadataVar::Pid);
+        cmd.ready_notification("READY");
+        let env = cmd.resolved_env().unwrap();
+        assert_eq!(
+            env,
+            [
+                ("B".into(), "1".into()),
+                ("A".into(), "2".into()),
+                ("READY".into(), "3".into()),
+                ("C".into(), "".into()),
+            ]
+        );
+        cmd.env("BAD", "a\0b");
+        assert!(matches!(
+            cmd.resolved_env(),
+            Err(Error::NulByteInEnvironment { .. })
+        ));
+    }
+
+    #[test]
+    fn test_raw_env() {
+        let entries = vec![
+            CString::new("B=1").unwrap(),
+            CString::new("NO_EQUALS").unwrap(),
+            CString::new("B=2").unwrap(),
+        ];
+        let mut cmd = Command::new("/bin/cat");
+        cmd.arg("/proc/self/environ").stdout(Stdio::piped());
+        cmd.env("DROPPED", "1").raw_env(entries);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"B=1\0NO_EQUALS\0B=2\0");
+
+        cmd.env_var_with_metadata("C", MetadataVar::ParentPid);
+        let output = cmd.output().unwrap();
+        let expected = format!("B=1\0NO_EQUALS\0B=2\0C={}\0", std::process::id());
+        assert_eq!(output.stdout, expected.as_bytes());
+
+        cmd.env("A", "1");
+        match cmd.output() {
+            Err(Error::ConflictingOptions { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        cmd.env_clear().env("A", "1");
+        assert_eq!(cmd.output().unwrap().stdout, b"A=1\0");
+    }
+}
@@ -1,6 +1,12 @@
+use crate::fds::FdPlanError;
+use crate::namespace::Namespace;
+use crate::output::Output;
 use crate::status::ExitStatus;
+use crate::BoxError;
+use std::ffi::OsString;
 use std::fmt;
 use std::io;
+use std::path::PathBuf;
 
 use nix;
 
@@ -17,16 +23,81 @@ pub enum ErrorCode {
     ChangeRoot = 9,
     SetIdMap = 10,
     SetPGid = 11,
+    /// The value is the index of the namespace in the `setns` order in
+    /// the high 16 bits and errno in the low ones
     SetNs = 12,
     CapSet = 13,
     PreExec = 14,
+    SetSid = 15,
+    ControllingTty = 16,
+    EnvMetadata = 17,
+    /// Same as `Exec` but the program exists and its interpreter doesn't
+    ExecInterpreter = 18,
+    Mount = 19,
+    TraceMe = 20,
+    /// Reported for each descriptor found by `audit_fds_for_escape`,
+    /// the value is the descriptor number instead of errno
+    EscapeFd = 21,
+    Mitigation = 22,
+    MitigationUnsupported = 23,
+    OpenInRoot = 24,
+    ResourceLimit = 25,
+    LoginUid = 26,
+    /// The value is the index of the mount in the high 16 bits and errno
+    /// in the low ones
+    MountAt = 27,
+    NumaBind = 28,
+}
+
+/// A stage of setting up the child, see `Error::stage`
+///
+/// Stages are listed in the order they happen, the ones done in the child
+/// match the `Child` phases of `SpawnPhase`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SetupStage {
+    /// Right after `clone`, before the child is woken up
+    Startup,
+    /// Writing uid/gid maps by the parent
+    WriteIdMaps,
+    /// Joining namespaces, session and controlling terminal setup,
+    /// `env_var_with_metadata` variables and loginuid
+    Namespaces,
+    /// `pivot_root`, `chroot` and mounting filesystems
+    ChangeRoot,
+    /// Setting user, groups and capabilities
+    SetUser,
+    /// Setting working directory and file descriptors
+    Fds,
+    /// Resource limits, mitigations and `pre_exec` callback
+    PreExec,
+    /// Executing the program
+    Exec,
+}
+
+impl SetupStage {
+    /// Decodes the stage byte of the records sent by the child
+    pub(crate) fn from_id(id: u8) -> Option<SetupStage> {
+        use self::SetupStage::*;
+        Some(match id {
+            0 => Startup,
+            1 => WriteIdMaps,
+            2 => Namespaces,
+            3 => ChangeRoot,
+            4 => SetUser,
+            5 => Fds,
+            6 => PreExec,
+            7 => Exec,
+            _ => return None,
+        })
+    }
 }
 
 /// Error runnning process
 ///
 /// This type has very large number of options and it's enum only to be
 /// compact. Probably you shouldn't match on the error cases but just format
-/// it for user into string.
+/// it for user into string. The errors of the child setup are returned in
+/// `AtStage`, so match on `Error::inner` for them.
 #[derive(Debug)]
 pub enum Error {
     /// Unknown nix error
@@ -45,8 +116,44 @@ pub enum Error {
     CreatePipe(i32),
     /// Error when forking/cloning process
     Fork(i32),
+    /// Forking/cloning process failed with a transient error on every
+    /// attempt allowed by `Command::retry_transient`
+    ForkAttempts {
+        /// The error code of the last attempt
+        errno: i32,
+        /// Number of attempts made
+        attempts: u32,
+    },
     /// Error when running execve() systemcall
+    ///
+    /// This is reported by the child after everything else is set up, so
+    /// it's always an error of the `execve` itself, e.g. `ENOENT` if the
+    /// program is not found, `EACCES` if the file is not executable, or
+    /// `ENOEXEC` if it's not a valid executable. Note that glibc runs
+    /// executable files without `#!` line using `/bin/sh`, so `ENOEXEC`
+    /// is only returned if that fails too.
     Exec(i32),
+    /// Error when running execve() systemcall inside `chroot_dir` or
+    /// `pivot_root`
+    ///
+    /// Everything is the same as for `Exec`, but the error tells which file
+    /// was actually tried, because looking up the program path on the host
+    /// filesystem is misleading.
+    ExecInRoot {
+        /// The error code of `execve`
+        errno: i32,
+        /// The new root directory as seen by the parent process
+        root: PathBuf,
+        /// The program path inside the new root, absolute unless the
+        /// program is looked up in `PATH` or `work_dir_fd` is used
+        path: PathBuf,
+        /// The interpreter of the program (ELF dynamic linker or `#!`
+        /// interpreter) is not found inside the new root, while the program
+        /// itself exists
+        ///
+        /// The path is empty if it can't be read by the parent process.
+        missing_interpreter: Option<PathBuf>,
+    },
     /// Error when setting working directory specified by user
     Chdir(i32),
     /// Unable to set death signal (probably signal number invalid)
@@ -77,21 +184,194 @@ pub enum Error {
     /// Note that failing to run the binary results to `SedIdMap(sys_errno)`,
     /// this error contains status code of command that was succesfullly
     /// spawned.
+    ///
+    /// Not returned anymore, the failures are reported as `IdMapHelper`.
     AuxCommandExited(i32),
     /// Auxillary command was killed by signal
     ///
     /// Similar to `AuxCommandExited` but when command was killed
     AuxCommandKilled(i32),
+    /// The command set by `set_id_map_commands` failed, was killed after
+    /// `id_map_commands_timeout`, or exited successfully without writing
+    /// the map
+    ///
+    /// Failing to run the command at all results in `SetIdMap(errno)`.
+    IdMapHelper {
+        /// The command
+        program: PathBuf,
+        /// The arguments passed to the command
+        args: Vec<OsString>,
+        /// The exit status, or `None` if the command timed out
+        status: Option<ExitStatus>,
+        /// Both stdout and stderr of the command (first 4 KiB)
+        stderr: String,
+    },
     /// Error when calling setpgid function
     SetPGid(i32),
-    /// Error when calling setns syscall
-    SetNs(i32),
+    /// Error joining a namespace set by `set_namespace` (or captured
+    /// by `spawn_many`)
+    SetNs {
+        /// The namespace which is joined
+        namespace: Namespace,
+        /// Where the namespace descriptor came from, i.e.
+        /// `fd 5 (net:[4026531992])` or a path in `/proc`
+        source_description: String,
+        /// The error code of `setns` (or of opening the namespace file)
+        errno: i32,
+    },
+    /// Error mounting a filesystem requested by `Command::mount` or
+    /// `private_shm`
+    MountAt {
+        /// The target of the mount
+        target: PathBuf,
+        /// The error code of `mount`
+        errno: i32,
+    },
     /// Error when calling capset syscall
     CapSet(i32),
     /// Before unfreeze callback error
     BeforeUnfreeze(Box<dyn (::std::error::Error) + Send + Sync + 'static>),
     /// Before exec callback error
     PreExec(i32),
+    /// Error when calling setsid function
+    SetSid(i32),
+    /// Error when attaching or detaching the controlling terminal
+    ControllingTty(i32),
+    /// Program path contains a nul byte
+    NulByteInProgram,
+    /// Argument contains a nul byte, index zero is `arg0`
+    NulByteInArgument {
+        /// Index of the argument with the nul byte
+        index: usize,
+    },
+    /// Path or option of a mount contains a nul byte
+    NulByteInMount {
+        /// Index of the mount, in the order the methods adding them were
+        /// called
+        index: usize,
+    },
+    /// Either name or value of environment variable contains a nul byte
+    NulByteInEnvironment {
+        /// Name of the environment variable
+        key: OsString,
+    },
+    /// Arguments and environment together exceed the `ARG_MAX` limit
+    ///
+    /// This is checked before starting the process, because `execve` just
+    /// returns `E2BIG` in this case.
+    ArgumentListTooLong {
+        /// Size of arguments, environment, and pointers to them in bytes
+        bytes: usize,
+        /// The limit (`sysconf(_SC_ARG_MAX)`)
+        limit: usize,
+    },
+    /// Error getting a value for environment variable set by
+    /// `env_var_with_metadata`
+    EnvMetadata(i32),
+    /// Uid or gid map entries are invalid (see `UidMap::validate_ranges`)
+    InvalidIdMap {
+        /// Description of the problem
+        message: String,
+    },
+    /// Kernel doesn't support `clone3` with `set_tid` (Linux 5.5 is
+    /// required), see `Command::set_tid`
+    SetTidUnsupported,
+    /// The child didn't exec in time set by `Command::spawn_timeout`
+    SpawnTimeout,
+    /// Options set on the command can't be used together
+    ConflictingOptions {
+        /// Description of the problem
+        message: &'static str,
+    },
+    /// Error mounting filesystem requested by `mount_sysfs`
+    Mount(i32),
+    /// Error moving data by `PipeReader::splice_to` or
+    /// `PipeWriter::splice_from`
+    Splice(i32),
+    /// Error moving network interface requested by `move_interface`
+    MoveInterface {
+        /// The name of the interface
+        name: String,
+        /// The error code returned by the kernel, i.e. `ENODEV` if there
+        /// is no such interface or `EPERM` without `CAP_NET_ADMIN`
+        errno: i32,
+    },
+    /// Error persisting namespace requested by `persist_namespace`
+    PersistNamespace {
+        /// The path the namespace is bind-mounted to
+        path: PathBuf,
+        /// The error code of creating the file or of `mount`
+        errno: i32,
+    },
+    /// Error calling `ptrace(PTRACE_TRACEME)` requested by `trace_me`
+    TraceMe(i32),
+    /// Error setting up cgroup requested by `cgroup_limits`
+    Cgroup {
+        /// The cgroup directory if it can't be created, or the file written
+        path: PathBuf,
+        /// The value rejected, `None` if the directory can't be created
+        value: Option<String>,
+        /// The error code of `mkdir` or `write`
+        errno: i32,
+    },
+    /// Error reading information about the child from `/proc`, i.e. by
+    /// `Child::start_time`. It's `ESRCH` if the child has exited
+    ProcessInfo(i32),
+    /// Error creating the memfd or writing the program to it in
+    /// `Command::sealed_program`
+    WriteProgram(i32),
+    /// Error sealing the memfd in `Command::sealed_program`
+    SealProgram(i32),
+    /// Descriptors of the child refer to directories outside of the new
+    /// root, found by `audit_fds_for_escape`
+    FdsOutsideRoot {
+        /// The descriptor numbers in the child
+        fds: Vec<i32>,
+    },
+    /// Error applying `speculation_mitigations` (i.e. `EPERM` when enabling
+    /// the mitigation which is force-disabled)
+    Mitigation(i32),
+    /// The mitigation requested by `speculation_mitigations` is not supported
+    /// by this kernel or CPU, or can't be controlled per process (`EINVAL` or
+    /// `ENXIO` from `prctl`)
+    MitigationUnsupported(i32),
+    /// Error opening a file inside the new root for `Stdio::null_in_root`
+    /// or `Fd::read_in_root`/`write_in_root`
+    OpenInRoot(i32),
+    /// Error setting resource limit requested by `cpu_time_limit`
+    ResourceLimit(i32),
+    /// The descriptors are configured inconsistently, see `Command::fd_plan`
+    FdConflict(FdPlanError),
+    /// Error writing `/proc/self/loginuid` requested by `loginuid`
+    LoginUid(i32),
+    /// The process has other threads, refused because of
+    /// `require_single_thread`
+    MultiThreaded {
+        /// The number of threads of the process, including the calling one
+        thread_count: usize,
+    },
+    /// A hook registered with `at_spawn_check` returned an error
+    SpawnCheck(BoxError),
+    /// The process set by `external_unfreezer` aborted the spawn with this
+    /// error code
+    UnfreezeAborted(i32),
+    /// The process set by `external_unfreezer` doesn't follow the protocol
+    UnfreezerProtocol {
+        /// Description of the problem
+        message: &'static str,
+    },
+    /// The command run by `Command::output_timeout` didn't exit in time
+    ///
+    /// The child is killed, the output has its status and all it wrote
+    /// before the deadline.
+    Timeout(Box<Output>),
+    /// Error setting the memory policy requested by `numa_bind`
+    NumaBind(i32),
+    /// The error of the child setup with the stage which has failed
+    ///
+    /// Formatted the same way as the inner error, use `Error::inner` to
+    /// match on it.
+    AtStage(SetupStage, Box<Error>),
 }
 
 impl Error {
@@ -103,7 +383,9 @@ impl Error {
             &NixError => None,
             &CreatePipe(x) => Some(x),
             &Fork(x) => Some(x),
+            &ForkAttempts { errno, .. } => Some(errno),
             &Exec(x) => Some(x),
+            &ExecInRoot { errno, .. } => Some(errno),
             &Chdir(x) => Some(x),
             &ParentDeathSignal(x) => Some(x),
             &PipeError(x) => Some(x),
@@ -114,11 +396,48 @@ impl Error {
             &SetIdMap(x) => Some(x),
             &AuxCommandExited(..) => None,
             &AuxCommandKilled(..) => None,
+            &IdMapHelper { .. } => None,
             &SetPGid(x) => Some(x),
-            &SetNs(x) => Some(x),
+            &SetNs { errno, .. } => Some(errno),
+            &MountAt { errno, .. } => Some(errno),
             &CapSet(x) => Some(x),
             &BeforeUnfreeze(..) => None,
             &PreExec(x) => Some(x),
+            &SetSid(x) => Some(x),
+            &ControllingTty(x) => Some(x),
+            &NulByteInProgram => None,
+            &NulByteInArgument { .. } => None,
+            &NulByteInMount { .. } => None,
+            &NulByteInEnvironment { .. } => None,
+            &ArgumentListTooLong { .. } => None,
+            &EnvMetadata(x) => Some(x),
+            &InvalidIdMap { .. } => None,
+            &SetTidUnsupported => None,
+            &SpawnTimeout => None,
+            &ConflictingOptions { .. } => None,
+            &Mount(x) => Some(x),
+            &Splice(x) => Some(x),
+            &MoveInterface { errno, .. } => Some(errno),
+            &PersistNamespace { errno, .. } => Some(errno),
+            &Cgroup { errno, .. } => Some(errno),
+            &TraceMe(x) => Some(x),
+            &ProcessInfo(x) => Some(x),
+            &WriteProgram(x) => Some(x),
+            &SealProgram(x) => Some(x),
+            &FdsOutsideRoot { .. } => None,
+            &FdConflict(..) => None,
+            &MultiThreaded { .. } => None,
+            &SpawnCheck(..) => None,
+            &UnfreezerProtocol { .. } => None,
+            &Mitigation(x) => Some(x),
+            &MitigationUnsupported(x) => Some(x),
+            &OpenInRoot(x) => Some(x),
+            &ResourceLimit(x) => Some(x),
+            &LoginUid(x) => Some(x),
+            &UnfreezeAborted(x) => Some(x),
+            &Timeout(..) => None,
+            &NumaBind(x) => Some(x),
+            &AtStage(_, ref err) => err.raw_os_error(),
         }
     }
 }
@@ -131,7 +450,9 @@ impl Error {
             &NixError => "some unknown nix error",
             &CreatePipe(_) => "can't create pipe",
             &Fork(_) => "error when forking",
+            &ForkAttempts { .. } => "error when forking",
             &Exec(_) => "error when executing",
+            &ExecInRoot { .. } => "error when executing",
             &Chdir(_) => "error when setting working directory",
             &ParentDeathSignal(_) => "error when death signal",
             &PipeError(_) => "error in signalling pipe",
@@ -142,11 +463,130 @@ impl Error {
             &SetIdMap(_) => "error setting uid/gid mappings",
             &AuxCommandExited(_) => "aux command exited with non-zero code",
             &AuxCommandKilled(_) => "aux command was killed by signal",
+            &IdMapHelper { .. } => "id map command failed",
             &SetPGid(_) => "error when calling setpgid",
-            &SetNs(_) => "error when calling setns",
+            &SetNs { .. } => "error joining namespace",
+            &MountAt { .. } => "error mounting filesystem",
             &CapSet(_) => "error when setting capabilities",
             &BeforeUnfreeze(_) => "error in before_unfreeze callback",
             &PreExec(_) => "error in pre_exec callback",
+            &SetSid(_) => "error when calling setsid",
+            &ControllingTty(_) => "error setting up controlling terminal",
+            &NulByteInProgram => "program path contains a nul byte",
+            &NulByteInArgument { .. } => "argument contains a nul byte",
+            &NulByteInMount { .. } => "mount path or option contains a nul byte",
+            &NulByteInEnvironment { .. } => "environment variable contains a nul byte",
+            &ArgumentListTooLong { .. } => "argument list too long",
+            &EnvMetadata(_) => "error getting value of metadata environment variable",
+            &InvalidIdMap { .. } => "invalid uid/gid mapping",
+            &SetTidUnsupported => "clone3 with set_tid is not supported by the kernel",
+            &SpawnTimeout => "timed out waiting for the child to exec",
+            &ConflictingOptions { .. } => "conflicting options",
+            &Mount(_) => "error mounting filesystem",
+            &Splice(_) => "error moving data through pipe",
+            &MoveInterface { .. } => "error moving network interface",
+            &PersistNamespace { .. } => "error persisting namespace at",
+            &Cgroup { .. } => "error setting up cgroup at",
+            &TraceMe(_) => "error when requesting to be traced",
+            &ProcessInfo(_) => "error reading process information",
+            &WriteProgram(_) => "error writing program to memfd",
+            &SealProgram(_) => "error sealing program memfd",
+            &FdsOutsideRoot { .. } => "descriptors refer to directories outside of the new root",
+            &FdConflict(..) => "conflicting descriptor configuration",
+            &MultiThreaded { .. } => "process has multiple threads, unsafe to spawn from",
+            &SpawnCheck(..) => "spawn check failed",
+            &UnfreezerProtocol { .. } => "external unfreezer protocol error",
+            &Mitigation(_) => "error applying process mitigations",
+            &MitigationUnsupported(_) => "mitigation is not supported by this kernel or CPU",
+            &OpenInRoot(_) => "error opening file inside the new root",
+            &ResourceLimit(_) => "error setting resource limit",
+            &LoginUid(_) => "error setting loginuid",
+            &UnfreezeAborted(_) => "spawn aborted by external unfreezer",
+            &Timeout(..) => "command timed out",
+            &NumaBind(_) => "error binding memory to NUMA node",
+            &AtStage(_, ref err) => err.title(),
+        }
+    }
+}
+
+impl Error {
+    /// The stage of the child setup which has failed
+    ///
+    /// The child reports the stage it was in together with the error, and
+    /// so does the parent for writing id maps, so this tells how far the
+    /// setup went without parsing the message. Returns `None` for other
+    /// errors, i.e. failures to create pipes or to `clone`, which happen
+    /// before the child is started.
+    pub fn stage(&self) -> Option<SetupStage> {
+        match *self {
+            Error::AtStage(stage, _) => Some(stage),
+            _ => None,
+        }
+    }
+
+    /// The error without the stage of `AtStage`, i.e. `Exec(ENOENT)`
+    ///
+    /// Other errors are returned as is.
+    pub fn inner(&self) -> &Error {
+        match *self {
+            Error::AtStage(_, ref err) => err.inner(),
+            _ => self,
+        }
+    }
+
+    /// Same as `inner`, but takes the error by value
+    ///
+    /// Handy to match on the result of `spawn`, i.e.
+    /// `cmd.spawn().map_err(Error::into_inner)`.
+    pub fn into_inner(self) -> Error {
+        match self {
+            Error::AtStage(_, err) => err.into_inner(),
+            err => err,
+        }
+    }
+
+    fn hint(&self) -> Option<&'static str> {
+        match *self {
+            Error::Exec(libc::ENOENT) => Some("program or its interpreter not found"),
+            Error::ExecInRoot {
+                errno: libc::ENOENT,
+                missing_interpreter: Some(_),
+                ..
+            } => Some("interpreter is not found inside the new root"),
+            Error::ExecInRoot {
+                errno: libc::ENOENT,
+                ..
+            } => Some("program is not found inside the new root"),
+            Error::ExecInRoot { errno, .. } => Error::Exec(errno).hint(),
+            Error::AtStage(_, ref err) => err.hint(),
+            Error::Exec(libc::EACCES) => {
+                Some("file is not executable or directory is not searchable")
+            }
+            Error::SetNs {
+                errno: libc::EINVAL,
+                ..
+            } => Some(
+                "descriptor doesn't refer to a namespace of this type, \
+                 or the namespace can't be joined from here",
+            ),
+            Error::OpenInRoot(libc::ENOENT) => Some(
+                "file is not found inside the new root, `null_in_root` needs `/dev/null` there",
+            ),
+            Error::NumaBind(libc::ENOSYS) => Some("the kernel is built without NUMA support"),
+            Error::NumaBind(libc::EINVAL) => {
+                Some("the node doesn't exist or has no memory (see /sys/devices/system/node)")
+            }
+            Error::LoginUid(libc::EPERM) | Error::LoginUid(libc::EACCES) => Some(
+                "changing loginuid once it's set requires CAP_AUDIT_CONTROL \
+                 in the initial user namespace, and may be forbidden by the kernel",
+            ),
+            Error::LoginUid(libc::EINVAL) => {
+                Some("uid is not mapped in the user namespace of the child")
+            }
+            Error::Exec(libc::ENOEXEC) => {
+                Some("file is not a valid executable (missing `#!` line?)")
+            }
+            _ => None,
         }
     }
 }
@@ -154,31 +594,130 @@ impl Error {
 impl fmt::Display for Error {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
         use crate::Error::*;
+        if let AtStage(_, err) = self {
+            return fmt::Display::fmt(err, fmt);
+        }
         if let Some(code) = self.raw_os_error() {
+            match self {
+                ExecInRoot { root, path, .. } => {
+                    write!(fmt, "{} {:?} inside {:?}", self.title(), path, root)?;
+                }
+                MoveInterface { name, .. } => write!(fmt, "{} {:?}", self.title(), name)?,
+                PersistNamespace { path, .. } => write!(fmt, "{} {:?}", self.title(), path)?,
+                Cgroup {
+                    path,
+                    value: Some(value),
+                    ..
+                } => write!(fmt, "{} {:?} writing {:?}", self.title(), path, value)?,
+                Cgroup { path, .. } => write!(fmt, "{} {:?}", self.title(), path)?,
+                SetNs {
+                    namespace,
+                    source_description,
+                    ..
+                } => write!(
+                    fmt,
+                    "error joining {} namespace from {}",
+                    namespace, source_description
+                )?,
+                MountAt { target, .. } => write!(fmt, "{} at {:?}", self.title(), target)?,
+                _ => write!(fmt, "{}", self.title())?,
+            }
             let errno = nix::errno::from_i32(code);
             if let nix::errno::Errno::UnknownErrno = errno {
                 // May be OS knows error name better
-                write!(
-                    fmt,
-                    "{}: {}",
-                    self.title(),
-                    io::Error::from_raw_os_error(code)
-                )
+                write!(fmt, ": {}", io::Error::from_raw_os_error(code))?;
             } else {
                 // Format similar to that of std::io::Error
-                write!(
-                    fmt,
-                    "{}: {} (os error {})",
-                    self.title(),
-                    errno.desc(),
-                    code
-                )
+                write!(fmt, ": {} (os error {})", errno.desc(), code)?;
             }
+            match self {
+                ExecInRoot {
+                    missing_interpreter: Some(interp),
+                    ..
+                } if !interp.as_os_str().is_empty() => {
+                    write!(
+                        fmt,
+                        ": interpreter {:?} is not found inside the new root",
+                        interp
+                    )?;
+                }
+                ForkAttempts { attempts, .. } => {
+                    write!(fmt, ": gave up after {} attempts", attempts)?;
+                }
+                _ => {
+                    if let Some(hint) = self.hint() {
+                        write!(fmt, ": {}", hint)?;
+                    }
+                }
+            }
+            Ok(())
         } else {
             match self {
                 BeforeUnfreeze(err) => {
                     write!(fmt, "{}: {}", self.title(), err)
                 }
+                NulByteInArgument { index } => {
+                    write!(fmt, "{}: argument #{}", self.title(), index)
+                }
+                NulByteInMount { index } => {
+                    write!(fmt, "{}: mount #{}", self.title(), index)
+                }
+                InvalidIdMap { message } => {
+                    write!(fmt, "{}: {}", self.title(), message)
+                }
+                ConflictingOptions { message } => {
+                    write!(fmt, "{}: {}", self.title(), message)
+                }
+                NulByteInEnvironment { key } => {
+                    write!(fmt, "{}: {:?}", self.title(), key)
+                }
+                FdsOutsideRoot { fds } => {
+                    write!(fmt, "{}: {:?}", self.title(), fds)
+                }
+                FdConflict(err) => {
+                    write!(fmt, "{}: {}", self.title(), err)
+                }
+                MultiThreaded { thread_count } => {
+                    write!(fmt, "{}: {} threads", self.title(), thread_count)
+                }
+                SpawnCheck(err) => {
+                    write!(fmt, "{}: {}", self.title(), err)
+                }
+                UnfreezerProtocol { message } => {
+                    write!(fmt, "{}: {}", self.title(), message)
+                }
+                Timeout(output) => write!(
+                    fmt,
+                    "{}: killed after writing {} bytes to stdout and {} bytes to stderr",
+                    self.title(),
+                    output.stdout_total,
+                    output.stderr_total
+                ),
+                IdMapHelper {
+                    program,
+                    status,
+                    stderr,
+                    ..
+                } => {
+                    write!(fmt, "{} {:?}: ", self.title(), program)?;
+                    match status {
+                        None => write!(fmt, "timed out")?,
+                        Some(s) if s.success() => write!(fmt, "map is not written")?,
+                        Some(s) => write!(fmt, "{}", s)?,
+                    }
+                    if !stderr.trim().is_empty() {
+                        write!(fmt, ": {}", stderr.trim())?;
+                    }
+                    Ok(())
+                }
+                ArgumentListTooLong { bytes, limit } => write!(
+                    fmt,
+                    "{}: arguments and environment take {} bytes, \
+                     but limit is {} bytes (ARG_MAX)",
+                    self.title(),
+                    bytes,
+                    limit
+                ),
                 _ => write!(fmt, "{}", self.title()),
             }
         }
@@ -190,18 +729,6 @@ pub fn result<T, E: IntoError>(code: ErrorCode, r: Result<T, E>) -> Result<T, Er
     r.map_err(|e| e.into_error(code))
 }
 
-#[inline]
-pub fn cmd_result<E: IntoError>(
-    def_code: ErrorCode,
-    r: Result<ExitStatus, E>,
-) -> Result<(), Error> {
-    match r.map_err(|e| e.into_error(def_code))? {
-        ExitStatus::Exited(0) => Ok(()),
-        ExitStatus::Exited(x) => Err(Error::AuxCommandExited(x as i32)),
-        ExitStatus::Signaled(x, _) => Err(Error::AuxCommandKilled(x as i32)),
-    }
-}
-
 pub trait IntoError {
     fn into_error(self, code: ErrorCode) -> Error;
 }
@@ -228,6 +755,40 @@ impl IntoError for Error {
 }
 
 impl ErrorCode {
+    /// Name of the error as written by `capture_child_errors`
+    pub fn name(self) -> &'static str {
+        use self::ErrorCode as C;
+        match self {
+            C::CreatePipe => "create_pipe",
+            C::Fork => "fork",
+            C::Exec => "exec",
+            C::Chdir => "chdir",
+            C::ParentDeathSignal => "parent_death_signal",
+            C::PipeError => "pipe_error",
+            C::StdioError => "stdio_error",
+            C::SetUser => "set_user",
+            C::ChangeRoot => "change_root",
+            C::SetIdMap => "set_id_map",
+            C::SetPGid => "set_pgid",
+            C::SetNs => "set_ns",
+            C::CapSet => "cap_set",
+            C::PreExec => "pre_exec",
+            C::SetSid => "set_sid",
+            C::ControllingTty => "controlling_tty",
+            C::EnvMetadata => "env_metadata",
+            C::ExecInterpreter => "exec_interpreter",
+            C::Mount => "mount",
+            C::TraceMe => "trace_me",
+            C::EscapeFd => "escape_fd",
+            C::Mitigation => "mitigation",
+            C::MitigationUnsupported => "mitigation_unsupported",
+            C::OpenInRoot => "open_in_root",
+            C::ResourceLimit => "resource_limit",
+            C::LoginUid => "login_uid",
+            C::MountAt => "mount_at",
+            C::NumaBind => "numa_bind",
+        }
+    }
     pub fn wrap(self, errno: i32) -> Error {
         use self::Error as E;
         use self::ErrorCode as C;
@@ -243,9 +804,24 @@ impl ErrorCode {
             C::ChangeRoot => E::ChangeRoot(errno),
             C::SetIdMap => E::SetIdMap(errno),
             C::SetPGid => E::SetPGid(errno),
-            C::SetNs => E::SetNs(errno),
+            // the namespace is known by the caller only
+            C::SetNs => E::UnknownError,
+            C::MountAt => E::UnknownError,
             C::CapSet => E::CapSet(errno),
             C::PreExec => E::PreExec(errno),
+            C::SetSid => E::SetSid(errno),
+            C::ControllingTty => E::ControllingTty(errno),
+            C::EnvMetadata => E::EnvMetadata(errno),
+            C::ExecInterpreter => E::Exec(errno),
+            C::Mount => E::Mount(errno),
+            C::TraceMe => E::TraceMe(errno),
+            C::EscapeFd => E::FdsOutsideRoot { fds: vec![errno] },
+            C::Mitigation => E::Mitigation(errno),
+            C::MitigationUnsupported => E::MitigationUnsupported(errno),
+            C::OpenInRoot => E::OpenInRoot(errno),
+            C::ResourceLimit => E::ResourceLimit(errno),
+            C::LoginUid => E::LoginUid(errno),
+            C::NumaBind => E::NumaBind(errno),
         }
     }
     pub fn from_i32(code: i32, errno: i32) -> Error {
@@ -263,11 +839,162 @@ impl ErrorCode {
             c if c == C::ChangeRoot as i32 => E::ChangeRoot(errno),
             c if c == C::SetIdMap as i32 => E::SetIdMap(errno),
             c if c == C::SetPGid as i32 => E::SetPGid(errno),
-            c if c == C::SetNs as i32 => E::SetNs(errno),
+            // no SetNs and MountAt, the records are decoded by `Command`
             c if c == C::CapSet as i32 => E::CapSet(errno),
             // no BeforeUnfreeze, because can't be in a child
             c if c == C::PreExec as i32 => E::PreExec(errno),
+            c if c == C::SetSid as i32 => E::SetSid(errno),
+            c if c == C::ControllingTty as i32 => E::ControllingTty(errno),
+            c if c == C::EnvMetadata as i32 => E::EnvMetadata(errno),
+            c if c == C::ExecInterpreter as i32 => E::Exec(errno),
+            c if c == C::Mount as i32 => E::Mount(errno),
+            c if c == C::TraceMe as i32 => E::TraceMe(errno),
+            c if c == C::EscapeFd as i32 => E::FdsOutsideRoot { fds: vec![errno] },
+            c if c == C::Mitigation as i32 => E::Mitigation(errno),
+            c if c == C::MitigationUnsupported as i32 => E::MitigationUnsupported(errno),
+            c if c == C::OpenInRoot as i32 => E::OpenInRoot(errno),
+            c if c == C::ResourceLimit as i32 => E::ResourceLimit(errno),
+            c if c == C::LoginUid as i32 => E::LoginUid(errno),
+            c if c == C::NumaBind as i32 => E::NumaBind(errno),
             _ => E::UnknownError,
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::{Command, Error, SetupStage};
+
+    #[test]
+    fn test_stage() {
+        let stage = |cmd: &mut Command| cmd.spawn().err().and_then(|e| e.stage());
+        assert_eq!(
+            stage(Command::new("/bin/true").current_dir("/nonexistent")),
+            Some(SetupStage::Fds)
+        );
+        assert_eq!(
+            stage(Command::new("/bin/true").chroot_dir("/nonexistent")),
+            Some(SetupStage::ChangeRoot)
+        );
+        assert_eq!(
+            stage(&mut Command::new("/nonexistent")),
+            Some(SetupStage::Exec)
+        );
+        let mut cmd = Command::new("/bin/true");
+        unsafe { cmd.pre_exec(|| Err(std::io::Error::from_raw_os_error(libc::EPERM))) };
+        assert_eq!(stage(&mut cmd), Some(SetupStage::PreExec));
+        // the errors of the parent are not tagged, even if the same error
+        // is reported by the child too
+        assert_eq!(Error::Fork(libc::EAGAIN).stage(), None);
+        assert_eq!(Error::StdioError(libc::EBADF).stage(), None);
+    }
+
+    #[test]
+    fn test_exec_not_found() {
+        match Command::new("/nonexistent/program")
+            .spawn()
+            .map_err(Error::into_inner)
+        {
+            Err(e @ Error::Exec(libc::ENOENT)) => {
+                assert!(e.to_string().starts_with("error when executing"));
+                assert!(e
+                    .to_string()
+                    .ends_with("program or its interpreter not found"));
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        match Command::new("nonexistent-program-in-path")
+            .spawn()
+            .map_err(Error::into_inner)
+        {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_exec_not_executable() {
+        let path = std::env::temp_dir().join(format!("unshare-noexec-{}", std::process::id()));
+        fs::write(&path, "#!/bin/sh\n").unwrap();
+        let result = Command::new(&path).spawn();
+        fs::remove_file(&path).unwrap();
+        match result.map_err(Error::into_inner) {
+            Err(Error::Exec(libc::EACCES)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("unshare-{}-{}", name, std::process::id()));
+        fs::create_dir_all(root.join("bin")).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_exec_in_root_not_found() {
+        let root = temp_root("noprog");
+        let result = Command::new("/bin/prog").chroot_dir(&root).spawn();
+        fs::remove_dir_all(&root).unwrap();
+        match result.map_err(Error::into_inner) {
+            Err(
+                e @ Error::ExecInRoot {
+                    errno: libc::ENOENT,
+                    missing_interpreter: None,
+                    ..
+                },
+            ) => {
+                assert_eq!(
+                    e.to_string(),
+                    format!(
+                        "error when executing \"/bin/prog\" inside {:?}: \
+                         No such file or directory (os error 2): \
+                         program is not found inside the new root",
+                        root
+                    )
+                );
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_exec_in_root_missing_interpreter() {
+        let root = temp_root("nointerp");
+        fs::copy("/bin/true", root.join("bin/true")).unwrap();
+        let result = Command::new("./true")
+            .chroot_dir(&root)
+            .current_dir("/bin")
+            .spawn();
+        fs::remove_dir_all(&root).unwrap();
+        match result.map_err(Error::into_inner) {
+            Err(
+                ref e @ Error::ExecInRoot {
+                    errno: libc::ENOENT,
+                    ref path,
+                    missing_interpreter: Some(ref interp),
+                    ..
+                },
+            ) => {
+                assert_eq!(path, Path::new("/bin/true"));
+                assert!(interp.is_absolute());
+                assert!(e.to_string().ends_with(&format!(
+                    ": interpreter {:?} is not found inside the new root",
+                    interp
+                )));
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_exec_format_hint() {
+        assert_eq!(
+            Error::Exec(libc::ENOEXEC).to_string(),
+            "error when executing: Exec format error (os error 8): \
+             file is not a valid executable (missing `#!` line?)"
+        );
+    }
+}
@@ -1,13 +1,19 @@
+use std::error::Error as StdError;
+use std::ffi::OsStr;
+use std::fmt;
 use std::mem::zeroed;
 use std::ops::{Range, RangeFrom, RangeFull, RangeTo};
-use std::os::unix::io::RawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 
 use libc::getrlimit;
 use libc::RLIMIT_NOFILE;
 use nix::errno::errno;
 
 use crate::stdio::Fd;
-use crate::Command;
+use crate::{Command, Error};
 
 /// This is just a temporary enum to coerce `std::ops::Range*` variants
 /// into single value for convenience. Used in `close_fds` method.
@@ -16,6 +22,68 @@ pub enum AnyRange {
     Range(RawFd, RawFd),
 }
 
+/// An operation on descriptors made by the child, see `Command::fd_plan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdAction {
+    /// `dup2` a descriptor to `fd`
+    Dup2 {
+        /// The number of the descriptor passed by the application (the child
+        /// receives a copy if the number is also a target), `None` for pipes
+        /// and `/dev/null` which are opened by `spawn`
+        source: Option<RawFd>,
+        /// The descriptor in the child
+        fd: RawFd,
+    },
+    /// Clear `CLOEXEC` flag of the inherited `fd`
+    Inherit(RawFd),
+    /// Open the path inside the new root as `fd`
+    Open {
+        /// The path relative to the new root
+        path: PathBuf,
+        /// The descriptor in the child
+        fd: RawFd,
+    },
+    /// Set `CLOEXEC` flag of `fd`, requested by `file_descriptor_cloexec`
+    SetCloexec(RawFd),
+    /// Close descriptors `start..end`, except the stdio, the targets of the
+    /// actions above and the internal descriptors of `unshare`
+    Close {
+        /// The first descriptor of the range
+        start: RawFd,
+        /// The end of the range (exclusive)
+        end: RawFd,
+    },
+}
+
+/// The descriptor configuration contradicts itself
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FdPlanError {
+    /// The descriptor configured for the child with `file_descriptor` is in
+    /// the range passed to `close_fds` with an upper bound
+    TargetInCloseRange {
+        /// The target descriptor
+        fd: RawFd,
+        /// The start of the range
+        start: RawFd,
+        /// The end of the range (exclusive)
+        end: RawFd,
+    },
+}
+
+impl fmt::Display for FdPlanError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FdPlanError::TargetInCloseRange { fd, start, end } => write!(
+                fmt,
+                "fd {} is passed to the child but is in close range {}..{}",
+                fd, start, end
+            ),
+        }
+    }
+}
+
+impl StdError for FdPlanError {}
+
 impl Command {
     /// Configuration for any other file descriptor (panics for fds < 3) use
     /// stdin/stdout/stderr for them
@@ -23,6 +91,14 @@ impl Command {
     /// Rust creates file descriptors with CLOEXEC flag by default, so no
     /// descriptors are inherited except ones specifically configured here
     /// (and stdio which is inherited by default)
+    ///
+    /// The descriptor is passed to the program without CLOEXEC flag, so it's
+    /// also inherited by any processes the program runs itself. There is no
+    /// way to pass a descriptor through `execve` and have CLOEXEC set after
+    /// it: the flag is checked on every `execve`, including the one
+    /// starting the program. If grandchildren must not inherit the
+    /// descriptor, the program has to set the flag itself (e.g. with
+    /// `fcntl(fd, F_SETFD, FD_CLOEXEC)`) as soon as it started.
     pub fn file_descriptor(&mut self, target_fd: RawFd, cfg: Fd) -> &mut Command {
         if target_fd <= 2 {
             panic!(
@@ -32,9 +108,68 @@ impl Command {
             )
         }
         self.fds.insert(target_fd, cfg);
+        self.config.cloexec_fds.retain(|&fd| fd != target_fd);
         self
     }
 
+    /// Same as `file_descriptor` but allows to keep CLOEXEC flag set on the
+    /// descriptor in the child
+    ///
+    /// With `cloexec` being `false` this is exactly `file_descriptor`. When
+    /// it's `true`, the descriptor is placed at `target_fd` but is closed
+    /// by `execve` of the program, so it's only available to the `pre_exec`
+    /// callback and the closure of `spawn_fn`, and isn't inherited by the
+    /// programs they run (see `file_descriptor` for why the program itself
+    /// can't receive it with the flag set).
+    pub fn file_descriptor_cloexec(
+        &mut self,
+        target_fd: RawFd,
+        cfg: Fd,
+        cloexec: bool,
+    ) -> &mut Command {
+        self.file_descriptor(target_fd, cfg);
+        if cloexec {
+            self.config.cloexec_fds.push(target_fd);
+        }
+        self
+    }
+
+    /// Pass a connected control socket to the child as `child_fd`
+    ///
+    /// Creates an `AF_UNIX` socket pair of `SOCK_SEQPACKET` type (which
+    /// keeps the boundaries of the messages, each `read` returns one
+    /// message), one end is passed to the child with `Fd::take`, and the
+    /// other one is returned right away, so it can be set up before
+    /// `spawn`. Both ends have `CLOEXEC` flag in the parent, so children of
+    /// other commands don't inherit them, and the end of the child is closed
+    /// in the parent when `spawn` returns, so the returned socket gets
+    /// end-of-file when the child exits. Call it again for the next spawn.
+    ///
+    /// Descriptors can be sent over the socket with `SCM_RIGHTS`.
+    pub fn control_socket(&mut self, child_fd: RawFd) -> Result<UnixStream, Error> {
+        self.socket_pair(child_fd, libc::SOCK_SEQPACKET)
+    }
+
+    /// Same as `control_socket` but the socket is of `SOCK_STREAM` type
+    ///
+    /// This is for programs that treat the socket as a byte stream (e.g.
+    /// shell scripts), the messages are not delimited.
+    pub fn control_stream_socket(&mut self, child_fd: RawFd) -> Result<UnixStream, Error> {
+        self.socket_pair(child_fd, libc::SOCK_STREAM)
+    }
+
+    fn socket_pair(&mut self, child_fd: RawFd, kind: libc::c_int) -> Result<UnixStream, Error> {
+        let mut fds = [0; 2];
+        let kind = kind | libc::SOCK_CLOEXEC;
+        if unsafe { libc::socketpair(libc::AF_UNIX, kind, 0, fds.as_mut_ptr()) } != 0 {
+            return Err(Error::CreatePipe(errno()));
+        }
+        let (parent, child) =
+            unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) };
+        self.file_descriptor(child_fd, Fd::take(child));
+        Ok(UnixStream::from(parent))
+    }
+
     /// Close a range of file descriptors as soon as process forks
     ///
     /// Subsequent calls to this method add additional range. Use `reset_fds`
@@ -44,7 +179,9 @@ impl Command {
     ///
     /// * the stdio file descriptors
     /// * descriptors configured using `file_descriptor`/`file_descriptor_raw`
-    ///   methods
+    ///   methods, when the range has no upper bound (a range like `3..12`
+    ///   which covers one is a mistake, so `spawn` fails with
+    ///   `Error::FdConflict` instead, see `fd_plan`)
     /// * internal file descriptors used for parent child notification by
     ///   unshare crate itself (they are guaranteed to have CLOEXEC)
     ///
@@ -75,7 +212,7 @@ impl Command {
         self.close_fds.push(match range.into() {
             AnyRange::Range(x, y) => {
                 assert!(x >= 3);
-                (x, y)
+                (x, y, true)
             }
             AnyRange::RangeFrom(x) => unsafe {
                 assert!(x >= 3);
@@ -84,12 +221,122 @@ impl Command {
                 if rc < 0 {
                     panic!("Can't get rlimit: errno {}", errno());
                 }
-                (x, rlim.rlim_cur as RawFd)
+                (x, rlim.rlim_cur as RawFd, false)
             },
         });
         self
     }
 
+    /// File descriptors used internally by the last `spawn`
+    ///
+    /// These are the ends of the pipes used to wake up the child and to
+    /// report errors back to the parent, as numbered in the child, and
+    /// a copy of the `capture_child_errors` descriptor if it's set. They
+    /// have CLOEXEC flag so never leak into the executed program. Empty if
+    /// the command wasn't spawned yet.
+    ///
+    /// Internal descriptors are always numbered above any target configured
+    /// with `file_descriptor` (and at least `min_internal_fd`), so they
+    /// never collide. Also they are never closed by `close_fds`. So this is
+    /// useful only for debugging.
+    pub fn reserved_fds(&self) -> Vec<RawFd> {
+        self.reserved_fds.clone()
+    }
+
+    /// Allocate all internal descriptors at or above `fd`
+    ///
+    /// This covers exactly these descriptors, in both the parent and the
+    /// child:
+    ///
+    /// * the sync pipes and the copy of the `capture_child_errors`
+    ///   pipe, which are listed by `reserved_fds`
+    /// * the ends of the pipes for the configured stdio and
+    ///   `file_descriptor` targets, including the ones returned as
+    ///   `Child::stdin` and friends
+    /// * `/dev/null`, the tee captures and the `ready_notification` pipe
+    ///
+    /// They are moved with `fcntl(F_DUPFD_CLOEXEC)`, so the low numbers
+    /// stay free for descriptors the application opens itself meanwhile.
+    /// Without it the internal descriptors in the parent take the lowest
+    /// free numbers.
+    ///
+    /// Not moved are the pipe of the id map helpers (`set_id_map_commands`),
+    /// which is closed before `spawn` returns, and the descriptors the
+    /// application passes or gets itself: the one of `external_unfreezer`
+    /// and both ends of `control_socket` (they are created when it's
+    /// called).
+    ///
+    /// `spawn` fails with `Error::CreatePipe` if `fd` is above the
+    /// `RLIMIT_NOFILE` limit.
+    pub fn min_internal_fd(&mut self, fd: RawFd) -> &mut Command {
+        self.config.min_internal_fd = Some(fd);
+        self
+    }
+
+    /// The operations on descriptors the child makes, in order
+    ///
+    /// The descriptors are placed in the order of their numbers (the
+    /// notification pipe of `ready_notification` among them), then the
+    /// files opened in the new root, then `CLOEXEC` is set for
+    /// `file_descriptor_cloexec` and the ranges of `close_fds` are closed.
+    /// Internal descriptors of `unshare` are not listed.
+    ///
+    /// The same check is made by `spawn`, which fails with
+    /// `Error::FdConflict` if this returns an error.
+    pub fn fd_plan(&self) -> Result<Vec<FdAction>, FdPlanError> {
+        for &(start, end, bounded) in &self.close_fds {
+            let fd = self.fds.keys().filter(|&&fd| fd >= start && fd < end).min();
+            if let (true, Some(&fd)) = (bounded, fd) {
+                return Err(FdPlanError::TargetInCloseRange { fd, start, end });
+            }
+        }
+        // the notification pipe is the only one without configuration
+        let mut targets = self
+            .fds
+            .iter()
+            .map(|(&fd, cfg)| (fd, Some(cfg)))
+            .chain(self.ready_fd().map(|fd| (fd, None)))
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|&(fd, _)| fd);
+        let mut actions = Vec::new();
+        let mut opened = Vec::new();
+        for (fd, cfg) in targets {
+            actions.push(match cfg {
+                Some(Fd::Inherit) => FdAction::Inherit(fd),
+                Some(Fd::Fd(x)) | Some(Fd::Moved(x)) => FdAction::Dup2 {
+                    source: Some(x.as_raw_fd()),
+                    fd,
+                },
+                Some(&Fd::Borrowed(x)) => FdAction::Dup2 {
+                    source: Some(x),
+                    fd,
+                },
+                Some(Fd::OpenInRoot(path, _)) => {
+                    opened.push(FdAction::Open {
+                        path: OsStr::from_bytes(path.as_bytes()).into(),
+                        fd,
+                    });
+                    continue;
+                }
+                _ => FdAction::Dup2 { source: None, fd },
+            });
+        }
+        actions.extend(opened);
+        actions.extend(
+            self.config
+                .cloexec_fds
+                .iter()
+                .map(|&fd| FdAction::SetCloexec(fd)),
+        );
+        actions.extend(
+            self.close_fds
+                .iter()
+                .filter(|&&(start, end, _)| start < end)
+                .map(|&(start, end, _)| FdAction::Close { start, end }),
+        );
+        Ok(actions)
+    }
+
     /// Reset file descriptor including stdio to the initial state
     ///
     /// Initial state is inherit all the stdio and do nothing to other fds.
@@ -98,6 +345,7 @@ impl Command {
             .into_iter()
             .collect();
         self.close_fds.clear();
+        self.config.cloexec_fds.clear();
         self
     }
 }
@@ -125,3 +373,415 @@ impl Into<AnyRange> for RangeFull {
         return AnyRange::RangeFrom(3);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+    use std::ptr;
+
+    use crate::pipe::Pipe;
+    use crate::{Command, Error, ExitStatus, Fd, FdAction, FdPlanError, Stdio};
+
+    fn map_fds(cmd: &mut Command) {
+        // internal pipes would get one of these numbers if they were not
+        // moved above the targets
+        for fd in 3..64 {
+            cmd.file_descriptor(fd, Fd::ReadNull);
+        }
+    }
+
+    #[test]
+    fn test_min_internal_fd() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("echo hello")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .min_internal_fd(100);
+        let mut child = cmd.spawn().unwrap();
+        let reserved = cmd.reserved_fds();
+        assert_eq!(reserved.len(), 2);
+        assert!(reserved.iter().all(|&fd| fd >= 100));
+        let mut stdout = child.stdout.take().unwrap();
+        assert!(stdout.as_raw_fd() >= 100);
+        let mut output = String::new();
+        stdout.read_to_string(&mut output).unwrap();
+        assert!(child.wait().unwrap().success());
+        assert_eq!(output, "hello\n");
+    }
+
+    #[test]
+    fn test_errors_with_mapped_fds() {
+        let mut cmd = Command::new("/nonexistent");
+        map_fds(&mut cmd);
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let reserved = cmd.reserved_fds();
+        assert_eq!(reserved.len(), 2);
+        assert!(reserved.iter().all(|&fd| fd >= 64));
+    }
+
+    #[test]
+    fn test_errors_with_closed_fds() {
+        let mut cmd = Command::new("/nonexistent");
+        cmd.close_fds(..);
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_mapped_fd_inherited_by_grandchild() {
+        let mut cmd = Command::new("/bin/sh");
+        // the first check is made by the child itself, the second one by
+        // a grandchild
+        cmd.args(&[
+            "-c",
+            "test -e /proc/$$/fd/7 && sh -c 'test -e /proc/$$/fd/7'",
+        ]);
+        cmd.file_descriptor(7, Fd::ReadNull);
+        assert!(cmd.status().unwrap().success());
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exec 7<&-; sh -c 'test ! -e /proc/$$/fd/7'"]);
+        cmd.file_descriptor(7, Fd::ReadNull);
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_mapped_fds_inherited() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "test -e /proc/$$/fd/63"]);
+        map_fds(&mut cmd);
+        assert!(Command::new("/bin/true").reserved_fds().is_empty());
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_borrowed_fd_shared() {
+        let path = std::env::temp_dir().join(format!("unshare-log-{}", std::process::id()));
+        let mut log = fs::OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        for word in &["one", "two"] {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.args(&["-c", &format!("echo {} >&5", word)]);
+            cmd.file_descriptor(5, Fd::dup_of(log.as_raw_fd()));
+            assert!(cmd.status().unwrap().success());
+        }
+        // still open in the parent
+        writeln!(log, "three").unwrap();
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data, "one\ntwo\nthree\n");
+    }
+
+    fn moved_pipe(cmd: &mut Command) -> impl Read {
+        let (rd, wr) = Pipe::new().unwrap().split();
+        let wr = unsafe { OwnedFd::from_raw_fd(wr.into_fd()) };
+        cmd.file_descriptor(5, Fd::take(wr));
+        rd
+    }
+
+    #[test]
+    fn test_moved_fd() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "test -e /proc/$$/fd/5 && echo hello >&5"]);
+        let mut rd = moved_pipe(&mut cmd);
+        let mut child = cmd.spawn().unwrap();
+        // end of file means the parent's copy is closed, while `cmd` is
+        // still alive
+        let mut output = String::new();
+        rd.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "hello\n");
+        assert!(child.wait().unwrap().success());
+        // the next spawn doesn't get the descriptor
+        assert!(!cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_moved_fd_spawn_error() {
+        let mut cmd = Command::new("/nonexistent");
+        let mut rd = moved_pipe(&mut cmd);
+        assert!(cmd.spawn().is_err());
+        let mut output = String::new();
+        rd.read_to_string(&mut output).unwrap();
+        assert_eq!(output, "");
+    }
+
+    #[test]
+    fn test_null_in_root() {
+        let root = std::env::temp_dir().join(format!("unshare-null-{}", std::process::id()));
+        fs::create_dir_all(root.join("dev")).unwrap();
+        let spawn = |stdin: Stdio| {
+            let mut cmd = Command::new("/bin/true");
+            cmd.chroot_dir(&root);
+            cmd.stdin(stdin);
+            // stdin is the `/dev/null` node of the new root, not the one
+            // of the host
+            unsafe {
+                cmd.spawn_fn(|| {
+                    let mut fd: libc::stat = std::mem::zeroed();
+                    let mut node: libc::stat = std::mem::zeroed();
+                    libc::fstat(0, &mut fd);
+                    if libc::stat(b"/dev/null\0".as_ptr() as *const _, &mut node) != 0 {
+                        return 2;
+                    }
+                    (fd.st_dev != node.st_dev || fd.st_ino != node.st_ino) as i32
+                })
+            }
+            .map(|mut child| child.wait().unwrap().code())
+        };
+        let missing = spawn(Stdio::null_in_root());
+        let dev_null =
+            std::ffi::CString::new(root.join("dev/null").as_os_str().as_bytes()).unwrap();
+        let rc = unsafe {
+            libc::mknod(
+                dev_null.as_ptr(),
+                libc::S_IFCHR | 0o666,
+                libc::makedev(1, 3),
+            )
+        };
+        assert_eq!(rc, 0);
+        let in_root = spawn(Stdio::null_in_root());
+        let host = spawn(Stdio::null());
+        fs::remove_dir_all(&root).unwrap();
+        match missing.map_err(Error::into_inner) {
+            Err(Error::OpenInRoot(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert_eq!(in_root.unwrap(), Some(0));
+        assert_eq!(host.unwrap(), Some(1));
+    }
+
+    #[test]
+    fn test_file_descriptor_cloexec() {
+        let flags = |cloexec| {
+            let file = fs::File::open("/dev/null").unwrap();
+            let mut cmd = Command::new("unused");
+            cmd.file_descriptor_cloexec(7, Fd::dup_file(&file).unwrap(), cloexec);
+            let mut child = unsafe { cmd.spawn_fn(|| libc::fcntl(7, libc::F_GETFD)) }.unwrap();
+            child.wait().unwrap()
+        };
+        assert_eq!(flags(true), ExitStatus::Exited(libc::FD_CLOEXEC as i8));
+        assert_eq!(flags(false), ExitStatus::Exited(0));
+
+        // the program doesn't get it
+        let file = fs::File::open("/dev/null").unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "test -e /proc/self/fd/7"])
+            .file_descriptor_cloexec(7, Fd::dup_file(&file).unwrap(), true);
+        assert_eq!(cmd.status().unwrap(), ExitStatus::Exited(1));
+        cmd.file_descriptor(7, Fd::dup_file(&file).unwrap());
+        assert_eq!(cmd.status().unwrap(), ExitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_close_range_covers_target() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.file_descriptor(7, Fd::ReadNull);
+        cmd.close_fds(5..10);
+        let err = FdPlanError::TargetInCloseRange {
+            fd: 7,
+            start: 5,
+            end: 10,
+        };
+        assert_eq!(cmd.fd_plan(), Err(err.clone()));
+        match cmd.spawn() {
+            Err(Error::FdConflict(e)) => assert_eq!(e, err),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_target_in_close_range() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.close_fds(3..8);
+        cmd.file_descriptor(9, Fd::ReadNull);
+        assert!(cmd.fd_plan().is_ok());
+        cmd.file_descriptor(5, Fd::ReadNull);
+        cmd.file_descriptor(4, Fd::ReadNull);
+        assert_eq!(
+            cmd.fd_plan(),
+            Err(FdPlanError::TargetInCloseRange {
+                fd: 4,
+                start: 3,
+                end: 8
+            })
+        );
+        assert_eq!(
+            cmd.fd_plan().unwrap_err().to_string(),
+            "fd 4 is passed to the child but is in close range 3..8"
+        );
+    }
+
+    #[test]
+    fn test_open_ended_close_range() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "test -e /proc/$$/fd/7"]);
+        cmd.file_descriptor(7, Fd::ReadNull);
+        cmd.close_fds(..);
+        assert!(cmd.fd_plan().is_ok());
+        assert_eq!(cmd.status().unwrap(), ExitStatus::Exited(0));
+    }
+
+    #[test]
+    fn test_fd_plan_golden() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+        cmd.file_descriptor(3, Fd::Borrowed(100));
+        cmd.file_descriptor(5, Fd::read_in_root("/etc/hostname"));
+        cmd.file_descriptor(6, Fd::inherit());
+        cmd.file_descriptor_cloexec(8, Fd::WritePipe, true);
+        cmd.ready_notification("READY_FD");
+        cmd.close_fds(10..20);
+        cmd.close_fds(7..7);
+        assert_eq!(
+            cmd.fd_plan().unwrap(),
+            vec![
+                FdAction::Dup2 {
+                    source: None,
+                    fd: 0
+                },
+                FdAction::Dup2 {
+                    source: None,
+                    fd: 1
+                },
+                FdAction::Inherit(2),
+                FdAction::Dup2 {
+                    source: Some(100),
+                    fd: 3
+                },
+                FdAction::Dup2 {
+                    source: None,
+                    fd: 4
+                },
+                FdAction::Inherit(6),
+                FdAction::Dup2 {
+                    source: None,
+                    fd: 8
+                },
+                FdAction::Open {
+                    path: "/etc/hostname".into(),
+                    fd: 5
+                },
+                FdAction::SetCloexec(8),
+                FdAction::Close { start: 10, end: 20 },
+            ]
+        );
+    }
+
+    /// Sends `data` and `fd` (with `SCM_RIGHTS`) in one message
+    fn send_fd(sock: RawFd, data: &[u8], fd: RawFd) -> isize {
+        unsafe {
+            let mut iov = libc::iovec {
+                iov_base: data.as_ptr() as *mut libc::c_void,
+                iov_len: data.len(),
+            };
+            let mut control = [0u64; 4];
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = libc::CMSG_SPACE(4) as _;
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(4) as _;
+            ptr::write_unaligned(libc::CMSG_DATA(cmsg) as *mut RawFd, fd);
+            libc::sendmsg(sock, &msg, 0)
+        }
+    }
+
+    /// Receives a message, the descriptor is `-1` if none is passed
+    ///
+    /// Doesn't allocate, so it's usable in the closure of `spawn_fn`.
+    fn recv_fd(sock: RawFd, buf: &mut [u8]) -> (isize, RawFd) {
+        unsafe {
+            let mut iov = libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            };
+            let mut control = [0u64; 4];
+            let mut msg: libc::msghdr = mem::zeroed();
+            msg.msg_iov = &mut iov;
+            msg.msg_iovlen = 1;
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = mem::size_of_val(&control) as _;
+            let len = libc::recvmsg(sock, &mut msg, libc::MSG_CMSG_CLOEXEC);
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            if len < 0 || cmsg.is_null() || (*cmsg).cmsg_type != libc::SCM_RIGHTS {
+                return (len, -1);
+            }
+            (
+                len,
+                ptr::read_unaligned(libc::CMSG_DATA(cmsg) as *const RawFd),
+            )
+        }
+    }
+
+    #[test]
+    fn test_control_socket() {
+        let mut cmd = Command::new("payload");
+        let sock = cmd.control_socket(5).unwrap();
+        let flags = unsafe { libc::fcntl(sock.as_raw_fd(), libc::F_GETFD) };
+        assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        let (mut rd, wr) = Pipe::new().unwrap().split();
+        assert_eq!(send_fd(sock.as_raw_fd(), b"ping", wr.as_raw_fd()), 4);
+        assert_eq!(send_fd(sock.as_raw_fd(), b"second", wr.as_raw_fd()), 6);
+        drop(wr);
+        let mut child = unsafe {
+            cmd.spawn_fn(|| {
+                let mut buf = [0u8; 16];
+                let (len, fd) = recv_fd(5, &mut buf);
+                // each message is received separately
+                if &buf[..len.max(0) as usize] != b"ping" || fd < 0 {
+                    return 1;
+                }
+                libc::write(fd, b"pong".as_ptr() as *const libc::c_void, 4);
+                libc::close(fd);
+                let (len, fd) = recv_fd(5, &mut buf);
+                if &buf[..len.max(0) as usize] != b"second" {
+                    return 2;
+                }
+                libc::close(fd);
+                libc::write(5, b"ok".as_ptr() as *const libc::c_void, 2);
+                0
+            })
+        }
+        .unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+        let mut buf = [0u8; 16];
+        let (len, fd) = recv_fd(sock.as_raw_fd(), &mut buf);
+        assert_eq!((&buf[..len as usize], fd), (&b"ok"[..], -1));
+        // the end of the child is closed in the parent too
+        assert_eq!(recv_fd(sock.as_raw_fd(), &mut buf).0, 0);
+        drop(sock);
+        let mut pong = Vec::new();
+        rd.read_to_end(&mut pong).unwrap();
+        assert_eq!(pong, b"pong");
+        assert!(!cmd.fds.contains_key(&5));
+    }
+
+    #[test]
+    fn test_control_stream_socket() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "read x <&5; echo \"got $x\" >&5"]);
+        let mut sock = cmd.control_stream_socket(5).unwrap();
+        sock.write_all(b"hello\n").unwrap();
+        let mut child = cmd.spawn().unwrap();
+        let mut reply = String::new();
+        sock.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "got hello\n");
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+    }
+}
@@ -1,6 +1,26 @@
 use std::ffi::{CString, OsStr};
 use std::os::unix::ffi::OsStrExt;
 
+use crate::Error;
+
+/// Place where nul byte was found, reported as an error by `spawn()`
+#[derive(Clone, Copy, Debug)]
+pub enum NulByte {
+    Program,
+    Argument(usize),
+    Mount(usize),
+}
+
+impl NulByte {
+    pub fn to_error(self) -> Error {
+        match self {
+            NulByte::Program => Error::NulByteInProgram,
+            NulByte::Argument(index) => Error::NulByteInArgument { index },
+            NulByte::Mount(index) => Error::NulByteInMount { index },
+        }
+    }
+}
+
 pub trait ToCString {
     fn to_cstring(&self) -> CString;
 }
@@ -0,0 +1,132 @@
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use crate::proc_stat::stat_fields;
+use crate::{BoxError, Command, Error};
+
+type SpawnCheck = Arc<dyn Fn() -> Result<(), BoxError> + Send + Sync>;
+
+static SPAWN_CHECKS: Mutex<Vec<SpawnCheck>> = Mutex::new(Vec::new());
+
+/// Register a check which runs right before every `clone` of a child
+///
+/// The hook runs in the thread calling `spawn` of any command, after all
+/// the other preparation is done. If it returns an error, the child is not
+/// created and `spawn` fails with `Error::SpawnCheck`. It's useful to catch
+/// the state that is unsafe to copy into the child, i.e. to assert that the
+/// application holds no locks which the child (or the closure of
+/// `spawn_fn`) might need.
+///
+/// Hooks are cumulative and run in the order they are registered, there is
+/// no way to remove them. There are none by default.
+pub fn at_spawn_check<F>(hook: F)
+where
+    F: Fn() -> Result<(), BoxError> + Send + Sync + 'static,
+{
+    SPAWN_CHECKS.lock().unwrap().push(Arc::new(hook));
+}
+
+/// Returns the number of threads of the current process
+fn thread_count() -> Result<usize, Error> {
+    let stat = fs::read_to_string("/proc/self/stat")
+        .map_err(|e| Error::ProcessInfo(e.raw_os_error().unwrap_or(libc::EIO)))?;
+    // the 20th field
+    stat_fields(&stat)
+        .and_then(|fields| fields.get(20 - 3).and_then(|value| value.parse().ok()))
+        .ok_or(Error::ProcessInfo(libc::EINVAL))
+}
+
+impl Command {
+    /// Refuse to spawn the child when the current process has other threads
+    ///
+    /// The child is a copy of the process with only the calling thread. Locks
+    /// held by other threads at that moment stay locked in the child forever,
+    /// which is where most hangs of `pre_exec` callbacks and `spawn_fn`
+    /// closures come from. With this set, the threads are counted (from
+    /// `/proc/self/stat`) right before `clone`, and `spawn` fails with
+    /// `Error::MultiThreaded` if there is more than one. Off by default.
+    ///
+    /// Note: this is a debugging aid, threads started by other code after
+    /// the check are not noticed.
+    pub fn require_single_thread(&mut self, require: bool) -> &mut Command {
+        self.config.require_single_thread = require;
+        self
+    }
+
+    /// Runs `require_single_thread` and `at_spawn_check` checks
+    pub(crate) fn check_before_clone(&self) -> Result<(), Error> {
+        if self.config.require_single_thread {
+            let thread_count = thread_count()?;
+            if thread_count > 1 {
+                return Err(Error::MultiThreaded { thread_count });
+            }
+        }
+        // the lock isn't held while the hooks run, so they may spawn too
+        let checks = SPAWN_CHECKS.lock().unwrap().clone();
+        for check in checks {
+            check().map_err(Error::SpawnCheck)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::at_spawn_check;
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_require_single_thread() {
+        let (tx, rx) = mpsc::channel::<()>();
+        let background = thread::spawn(move || rx.recv().ok());
+        let mut cmd = Command::new("/bin/true");
+        assert!(cmd.status().unwrap().success());
+        cmd.require_single_thread(true);
+        match cmd.spawn() {
+            // the test harness runs its own threads too
+            Err(err @ Error::MultiThreaded { thread_count }) => {
+                assert!(thread_count >= 2);
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "process has multiple threads, unsafe to spawn from: {} threads",
+                        thread_count
+                    )
+                );
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        drop(tx);
+        background.join().unwrap();
+    }
+
+    thread_local! {
+        static FAIL_SPAWNS: Cell<bool> = Cell::new(false);
+    }
+
+    #[test]
+    fn test_at_spawn_check() {
+        // other tests spawn concurrently, they are running in other threads
+        at_spawn_check(|| {
+            if FAIL_SPAWNS.with(|fail| fail.get()) {
+                return Err("lock is held".into());
+            }
+            Ok(())
+        });
+        let mut cmd = Command::new("/bin/true");
+        assert!(cmd.status().unwrap().success());
+        FAIL_SPAWNS.with(|fail| fail.set(true));
+        match cmd.spawn() {
+            Err(err @ Error::SpawnCheck(_)) => {
+                assert_eq!(err.to_string(), "spawn check failed: lock is held")
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        FAIL_SPAWNS.with(|fail| fail.set(false));
+        assert!(cmd.status().unwrap().success());
+    }
+}
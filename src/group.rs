@@ -0,0 +1,185 @@
+use std::fs;
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use libc::pid_t;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::{getpgrp, Pid};
+
+use crate::proc_stat::stat_fields;
+use crate::wait::reap_exited;
+use crate::Child;
+
+fn nix_error(e: nix::Error) -> io::Error {
+    match e {
+        nix::Error::Sys(x) => io::Error::from_raw_os_error(x as i32),
+        nix::Error::InvalidPath => unreachable!(),
+        nix::Error::InvalidUtf8 => unreachable!(),
+        nix::Error::UnsupportedOperation => io::Error::other("nix error: unsupported operation"),
+    }
+}
+
+/// Send a signal to every process of the process group
+///
+/// This is `killpg`, but pgids `0` and `1`, which would mean the group of
+/// the caller and all processes respectively, are rejected with
+/// `InvalidInput`.
+pub fn signal_process_group(pgid: pid_t, signal: Signal) -> Result<(), io::Error> {
+    if pgid <= 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid argument: pgid must be greater than 1",
+        ));
+    }
+    killpg(Pid::from_raw(pgid), signal).map_err(nix_error)
+}
+
+/// Returns `true` if the group has zombie members but no live ones
+///
+/// Orphaned zombies are only reaped if the init process (or subreaper) does
+/// that, so they are not waited for. If no members are found (i.e. `/proc`
+/// is not available or a process is in the middle of `execve`, so its
+/// `stat` can't be read), returns `false` as `kill` knows better.
+///
+/// The `own` zombie (the child which is not reaped yet) also returns
+/// `false`: it has just exited and the processes it forked may be missing
+/// from a `/proc` listing made concurrently with the exit.
+fn only_zombies_left(pgid: pid_t, own: pid_t) -> bool {
+    let dir = match fs::read_dir("/proc") {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+    let mut zombies = false;
+    for stat in dir
+        .filter_map(|entry| entry.ok())
+        .filter_map(|entry| fs::read_to_string(entry.path().join("stat")).ok())
+    {
+        let fields = match stat_fields(&stat) {
+            Some(fields) => fields,
+            None => continue,
+        };
+        // fields after the name are: state, ppid, pgrp
+        if fields.len() > 2 && fields[2].parse() == Ok(pgid) {
+            if fields[0] != "Z" || stat.split(' ').next() == Some(&own.to_string()[..]) {
+                return false;
+            }
+            zombies = true;
+        }
+    }
+    zombies
+}
+
+impl Child {
+    /// Returns the process group id of the child, as set up by `spawn`
+    ///
+    /// This is the pid of the child if `make_group_leader` or
+    /// `make_session_leader` is set, the group set by `process_group`, or
+    /// the group of the parent otherwise. It doesn't track `setpgid`
+    /// calls made by the program itself.
+    pub fn pgid(&self) -> pid_t {
+        self.pgid
+    }
+
+    /// Wait until all processes of the child's process group exit
+    ///
+    /// Returns `false` if some processes are still alive after `timeout`.
+    /// The child itself is reaped (so its status is available from
+    /// `wait`), other members of the group are reaped by their parents,
+    /// orphaned zombies count as exited. The group is polled with
+    /// increasing intervals (up to 50 ms).
+    ///
+    /// This is useful to make sure all the workers spawned by the child
+    /// are gone, i.e. after `signal_process_group`. Note that processes
+    /// which moved to another process group or session are not waited for.
+    ///
+    /// Returns `InvalidInput` error if the child is in the process group
+    /// of the parent, as the parent would wait for itself.
+    pub fn wait_group_exit(&mut self, timeout: Duration) -> Result<bool, io::Error> {
+        if self.pgid == getpgrp().as_raw() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid argument: child is in the process group of the parent",
+            ));
+        }
+        let deadline = Instant::now() + timeout;
+        let mut interval = Duration::from_millis(1);
+        loop {
+            if self.status.is_none() {
+                if let Some((_, status)) = reap_exited(self.pid)? {
+                    self.status = Some(status);
+                }
+            }
+            let alive = match killpg(Pid::from_raw(self.pgid), None) {
+                Ok(()) => !only_zombies_left(self.pgid, self.pid),
+                Err(e) if e.as_errno() == Some(nix::errno::Errno::ESRCH) => false,
+                Err(e) => return Err(nix_error(e)),
+            };
+            if !alive {
+                return Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(Duration::from_millis(50));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::time::{Duration, Instant};
+
+    use super::signal_process_group;
+    use crate::{Command, Signal};
+
+    #[test]
+    fn test_pgid() {
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert_eq!(child.pgid(), nix::unistd::getpgrp().as_raw());
+        child.wait().unwrap();
+        let mut child = Command::new("/bin/true")
+            .make_group_leader(true)
+            .spawn()
+            .unwrap();
+        assert_eq!(child.pgid(), child.pid().as_raw());
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_wait_grandchild() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "sleep 0.3 & exit 0"]);
+        cmd.make_group_leader(true);
+        let mut child = cmd.spawn().unwrap();
+        let start = Instant::now();
+        assert!(child.wait_group_exit(Duration::from_secs(5)).unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(300));
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_signal_group() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "sleep 10 & wait"]);
+        cmd.make_group_leader(true);
+        let mut child = cmd.spawn().unwrap();
+        assert!(!child.wait_group_exit(Duration::from_millis(100)).unwrap());
+        signal_process_group(child.pgid(), Signal::SIGKILL).unwrap();
+        assert!(child.wait_group_exit(Duration::from_secs(5)).unwrap());
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+    }
+
+    #[test]
+    fn test_invalid_group() {
+        let err = signal_process_group(1, Signal::SIGTERM).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        let err = child.wait_group_exit(Duration::from_secs(1)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        child.wait().unwrap();
+    }
+}
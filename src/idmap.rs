@@ -1,4 +1,21 @@
-use libc::{gid_t, uid_t};
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use libc::{gid_t, pid_t, uid_t};
+
+use crate::error::{result, ErrorCode as Err};
+use crate::pipe::Pipe;
+use crate::{Capability, Command, Error, Stdio};
+
+pub(crate) type UidArgs = Box<dyn Fn(pid_t, &[UidMap]) -> Vec<OsString>>;
+pub(crate) type GidArgs = Box<dyn Fn(pid_t, &[GidMap]) -> Vec<OsString>>;
+
+/// Maximum number of lines in uid_map/gid_map since Linux 4.15
+const MAX_ENTRIES: usize = 340;
 
 /// Entry (row) in the uid map
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -21,3 +38,344 @@ pub struct GidMap {
     /// Number of gids that this entry allows starting from inside/outside gid
     pub count: gid_t,
 }
+
+impl UidMap {
+    /// Maps `count` uids starting from `outside_uid` in the host namespace
+    /// to the ones starting from `inside_uid` inside the namespace
+    pub fn range(inside_uid: uid_t, outside_uid: uid_t, count: uid_t) -> UidMap {
+        UidMap {
+            inside_uid,
+            outside_uid,
+            count,
+        }
+    }
+
+    /// Checks that uid map entries are acceptable for the kernel
+    ///
+    /// Entries must have non-zero count, must not overflow and neither inside
+    /// nor outside ranges may overlap. This is checked by `spawn` too, so
+    /// the misconfiguration is reported before the child is started instead
+    /// of as `EINVAL` when writing `/proc/<pid>/uid_map`.
+    pub fn validate_ranges(maps: &[UidMap]) -> Result<(), Error> {
+        validate(
+            "uid",
+            maps.iter().map(|m| (m.inside_uid, m.outside_uid, m.count)),
+        )
+    }
+}
+
+impl GidMap {
+    /// Maps `count` gids starting from `outside_gid` in the host namespace
+    /// to the ones starting from `inside_gid` inside the namespace
+    pub fn range(inside_gid: gid_t, outside_gid: gid_t, count: gid_t) -> GidMap {
+        GidMap {
+            inside_gid,
+            outside_gid,
+            count,
+        }
+    }
+
+    /// Checks that gid map entries are acceptable for the kernel
+    ///
+    /// See `UidMap::validate_ranges` for details.
+    pub fn validate_ranges(maps: &[GidMap]) -> Result<(), Error> {
+        validate(
+            "gid",
+            maps.iter().map(|m| (m.inside_gid, m.outside_gid, m.count)),
+        )
+    }
+}
+
+/// Arguments for `newuidmap`: `<pid> <uid> <loweruid> <count>...`
+pub(crate) fn default_uid_args(pid: pid_t, maps: &[UidMap]) -> Vec<OsString> {
+    let mut args = vec![pid.to_string().into()];
+    for map in maps {
+        args.push(map.inside_uid.to_string().into());
+        args.push(map.outside_uid.to_string().into());
+        args.push(map.count.to_string().into());
+    }
+    args
+}
+
+/// Arguments for `newgidmap`: `<pid> <gid> <lowergid> <count>...`
+pub(crate) fn default_gid_args(pid: pid_t, maps: &[GidMap]) -> Vec<OsString> {
+    let mut args = vec![pid.to_string().into()];
+    for map in maps {
+        args.push(map.inside_gid.to_string().into());
+        args.push(map.outside_gid.to_string().into());
+        args.push(map.count.to_string().into());
+    }
+    args
+}
+
+/// Whether the current process has `CAP_SETGID` in its effective set
+///
+/// Parsed from `CapEff` of `/proc/self/status`, the capability is assumed
+/// missing if the file can't be read.
+pub(crate) fn has_cap_setgid() -> bool {
+    fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| cap_setgid_in_status(&status))
+        .unwrap_or(false)
+}
+
+fn cap_setgid_in_status(status: &str) -> Option<bool> {
+    let line = status.lines().find(|line| line.starts_with("CapEff:"))?;
+    let mask = u64::from_str_radix(line["CapEff:".len()..].trim(), 16).ok()?;
+    Some(mask & (1 << Capability::CAP_SETGID as u32) != 0)
+}
+
+/// Writes the uid and gid maps of the child `pid` directly
+///
+/// Without `CAP_SETGID` the kernel accepts the gid map only after
+/// `setgroups` is denied for the namespace, so "deny" is written to
+/// `/proc/<pid>/setgroups` first in that case. A privileged parent leaves
+/// `setgroups` allowed, so the child can still set supplementary groups.
+pub(crate) fn write_maps(pid: pid_t, uids: &[UidMap], gids: &[GidMap]) -> Result<(), Error> {
+    let write = |name: &str, data: &[u8]| {
+        result(
+            Err::SetIdMap,
+            File::create(format!("/proc/{}/{}", pid, name)).and_then(|mut f| f.write_all(data)),
+        )
+    };
+    let mut buf = Vec::new();
+    for map in uids {
+        writeln!(
+            &mut buf,
+            "{} {} {}",
+            map.inside_uid, map.outside_uid, map.count
+        )
+        .unwrap();
+    }
+    write("uid_map", &buf)?;
+    if !has_cap_setgid() {
+        write("setgroups", b"deny")?;
+    }
+    let mut buf = Vec::new();
+    for map in gids {
+        writeln!(
+            &mut buf,
+            "{} {} {}",
+            map.inside_gid, map.outside_gid, map.count
+        )
+        .unwrap();
+    }
+    write("gid_map", &buf)
+}
+
+/// The output of id map commands kept for `Error::IdMapHelper`
+const MAX_HELPER_OUTPUT: usize = 4096;
+
+/// Runs `newuidmap`/`newgidmap` command and checks that it has written
+/// the `map_file` (i.e. `/proc/<pid>/uid_map`)
+///
+/// Both stdout and stderr of the command are captured into a single pipe.
+/// The command is killed if it doesn't exit (and close the pipe) until
+/// `timeout`.
+pub(crate) fn run_helper(
+    program: &Path,
+    args: &[OsString],
+    map_file: &str,
+    timeout: Duration,
+) -> Result<(), Error> {
+    let deadline = Instant::now() + timeout;
+    let (mut reader, writer) = Pipe::new()?.split();
+    let mut cmd = Command::new(program);
+    cmd.args(args);
+    cmd.stdout(result(Err::CreatePipe, Stdio::dup_file(&writer))?);
+    cmd.stderr(result(Err::CreatePipe, Stdio::dup_file(&writer))?);
+    let mut child = cmd.spawn().map_err(|e| match e.into_inner() {
+        Error::Exec(errno) => Error::SetIdMap(errno),
+        e => e,
+    })?;
+    drop(cmd);
+    drop(writer);
+
+    let mut output = Vec::new();
+    let mut buf = [0u8; 4096];
+    let mut timed_out = false;
+    loop {
+        let left = deadline.saturating_duration_since(Instant::now());
+        let mut pollfd = libc::pollfd {
+            fd: reader.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let rc = unsafe {
+            libc::poll(
+                &mut pollfd,
+                1,
+                left.as_millis().min(i32::MAX as u128) as i32,
+            )
+        };
+        if rc == 0 {
+            timed_out = true;
+            break;
+        }
+        if rc < 0 && nix::errno::errno() == libc::EINTR {
+            continue;
+        }
+        match reader.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                let keep = len.min(MAX_HELPER_OUTPUT - output.len().min(MAX_HELPER_OUTPUT));
+                output.extend_from_slice(&buf[..keep]);
+            }
+            Err(ref e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    let left = deadline.saturating_duration_since(Instant::now());
+    let status = match child.wait_timeout(left) {
+        Ok(Some(status)) if !timed_out => Some(status),
+        _ => {
+            let _ = child.kill();
+            let _ = child.wait();
+            None
+        }
+    };
+    // the map of a new user namespace is empty until it's written
+    let written = fs::read(map_file)
+        .map(|data| !data.is_empty())
+        .unwrap_or(false);
+    match status {
+        Some(status) if status.success() && written => Ok(()),
+        status => Err(Error::IdMapHelper {
+            program: program.to_path_buf(),
+            args: args.to_vec(),
+            status,
+            stderr: String::from_utf8_lossy(&output).into_owned(),
+        }),
+    }
+}
+
+fn overlaps(a: (u64, u64), b: (u64, u64)) -> bool {
+    a.0 < b.0 + b.1 && b.0 < a.0 + a.1
+}
+
+fn validate<I>(kind: &'static str, maps: I) -> Result<(), Error>
+where
+    I: Iterator<Item = (u32, u32, u32)>,
+{
+    let invalid = |message: String| Error::InvalidIdMap { message };
+    let maps = maps
+        .map(|(inside, outside, count)| (inside as u64, outside as u64, count as u64))
+        .collect::<Vec<_>>();
+    if maps.len() > MAX_ENTRIES {
+        return Err(invalid(format!(
+            "{} {} map entries, at most {} are supported",
+            maps.len(),
+            kind,
+            MAX_ENTRIES
+        )));
+    }
+    // uid 4294967295 is (uid_t)-1, so ranges must end below it
+    let limit = u32::MAX as u64;
+    for (idx, &(inside, outside, count)) in maps.iter().enumerate() {
+        if count == 0 {
+            return Err(invalid(format!(
+                "{} map entry {}:{} has zero count",
+                kind, inside, outside
+            )));
+        }
+        if inside + count > limit || outside + count > limit {
+            return Err(invalid(format!(
+                "{} map entry {}:{}:{} overflows",
+                kind, inside, outside, count
+            )));
+        }
+        for &(other_in, other_out, other_count) in &maps[..idx] {
+            if overlaps((inside, count), (other_in, other_count)) {
+                return Err(invalid(format!(
+                    "inside {} ranges {}..{} and {}..{} overlap",
+                    kind,
+                    other_in,
+                    other_in + other_count,
+                    inside,
+                    inside + count
+                )));
+            }
+            if overlaps((outside, count), (other_out, other_count)) {
+                return Err(invalid(format!(
+                    "outside {} ranges {}..{} and {}..{} overlap",
+                    kind,
+                    other_out,
+                    other_out + other_count,
+                    outside,
+                    outside + count
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+
+    use super::{cap_setgid_in_status, default_gid_args, default_uid_args, GidMap, UidMap};
+
+    #[test]
+    fn test_default_args() {
+        let args = default_uid_args(
+            123,
+            &[UidMap::range(0, 1000, 1), UidMap::range(1, 100000, 65536)],
+        );
+        let expected: Vec<OsString> = ["123", "0", "1000", "1", "1", "100000", "65536"]
+            .iter()
+            .map(|x| x.into())
+            .collect();
+        assert_eq!(args, expected);
+        let args = default_gid_args(7, &[GidMap::range(0, 100, 1)]);
+        let expected: Vec<OsString> = ["7", "0", "100", "1"].iter().map(|x| x.into()).collect();
+        assert_eq!(args, expected);
+    }
+
+    #[test]
+    fn test_cap_setgid_in_status() {
+        let status = "Name:\tcat\nCapInh:\t0000000000000000\nCapEff:\t%s\n";
+        let check = |mask: &str| cap_setgid_in_status(&status.replace("%s", mask));
+        assert_eq!(check("000001ffffffffff"), Some(true));
+        assert_eq!(check("0000000000000040"), Some(true));
+        assert_eq!(check("ffffffffffffffbf"), Some(false));
+        assert_eq!(check("0000000000000000"), Some(false));
+        assert_eq!(check("junk"), None);
+        assert_eq!(cap_setgid_in_status("Name:\tcat\n"), None);
+    }
+
+    #[test]
+    fn test_valid() {
+        assert!(UidMap::validate_ranges(&[]).is_ok());
+        assert!(UidMap::validate_ranges(&[
+            UidMap::range(0, 1000, 1),
+            UidMap::range(1, 100000, 65536),
+        ])
+        .is_ok());
+        assert!(GidMap::validate_ranges(&[GidMap::range(0, 0, u32::MAX)]).is_ok());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let err = UidMap::validate_ranges(&[UidMap::range(0, 1000, 0)]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid uid/gid mapping: uid map entry 0:1000 has zero count"
+        );
+        let err =
+            GidMap::validate_ranges(&[GidMap::range(0, 1000, 10), GidMap::range(5, 2000, 10)])
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid uid/gid mapping: inside gid ranges 0..10 and 5..15 overlap"
+        );
+        let err =
+            UidMap::validate_ranges(&[UidMap::range(0, 1000, 10), UidMap::range(10, 1009, 10)])
+                .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "invalid uid/gid mapping: outside uid ranges 1000..1010 and 1009..1019 overlap"
+        );
+        assert!(UidMap::validate_ranges(&[UidMap::range(1, 0, u32::MAX)]).is_err());
+    }
+}
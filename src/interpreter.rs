@@ -0,0 +1,139 @@
+//! Finding the interpreter of an executable for error reporting
+//!
+//! This code runs in the child after `execve` failed, so it must not
+//! allocate memory. The file is accessed by the `read_at` callback so the
+//! same code is used by the parent to read the file using stdlib.
+
+/// Maximum length of interpreter path including the terminating nul
+pub const INTERP_MAX: usize = 256;
+
+const PT_INTERP: u32 = 3;
+
+#[cfg(target_endian = "little")]
+const NATIVE_DATA: u8 = 1;
+#[cfg(target_endian = "big")]
+const NATIVE_DATA: u8 = 2;
+
+/// Finds the ELF interpreter (`PT_INTERP`) or the `#!` interpreter
+///
+/// The `read_at` callback reads the file at the offset and returns number
+/// of bytes read (zero on error). The path is stored in `buf` and its length
+/// is returned, the length is always less than the size of the `buf`, so
+/// there is room for a terminating nul.
+pub fn find_interpreter<F>(mut read_at: F, buf: &mut [u8; INTERP_MAX]) -> Option<usize>
+where
+    F: FnMut(u64, &mut [u8]) -> usize,
+{
+    let mut hdr = [0u8; 64];
+    let n = read_at(0, &mut hdr);
+    if n >= 2 && hdr[..2] == *b"#!" {
+        let n = read_at(2, &mut buf[..INTERP_MAX - 1]);
+        let start = buf[..n].iter().position(|&c| c != b' ' && c != b'\t')?;
+        let len = buf[start..n]
+            .iter()
+            .position(|&c| c == b' ' || c == b'\t' || c == b'\n')
+            .unwrap_or(n - start);
+        buf.copy_within(start..start + len, 0);
+        return Some(len);
+    }
+    if n < 52 || hdr[..4] != *b"\x7fELF" || hdr[5] != NATIVE_DATA {
+        return None;
+    }
+    let elf64 = match hdr[4] {
+        1 => false,
+        2 if n == 64 => true,
+        _ => return None,
+    };
+    let (phoff, phentsize, phnum) = if elf64 {
+        (u64_at(&hdr, 32), u16_at(&hdr, 54), u16_at(&hdr, 56))
+    } else {
+        (u32_at(&hdr, 28) as u64, u16_at(&hdr, 42), u16_at(&hdr, 44))
+    };
+    let mut phdr = [0u8; 56];
+    let phdr = if elf64 {
+        &mut phdr[..]
+    } else {
+        &mut phdr[..32]
+    };
+    if (phentsize as usize) < phdr.len() {
+        return None;
+    }
+    for i in 0..phnum as u64 {
+        let offset = phoff.checked_add(i * phentsize as u64)?;
+        if read_at(offset, phdr) != phdr.len() {
+            return None;
+        }
+        if u32_at(phdr, 0) != PT_INTERP {
+            continue;
+        }
+        let (offset, size) = if elf64 {
+            (u64_at(phdr, 8), u64_at(phdr, 32))
+        } else {
+            (u32_at(phdr, 4) as u64, u32_at(phdr, 16) as u64)
+        };
+        let size = size.min(INTERP_MAX as u64 - 1) as usize;
+        let n = read_at(offset, &mut buf[..size]);
+        return Some(buf[..n].iter().position(|&c| c == 0).unwrap_or(n));
+    }
+    None
+}
+
+fn u16_at(buf: &[u8], off: usize) -> u16 {
+    u16::from_ne_bytes([buf[off], buf[off + 1]])
+}
+
+fn u32_at(buf: &[u8], off: usize) -> u32 {
+    u32::from_ne_bytes([buf[off], buf[off + 1], buf[off + 2], buf[off + 3]])
+}
+
+fn u64_at(buf: &[u8], off: usize) -> u64 {
+    let mut b = [0u8; 8];
+    b.copy_from_slice(&buf[off..off + 8]);
+    u64::from_ne_bytes(b)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::File;
+    use std::os::unix::fs::FileExt;
+
+    use super::{find_interpreter, INTERP_MAX};
+
+    fn interpreter(data: &[u8]) -> Option<Vec<u8>> {
+        let mut buf = [0u8; INTERP_MAX];
+        let read = |off: u64, dest: &mut [u8]| {
+            let data = data.get(off as usize..).unwrap_or(&[]);
+            let n = data.len().min(dest.len());
+            dest[..n].copy_from_slice(&data[..n]);
+            n
+        };
+        find_interpreter(read, &mut buf).map(|n| buf[..n].to_vec())
+    }
+
+    #[test]
+    fn test_shebang() {
+        assert_eq!(interpreter(b"#!/bin/sh\necho"), Some(b"/bin/sh".to_vec()));
+        assert_eq!(
+            interpreter(b"#! /usr/bin/env python3\n"),
+            Some(b"/usr/bin/env".to_vec())
+        );
+        assert_eq!(interpreter(b"#!/bin/sh"), Some(b"/bin/sh".to_vec()));
+        assert_eq!(interpreter(b"#!\n"), Some(b"".to_vec()));
+        assert_eq!(interpreter(b"echo hello\n"), None);
+    }
+
+    #[test]
+    fn test_elf() {
+        let file = File::open("/bin/sh").unwrap();
+        let mut buf = [0u8; INTERP_MAX];
+        let read = |off, dest: &mut [u8]| file.read_at(dest, off).unwrap_or(0);
+        let len = find_interpreter(read, &mut buf).expect("/bin/sh is dynamic");
+        assert!(buf[..len].starts_with(b"/"));
+        assert!(buf[..len].windows(3).any(|w| w == b"ld-"));
+    }
+
+    #[test]
+    fn test_truncated_elf() {
+        assert_eq!(interpreter(b"\x7fELF\x02\x01\x01"), None);
+    }
+}
@@ -0,0 +1,213 @@
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use libc::pid_t;
+use nix::unistd::gettid;
+
+use crate::{Child, ExitStatus, Signal};
+
+/// The likely cause of the child being killed by `SIGKILL`
+///
+/// See `Child::kill_reason`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KillReason {
+    /// The kernel OOM killer has killed the child (either global or the
+    /// one of the memory cgroup)
+    OomKill,
+    /// The thread which spawned the child has exited and `SIGKILL` was
+    /// sent as the `death_sig`
+    ParentDeath,
+    /// Nothing points to the cause, i.e. it was `kill` by some process
+    Unknown,
+}
+
+/// Things remembered at spawn to find out the kill reason later
+#[derive(Debug)]
+pub(crate) struct KillContext {
+    /// The thread which spawned the child if `death_sig` is `SIGKILL`
+    spawn_tid: Option<pid_t>,
+    /// `CLOCK_MONOTONIC` in microseconds, as kernel log timestamps
+    started: u64,
+    /// `oom_kill` counter of the cgroup before the child joined it
+    oom_kills: u64,
+}
+
+impl KillContext {
+    pub(crate) fn new(death_sig: Option<Signal>, cgroup: Option<&Path>) -> KillContext {
+        KillContext {
+            spawn_tid: if death_sig == Some(Signal::SIGKILL) {
+                Some(gettid().as_raw())
+            } else {
+                None
+            },
+            started: monotonic_micros().saturating_sub(1_000_000),
+            oom_kills: cgroup.and_then(oom_kills).unwrap_or(0),
+        }
+    }
+}
+
+fn monotonic_micros() -> u64 {
+    let mut now = libc::timespec {
+        tv_sec: 0,
+        tv_nsec: 0,
+    };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now) };
+    now.tv_sec as u64 * 1_000_000 + now.tv_nsec as u64 / 1000
+}
+
+/// Returns `oom_kill` counter from `memory.events` of the cgroup
+fn oom_kills(cgroup: &Path) -> Option<u64> {
+    let events = fs::read_to_string(cgroup.join("memory.events")).ok()?;
+    events
+        .lines()
+        .find_map(|line| line.strip_prefix("oom_kill "))
+        .and_then(|value| value.trim().parse().ok())
+}
+
+/// Looks for the OOM killer message about `pid` in the kernel log
+///
+/// Each read of `/dev/kmsg` returns a single record of the form
+/// `level,seq,timestamp,flags;message`, the messages of the OOM killer
+/// are `Out of memory: Killed process 123 (name) ...` or the same
+/// prefixed by `Memory cgroup`.
+fn oom_logged(pid: pid_t, since: u64) -> bool {
+    let mut kmsg = match fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open("/dev/kmsg")
+    {
+        Ok(file) => file,
+        Err(_) => return false,
+    };
+    let needle = format!("Killed process {} (", pid);
+    let mut buf = [0u8; 8192];
+    loop {
+        let len = match kmsg.read(&mut buf) {
+            Ok(0) => return false,
+            Ok(len) => len,
+            // the record is overwritten while reading
+            Err(ref e) if e.raw_os_error() == Some(libc::EPIPE) => continue,
+            Err(_) => return false, // EAGAIN at the end of the log
+        };
+        let record = String::from_utf8_lossy(&buf[..len]);
+        let (header, message) = match record.split_once(';') {
+            Some(pair) => pair,
+            None => continue,
+        };
+        let timestamp = header.split(',').nth(2).and_then(|t| t.parse::<u64>().ok());
+        if timestamp.is_some_and(|t| t >= since) && message.contains(&needle) {
+            return true;
+        }
+    }
+}
+
+impl Child {
+    /// Returns the likely reason the child was killed by `SIGKILL`
+    ///
+    /// Returns `None` unless the child is waited for and its status is
+    /// the death by `SIGKILL`. Otherwise it's a best-effort guess:
+    ///
+    /// * `OomKill` if the `oom_kill` counter of the cgroup set by
+    ///   `cgroup_limits` has increased, or the kernel log (`/dev/kmsg`,
+    ///   which usually requires root) has the OOM killer message for the
+    ///   pid of the child
+    /// * `ParentDeath` if `death_sig` is `SIGKILL` (the default) and the
+    ///   thread which called `spawn` has exited
+    /// * `Unknown` otherwise, including when the files can't be read
+    ///
+    /// The cause is looked up at the time of the call, not when waiting,
+    /// so waiting is never delayed.
+    pub fn kill_reason(&self) -> Option<KillReason> {
+        match self.status {
+            Some(ExitStatus::Signaled(Signal::SIGKILL, _)) => {}
+            _ => return None,
+        }
+        let ctx = &self.kill_context;
+        let oom = match self.cgroup.as_deref().and_then(oom_kills) {
+            Some(kills) if kills > ctx.oom_kills => true,
+            _ => oom_logged(self.pid, ctx.started),
+        };
+        if oom {
+            return Some(KillReason::OomKill);
+        }
+        if let Some(tid) = ctx.spawn_tid {
+            if !Path::new(&format!("/proc/self/task/{}", tid)).exists() {
+                return Some(KillReason::ParentDeath);
+            }
+        }
+        Some(KillReason::Unknown)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::thread;
+
+    use super::KillReason;
+    use crate::test_util::cgroup_with_controller;
+    use crate::{CgroupLimits, Command, Signal};
+
+    #[test]
+    fn test_not_killed() {
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert_eq!(child.kill_reason(), None);
+        child.wait().unwrap();
+        assert_eq!(child.kill_reason(), None);
+    }
+
+    #[test]
+    fn test_unknown() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(child.kill_reason(), Some(KillReason::Unknown));
+    }
+
+    #[test]
+    fn test_parent_death() {
+        let mut child = thread::spawn(|| Command::new("/bin/sleep").arg("10").spawn().unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(Signal::SIGKILL as i32));
+        assert_eq!(child.kill_reason(), Some(KillReason::ParentDeath));
+
+        let mut child = thread::spawn(|| {
+            Command::new("/bin/sleep")
+                .arg("10")
+                .set_parent_death_signal(Signal::SIGTERM)
+                .spawn()
+                .unwrap()
+        })
+        .join()
+        .unwrap();
+        assert_eq!(child.wait().unwrap().signal(), Some(Signal::SIGTERM as i32));
+        assert_eq!(child.kill_reason(), None);
+    }
+
+    #[test]
+    fn test_oom_kill() {
+        let dir = match cgroup_with_controller("memory") {
+            Some(dir) => dir.join(format!("unshare-oom-{}", std::process::id())),
+            None => return, // cgroup2 with memory controller is not available
+        };
+        let mut cmd = Command::new("/bin/sh");
+        // the string doubles until it doesn't fit
+        cmd.args(&["-c", "x=xxxxxxxx; while :; do x=$x$x; done"]);
+        cmd.cgroup_limits(
+            &dir,
+            CgroupLimits {
+                memory_max: Some(16 << 20),
+                memory_swap_max: Some(0),
+                ..CgroupLimits::default()
+            },
+        );
+        let mut child = cmd.spawn().unwrap();
+        let status = child.wait().unwrap();
+        fs::remove_dir(&dir).unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGKILL as i32));
+        assert_eq!(child.kill_reason(), Some(KillReason::OomKill));
+    }
+}
@@ -23,6 +23,14 @@
 //!    cases of `chroot`, `pivot_root`, user and mount namespaces. So we expect
 //!    its easier to do for your specific container setup.
 //!
+//! 4. The child always gets its own copy of the file descriptor table, there
+//!    is no way to clone with `CLONE_FILES`. The kernel unshares the table
+//!    on `execve` anyway, so descriptors opened after the program started
+//!    would never be shared, and before `execve` the child rearranges
+//!    descriptors, which with a shared table would clobber the parent's
+//!    ones. Pass descriptors explicitly with `file_descriptor` or use
+//!    `SCM_RIGHTS` over a unix socket to hand them over later.
+//!
 //! Anyway this is low-level interface. You may want to use some higher level
 //! abstraction which mounts filesystems, sets network and monitors processes.
 //!
@@ -32,42 +40,103 @@ extern crate nix;
 #[cfg(test)]
 extern crate rand;
 
+#[cfg(feature = "async")]
+mod async_child;
 mod callbacks;
 mod caps;
+mod cgroup;
 mod child;
 mod chroot;
+mod clone3;
 mod config;
 mod debug;
+mod env_file;
+mod environ;
 mod error;
 mod fds;
 mod ffi_util;
+mod fork_check;
+mod group;
 mod idmap;
+mod interpreter;
+mod kill_reason;
 mod linux;
+mod metadata;
+#[cfg(feature = "mio")]
+mod mio_source;
+mod mitigations;
+mod mount;
 mod namespace;
+mod netlink;
+mod output;
+mod persist;
+mod pid;
 mod pipe;
+mod pipeline;
+mod plan;
+mod proc_stat;
+mod program;
+mod ready;
+mod reaper;
+mod retry;
 mod run;
+mod spawn_many;
+mod splice;
 mod status;
 mod std_api;
 mod stdio;
+mod tee;
+#[cfg(test)]
+mod test_util;
+mod trace;
+mod unfreezer;
 mod wait;
 mod zombies;
 
+#[cfg(feature = "async")]
+pub use crate::async_child::AsyncChild;
 pub use crate::caps::Capability;
+pub use crate::cgroup::CgroupLimits;
 pub use crate::debug::{Printer, Style};
-pub use crate::error::Error;
+pub use crate::env_file::EnvFileError;
+pub use crate::environ::EnvAction;
+pub use crate::error::{Error, SetupStage};
+pub use crate::fds::{FdAction, FdPlanError};
+pub use crate::fork_check::at_spawn_check;
+pub use crate::group::signal_process_group;
 pub use crate::idmap::{GidMap, UidMap};
-pub use crate::namespace::Namespace;
+pub use crate::kill_reason::KillReason;
+pub use crate::metadata::MetadataVar;
+pub use crate::mitigations::{SpecMitigations, SpecMode};
+pub use crate::namespace::{Namespace, ParseNamespaceError};
+pub use crate::output::Output;
+pub use crate::persist::unpersist_namespace;
+pub use crate::pid::{Pid, ReapedError};
 pub use crate::pipe::{PipeReader, PipeWriter};
+pub use crate::pipeline::{Pipeline, PipelineChildren};
+pub use crate::plan::{PlanStep, SpawnPlan};
+pub use crate::program::ProgramBase;
+pub use crate::ready::Readiness;
+pub use crate::reaper::AutoReaper;
+pub use crate::retry::RetryPolicy;
+pub use crate::spawn_many::{PerChildOverrides, SpawnManyError};
 pub use crate::status::ExitStatus;
 pub use crate::stdio::{Fd, Stdio};
-pub use crate::zombies::{child_events, reap_zombies, ChildEvent};
+pub use crate::trace::SpawnPhase;
+pub use crate::unfreezer::UNFREEZE_PROTOCOL_VERSION;
+pub use crate::wait::{set_wait_interrupt_flag, WaitOutcome};
+pub use crate::zombies::{child_events, reap_into, reap_zombies, ChildEvent};
+pub use nix::mount::MsFlags;
+pub use nix::sched::CloneFlags;
 pub use nix::sys::signal::Signal;
 
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
 use std::ffi::{CString, OsString};
 use std::io;
 use std::os::unix::io::RawFd;
 use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
 
 use crate::pipe::PipeHolder;
 
@@ -79,25 +148,44 @@ type BoxError = Box<dyn (::std::error::Error) + Send + Sync + 'static>;
 pub struct Command {
     filename: CString,
     args: Vec<CString>,
-    environ: Option<HashMap<OsString, OsString>>,
+    nul_byte: Option<ffi_util::NulByte>,
+    env_base: environ::EnvBase,
+    env_filters: Vec<environ::EnvRewrite>,
+    environ: environ::VarMap<Option<OsString>>,
     config: config::Config,
     fds: HashMap<RawFd, Fd>,
-    close_fds: Vec<(RawFd, RawFd)>,
+    /// `(start, end, bounded)`, the last one is `false` for `close_fds(n..)`
+    close_fds: Vec<(RawFd, RawFd, bool)>,
     chroot_dir: Option<PathBuf>,
-    pivot_root: Option<(PathBuf, PathBuf, bool)>,
+    /// `put_old` is `None` for `pivot_root_auto`
+    pivot_root: Option<(PathBuf, Option<PathBuf>)>,
+    unmount_old_root: bool,
     id_map_commands: Option<(PathBuf, PathBuf)>,
-    pid_env_vars: HashSet<OsString>,
+    id_map_args: Option<(idmap::UidArgs, idmap::GidArgs)>,
+    metadata_env_vars: environ::VarMap<MetadataVar>,
     keep_caps: Option<[u32; 2]>,
     before_unfreeze: Option<Box<dyn FnMut(u32) -> Result<(), BoxError>>>,
     pre_exec: Option<Box<dyn Fn() -> Result<(), io::Error>>>,
+    after_exec: Option<Box<dyn FnMut(pid_t)>>,
+    tracer: Option<trace::Tracer>,
+    reserved_fds: Vec<RawFd>,
+    progress: Option<PipeWriter>,
+    /// Set by `prepare`, reset when the environment settings change
+    prepared_env: Option<Vec<Vec<u8>>>,
 }
 
 /// The reference to the running child
 #[derive(Debug)]
 pub struct Child {
     pid: pid_t,
+    pgid: pid_t,
     status: Option<ExitStatus>,
     fds: HashMap<RawFd, PipeHolder>,
+    tees: HashMap<RawFd, Arc<AtomicBool>>,
+    output_limits: Option<(usize, usize)>,
+    cgroup: Option<PathBuf>,
+    ready: Option<ready::ReadyPipe>,
+    kill_context: kill_reason::KillContext,
     /// Stdin of a child if it is a pipe
     pub stdin: Option<PipeWriter>,
     /// Stdout of a child if it is a pipe
@@ -1,16 +1,28 @@
-use std::ffi::OsStr;
+use std::ffi::{OsStr, OsString};
+use std::fs;
 use std::io;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 use std::path::Path;
+use std::time::Duration;
 
+use libc::{pid_t, uid_t};
+use nix::sched::CloneFlags;
 use nix::sys::signal::Signal;
 
 use crate::caps::Capability;
-use crate::ffi_util::ToCString;
+use crate::ffi_util::NulByte;
 use crate::idmap::{GidMap, UidMap};
 use crate::namespace::to_clone_flag;
 use crate::stdio::dup_file_cloexec;
-use crate::{Command, Namespace};
+use crate::{Command, MetadataVar, Namespace};
+
+/// Describes the descriptor as `fd 5 (net:[4026531992])`
+fn describe_fd(fd: RawFd) -> String {
+    match fs::read_link(format!("/proc/self/fd/{}", fd)) {
+        Ok(target) => format!("fd {} ({})", fd, target.display()),
+        Err(_) => format!("fd {}", fd),
+    }
+}
 
 impl Command {
     /// Allow child process to daemonize. By default we run equivalent of
@@ -46,10 +58,12 @@ impl Command {
     /// ``cmd.unshare(Namespace::Pid)``, but you may need to setup mount points
     /// and other important things (which are out of scope too).
     ///
-    /// To reset this behavior use ``allow_daemonize()``.
+    /// To reset this behavior use ``allow_daemonize()`` or pass `None`, in
+    /// which case `PR_SET_PDEATHSIG` is not called at all (so the child
+    /// has no death signal, as it's not inherited by `clone`).
     ///
-    pub fn set_parent_death_signal(&mut self, sig: Signal) -> &mut Command {
-        self.config.death_sig = Some(sig);
+    pub fn set_parent_death_signal<S: Into<Option<Signal>>>(&mut self, sig: S) -> &mut Command {
+        self.config.death_sig = sig.into();
         self
     }
 
@@ -77,10 +91,32 @@ impl Command {
         self
     }
 
+    /// Check that no descriptor lets the child escape the new root
+    ///
+    /// A directory descriptor opened outside of the new root (or the
+    /// working directory left there) is enough to get out of `chroot`
+    /// with `fchdir` or `openat(fd, "..")`. When enabled, right before
+    /// `execve` (after `pre_exec`) the child lists its descriptors in
+    /// `/proc/self/fd` and, for each directory which is not close-on-exec,
+    /// walks `..` up until it
+    /// reaches either the new root or the real one. The spawn fails with
+    /// `Error::FdsOutsideRoot` listing the descriptors which are outside.
+    ///
+    /// This costs a few syscalls per descriptor, so it's disabled by
+    /// default. Only has effect with `chroot_dir` or `pivot_root`. Note
+    /// that the working directory is always checked to be inside the new
+    /// root, whether the audit is enabled or not (`current_dir_fd` outside
+    /// of it fails spawn with `Error::ChangeRoot(EXDEV)`).
+    pub fn audit_fds_for_escape(&mut self, enable: bool) -> &mut Command {
+        self.config.audit_fds = enable;
+        self
+    }
+
     /// Moves the root of the file system to the directory `put_old` and
-    /// makes `new_root` the new root file system. Also it's optionally
-    /// unmount `new_root` mount point after moving root (but it must exist
-    /// anyway).
+    /// makes `new_root` the new root file system
+    ///
+    /// The old root stays mounted at `put_old` (as seen inside the new
+    /// root), unless `pivot_root_unmount_old` is set.
     ///
     /// The documentation says that `put_old` must be underneath the
     /// `new_root`.  Currently we have a restriction that both must be absolute
@@ -88,12 +124,9 @@ impl Command {
     ///
     /// **Warning** if you don't unshare the mount namespace you will get
     /// moved filesystem root for *all processes running in that namespace*
-    /// including parent (currently running) process itself. If you don't
-    /// run equivalent to ``mount --make-private`` for the old root filesystem
-    /// and set ``unmount`` to true, you may get unmounted filesystem for
-    /// running processes too.
+    /// including parent (currently running) process itself.
     ///
-    /// See `man 2 pivot` for further details
+    /// See `man 2 pivot_root` for further details
     ///
     /// Note that if both chroot dir and pivot_root specified. The chroot dir
     /// is applied after pivot root.
@@ -106,7 +139,6 @@ impl Command {
         &mut self,
         new_root: A,
         put_old: B,
-        unmount: bool,
     ) -> &mut Command {
         let new_root = new_root.as_ref();
         let put_old = put_old.as_ref();
@@ -122,7 +154,55 @@ impl Command {
                 panic!("The new_root is not a prefix of put old");
             }
         }
-        self.pivot_root = Some((new_root.to_path_buf(), put_old.to_path_buf(), unmount));
+        self.pivot_root = Some((new_root.to_path_buf(), Some(put_old.to_path_buf())));
+        self
+    }
+
+    /// Same as `pivot_root`, but `put_old` is a new directory created by
+    /// the child in the `new_root`
+    ///
+    /// The directory is named `.pivot_old.XXXXXX` with a random suffix and
+    /// created with mode 0700, so it can't clash with the contents of the
+    /// new root and other users can't get to the old root through it.
+    ///
+    /// If `unmount_old` is set (see `pivot_root_unmount_old`), the old root
+    /// is detached and the directory is removed right after pivoting.
+    /// Otherwise it stays mounted there for the program, and the directory
+    /// is only left in the `new_root` when `spawn` succeeds: on failures
+    /// after it's created the child detaches the old root and removes the
+    /// directory before exiting (which is skipped if the child has no
+    /// privileges for that anymore, i.e. after `uid`).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `new_root` is not absolute.
+    pub fn pivot_root_auto<A: AsRef<Path>>(
+        &mut self,
+        new_root: A,
+        unmount_old: bool,
+    ) -> &mut Command {
+        let new_root = new_root.as_ref();
+        if !new_root.is_absolute() {
+            panic!("New root must be absolute");
+        };
+        self.pivot_root = Some((new_root.to_path_buf(), None));
+        self.unmount_old_root = unmount_old;
+        self
+    }
+
+    /// Unmount the old root after `pivot_root` (default is `false`)
+    ///
+    /// The old root is detached (`MNT_DETACH`) from `put_old` right after
+    /// the root is changed, so the child can't reach the file system of
+    /// the parent by it. Usually that's what is wanted for isolation, but
+    /// it's easy to get wrong: the mount points of the old root are shared
+    /// with the parent's namespace unless you `mount --make-private` them
+    /// (or they are private already), in which case the unmount propagates
+    /// and file systems get unmounted for running processes too.
+    ///
+    /// Has no effect without `pivot_root`.
+    pub fn pivot_root_unmount_old(&mut self, unmount: bool) -> &mut Command {
+        self.unmount_old_root = unmount;
         self
     }
 
@@ -137,6 +217,110 @@ impl Command {
         self
     }
 
+    /// Add flags to the `clone` call of the child as they are
+    ///
+    /// This is for the namespaces (or other flags) which `Namespace`
+    /// doesn't cover yet, the flags which `nix` doesn't know either can be
+    /// made with `CloneFlags::from_bits_unchecked`. Flags are cumulative,
+    /// and the flags of the namespaces listed in `Namespace` are just the
+    /// same as `unshare`.
+    ///
+    /// Nothing is done for the flags the crate doesn't recognize, i.e. no
+    /// id maps are written and nothing is mounted for them, the child gets
+    /// whatever the kernel does on `clone`. And the flags which change how
+    /// the child is created are managed by the
+    /// crate itself: `CLONE_VM`, `CLONE_FS`, `CLONE_FILES`, `CLONE_SIGHAND`,
+    /// `CLONE_THREAD`, `CLONE_VFORK`, `CLONE_PARENT`, `CLONE_PTRACE`, the
+    /// tid and TLS flags, `CLONE_PIDFD` and the exit signal (the lowest
+    /// byte). `spawn` returns `Error::ConflictingOptions` if any of them is
+    /// set.
+    pub fn raw_clone_flags(&mut self, flags: CloneFlags) -> &mut Command {
+        self.config.namespaces |= flags;
+        self
+    }
+
+    /// Make the child reap orphans when it's the init of a new pid namespace
+    ///
+    /// The first process of a pid namespace (pid 1) inherits all the
+    /// orphaned processes of the namespace, and if it doesn't wait for them
+    /// (most programs don't expect to be an init) zombies accumulate. With
+    /// this option the child forks right before `execve`: the program runs
+    /// in the forked process, while the child stays a tiny init which
+    /// reaps all the processes and forwards `SIGHUP`, `SIGINT`, `SIGQUIT`,
+    /// `SIGTERM`, `SIGUSR1` and `SIGUSR2` to the program. When the program
+    /// exits, the init exits with the same code (or `128 + signal` if the
+    /// program was killed), and the kernel kills the rest of the namespace.
+    ///
+    /// Errors of `execve` are reported by `spawn` as usual. Has no effect
+    /// if the child is not pid 1 (i.e. `Namespace::Pid` isn't unshared).
+    /// Can't be combined with `trace_me`, as the program is not a child of
+    /// the caller.
+    ///
+    /// This is a minimal init: a real one (like `tini` or `dumb-init`) is
+    /// better when the program needs job control, signals forwarded to
+    /// the whole process group, or other init duties.
+    pub fn reap_orphans_as_init(&mut self, reap: bool) -> &mut Command {
+        self.config.reap_orphans = reap;
+        self
+    }
+
+    /// Limit CPU time of the child to `secs` seconds (`RLIMIT_CPU`)
+    ///
+    /// Unlike a wall-clock timeout, only the time the process actually
+    /// runs on CPU is counted, so a program waiting for input is not
+    /// killed. The soft limit is `secs`: when it's reached the kernel
+    /// sends `SIGXCPU`, which kills the process by default, but it may be
+    /// caught or ignored (the kernel then sends it again every second).
+    /// The hard limit is one second more, when it's reached the process
+    /// is killed by `SIGKILL`, so the program can clean up when it's told
+    /// to, but never runs much longer. Both limits are clamped to the
+    /// current hard limit, as only privileged processes can raise it.
+    ///
+    /// The limit is set right before `pre_exec`, so the setup of the child
+    /// is not counted, and like other resource limits it's preserved by
+    /// `execve` and inherited by the processes the program forks (each of
+    /// them gets its own budget, the time is not shared).
+    pub fn cpu_time_limit(&mut self, secs: u64) -> &mut Command {
+        self.config.cpu_time_limit = Some(secs);
+        self
+    }
+
+    /// Bind the memory of the child to the NUMA node `node`
+    ///
+    /// The memory policy is set to `MPOL_BIND` with `set_mempolicy` right
+    /// before `pre_exec`, so all the memory the program allocates comes
+    /// from this node only (and it's killed by OOM rather than falling back
+    /// to other nodes). The policy is preserved by `execve` and `fork`,
+    /// like with `numactl --membind`. It's not possible to bind the memory
+    /// of the setup itself, the nodes of already allocated pages aren't
+    /// changed.
+    ///
+    /// CPUs are not restricted, the node's CPUs are listed in
+    /// `/sys/devices/system/node/node<N>/cpulist`. Failures are reported
+    /// as `Error::NumaBind`, `ENOSYS` if the kernel has no NUMA support
+    /// and `EINVAL` if there is no such node with memory.
+    pub fn numa_bind(&mut self, node: u32) -> &mut Command {
+        self.config.numa_node = Some(node);
+        self
+    }
+
+    /// Set the audit login uid of the child (`/proc/self/loginuid`)
+    ///
+    /// The audit subsystem attributes the actions of the process and all
+    /// its descendants to this uid, whatever user they run as. Any process
+    /// can set it while it's unset (`4294967295`), but changing the value
+    /// inherited from the parent requires `CAP_AUDIT_CONTROL` in the initial
+    /// user namespace, and may be forbidden by the kernel altogether. In a
+    /// new user namespace the uid must be mapped by `set_id_maps`.
+    ///
+    /// The value is written before the root is changed (as `/proc` may be
+    /// not there) and before `uid`/`gid` drop privileges. Errors are
+    /// reported as `Error::LoginUid`, with a hint for `EPERM`/`EACCES`.
+    pub fn loginuid(&mut self, uid: uid_t) -> &mut Command {
+        self.config.loginuid = Some(uid);
+        self
+    }
+
     /// Reassociate child process with a namespace specified by a file
     /// descriptor
     ///
@@ -147,13 +331,25 @@ impl Command {
     /// See `man 2 setns` for further details
     ///
     /// Note: using `unshare` and `setns` for the same namespace is meaningless.
+    ///
+    /// Namespaces are joined in a fixed order whatever order they are set
+    /// in: user namespace first (as the others may be owned by it), then
+    /// pid namespace, then the rest, and mount namespace last, like
+    /// `nsenter` does. Failures are reported as `Error::SetNs` naming the
+    /// namespace and the descriptor.
+    ///
+    /// Joining pid namespace only moves the processes which the child forks
+    /// afterwards, so it can't be used with `spawn_fn` (which returns
+    /// `Error::ConflictingOptions`), while the program run by `spawn` is
+    /// expected to fork the processes which are in the namespace.
     pub fn set_namespace<F: AsRawFd>(
         &mut self,
         file: &F,
         ns: Namespace,
     ) -> io::Result<&mut Command> {
         let fd = dup_file_cloexec(file)?;
-        self.config.setns_namespaces.insert(ns, fd);
+        let source = describe_fd(file.as_raw_fd());
+        self.config.setns_namespaces.insert(ns, (fd, source));
         Ok(self)
     }
 
@@ -168,7 +364,11 @@ impl Command {
     /// 2. Invoke a `newuidmap`, `newgidmap` commands
     ///
     /// First option works either if current process is root or if resulting
-    /// map only contains current user in the mapping.
+    /// map only contains current user in the mapping. In the latter case,
+    /// i.e. without `CAP_SETGID`, "deny" is written to `/proc/<pid>/setgroups`
+    /// before the gid map, as the kernel requires, so the child can't call
+    /// `setgroups` (and `groups` fails). With the commands `setgroups` is
+    /// left to them.
     ///
     /// The library will not try to guess the behavior. By default it will
     /// write directly. You need to call the `set_id_map_commands` when you
@@ -190,6 +390,17 @@ impl Command {
     ///
     /// See `man 1 newuidmap`, `man 1 newgidmap` for details
     ///
+    /// The utilities are run as `newuidmap <pid> <uid> <loweruid> <count>...`
+    /// with a triple of arguments for each map entry (and the same
+    /// for `newgidmap`), use `id_map_command_args` to change that.
+    ///
+    /// The output of the commands is captured and, along with the exit
+    /// status, returned in `Error::IdMapHelper` if a command fails. As the
+    /// commands may exit successfully without doing anything, the map
+    /// file of the child is checked to be written too. A command which
+    /// doesn't exit in `id_map_commands_timeout` (5 seconds by default) is
+    /// killed and spawn fails.
+    ///
     /// This method is no-op unless `set_id_maps` is called.
     pub fn set_id_map_commands<A: AsRef<Path>, B: AsRef<Path>>(
         &mut self,
@@ -203,11 +414,38 @@ impl Command {
         self
     }
 
+    /// Set how long each of the commands set by `set_id_map_commands` may
+    /// run, 5 seconds by default
+    ///
+    /// The child is frozen while the commands run, so a hung command
+    /// would block `spawn` forever otherwise.
+    pub fn id_map_commands_timeout(&mut self, timeout: Duration) -> &mut Command {
+        self.config.id_map_timeout = timeout;
+        self
+    }
+
+    /// Override arguments passed to the commands set by `set_id_map_commands`
+    ///
+    /// Each builder receives the pid of the child (as seen by the parent)
+    /// and the map entries, and returns the arguments for the command
+    /// (not including the command itself).
+    ///
+    /// This method is no-op unless `set_id_map_commands` is called.
+    pub fn id_map_command_args<U, G>(&mut self, uid_args: U, gid_args: G) -> &mut Command
+    where
+        U: Fn(pid_t, &[UidMap]) -> Vec<OsString> + 'static,
+        G: Fn(pid_t, &[GidMap]) -> Vec<OsString> + 'static,
+    {
+        self.id_map_args = Some((Box::new(uid_args), Box::new(gid_args)));
+        self
+    }
+
     /// Keep signal mask intact after executing child, keeps also ignored
     /// signals
     ///
     /// By default signal mask is empty and all signals are reset to the
-    /// `SIG_DFL` value right before `execve()` syscall.
+    /// `SIG_DFL` value right before `execve()` syscall (see also
+    /// `inherit_signal_handlers`).
     ///
     /// This is only useful if started process is aware of the issue and sets
     /// sigmasks to some reasonable value. When used wisely it may avoid some
@@ -218,6 +456,44 @@ impl Command {
         self
     }
 
+    /// Keep signal dispositions of the parent (i.e. `SIG_IGN` for
+    /// `SIGPIPE`, which Rust programs set) or reset them to `SIG_DFL`
+    ///
+    /// By default dispositions are reset along with the signal mask, and
+    /// kept if `keep_sigmask` is called. This method overrides that in
+    /// both directions, so `inherit_signal_handlers(false)` gives the child
+    /// a clean signal state unless `keep_sigmask` is used too, and
+    /// `inherit_signal_handlers(true)` keeps ignored signals while the mask
+    /// is still reset.
+    ///
+    /// Note that `execve` itself resets the signals which have handlers
+    /// (not ignored) to `SIG_DFL`, so only ignored signals are inherited
+    /// by the program.
+    pub fn inherit_signal_handlers(&mut self, inherit: bool) -> &mut Command {
+        self.config.inherit_handlers = Some(inherit);
+        self
+    }
+
+    /// Make the child traced by the parent, by calling
+    /// `ptrace(PTRACE_TRACEME)` right before `execve()`
+    ///
+    /// The parent process (precisely, the thread calling `spawn`) becomes
+    /// the tracer, so the child stops with
+    /// `SIGTRAP` when `execve` succeeds, before running any instruction of
+    /// the new program. The stop must be handled by the caller: wait for it
+    /// with `waitpid(pid, __WALL)` and resume the child with `PTRACE_CONT`
+    /// (or any other ptrace request). `Child::wait` returns the stops of
+    /// the traced child as `ExitStatus::Stopped` without remembering them,
+    /// so it serves as that `waitpid` too.
+    ///
+    /// The parent death signal and all the other setup happen before, so
+    /// errors are reported by `spawn` as usual. Has no effect on the
+    /// closure run by `spawn_fn`.
+    pub fn trace_me(&mut self) -> &mut Command {
+        self.config.trace_me = true;
+        self
+    }
+
     /// Set the argument zero for the process
     ///
     /// By default argument zero is same as path to the program to run. You
@@ -225,7 +501,31 @@ impl Command {
     /// pretend there is a symlink to a program (for example to run `gzip` as
     /// `gunzip`).
     pub fn arg0<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
-        self.args[0] = arg.to_cstring();
+        self.args[0] = self.checked_cstring(arg.as_ref(), NulByte::Argument(0));
+        self
+    }
+
+    /// Set the name of the child in the kernel (`comm`) with `PR_SET_NAME`
+    ///
+    /// This is the name shown by `ps -o comm`, `top` and in
+    /// `/proc/<pid>/comm`, unlike `arg0` which changes only the command
+    /// line (`/proc/<pid>/cmdline`). The kernel keeps at most 15 bytes, so
+    /// the name is truncated at a character boundary, and at the first nul
+    /// byte.
+    ///
+    /// The name is set right after `clone`, so it identifies the child
+    /// while it's set up, and it's kept by the closure of `spawn_fn`. But
+    /// `execve` resets the name to the file name of the executed program,
+    /// which is what `arg0` can't change.
+    pub fn comm(&mut self, name: &str) -> &mut Command {
+        let name = name.split('\0').next().unwrap_or("");
+        let mut len = name.len().min(15);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut buf = [0u8; 16];
+        buf[..len].copy_from_slice(&name.as_bytes()[..len]);
+        self.config.comm = Some(buf);
         self
     }
 
@@ -243,6 +543,118 @@ impl Command {
         self
     }
 
+    /// Puts child process into an existing process group
+    ///
+    /// Both the parent (before unfreezing the child) and the child itself
+    /// call `setpgid` like shells do to avoid the race. The `pgid` of zero
+    /// means a new group with child's pid as an id. This option takes
+    /// precedence over `make_group_leader`.
+    ///
+    /// Returns an error if `make_session_leader` is set, because session
+    /// leader can't be moved to another process group. (This is also
+    /// checked when spawning)
+    pub fn process_group(&mut self, pgid: pid_t) -> io::Result<&mut Command> {
+        if self.config.make_session_leader {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "process_group can't be combined with make_session_leader",
+            ));
+        }
+        self.config.process_group = Some(pgid);
+        Ok(self)
+    }
+
+    /// Makes the process group of the child a foreground group of terminal
+    ///
+    /// The child calls `tcsetpgrp` on the terminal (with `SIGTTOU`
+    /// blocked), after it's put into process group by `process_group` or
+    /// `make_group_leader`. The terminal must be the controlling terminal of
+    /// the child, i.e. of the current process, as the option can't be
+    /// combined with `make_session_leader`.
+    ///
+    /// The file descriptor is duplicated, so you can close it after the
+    /// call.
+    pub fn foreground_tty<F: AsRawFd>(&mut self, tty: &F) -> io::Result<&mut Command> {
+        if self.config.make_session_leader {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "foreground_tty can't be combined with make_session_leader",
+            ));
+        }
+        self.config.foreground_tty = Some(dup_file_cloexec(tty)?);
+        Ok(self)
+    }
+
+    /// Request specific pids for the child
+    ///
+    /// The first element is the pid in the innermost pid namespace of the
+    /// child, the next one is in the parent of that namespace, and so on.
+    /// The list may be shorter than the nesting level, then pids in the
+    /// outer namespaces are allocated as usual. Note that if `Pid`
+    /// namespace is unshared, the first pid must be `1`.
+    ///
+    /// This uses `clone3` system call instead of `clone`, which requires
+    /// Linux 5.5, and `CAP_CHECKPOINT_RESTORE` (or `CAP_SYS_ADMIN`) in the
+    /// user namespaces owning the respective pid namespaces. On older
+    /// kernels spawn fails with `Error::SetTidUnsupported`. The call fails
+    /// with `Error::Fork(EEXIST)` if the pid is already taken.
+    ///
+    /// This is mostly useful for checkpoint/restore of processes.
+    pub fn set_tid(&mut self, tids: Vec<pid_t>) -> &mut Command {
+        self.config.set_tid = Some(tids);
+        self
+    }
+
+    /// Makes child process a session leader
+    ///
+    /// The child calls `setsid()` right after it's unfrozen, so it also
+    /// becomes a leader of a new process group (i.e. `make_group_leader` is
+    /// implied). The new session has no controlling terminal, unless
+    /// `inherit_controlling_tty(true)` is set.
+    ///
+    /// Can't be combined with `process_group` and `foreground_tty`, spawn
+    /// returns `Error::ConflictingOptions` in this case.
+    pub fn make_session_leader(&mut self, make_session_leader: bool) -> &mut Command {
+        self.config.make_session_leader = make_session_leader;
+        self
+    }
+
+    /// Explicitly choose whether child keeps a controlling terminal
+    ///
+    /// By default child keeps the controlling terminal of the parent unless
+    /// it is made a session leader. This method makes the intent explicit:
+    ///
+    /// | `make_session_leader` | `inherit_controlling_tty` | Result |
+    /// |---|---|---|
+    /// | `false` | not set or `true` | child shares parent's session and controlling terminal |
+    /// | `false` | `false` | child stays in parent's session but detaches from the terminal (`TIOCNOTTY`) |
+    /// | `true` | not set or `false` | new session without controlling terminal |
+    /// | `true` | `true` | new session, terminal on child's stdin becomes its controlling terminal (`TIOCSCTTY`, like `setsid --ctty`) |
+    ///
+    /// Note: the last option only works when stdin of the child is a
+    /// terminal not owned by any session (like a newly opened pty), spawn
+    /// fails with `Error::ControllingTty(EPERM)` otherwise. Use
+    /// `steal_controlling_tty` to take the terminal away from another session.
+    ///
+    /// Detaching is done before changing the root, using `/dev/tty`. It's
+    /// not an error when there is no controlling terminal in the first place.
+    pub fn inherit_controlling_tty(&mut self, inherit: bool) -> &mut Command {
+        self.config.inherit_ctty = Some(inherit);
+        self
+    }
+
+    /// Take the terminal even if it's a controlling terminal of other session
+    ///
+    /// Only matters together with `make_session_leader(true)` and
+    /// `inherit_controlling_tty(true)`: the terminal is then acquired with
+    /// `TIOCSCTTY` with the argument set to `1`, which removes it from the
+    /// session owning it (usually the parent's one). This requires
+    /// `CAP_SYS_ADMIN`. By default (`false`) the terminal is never stolen.
+    pub fn steal_controlling_tty(&mut self, steal: bool) -> &mut Command {
+        self.config.steal_ctty = steal;
+        self
+    }
+
     /// Inserts a magic environment variable that will contain pid of spawned
     /// process
     ///
@@ -263,9 +675,43 @@ impl Command {
     where
         K: AsRef<OsStr>,
     {
-        self.init_env_map();
-        self.environ.as_mut().unwrap().remove(key.as_ref());
-        self.pid_env_vars.insert(key.as_ref().to_os_string());
+        self.env_var_with_metadata(key, MetadataVar::Pid)
+    }
+
+    /// Set environment variable to the pid of the program in its innermost
+    /// pid namespace
+    ///
+    /// Unlike `env_var_with_pid` it's the pid of the program even when
+    /// `reap_orphans_as_init` is set (so it's `2` in a new pid namespace).
+    /// The pids in the other namespaces are available from
+    /// `Child::ns_pids`.
+    pub fn inner_pid_env_var<K>(&mut self, key: K) -> &mut Command
+    where
+        K: AsRef<OsStr>,
+    {
+        self.env_var_with_metadata(key, MetadataVar::InnerPid)
+    }
+
+    /// Inserts a magic environment variable that will contain some value
+    /// known only when process is spawned
+    ///
+    /// This is a generalization of `env_var_with_pid`, see `MetadataVar` for
+    /// the values that can be used.
+    ///
+    /// # Example
+    ///
+    /// ```rust,ignore
+    /// cmd.env_var_with_metadata("SANDBOX_NET", MetadataVar::NamespaceId(Namespace::Net));
+    /// cmd.env_var_with_metadata("SUPERVISOR_PID", MetadataVar::ParentPid);
+    /// ```
+    pub fn env_var_with_metadata<K>(&mut self, key: K, value: MetadataVar) -> &mut Command
+    where
+        K: AsRef<OsStr>,
+    {
+        self.environ.remove(key.as_ref());
+        self.metadata_env_vars
+            .insert(key.as_ref().to_os_string(), value);
+        self.prepared_env = None;
         self
     }
 
@@ -291,3 +737,923 @@ impl Command {
         self.keep_caps = Some(buf);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    use nix::pty::openpty;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use crate::proc_stat::stat_fields;
+    use crate::test_util::inner_test;
+    use crate::{
+        Capability, CloneFlags, Command, Error, ExitStatus, Fd, GidMap, MetadataVar, Namespace,
+        Signal, Stdio, UidMap,
+    };
+
+    fn stub_id_map_command(dir: &std::path::Path, name: &str) -> std::path::PathBuf {
+        let path = dir.join(name);
+        // the pid is the first argument, possibly after `=`, the map is
+        // written as `spawn` checks it
+        let map = if name.contains("uid") {
+            "uid_map"
+        } else {
+            "gid_map"
+        };
+        let script = format!(
+            "#!/bin/sh\necho \"$@\" > {}\necho 0 0 1 > /proc/${{1##*=}}/{}\n",
+            dir.join(format!("{}.args", name)).display(),
+            map
+        );
+        fs::write(&path, script).unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+        path
+    }
+
+    #[test]
+    fn test_id_map_command_args() {
+        let dir = std::env::temp_dir().join(format!("unshare-idmap-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let newuidmap = stub_id_map_command(&dir, "newuidmap");
+        let newgidmap = stub_id_map_command(&dir, "newgidmap");
+
+        let mut cmd = Command::new("/bin/true");
+        cmd.set_id_maps(
+            vec![UidMap::range(0, 1000, 1), UidMap::range(1, 100000, 65536)],
+            vec![GidMap::range(0, 1000, 1)],
+        );
+        cmd.set_id_map_commands(&newuidmap, &newgidmap);
+        let mut child = cmd.spawn().unwrap();
+        let pid = child.pid();
+        child.wait().unwrap();
+        let uid_args = fs::read_to_string(dir.join("newuidmap.args")).unwrap();
+        let gid_args = fs::read_to_string(dir.join("newgidmap.args")).unwrap();
+        assert_eq!(uid_args, format!("{} 0 1000 1 1 100000 65536\n", pid));
+        assert_eq!(gid_args, format!("{} 0 1000 1\n", pid));
+
+        cmd.id_map_command_args(
+            |pid, maps| {
+                vec![
+                    format!("--pid={}", pid).into(),
+                    maps.len().to_string().into(),
+                ]
+            },
+            |pid, _| vec![format!("gids={}", pid).into()],
+        );
+        let mut child = cmd.spawn().unwrap();
+        let pid = child.pid();
+        child.wait().unwrap();
+        let uid_args = fs::read_to_string(dir.join("newuidmap.args")).unwrap();
+        let gid_args = fs::read_to_string(dir.join("newgidmap.args")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(uid_args, format!("--pid={} 2\n", pid));
+        assert_eq!(gid_args, format!("gids={}\n", pid));
+    }
+
+    #[test]
+    fn test_comm() {
+        let mut cmd = Command::new("payload");
+        cmd.comm("worker-abcdefgü");
+        cmd.stdout(Stdio::piped());
+        let mut child = unsafe {
+            cmd.spawn_fn(|| {
+                let comm = fs::read_to_string("/proc/self/comm").unwrap();
+                write!(std::io::stdout(), "{}", comm).unwrap();
+                0
+            })
+        }
+        .unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+        // `ü` is two bytes, only one fits into 15
+        assert_eq!(output, "worker-abcdefg\n");
+
+        // exec resets the name
+        let mut cmd = Command::new("/bin/cat");
+        cmd.arg("/proc/self/comm").arg0("other").comm("worker");
+        let output = cmd.output().unwrap();
+        assert_eq!(output.stdout, b"cat\n");
+    }
+
+    #[test]
+    fn test_setgroups() {
+        let dir = std::env::temp_dir().join(format!("unshare-setgroups-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let newuidmap = stub_id_map_command(&dir, "newuidmap");
+        let newgidmap = dir.join("newgidmap");
+        // the real helper is setuid, the script regains root's capabilities
+        // on exec, so it can write both the map and `setgroups`
+        fs::write(
+            &newgidmap,
+            format!(
+                "#!/bin/sh\ncat /proc/$1/setgroups > {}\necho 0 0 1 > /proc/$1/gid_map\n",
+                dir.join("setgroups").display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&newgidmap, fs::Permissions::from_mode(0o755)).unwrap();
+        let setgroups = |helpers: bool| {
+            let mut cmd = Command::new("/bin/cat");
+            cmd.arg("/proc/self/setgroups");
+            cmd.set_id_maps(vec![UidMap::range(0, 0, 1)], vec![GidMap::range(0, 0, 1)]);
+            if helpers {
+                cmd.set_id_map_commands(&newuidmap, &newgidmap);
+            }
+            let output = cmd.output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        // a privileged parent keeps setgroups allowed
+        assert_eq!(setgroups(false), "allow\n");
+
+        // an unprivileged one must deny it to write the gid map directly,
+        // but leaves it to the helpers otherwise
+        let mut cmd = Command::new("payload");
+        // mapping host root needs `CAP_SETFCAP` since Linux 5.12
+        cmd.keep_caps(&[Capability::CAP_SETFCAP]);
+        cmd.stdout(Stdio::piped());
+        let mut child = unsafe {
+            cmd.spawn_fn(|| {
+                let direct = setgroups(false);
+                let helpers = setgroups(true);
+                write!(std::io::stdout(), "{}{}", direct, helpers).unwrap();
+                0
+            })
+        }
+        .unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(0));
+        let seen_by_helper = fs::read_to_string(dir.join("setgroups")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(output, "deny\nallow\n");
+        assert_eq!(seen_by_helper, "allow\n");
+    }
+
+    #[test]
+    fn test_id_map_command_failures() {
+        let dir = std::env::temp_dir().join(format!("unshare-idmap-fail-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let script = |name: &str, body: &str| {
+            let path = dir.join(name);
+            fs::write(&path, format!("#!/bin/sh\n{}\n", body)).unwrap();
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o755)).unwrap();
+            path
+        };
+        let writes = script("writes", "echo 0 0 1 > /proc/$1/uid_map");
+        let fails = script("fails", "echo no subuid entry >&2; exit 3");
+        let hangs = script("hangs", "exec sleep 10");
+        let lies = script("lies", "exit 0");
+        let spawn = |newuidmap: &std::path::Path| {
+            let mut cmd = Command::new("/bin/true");
+            cmd.unshare(&[Namespace::User]);
+            cmd.set_id_maps(vec![UidMap::range(0, 0, 1)], vec![]);
+            cmd.set_id_map_commands(newuidmap, &writes);
+            cmd.id_map_command_args(
+                |pid, _| vec![pid.to_string().into()],
+                |pid, _| vec![pid.to_string().into()],
+            );
+            cmd.id_map_commands_timeout(Duration::from_millis(200));
+            cmd.spawn().map(|mut child| child.wait().unwrap())
+        };
+        // the gid map helper writes the uid map, which has already been
+        // written, so it fails
+        match spawn(&writes).map_err(Error::into_inner) {
+            Err(Error::IdMapHelper { ref program, .. }) if program == &writes => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        match spawn(&fails).map_err(Error::into_inner) {
+            Err(Error::IdMapHelper {
+                status: Some(ExitStatus::Exited(3)),
+                ref stderr,
+                ..
+            }) => assert_eq!(stderr, "no subuid entry\n"),
+            other => panic!("unexpected result {:?}", other),
+        }
+        let start = SystemTime::now();
+        match spawn(&hangs).map_err(Error::into_inner) {
+            Err(Error::IdMapHelper { status: None, .. }) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(start.elapsed().unwrap() < Duration::from_secs(5));
+        match spawn(&lies).map_err(Error::into_inner) {
+            Err(e @ Error::IdMapHelper { .. }) => {
+                assert!(e.to_string().ends_with("map is not written"), "{}", e)
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_process_group_conflicts() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.make_session_leader(true);
+        assert!(cmd.process_group(0).is_err());
+        let mut cmd = Command::new("/bin/true");
+        cmd.process_group(0).unwrap();
+        cmd.make_session_leader(true);
+        match cmd.spawn() {
+            Err(Error::ConflictingOptions { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_foreground_tty() {
+        // tcsetpgrp only works on the controlling terminal, so the test
+        // re-runs itself in a new session with the pty slave as a
+        // controlling terminal
+        let pty = openpty(None, None).unwrap();
+        let slave = unsafe { fs::File::from_raw_fd(pty.slave) };
+        let mut cmd = inner_test(
+            "linux::test::foreground_tty_inner",
+            "UNSHARE_TEST_FOREGROUND",
+        );
+        cmd.stdin(Stdio::dup_file(&slave).unwrap());
+        cmd.make_session_leader(true).inherit_controlling_tty(true);
+        let status = cmd.status().unwrap();
+        drop(slave);
+        unsafe { libc::close(pty.master) };
+        assert!(status.success(), "{}", status);
+    }
+
+    #[test]
+    fn test_steal_controlling_tty() {
+        let pty = openpty(None, None).unwrap();
+        let slave = unsafe { fs::File::from_raw_fd(pty.slave) };
+        let session = |steal| {
+            let mut cmd = Command::new("/bin/sleep");
+            cmd.arg("10");
+            cmd.stdin(Stdio::dup_file(&slave).unwrap());
+            cmd.make_session_leader(true).inherit_controlling_tty(true);
+            cmd.steal_controlling_tty(steal);
+            cmd.spawn()
+        };
+        let mut owner = session(false).unwrap();
+        // the terminal is owned by the first child now
+        match session(false).map_err(Error::into_inner) {
+            Err(Error::ControllingTty(libc::EPERM)) => {}
+            other => panic!("unexpected result {:?}", other.map(|c| c.pid())),
+        }
+        let mut thief = session(true).unwrap();
+        thief.kill().unwrap();
+        thief.wait().unwrap();
+        owner.kill().unwrap();
+        owner.wait().unwrap();
+        drop(slave);
+        unsafe { libc::close(pty.master) };
+    }
+
+    #[test]
+    #[ignore]
+    fn foreground_tty_inner() {
+        if std::env::var_os("UNSHARE_TEST_FOREGROUND").is_none() {
+            return;
+        }
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "cat /proc/$$/stat"]);
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+        cmd.process_group(0).unwrap();
+        cmd.foreground_tty(&std::io::stdin()).unwrap();
+        let mut child = cmd.spawn().unwrap();
+        let mut stat = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut stat)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        // pid (comm) state ppid pgrp session tty_nr tpgid
+        let fields = stat.split_whitespace().collect::<Vec<_>>();
+        let pid = child.pid().to_string();
+        assert_eq!(fields[0], pid);
+        assert_eq!(fields[4], pid);
+        assert_eq!(fields[7], pid);
+    }
+
+    #[test]
+    fn test_raw_clone_flags() {
+        let mut cmd = Command::new("/bin/readlink");
+        cmd.arg("/proc/self/ns/uts");
+        cmd.raw_clone_flags(CloneFlags::CLONE_NEWUTS);
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let own = fs::read_link("/proc/self/ns/uts").unwrap();
+        assert_ne!(output.stdout, format!("{}\n", own.display()).as_bytes());
+
+        for &flags in &[
+            CloneFlags::CLONE_VM,
+            CloneFlags::CLONE_FILES | CloneFlags::CLONE_NEWNET,
+            unsafe { CloneFlags::from_bits_unchecked(libc::SIGUSR1) },
+        ] {
+            let mut cmd = Command::new("/bin/true");
+            cmd.raw_clone_flags(flags);
+            match cmd.spawn() {
+                Err(Error::ConflictingOptions { .. }) => {}
+                other => panic!("unexpected result {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_set_tid() {
+        let pid_max = fs::read_to_string("/proc/sys/kernel/pid_max").unwrap();
+        let pid_max: i32 = pid_max.trim().parse().unwrap();
+        let pid = (pid_max / 2..pid_max)
+            .rev()
+            .find(|pid| fs::metadata(format!("/proc/{}", pid)).is_err())
+            .unwrap();
+        let mut cmd = Command::new("/bin/true");
+        cmd.set_tid(vec![pid]);
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(child.pid().as_raw(), pid);
+        assert!(child.wait().unwrap().success());
+
+        // pid namespace needs `1` as the innermost pid
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "test $$ = 1"]);
+        cmd.unshare(&[Namespace::Pid]);
+        cmd.set_tid(vec![1, pid]);
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(child.pid().as_raw(), pid);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_overlapping_id_maps() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::User]);
+        cmd.set_id_maps(
+            vec![UidMap::range(0, 1000, 10), UidMap::range(5, 2000, 1)],
+            vec![GidMap::range(0, 1000, 1)],
+        );
+        match cmd.spawn() {
+            Err(Error::InvalidIdMap { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_metadata_env_vars() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg(r#"echo "$PID $HOST_PID $PARENT_PID $NET_NS $TS""#);
+        cmd.env_clear();
+        cmd.env_var_with_pid("PID");
+        cmd.env_var_with_metadata("HOST_PID", MetadataVar::HostPid);
+        cmd.env_var_with_metadata("PARENT_PID", MetadataVar::ParentPid);
+        cmd.env_var_with_metadata("NET_NS", MetadataVar::NamespaceId(Namespace::Net));
+        cmd.env_var_with_metadata("TS", MetadataVar::SpawnTimestamp);
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+
+        let values = out.split_whitespace().collect::<Vec<_>>();
+        assert_eq!(values.len(), 5, "{:?}", out);
+        assert_eq!(values[0], child.pid().to_string());
+        assert_eq!(values[1], child.pid().to_string());
+        assert_eq!(values[2], process::id().to_string());
+        let net_ns = fs::metadata("/proc/self/ns/net").unwrap().ino();
+        assert_eq!(values[3], net_ns.to_string());
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_secs_f64();
+        let ts: f64 = values[4].parse().unwrap();
+        assert!(ts <= now && now - ts < 60.0, "{} vs {}", ts, now);
+    }
+
+    /// Returns the states of the children of the process `ppid`
+    fn child_states(ppid: i32) -> Vec<String> {
+        let mut states = Vec::new();
+        for entry in fs::read_dir("/proc").unwrap().filter_map(|e| e.ok()) {
+            let stat = match fs::read_to_string(entry.path().join("stat")) {
+                Ok(stat) => stat,
+                Err(_) => continue,
+            };
+            let fields = stat_fields(&stat).unwrap();
+            if fields[1].parse() == Ok(ppid) {
+                states.push(fields[0].to_string());
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn test_reap_orphans_as_init() {
+        // the orphans appear when `sleep` is already running
+        let script = "(/bin/sleep 0.1; /bin/true &) & exec /bin/sleep 0.5";
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", script]);
+        cmd.unshare(&[Namespace::Pid]);
+        let mut child = cmd.spawn().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        // the program is the init, so the orphans are never reaped
+        assert_eq!(child_states(child.pid().as_raw()), ["Z", "Z"]);
+        child.kill().unwrap();
+        child.wait().unwrap();
+
+        cmd.reap_orphans_as_init(true);
+        let mut child = cmd.spawn().unwrap();
+        thread::sleep(Duration::from_millis(300));
+        // only the program itself is left
+        assert_eq!(child_states(child.pid().as_raw()), ["S"]);
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_init_status() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.unshare(&[Namespace::Pid]);
+        cmd.reap_orphans_as_init(true);
+        assert_eq!(cmd.status().unwrap(), ExitStatus::Exited(3));
+
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10");
+        cmd.unshare(&[Namespace::Pid]);
+        cmd.reap_orphans_as_init(true);
+        let mut child = cmd.spawn().unwrap();
+        // wait until the handlers are installed
+        thread::sleep(Duration::from_millis(100));
+        child.signal(Signal::SIGTERM).unwrap();
+        assert_eq!(
+            child.wait().unwrap(),
+            ExitStatus::Exited((128 + libc::SIGTERM) as i8)
+        );
+
+        let mut cmd = Command::new("/nonexistent");
+        cmd.unshare(&[Namespace::Pid]);
+        cmd.reap_orphans_as_init(true);
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    /// Runs `f` in a thread with its own mount namespace, where a fresh
+    /// tmpfs is mounted at the path passed to `f`
+    ///
+    /// So the root for `pivot_root` is not shared with the host, and
+    /// nothing is left there after the test.
+    fn with_tmpfs_root<F: FnOnce(&std::path::Path) + Send>(name: &str, f: F) {
+        let root = std::env::temp_dir().join(format!("unshare-{}-{}", name, process::id()));
+        fs::create_dir_all(&root).unwrap();
+        thread::scope(|scope| {
+            scope
+                .spawn(|| {
+                    use nix::mount::{mount, MsFlags};
+                    // the thread gets its own copy of the mounts, which
+                    // are made private, so nothing propagates to the host
+                    nix::sched::unshare(CloneFlags::CLONE_NEWNS).unwrap();
+                    mount(
+                        None::<&str>,
+                        "/",
+                        None::<&str>,
+                        MsFlags::MS_REC | MsFlags::MS_PRIVATE,
+                        None::<&str>,
+                    )
+                    .unwrap();
+                    mount(
+                        Some("tmpfs"),
+                        &root,
+                        Some("tmpfs"),
+                        MsFlags::empty(),
+                        None::<&str>,
+                    )
+                    .unwrap();
+                    f(&root)
+                })
+                .join()
+                .unwrap()
+        });
+        fs::remove_dir(&root).unwrap();
+    }
+
+    #[test]
+    fn test_pivot_root_unmount_old() {
+        with_tmpfs_root("pivot-old", |root| {
+            fs::create_dir(root.join("old")).unwrap();
+            let old_root_visible = |unmount| {
+                let mut cmd = Command::new("/bin/true");
+                cmd.unshare(&[Namespace::Mount]);
+                cmd.pivot_root(root, root.join("old"));
+                cmd.pivot_root_unmount_old(unmount);
+                let mut child = unsafe {
+                    cmd.spawn_fn(|| libc::access(b"/old/proc\0".as_ptr() as *const _, 0))
+                }
+                .unwrap();
+                child.wait().unwrap().success()
+            };
+            assert!(old_root_visible(false));
+            assert!(!old_root_visible(true));
+        });
+    }
+
+    #[test]
+    fn test_pivot_root_auto() {
+        with_tmpfs_root("pivot-auto", |root| {
+            let leftovers = || {
+                fs::read_dir(root)
+                    .unwrap()
+                    .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+                    .filter(|name| name.starts_with(".pivot_old."))
+                    .collect::<Vec<_>>()
+            };
+
+            let mut cmd = Command::new("/bin/true");
+            cmd.unshare(&[Namespace::Mount]);
+            cmd.pivot_root_auto(root, true);
+            let mut child = unsafe { cmd.spawn_fn(|| 0) }.unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(leftovers(), Vec::<String>::new());
+
+            // the failure is after the pivot
+            let mut cmd = Command::new("/nonexistent");
+            cmd.unshare(&[Namespace::Mount]);
+            cmd.pivot_root_auto(root, false);
+            match cmd.spawn().map_err(Error::into_inner) {
+                Err(Error::ExecInRoot { .. }) | Err(Error::Exec(_)) => {}
+                other => panic!("unexpected result {:?}", other.map(|_| ())),
+            }
+            assert_eq!(leftovers(), Vec::<String>::new());
+
+            // the old root is kept for the program
+            let mut child = unsafe {
+                cmd.spawn_fn(|| {
+                    let old = fs::read_dir("/")
+                        .unwrap()
+                        .map(|entry| entry.unwrap().path())
+                        .find(|path| path.to_str().unwrap().starts_with("/.pivot_old."));
+                    !old.unwrap().join("proc").exists() as i32
+                })
+            }
+            .unwrap();
+            assert_eq!(child.wait().unwrap().code(), Some(0));
+            let left = leftovers();
+            assert_eq!(left.len(), 1);
+            let mode = fs::metadata(root.join(&left[0])).unwrap().mode();
+            assert_eq!(mode & 0o777, 0o700);
+        });
+    }
+
+    #[test]
+    fn test_audit_fds_for_escape() {
+        let base = std::env::temp_dir().join(format!("unshare-escape-{}", process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("inside")).unwrap();
+        let spawn = |dir: &std::path::Path, audit| {
+            let mut cmd = Command::new("/bin/true");
+            cmd.chroot_dir(&root);
+            cmd.audit_fds_for_escape(audit);
+            cmd.file_descriptor(5, Fd::from_file(fs::File::open(dir).unwrap()));
+            unsafe { cmd.spawn_fn(|| 0) }.map(|mut child| child.wait().unwrap())
+        };
+        let outside = spawn(&base, true);
+        let inside = spawn(&root.join("inside"), true);
+        let not_audited = spawn(&base, false);
+        fs::remove_dir_all(&base).unwrap();
+        match outside.map_err(Error::into_inner) {
+            Err(Error::FdsOutsideRoot { fds }) => assert_eq!(fds, vec![5]),
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(inside.unwrap().success());
+        assert!(not_audited.unwrap().success());
+    }
+
+    #[test]
+    fn test_current_dir_fd_outside_root() {
+        let base = std::env::temp_dir().join(format!("unshare-cwd-escape-{}", process::id()));
+        let root = base.join("root");
+        fs::create_dir_all(root.join("inside")).unwrap();
+        let spawn = |dir: &std::path::Path, before| {
+            let mut cmd = Command::new("/bin/true");
+            cmd.chroot_dir(&root);
+            cmd.current_dir_fd(fs::File::open(dir).unwrap().into());
+            cmd.current_dir_fd_before_root(before);
+            unsafe { cmd.spawn_fn(|| 0) }.map(|mut child| child.wait().unwrap())
+        };
+        let results = [
+            spawn(&base, false),
+            spawn(&base, true),
+            spawn(&root.join("inside"), false),
+            spawn(&root.join("inside"), true),
+        ];
+        fs::remove_dir_all(&base).unwrap();
+        for result in &results[..2] {
+            match result.as_ref().map_err(Error::inner) {
+                Err(&Error::ChangeRoot(libc::EXDEV)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+        }
+        for result in &results[2..] {
+            assert!(result.as_ref().unwrap().success());
+        }
+    }
+
+    #[test]
+    fn test_no_parent_death_signal() {
+        let death_sig = |sig: Option<Signal>| {
+            let mut cmd = Command::new("/bin/true");
+            cmd.set_parent_death_signal(sig);
+            let mut child = unsafe {
+                cmd.spawn_fn(|| {
+                    let mut sig: libc::c_int = -1;
+                    libc::prctl(libc::PR_GET_PDEATHSIG, &mut sig as *mut _, 0, 0, 0);
+                    sig
+                })
+            }
+            .unwrap();
+            child.wait().unwrap().code()
+        };
+        assert_eq!(death_sig(None), Some(0));
+        assert_eq!(death_sig(Some(Signal::SIGTERM)), Some(libc::SIGTERM));
+        let mut cmd = Command::new("/bin/true");
+        cmd.set_parent_death_signal(Signal::SIGTERM);
+        assert_eq!(cmd.config.death_sig, Some(Signal::SIGTERM));
+        assert_eq!(
+            Command::without_defaults("/bin/true").config.death_sig,
+            None
+        );
+    }
+
+    #[test]
+    fn test_inherit_signal_handlers() {
+        // the test harness ignores `SIGPIPE`, as all Rust programs do
+        let pipe_ignored = |cmd: &mut Command| {
+            cmd.arg("/proc/self/status").stdout(Stdio::piped());
+            let output = cmd.output().unwrap();
+            let status = String::from_utf8(output.stdout).unwrap();
+            let ignored = status
+                .lines()
+                .find_map(|line| line.strip_prefix("SigIgn:"))
+                .unwrap();
+            let mask = u64::from_str_radix(ignored.trim(), 16).unwrap();
+            mask & (1 << (libc::SIGPIPE - 1)) != 0
+        };
+        assert!(!pipe_ignored(&mut Command::new("/bin/cat")));
+        assert!(pipe_ignored(Command::new("/bin/cat").keep_sigmask()));
+        assert!(pipe_ignored(
+            Command::new("/bin/cat").inherit_signal_handlers(true)
+        ));
+        assert!(!pipe_ignored(
+            Command::new("/bin/cat")
+                .keep_sigmask()
+                .inherit_signal_handlers(false)
+        ));
+        assert!(pipe_ignored(&mut Command::without_defaults("/bin/cat")));
+    }
+
+    #[test]
+    fn test_set_namespace_order() {
+        let all = [
+            Namespace::Mount,
+            Namespace::Ipc,
+            Namespace::Net,
+            Namespace::Pid,
+            Namespace::Uts,
+            Namespace::User,
+        ];
+        let mut helper = Command::new("/bin/sleep")
+            .arg("10")
+            .unshare(&all)
+            .spawn()
+            .unwrap();
+        let ns_path = |pid: u32, name: &str| format!("/proc/{}/ns/{}", pid, name);
+        let names = ["mnt", "ipc", "net", "pid", "uts", "user"];
+        let mut cmd = Command::new("/bin/readlink");
+        // registered in an order unlike the one they are joined in
+        for (&ns, name) in all.iter().zip(&names) {
+            cmd.set_namespace(&fs::File::open(ns_path(helper.id(), name)).unwrap(), ns)
+                .unwrap();
+        }
+        cmd.args(&[
+            "/proc/self/ns/mnt",
+            "/proc/self/ns/ipc",
+            "/proc/self/ns/net",
+        ])
+        .args(&["/proc/self/ns/pid_for_children", "/proc/self/ns/uts"])
+        .arg("/proc/self/ns/user")
+        .stdout(Stdio::piped());
+        let output = cmd.output();
+        let expected = names
+            .iter()
+            .map(|name| fs::read_link(ns_path(helper.id(), name)).unwrap())
+            .map(|link| format!("{}\n", link.display()))
+            .collect::<String>();
+        helper.kill().unwrap();
+        helper.wait().unwrap();
+        let output = output.unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_set_namespace_error() {
+        let null = fs::File::open("/dev/null").unwrap();
+        let mut cmd = Command::new("/bin/true");
+        cmd.set_namespace(&null, Namespace::Net).unwrap();
+        match cmd.status().map_err(Error::into_inner) {
+            Err(e @ Error::SetNs { .. }) => {
+                assert!(
+                    e.to_string().starts_with(&format!(
+                        "error joining net namespace from fd {} (/dev/null): \
+                         Invalid argument",
+                        null.as_raw_fd()
+                    )),
+                    "{}",
+                    e
+                );
+                match e {
+                    Error::SetNs {
+                        namespace, errno, ..
+                    } => {
+                        assert_eq!(namespace, Namespace::Net);
+                        assert_eq!(errno, libc::EINVAL);
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            r => panic!("unexpected result {:?}", r),
+        }
+
+        cmd.set_namespace(
+            &fs::File::open("/proc/self/ns/pid").unwrap(),
+            Namespace::Pid,
+        )
+        .unwrap();
+        assert!(matches!(
+            unsafe { cmd.spawn_fn(|| 0) },
+            Err(Error::ConflictingOptions { .. })
+        ));
+    }
+
+    #[test]
+    fn test_numa_bind() {
+        if !std::path::Path::new("/sys/devices/system/node").exists() {
+            return; // kernel without NUMA support
+        }
+        let output = match Command::new("/bin/cat")
+            .arg("/proc/self/numa_maps")
+            .numa_bind(0)
+            .output()
+        {
+            Err(Error::NumaBind(libc::ENOSYS)) => return,
+            result => result.unwrap(),
+        };
+        assert!(output.status.success());
+        let maps = String::from_utf8(output.stdout).unwrap();
+        assert!(
+            maps.lines().any(|line| line.contains(" bind:0 ")),
+            "{}",
+            maps
+        );
+
+        for &node in &[1000, 5000] {
+            match Command::new("/bin/true")
+                .numa_bind(node)
+                .spawn()
+                .map_err(Error::into_inner)
+            {
+                Err(e @ Error::NumaBind(libc::EINVAL)) => assert!(
+                    e.to_string().ends_with(
+                        "the node doesn't exist or has no memory \
+                         (see /sys/devices/system/node)"
+                    ),
+                    "{}",
+                    e
+                ),
+                other => panic!("unexpected result {:?}", other.map(|_| ())),
+            }
+        }
+    }
+
+    #[test]
+    fn test_cpu_time_limit() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "ulimit -St; ulimit -Ht"])
+            .cpu_time_limit(3)
+            .stdout(Stdio::piped());
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "3\n4\n");
+
+        let status = Command::new("/bin/sh")
+            .args(&["-c", "while :; do :; done"])
+            .cpu_time_limit(0)
+            .status()
+            .unwrap();
+        // SIGXCPU dumps core by default, if it's enabled
+        assert!(matches!(status, ExitStatus::Signaled(Signal::SIGXCPU, _)));
+    }
+
+    #[test]
+    fn test_loginuid() {
+        match fs::read_to_string("/proc/self/loginuid") {
+            Ok(ref uid) if uid == "4294967295" => {}
+            // no audit support, or already set and can't be changed
+            _ => return,
+        }
+        let mut cmd = Command::new("/bin/cat");
+        cmd.arg("/proc/self/loginuid")
+            .loginuid(1234)
+            .stdout(Stdio::piped());
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(output.stdout, b"1234");
+
+        // the uid is translated by the user namespace of the child
+        cmd.unshare(&[Namespace::User]);
+        match cmd.output().map_err(Error::into_inner) {
+            Err(err @ Error::LoginUid(libc::EINVAL)) => {
+                assert!(err.to_string().contains("not mapped"))
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        cmd.set_id_maps(
+            vec![UidMap {
+                inside_uid: 1234,
+                outside_uid: 0,
+                count: 1,
+            }],
+            vec![],
+        );
+        assert_eq!(cmd.output().unwrap().stdout, b"1234");
+
+        // changing it once it's set is the common failure, but the test
+        // process can't be given a loginuid
+        assert!(Error::LoginUid(libc::EPERM)
+            .to_string()
+            .contains("CAP_AUDIT_CONTROL"));
+    }
+
+    #[test]
+    fn test_ns_pids() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "echo $INNER_PID"])
+            .inner_pid_env_var("INNER_PID")
+            .stdout(Stdio::piped());
+        let inner_pid = |cmd: &mut Command| {
+            let mut child = cmd.spawn().unwrap();
+            let mut out = String::new();
+            child
+                .stdout
+                .take()
+                .unwrap()
+                .read_to_string(&mut out)
+                .unwrap();
+            // not reaped yet, so the file is still there
+            let ns_pids = child.ns_pids().unwrap();
+            assert_eq!(ns_pids[0], child.pid().as_raw());
+            assert!(child.wait().unwrap().success());
+            match child.ns_pids() {
+                Err(Error::ProcessInfo(libc::ESRCH)) => {}
+                other => panic!("unexpected result {:?}", other),
+            }
+            (ns_pids, out.trim().to_string())
+        };
+
+        let (ns_pids, value) = inner_pid(&mut cmd);
+        assert_eq!(*ns_pids.last().unwrap(), ns_pids[0]);
+        assert_eq!(value, ns_pids[0].to_string());
+
+        cmd.unshare(&[Namespace::Pid]);
+        let (ns_pids, value) = inner_pid(&mut cmd);
+        assert_eq!(ns_pids.len(), 2);
+        assert_eq!(ns_pids[1], 1);
+        assert_eq!(value, "1");
+
+        // the init is the child, the program is forked by it
+        cmd.reap_orphans_as_init(true);
+        let (ns_pids, value) = inner_pid(&mut cmd);
+        assert_eq!(ns_pids[1], 1);
+        assert_eq!(value, "2");
+    }
+}
@@ -0,0 +1,37 @@
+use crate::Namespace;
+
+/// A value of an environment variable that is known only at spawn time
+///
+/// Use `Command::env_var_with_metadata` to set one.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+#[non_exhaustive]
+pub enum MetadataVar {
+    /// Pid of the process as seen by the process itself
+    ///
+    /// This is what `Command::env_var_with_pid` sets. It's `1` if child is
+    /// the first process of a new pid namespace.
+    Pid,
+    /// Pid of the program in the innermost pid namespace
+    ///
+    /// This is what `Command::inner_pid_env_var` sets. It's the same as
+    /// `Pid`, except with `reap_orphans_as_init`, where the program is
+    /// forked by the init and its value is `2`.
+    InnerPid,
+    /// Pid of the process as seen by the parent (i.e. the value of
+    /// `Child::pid()`)
+    ///
+    /// The value is sent to the child by the parent right after clone, so
+    /// it's correct even if child is in a separate pid namespace.
+    HostPid,
+    /// Pid of the parent process (the one calling `spawn()`)
+    ParentPid,
+    /// Identifier of the namespace of the child
+    ///
+    /// This is an inode number of the `/proc/self/ns/<name>` file, as
+    /// shown by `readlink /proc/self/ns/<name>` or `lsns`. It's read after
+    /// joining and creating all the namespaces but before changing root.
+    NamespaceId(Namespace),
+    /// Unix timestamp of when `spawn()` was called, in seconds with
+    /// microsecond precision (i.e. `1600000000.123456`)
+    SpawnTimestamp,
+}
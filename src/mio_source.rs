@@ -0,0 +1,136 @@
+use std::io;
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use mio::event::Source;
+use mio::unix::SourceFd;
+use mio::{Interest, Registry, Token};
+
+use crate::{PipeReader, PipeWriter};
+
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if flags & libc::O_NONBLOCK == 0
+        && unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } < 0
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Registering the pipe makes it non-blocking, so reads and writes
+/// return `WouldBlock` instead of waiting (only with `mio` feature)
+impl Source for PipeReader {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd())?;
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+/// Registering the pipe makes it non-blocking, so reads and writes
+/// return `WouldBlock` instead of waiting (only with `mio` feature)
+impl Source for PipeWriter {
+    fn register(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        set_nonblocking(self.as_raw_fd())?;
+        SourceFd(&self.as_raw_fd()).register(registry, token, interests)
+    }
+    fn reregister(
+        &mut self,
+        registry: &Registry,
+        token: Token,
+        interests: Interest,
+    ) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).reregister(registry, token, interests)
+    }
+    fn deregister(&mut self, registry: &Registry) -> io::Result<()> {
+        SourceFd(&self.as_raw_fd()).deregister(registry)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{self, Read, Write};
+
+    use mio::{Events, Interest, Poll, Token};
+
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_poll_stdio() {
+        let mut child = Command::new("/bin/cat")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let mut poll = Poll::new().unwrap();
+        let registry = poll.registry();
+        registry
+            .register(&mut stdin, Token(0), Interest::WRITABLE)
+            .unwrap();
+        registry
+            .register(&mut stdout, Token(1), Interest::READABLE)
+            .unwrap();
+        let mut buf = [0u8; 16];
+        // non-blocking now
+        assert_eq!(
+            stdout.read(&mut buf).unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        let mut events = Events::with_capacity(4);
+        let mut stdin = Some(stdin);
+        let mut output = Vec::new();
+        loop {
+            poll.poll(&mut events, None).unwrap();
+            for event in &events {
+                match event.token() {
+                    Token(0) => {
+                        if let Some(mut pipe) = stdin.take() {
+                            pipe.write_all(b"hello").unwrap();
+                            poll.registry().deregister(&mut pipe).unwrap();
+                        }
+                    }
+                    Token(1) => loop {
+                        match stdout.read(&mut buf) {
+                            Ok(0) => break,
+                            Ok(n) => output.extend_from_slice(&buf[..n]),
+                            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                            Err(e) => panic!("read error: {}", e),
+                        }
+                    },
+                    _ => unreachable!(),
+                }
+            }
+            if output.len() >= 5 {
+                break;
+            }
+        }
+        assert_eq!(output, b"hello");
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use crate::Command;
+
+/// The state of a speculation feature, as `PR_SET_SPECULATION_CTRL` sets it
+///
+/// Note that the modes are about the speculation itself, so `Disable`
+/// turns the mitigation on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpecMode {
+    /// Speculation is allowed (`PR_SPEC_ENABLE`), the mitigation is off
+    Enable,
+    /// Speculation is disabled (`PR_SPEC_DISABLE`), the program may
+    /// enable it back
+    Disable,
+    /// Speculation is disabled and can't be enabled back
+    /// (`PR_SPEC_FORCE_DISABLE`)
+    ForceDisable,
+}
+
+/// Per-process mitigations, see `Command::speculation_mitigations`
+///
+/// Options which are `None` (or `false`) are left as inherited from the
+/// parent, which usually means the global defaults of the kernel.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SpecMitigations {
+    /// Speculative Store Bypass (`PR_SPEC_STORE_BYPASS`)
+    pub store_bypass: Option<SpecMode>,
+    /// Indirect Branch Speculation (`PR_SPEC_INDIRECT_BRANCH`)
+    pub indirect_branch: Option<SpecMode>,
+    /// Disable transparent huge pages (`PR_SET_THP_DISABLE`)
+    pub disable_thp: bool,
+}
+
+impl SpecMitigations {
+    /// Returns `prctl` calls to make as `(option, arg2, arg3)`
+    pub(crate) fn prctls(&self) -> [Option<(i32, u64, u64)>; 3] {
+        use crate::SpecMode::*;
+
+        let mode = |mode| match mode {
+            Enable => PR_SPEC_ENABLE,
+            Disable => PR_SPEC_DISABLE,
+            ForceDisable => PR_SPEC_FORCE_DISABLE,
+        };
+        [
+            self.store_bypass
+                .map(|m| (PR_SET_SPECULATION_CTRL, PR_SPEC_STORE_BYPASS, mode(m))),
+            self.indirect_branch
+                .map(|m| (PR_SET_SPECULATION_CTRL, PR_SPEC_INDIRECT_BRANCH, mode(m))),
+            if self.disable_thp {
+                Some((PR_SET_THP_DISABLE, 1, 0))
+            } else {
+                None
+            },
+        ]
+    }
+}
+
+// not defined by libc on every architecture
+const PR_SET_THP_DISABLE: i32 = 41;
+const PR_SET_SPECULATION_CTRL: i32 = 53;
+const PR_SPEC_STORE_BYPASS: u64 = 0;
+const PR_SPEC_INDIRECT_BRANCH: u64 = 1;
+const PR_SPEC_ENABLE: u64 = 1 << 1;
+const PR_SPEC_DISABLE: u64 = 1 << 2;
+const PR_SPEC_FORCE_DISABLE: u64 = 1 << 3;
+
+impl Command {
+    /// Set speculative execution mitigations and other per-process
+    /// hardening of the child, regardless of the global kernel defaults
+    ///
+    /// The settings are applied by `prctl` in the child right before
+    /// `pre_exec`, they are preserved across `execve` and inherited by
+    /// the processes the program forks.
+    ///
+    /// If the kernel or CPU doesn't support the control (or it's set
+    /// globally at boot, so can't be changed per process), `spawn`
+    /// returns `Error::MitigationUnsupported`, other failures are
+    /// reported as `Error::Mitigation`.
+    pub fn speculation_mitigations(&mut self, mitigations: SpecMitigations) -> &mut Command {
+        self.config.mitigations = mitigations;
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{SpecMitigations, SpecMode};
+    use crate::{Command, Error, Stdio};
+
+    fn status_field(cmd: &mut Command, name: &str) -> Result<String, Error> {
+        cmd.arg("/proc/self/status").stdout(Stdio::piped());
+        let output = cmd.output()?;
+        let status = String::from_utf8(output.stdout).unwrap();
+        Ok(status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .unwrap_or("")
+            .trim()
+            .to_string())
+    }
+
+    #[test]
+    fn test_store_bypass() {
+        let own = fs::read_to_string("/proc/self/status").unwrap();
+        if !own.contains("Speculation_Store_Bypass:\tthread") {
+            return; // not vulnerable or not controlled per thread
+        }
+        let mut cmd = Command::new("/bin/cat");
+        cmd.speculation_mitigations(SpecMitigations {
+            store_bypass: Some(SpecMode::ForceDisable),
+            ..SpecMitigations::default()
+        });
+        match status_field(&mut cmd, "Speculation_Store_Bypass:") {
+            Ok(value) => assert_eq!(value, "thread force mitigated"),
+            Err(Error::MitigationUnsupported(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+
+    #[test]
+    fn test_thp_disable() {
+        let own = fs::read_to_string("/proc/self/status").unwrap();
+        if !own.contains("THP_enabled:") {
+            return; // the field exists since linux 5.0
+        }
+        let mut cmd = Command::new("/bin/cat");
+        cmd.speculation_mitigations(SpecMitigations {
+            disable_thp: true,
+            ..SpecMitigations::default()
+        });
+        match status_field(&mut cmd, "THP_enabled:") {
+            Ok(value) => assert_eq!(value, "0"),
+            Err(Error::MitigationUnsupported(_)) => {}
+            Err(e) => panic!("unexpected error {}", e),
+        }
+    }
+}
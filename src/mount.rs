@@ -0,0 +1,361 @@
+use std::ffi::CString;
+use std::path::{Path, PathBuf};
+
+use nix::mount::MsFlags;
+use nix::sched::CloneFlags;
+
+use crate::ffi_util::NulByte;
+use crate::{Command, Error};
+
+/// A filesystem mounted by the child before running the program
+pub enum Mount {
+    Sysfs {
+        target: CString,
+        readonly: bool,
+    },
+    /// Tmpfs at `/dev/shm` set by `Command::private_shm`, the target is
+    /// kept for `Mount::target`
+    Shm {
+        target: CString,
+        data: CString,
+    },
+    /// Arguments of `mount(2)` as they are, set by `Command::mount`
+    Raw {
+        source: Option<CString>,
+        target: CString,
+        fstype: Option<CString>,
+        flags: MsFlags,
+        data: Option<CString>,
+    },
+}
+
+impl Mount {
+    pub fn target(&self) -> &CString {
+        match *self {
+            Mount::Sysfs { ref target, .. } => target,
+            Mount::Shm { ref target, .. } => target,
+            Mount::Raw { ref target, .. } => target,
+        }
+    }
+
+    /// Flags applied with `secure_mount_defaults`
+    ///
+    /// Raw mounts get exactly the flags they were given.
+    pub fn secure_flags(&self) -> MsFlags {
+        match *self {
+            Mount::Sysfs { .. } => MsFlags::MS_NOSUID | MsFlags::MS_NODEV | MsFlags::MS_NOEXEC,
+            Mount::Shm { .. } => MsFlags::MS_NOSUID | MsFlags::MS_NODEV,
+            Mount::Raw { .. } => MsFlags::empty(),
+        }
+    }
+}
+
+impl Command {
+    /// Mount sysfs at `at` in the child
+    ///
+    /// The path is inside the new root, i.e. it's mounted after `pivot_root`
+    /// and `chroot_dir`. If `readonly` is set, the mount is remounted
+    /// read-only, as most programs need to only read `/sys`. The flags of
+    /// `secure_mount_defaults` are applied too.
+    ///
+    /// Requires both mount and network namespaces to be unshared (sysfs
+    /// shows network devices of the network namespace it was mounted in),
+    /// otherwise `spawn` returns `Error::ConflictingOptions`.
+    ///
+    /// Before mounting anything the child makes all its mounts slaves
+    /// (like `mount --make-rslave /`), so mounts don't propagate back to
+    /// the parent mount namespace.
+    pub fn mount_sysfs<P: AsRef<Path>>(&mut self, at: P, readonly: bool) -> &mut Command {
+        let place = NulByte::Mount(self.config.mounts.len());
+        let target = self.checked_cstring(at.as_ref().as_os_str(), place);
+        self.config.mounts.push(Mount::Sysfs { target, readonly });
+        self
+    }
+
+    /// Mount a fresh tmpfs at `/dev/shm` in the child
+    ///
+    /// A new IPC namespace isolates SysV IPC and POSIX message queues, but
+    /// POSIX shared memory lives in the files of `/dev/shm`, which would
+    /// still be shared with the parent. The tmpfs is mounted with mode
+    /// `1777` (and `nosuid`, `nodev`, see `secure_mount_defaults`), and
+    /// limited to `size_bytes` if it's set (the kernel default is a half of
+    /// RAM). Like other mounts, it's done after changing root, in the order
+    /// the methods are called.
+    ///
+    /// Requires both mount and IPC namespaces to be unshared, otherwise
+    /// `spawn` returns `Error::ConflictingOptions`.
+    pub fn private_shm(&mut self, size_bytes: Option<u64>) -> &mut Command {
+        let data = match size_bytes {
+            Some(size) => format!("mode=1777,size={}", size),
+            None => "mode=1777".to_string(),
+        };
+        let place = NulByte::Mount(self.config.mounts.len());
+        let target = self.checked_cstring("/dev/shm".as_ref(), place);
+        let data = self.checked_cstring(data.as_ref(), place);
+        self.config.mounts.push(Mount::Shm { target, data });
+        self
+    }
+
+    /// Mount the filesystems of the helper methods with restrictive flags
+    ///
+    /// This is on by default, the flags depend on the filesystem:
+    ///
+    /// * `mount_sysfs` uses `nosuid`, `nodev` and `noexec`
+    /// * `private_shm` uses `nosuid` and `nodev`, but not `noexec`, as
+    ///   programs may map shared memory as executable (i.e. JIT compilers)
+    ///
+    /// With `false` the helpers mount without these flags. The mounts made
+    /// by `mount` are never changed, so that's the way to override the
+    /// flags of a specific mount point.
+    pub fn secure_mount_defaults(&mut self, enable: bool) -> &mut Command {
+        self.config.secure_mounts = enable;
+        self
+    }
+
+    /// Call `mount(2)` with these arguments in the child
+    ///
+    /// This is the escape hatch for the mounts which the other methods
+    /// don't cover, the arguments are passed to the syscall unchanged (so
+    /// it can also remount, bind or change propagation). Like `mount_sysfs`,
+    /// it's done after changing root, so the paths are inside the new
+    /// root, and all the mounts are done in the order their methods are
+    /// called.
+    ///
+    /// Requires the mount namespace to be unshared, otherwise `spawn`
+    /// returns `Error::ConflictingOptions`. The failure is reported as
+    /// `Error::MountAt` with the target of the mount. If any of the
+    /// arguments contains a nul byte, `spawn` returns
+    /// `Error::NulByteInMount`.
+    pub fn mount(
+        &mut self,
+        source: Option<PathBuf>,
+        target: PathBuf,
+        fstype: Option<String>,
+        flags: MsFlags,
+        data: Option<String>,
+    ) -> &mut Command {
+        let place = NulByte::Mount(self.config.mounts.len());
+        let source = source.map(|path| self.checked_cstring(path.as_os_str(), place));
+        let target = self.checked_cstring(target.as_os_str(), place);
+        let fstype = fstype.map(|fstype| self.checked_cstring(fstype.as_ref(), place));
+        let data = data.map(|data| self.checked_cstring(data.as_ref(), place));
+        self.config.mounts.push(Mount::Raw {
+            source,
+            target,
+            fstype,
+            flags,
+            data,
+        });
+        self
+    }
+
+    pub(crate) fn check_mounts(&self) -> Result<(), Error> {
+        for mount in &self.config.mounts {
+            let (required, message) = match *mount {
+                Mount::Sysfs { .. } => (
+                    CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWNET,
+                    "mount_sysfs requires unsharing mount and network namespaces",
+                ),
+                Mount::Shm { .. } => (
+                    CloneFlags::CLONE_NEWNS | CloneFlags::CLONE_NEWIPC,
+                    "private_shm requires unsharing mount and IPC namespaces",
+                ),
+                Mount::Raw { .. } => (
+                    CloneFlags::CLONE_NEWNS,
+                    "mount requires unsharing mount namespace",
+                ),
+            };
+            if !self.config.namespaces.contains(required) {
+                return Err(Error::ConflictingOptions { message });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+    use std::path::Path;
+
+    use nix::mount::MsFlags;
+
+    use crate::{Command, Error, Namespace, Stdio};
+
+    #[test]
+    fn test_sysfs() {
+        let dir = std::env::temp_dir().join(format!("unshare-sysfs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(format!(
+            "ls {0}/class/net; grep ' {0} ' /proc/self/mountinfo",
+            dir.display()
+        ));
+        cmd.unshare(&[Namespace::Mount, Namespace::Net]);
+        cmd.mount_sysfs(&dir, true);
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        fs::remove_dir(&dir).unwrap();
+        let mut lines = output.lines();
+        // new network namespace has only loopback
+        assert_eq!(lines.next(), Some("lo"));
+        let mountinfo = lines.next().unwrap();
+        let fields = mountinfo.split_whitespace().collect::<Vec<_>>();
+        assert!(fields[5].split(',').any(|opt| opt == "ro"), "{}", mountinfo);
+        assert!(mountinfo.contains(" - sysfs "), "{}", mountinfo);
+    }
+
+    #[test]
+    fn test_sysfs_requires_namespaces() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.mount_sysfs("/sys", true);
+        match cmd.spawn() {
+            Err(Error::ConflictingOptions { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_raw_mount() {
+        let dir = std::env::temp_dir().join(format!("unshare-mount-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cmd = Command::new("/bin/grep");
+        cmd.arg(format!(" {} ", dir.display()))
+            .arg("/proc/self/mountinfo");
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.mount(
+            Some("tmpfs".into()),
+            dir.clone(),
+            Some("tmpfs".into()),
+            MsFlags::MS_NOSUID,
+            Some("size=1m,mode=700".into()),
+        );
+        // done in order, so this remounts the tmpfs
+        cmd.mount(
+            None,
+            dir.clone(),
+            None,
+            MsFlags::MS_REMOUNT | MsFlags::MS_BIND | MsFlags::MS_RDONLY | MsFlags::MS_NOSUID,
+            None,
+        );
+        cmd.stdout(Stdio::piped());
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let mountinfo = String::from_utf8(output.stdout).unwrap();
+        let fields = mountinfo.split_whitespace().collect::<Vec<_>>();
+        assert!(fields[5].split(',').any(|opt| opt == "ro"), "{}", mountinfo);
+        assert!(mountinfo.contains(" - tmpfs "), "{}", mountinfo);
+        assert!(mountinfo.contains("mode=700"), "{}", mountinfo);
+
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.mount(None, dir.clone(), None, MsFlags::MS_BIND, None);
+        cmd.mount(None, "/mnt\0".into(), None, MsFlags::MS_BIND, None);
+        match cmd.spawn() {
+            Err(Error::NulByteInMount { index: 1 }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+
+        let mut cmd = Command::new("/bin/true");
+        cmd.mount(None, dir.join("missing"), None, MsFlags::MS_BIND, None);
+        match cmd.spawn() {
+            Err(Error::ConflictingOptions { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        cmd.unshare(&[Namespace::Mount]);
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(err @ Error::MountAt { .. }) => {
+                assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+                assert_eq!(
+                    err.to_string(),
+                    format!(
+                        "error mounting filesystem at {:?}: No such file or directory \
+                         (os error 2)",
+                        dir.join("missing")
+                    )
+                );
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_private_shm() {
+        let name = format!("unshare-shm-{}", std::process::id());
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg(format!(
+            "touch /dev/shm/{}; ls /dev/shm; grep ' /dev/shm ' /proc/self/mountinfo | tail -1",
+            name
+        ));
+        cmd.unshare(&[Namespace::Mount, Namespace::Ipc]);
+        cmd.private_shm(Some(1 << 20));
+        cmd.stdout(Stdio::piped());
+        let output = cmd.output().unwrap();
+        assert!(output.status.success());
+        let output = String::from_utf8(output.stdout).unwrap();
+        let mut lines = output.lines();
+        // only the file of the child is there
+        assert_eq!(lines.next(), Some(&name[..]));
+        let mountinfo = lines.next().unwrap();
+        assert!(mountinfo.contains(" - tmpfs "), "{}", mountinfo);
+        assert!(mountinfo.contains("size=1024k"), "{}", mountinfo);
+        assert!(!Path::new("/dev/shm").join(&name).exists());
+
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.private_shm(None);
+        match cmd.spawn() {
+            Err(Error::ConflictingOptions { .. }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_secure_mount_defaults() {
+        let dir = std::env::temp_dir().join(format!("unshare-secure-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let options = |secure: bool| {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c").arg(format!(
+                "grep -e ' {} ' -e ' /dev/shm ' /proc/self/mountinfo | tail -2",
+                dir.display()
+            ));
+            cmd.unshare(&[Namespace::Mount, Namespace::Net, Namespace::Ipc]);
+            cmd.mount_sysfs(&dir, false).private_shm(None);
+            cmd.secure_mount_defaults(secure);
+            let output = cmd.output().unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .map(|line| {
+                    let options = line.split_whitespace().nth(5).unwrap().split(',');
+                    ["nosuid", "nodev", "noexec"]
+                        .iter()
+                        .filter(|flag| options.clone().any(|opt| opt == **flag))
+                        .cloned()
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+        let secure = options(true);
+        let insecure = options(false);
+        fs::remove_dir(&dir).unwrap();
+        assert_eq!(
+            secure,
+            [vec!["nosuid", "nodev", "noexec"], vec!["nosuid", "nodev"]]
+        );
+        assert_eq!(insecure, [Vec::<&str>::new(), Vec::new()]);
+    }
+}
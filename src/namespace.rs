@@ -1,9 +1,13 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::str::FromStr;
+
 use nix::sched::CloneFlags;
 
 /// Namespace name to unshare
 ///
 /// See `man 7 namespaces` for more information
-#[derive(PartialEq, Eq, Hash, Clone, Copy)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum Namespace {
     /// Unshare the mount namespace. It basically means that you can now mount
     /// and unmount folders without touching parent mount points.
@@ -67,6 +71,78 @@ pub enum Namespace {
     Cgroup,
 }
 
+impl Namespace {
+    /// Returns all the namespaces, in the order of the variants
+    ///
+    /// Useful to list the valid names, i.e. in help text of a command-line
+    /// tool.
+    pub fn all() -> &'static [Namespace] {
+        use self::Namespace::*;
+        &[Mount, Uts, Ipc, User, Pid, Net, Cgroup]
+    }
+
+    /// Returns the short name of the namespace, as `unshare(1)` accepts it
+    ///
+    /// This is the same as `Display` and is parsed back by `FromStr`. Note
+    /// that the mount namespace is `mount` here, but its file in
+    /// `/proc/<pid>/ns/` is `mnt`.
+    pub fn name(self) -> &'static str {
+        match self {
+            Namespace::Mount => "mount",
+            Namespace::Uts => "uts",
+            Namespace::Ipc => "ipc",
+            Namespace::User => "user",
+            Namespace::Pid => "pid",
+            Namespace::Net => "net",
+            Namespace::Cgroup => "cgroup",
+        }
+    }
+}
+
+impl fmt::Display for Namespace {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(self.name())
+    }
+}
+
+/// The error parsing `Namespace` from an unknown name
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseNamespaceError {
+    /// The name which is not recognized
+    pub name: String,
+}
+
+impl fmt::Display for ParseNamespaceError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "unknown namespace {:?}, valid values are: ", self.name)?;
+        for (index, ns) in Namespace::all().iter().enumerate() {
+            if index > 0 {
+                fmt.write_str(", ")?;
+            }
+            fmt.write_str(ns.name())?;
+        }
+        Ok(())
+    }
+}
+
+impl StdError for ParseNamespaceError {}
+
+impl FromStr for Namespace {
+    type Err = ParseNamespaceError;
+
+    /// Parses the names returned by `Namespace::name`, the names of the
+    /// files in `/proc/<pid>/ns/` (i.e. `mnt`) are accepted too
+    fn from_str(name: &str) -> Result<Namespace, ParseNamespaceError> {
+        Namespace::all()
+            .iter()
+            .cloned()
+            .find(|&ns| ns.name() == name || to_ns_name(ns) == name)
+            .ok_or_else(|| ParseNamespaceError {
+                name: name.to_string(),
+            })
+    }
+}
+
 /// Convert namespace to a clone flag passed to syscalls
 // TODO(tailhook) should this method be private?
 pub fn to_clone_flag(ns: Namespace) -> CloneFlags {
@@ -80,3 +156,41 @@ pub fn to_clone_flag(ns: Namespace) -> CloneFlags {
         Namespace::Cgroup => CloneFlags::CLONE_NEWCGROUP,
     }
 }
+
+/// Name of the namespace file in `/proc/<pid>/ns/`
+pub fn to_ns_name(ns: Namespace) -> &'static str {
+    match ns {
+        Namespace::Mount => "mnt",
+        Namespace::Uts => "uts",
+        Namespace::Ipc => "ipc",
+        Namespace::User => "user",
+        Namespace::Pid => "pid",
+        Namespace::Net => "net",
+        Namespace::Cgroup => "cgroup",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Namespace;
+
+    #[test]
+    fn test_round_trip() {
+        for &ns in Namespace::all() {
+            assert_eq!(ns.to_string().parse::<Namespace>(), Ok(ns));
+        }
+        assert_eq!("mnt".parse::<Namespace>(), Ok(Namespace::Mount));
+        assert_eq!(Namespace::Net.to_string(), "net");
+    }
+
+    #[test]
+    fn test_unknown() {
+        let err = "network".parse::<Namespace>().unwrap_err();
+        assert_eq!(err.name, "network");
+        assert_eq!(
+            err.to_string(),
+            "unknown namespace \"network\", valid values are: \
+             mount, uts, ipc, user, pid, net, cgroup"
+        );
+    }
+}
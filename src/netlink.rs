@@ -0,0 +1,251 @@
+//! Minimal rtnetlink client used to move interfaces into the child's
+//! network namespace
+
+use std::mem;
+
+use libc::{c_void, pid_t};
+use nix::sched::CloneFlags;
+
+use crate::{Command, Error};
+
+const NLMSG_ERROR: u16 = 2;
+const NLM_F_REQUEST: u16 = 1;
+const NLM_F_ACK: u16 = 4;
+const IFLA_IFNAME: u16 = 3;
+const IFLA_NET_NS_PID: u16 = 19;
+/// Sizes of `struct nlmsghdr` and `struct ifinfomsg`
+const NLMSG_HDRLEN: usize = 16;
+const IFINFOMSG_LEN: usize = 16;
+
+impl Command {
+    /// Move the network interface into the network namespace of the child
+    ///
+    /// This is like `ip link set <name> netns <pid>`. The interface is moved
+    /// by the parent process while the child is still waiting to be
+    /// unfrozen, i.e. before `before_unfreeze` callback and before the
+    /// program starts. May be called multiple times to move several
+    /// interfaces, they are moved in the order of the calls.
+    ///
+    /// Requires the network namespace to be unshared, and `CAP_NET_ADMIN`
+    /// in the parent. If any interface can't be moved (i.e. it doesn't
+    /// exist), the child is killed and `spawn` returns
+    /// `Error::MoveInterface` with the name of the interface. Note that
+    /// interfaces moved before the failing one are lost along with the
+    /// namespace of the child (physical devices are returned to the initial
+    /// namespace by the kernel, virtual ones are destroyed).
+    pub fn move_interface(&mut self, name: &str) -> &mut Command {
+        self.config.move_interfaces.push(name.to_string());
+        self
+    }
+
+    pub(crate) fn check_move_interfaces(&self) -> Result<(), Error> {
+        if !self.config.move_interfaces.is_empty()
+            && !self.config.namespaces.contains(CloneFlags::CLONE_NEWNET)
+        {
+            return Err(Error::ConflictingOptions {
+                message: "move_interface requires unsharing network namespace",
+            });
+        }
+        Ok(())
+    }
+}
+
+/// A `NETLINK_ROUTE` socket, closed on drop
+struct Socket(i32);
+
+impl Drop for Socket {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn push_attr(buf: &mut Vec<u8>, kind: u16, data: &[u8]) {
+    let len = 4 + data.len();
+    buf.extend_from_slice(&(len as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(data);
+    // attributes are aligned to 4 bytes
+    buf.resize(buf.len() + (len.next_multiple_of(4) - len), 0);
+}
+
+fn link_message(name: &str, pid: pid_t, seq: u32) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(64);
+    // nlmsghdr, the length is filled in at the end
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    buf.extend_from_slice(&libc::RTM_NEWLINK.to_ne_bytes());
+    buf.extend_from_slice(&(NLM_F_REQUEST | NLM_F_ACK).to_ne_bytes());
+    buf.extend_from_slice(&seq.to_ne_bytes());
+    buf.extend_from_slice(&0u32.to_ne_bytes());
+    // ifinfomsg with zero index, so that kernel looks up `IFLA_IFNAME`
+    buf.resize(NLMSG_HDRLEN + IFINFOMSG_LEN, 0);
+    push_attr(&mut buf, IFLA_NET_NS_PID, &(pid as u32).to_ne_bytes());
+    let mut cname = name.as_bytes().to_vec();
+    cname.push(0);
+    push_attr(&mut buf, IFLA_IFNAME, &cname);
+    let len = (buf.len() as u32).to_ne_bytes();
+    buf[..4].copy_from_slice(&len);
+    buf
+}
+
+fn check(rc: isize) -> Result<usize, i32> {
+    if rc < 0 {
+        Err(nix::errno::errno())
+    } else {
+        Ok(rc as usize)
+    }
+}
+
+fn move_one(sock: &Socket, name: &str, pid: pid_t, seq: u32) -> Result<(), i32> {
+    if name.contains('\0') {
+        return Err(libc::EINVAL);
+    }
+    let msg = link_message(name, pid, seq);
+    loop {
+        match check(unsafe { libc::send(sock.0, msg.as_ptr() as *const c_void, msg.len(), 0) }) {
+            Err(libc::EINTR) => continue,
+            Err(errno) => return Err(errno),
+            Ok(_) => break,
+        }
+    }
+    let mut buf = [0u8; 4096];
+    loop {
+        let n = match check(unsafe {
+            libc::recv(sock.0, buf.as_mut_ptr() as *mut c_void, buf.len(), 0)
+        }) {
+            Err(libc::EINTR) => continue,
+            Err(errno) => return Err(errno),
+            Ok(n) => n,
+        };
+        if n < NLMSG_HDRLEN + 4 {
+            return Err(libc::EPROTO);
+        }
+        let kind = u16::from_ne_bytes([buf[4], buf[5]]);
+        let reply_seq = u32::from_ne_bytes([buf[8], buf[9], buf[10], buf[11]]);
+        if kind != NLMSG_ERROR || reply_seq != seq {
+            continue;
+        }
+        let error = i32::from_ne_bytes([buf[16], buf[17], buf[18], buf[19]]);
+        return if error == 0 { Ok(()) } else { Err(-error) };
+    }
+}
+
+/// Moves the interfaces into the network namespace of the process `pid`
+pub fn move_interfaces(names: &[String], pid: pid_t) -> Result<(), Error> {
+    if names.is_empty() {
+        return Ok(());
+    }
+    let fail = |name: &str, errno| Error::MoveInterface {
+        name: name.to_string(),
+        errno,
+    };
+    let fd = unsafe {
+        libc::socket(
+            libc::AF_NETLINK,
+            libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+            libc::NETLINK_ROUTE,
+        )
+    };
+    if fd < 0 {
+        return Err(fail(&names[0], nix::errno::errno()));
+    }
+    let sock = Socket(fd);
+    let mut addr: libc::sockaddr_nl = unsafe { mem::zeroed() };
+    addr.nl_family = libc::AF_NETLINK as u16;
+    let rc = unsafe {
+        libc::bind(
+            sock.0,
+            &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+            mem::size_of::<libc::sockaddr_nl>() as u32,
+        )
+    };
+    if rc < 0 {
+        return Err(fail(&names[0], nix::errno::errno()));
+    }
+    for (seq, name) in names.iter().enumerate() {
+        move_one(&sock, name, pid, seq as u32 + 1).map_err(|errno| fail(name, errno))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+
+    use crate::test_util::inner_test;
+    use crate::{Command, Error, Namespace, Stdio};
+
+    fn interfaces(proc_net_dev: &str) -> Vec<String> {
+        proc_net_dev
+            .lines()
+            .skip(2)
+            .filter_map(|line| line.split(':').next())
+            .map(|name| name.trim().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_move_veth() {
+        // the veth pair is created in a scratch network namespace
+        let mut cmd = inner_test("netlink::test::move_veth_inner", "UNSHARE_TEST_MOVE_VETH");
+        cmd.unshare(&[Namespace::Net]);
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    #[ignore]
+    fn move_veth_inner() {
+        if std::env::var_os("UNSHARE_TEST_MOVE_VETH").is_none() {
+            return;
+        }
+        let status = Command::new("/usr/sbin/ip")
+            .args(&[
+                "link", "add", "veth0", "type", "veth", "peer", "name", "veth1",
+            ])
+            .status()
+            .unwrap();
+        assert!(status.success());
+
+        // the namespace is kept until the outside is checked, as the veth
+        // pair is destroyed with it
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "cat /proc/net/dev; exec sleep 10 >/dev/null"]);
+        cmd.unshare(&[Namespace::Net]);
+        cmd.move_interface("veth1");
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        let outside = interfaces(&fs::read_to_string("/proc/net/dev").unwrap());
+        child.kill().unwrap();
+        child.wait().unwrap();
+        let mut inside = interfaces(&output);
+        inside.sort();
+        assert_eq!(inside, ["lo", "veth1"]);
+        assert!(outside.iter().any(|x| x == "veth0"), "{:?}", outside);
+        assert!(!outside.iter().any(|x| x == "veth1"), "{:?}", outside);
+    }
+
+    #[test]
+    fn test_move_missing() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Net]);
+        cmd.move_interface("nonexistent0");
+        match cmd.spawn() {
+            Err(
+                e @ Error::MoveInterface {
+                    errno: libc::ENODEV,
+                    ..
+                },
+            ) => {
+                assert!(e.to_string().contains("\"nonexistent0\""), "{}", e);
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+use std::io::{self, Read};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::Signal;
+
+use crate::{Child, Command, Error, ExitStatus, PipeReader, Stdio};
+
+/// The output of a finished process, see `Command::output`
+#[derive(Debug)]
+pub struct Output {
+    /// The status of the process
+    pub status: ExitStatus,
+    /// The captured stdout, at most the limit set by `max_output_bytes`
+    pub stdout: Vec<u8>,
+    /// The captured stderr, at most the limit set by `max_output_bytes`
+    pub stderr: Vec<u8>,
+    /// Whether some of the stdout was discarded because of the limit
+    pub stdout_truncated: bool,
+    /// Whether some of the stderr was discarded because of the limit
+    pub stderr_truncated: bool,
+    /// Total number of bytes written to stdout, including discarded ones
+    pub stdout_total: u64,
+    /// Total number of bytes written to stderr, including discarded ones
+    pub stderr_total: u64,
+}
+
+struct Capture {
+    pipe: Option<PipeReader>,
+    data: Vec<u8>,
+    limit: usize,
+    total: u64,
+}
+
+impl Capture {
+    fn new(pipe: Option<PipeReader>, limit: usize) -> Capture {
+        Capture {
+            pipe,
+            data: Vec::new(),
+            limit,
+            total: 0,
+        }
+    }
+    /// Reads a chunk of data, closes the pipe on end of file
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let pipe = match self.pipe {
+            Some(ref mut pipe) => pipe,
+            None => return Ok(()),
+        };
+        let n = match pipe.read(buf) {
+            Ok(n) => n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if n == 0 {
+            self.pipe = None;
+            return Ok(());
+        }
+        self.total += n as u64;
+        // the rest is just dropped, so the child doesn't block on a full pipe
+        let room = self.limit - self.data.len();
+        self.data.extend_from_slice(&buf[..n.min(room)]);
+        Ok(())
+    }
+    /// Reads the data which is already in the pipe, without waiting
+    ///
+    /// Only as much as was there at the start is read, so a writer that
+    /// keeps writing doesn't block the caller.
+    fn read_buffered(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        let mut left: libc::c_int = 0;
+        if let Some(ref pipe) = self.pipe {
+            if unsafe { libc::ioctl(pipe.as_raw_fd(), libc::FIONREAD, &mut left) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        while left > 0 && self.pipe.is_some() {
+            let len = (left as usize).min(buf.len());
+            let total = self.total;
+            self.read(&mut buf[..len])?;
+            left -= (self.total - total) as libc::c_int;
+        }
+        Ok(())
+    }
+    fn truncated(&self) -> bool {
+        self.total > self.data.len() as u64
+    }
+}
+
+impl Command {
+    /// Limit the number of bytes of stdout and stderr kept by `output`
+    ///
+    /// Once the limit is reached the rest of the output is read and
+    /// discarded, so the child isn't blocked on a full pipe. The returned
+    /// `Output` has `stdout_truncated`/`stderr_truncated` set in this case,
+    /// and the total number of bytes in `stdout_total`/`stderr_total`.
+    ///
+    /// The limit applies to `Command::output` and `Child::wait_with_output`
+    /// only. Pipes read by user code (i.e. `Child::stdout`) are not limited.
+    pub fn max_output_bytes(&mut self, stdout: usize, stderr: usize) -> &mut Command {
+        self.config.output_limits = Some((stdout, stderr));
+        self
+    }
+
+    /// Run the command and collect its stdout and stderr
+    ///
+    /// Both are always captured through pipes, i.e. `stdout` and `stderr`
+    /// settings are replaced by `Stdio::piped()` for this call only, they
+    /// are restored when the child is spawned. Stdin is left as configured.
+    pub fn output(&mut self) -> Result<Output, Error> {
+        self.spawn_captured()?
+            .wait_with_output()
+            .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))
+    }
+
+    /// Like `output`, but kill the child if it doesn't exit in `timeout`
+    ///
+    /// The time is counted from the start of `spawn`. On timeout the child
+    /// is killed with `SIGKILL` and reaped, whatever is left in the pipes is
+    /// read without waiting, and `Error::Timeout` is returned with the
+    /// output captured so far, so it's possible to see what the child has
+    /// printed before it hung. Limits of `max_output_bytes` apply as usual,
+    /// and `stdout` and `stderr` settings are restored like in `output`.
+    ///
+    /// Only the child itself is killed. If it has exited but started other
+    /// processes which keep the pipes open, the output written until the
+    /// deadline is returned as usual.
+    pub fn output_timeout(&mut self, timeout: Duration) -> Result<Output, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut child = self.spawn_captured()?;
+        let wait_error = |e: io::Error| Error::WaitError(e.raw_os_error().unwrap_or(-1));
+        let (mut out, mut err) = child.captures();
+        let drained = drain(&mut out, &mut err, Some(deadline)).map_err(wait_error)?;
+        let left = deadline.saturating_duration_since(Instant::now());
+        let status = child.wait_timeout(left).map_err(wait_error)?;
+        let killed = status.is_none();
+        let status = match status {
+            Some(status) => status,
+            None => {
+                let _ = child.signal(Signal::SIGKILL);
+                child.wait().map_err(wait_error)?
+            }
+        };
+        if !drained || killed {
+            let mut buf = [0u8; 8192];
+            out.read_buffered(&mut buf).map_err(wait_error)?;
+            err.read_buffered(&mut buf).map_err(wait_error)?;
+        }
+        let output = make_output(status, out, err);
+        if killed {
+            return Err(Error::Timeout(Box::new(output)));
+        }
+        Ok(output)
+    }
+
+    /// Spawns with stdout and stderr piped, keeping the configured ones
+    fn spawn_captured(&mut self) -> Result<Child, Error> {
+        let stdout = self.fds.insert(1, Stdio::piped().to_fd(true));
+        let stderr = self.fds.insert(2, Stdio::piped().to_fd(true));
+        let child = self.spawn();
+        for (fd, old) in [(1, stdout), (2, stderr)] {
+            match old {
+                Some(old) => self.fds.insert(fd, old),
+                None => self.fds.remove(&fd),
+            };
+        }
+        child
+    }
+}
+
+/// Reads both pipes until they are closed or `deadline` passes
+///
+/// Returns `false` if the deadline has passed.
+fn drain(out: &mut Capture, err: &mut Capture, deadline: Option<Instant>) -> io::Result<bool> {
+    let mut buf = [0u8; 8192];
+    while out.pipe.is_some() || err.pipe.is_some() {
+        // checked explicitly, as poll never times out if the child writes
+        // continuously
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            return Ok(false);
+        }
+        let mut pollfds = [&*out, &*err].map(|c| libc::pollfd {
+            fd: c.pipe.as_ref().map_or(-1, |p| p.as_raw_fd()),
+            events: libc::POLLIN,
+            revents: 0,
+        });
+        let timeout = match deadline {
+            // rounded up, so the deadline has passed when poll times out
+            Some(deadline) => {
+                let left = deadline.saturating_duration_since(Instant::now());
+                (left.as_micros().div_ceil(1000)).min(i32::MAX as u128) as i32
+            }
+            None => -1,
+        };
+        let rc = unsafe { libc::poll(pollfds.as_mut_ptr(), 2, timeout) };
+        if rc < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(e);
+        }
+        if rc == 0 {
+            return Ok(false);
+        }
+        if pollfds[0].revents != 0 {
+            out.read(&mut buf)?;
+        }
+        if pollfds[1].revents != 0 {
+            err.read(&mut buf)?;
+        }
+    }
+    Ok(true)
+}
+
+fn make_output(status: ExitStatus, out: Capture, err: Capture) -> Output {
+    Output {
+        status,
+        stdout_truncated: out.truncated(),
+        stderr_truncated: err.truncated(),
+        stdout_total: out.total,
+        stderr_total: err.total,
+        stdout: out.data,
+        stderr: err.data,
+    }
+}
+
+impl Child {
+    /// Closes stdin and takes the stdout and stderr pipes for reading
+    fn captures(&mut self) -> (Capture, Capture) {
+        drop(self.stdin.take());
+        let (out_limit, err_limit) = self.output_limits.unwrap_or((usize::MAX, usize::MAX));
+        (
+            Capture::new(self.stdout.take(), out_limit),
+            Capture::new(self.stderr.take(), err_limit),
+        )
+    }
+
+    /// Read all of stdout and stderr and wait for the child to exit
+    ///
+    /// Stdin (if it's a pipe) is closed first, so the child isn't waiting
+    /// for input. Only `stdout` and `stderr` pipes are read, if either is
+    /// not piped (or was taken from the `Child`) corresponding buffer in
+    /// `Output` is empty. See `Command::max_output_bytes` for limiting the
+    /// memory used for the output.
+    pub fn wait_with_output(mut self) -> Result<Output, io::Error> {
+        let (mut out, mut err) = self.captures();
+        drain(&mut out, &mut err, None)?;
+        let status = self.wait()?;
+        Ok(make_output(status, out, err))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use nix::sys::signal::{kill, Signal};
+    use nix::unistd::Pid;
+
+    use crate::{Command, Error, ExitStatus, Stdio};
+
+    #[test]
+    fn test_output() {
+        let out = Command::new("/bin/sh")
+            .args(&["-c", "echo hello; echo world >&2; exit 3"])
+            .output()
+            .unwrap();
+        assert_eq!(out.status, ExitStatus::Exited(3));
+        assert_eq!(out.stdout, b"hello\n");
+        assert_eq!(out.stderr, b"world\n");
+        assert!(!out.stdout_truncated && !out.stderr_truncated);
+        assert_eq!((out.stdout_total, out.stderr_total), (6, 6));
+    }
+
+    #[test]
+    fn test_output_keeps_stdio() {
+        let mut cmd = Command::new("/bin/echo");
+        cmd.arg("hello").stdout(Stdio::null());
+        assert_eq!(cmd.output().unwrap().stdout, b"hello\n");
+        let timed = cmd.output_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(timed.stdout, b"hello\n");
+        // stdout is still redirected to /dev/null, stderr inherited
+        let mut child = cmd.spawn().unwrap();
+        assert!(child.stdout.is_none() && child.stderr.is_none());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_limit() {
+        let mut cmd = Command::new("/usr/bin/yes");
+        cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+        cmd.max_output_bytes(1000, 10);
+        let child = cmd.spawn().unwrap();
+        let pid = Pid::from(child.pid());
+        let killer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            kill(pid, Signal::SIGTERM).unwrap();
+        });
+        let out = child.wait_with_output().unwrap();
+        killer.join().unwrap();
+        assert_eq!(out.status, ExitStatus::Signaled(Signal::SIGTERM, false));
+        assert_eq!(out.stdout.len(), 1000);
+        assert!(out.stdout.capacity() < 64 * 1024);
+        assert!(out.stdout.starts_with(b"y\ny\n"));
+        assert!(out.stdout_truncated);
+        // the child was not blocked by a full pipe
+        assert!(out.stdout_total > 1_000_000, "{}", out.stdout_total);
+        assert!(!out.stderr_truncated);
+        assert_eq!(out.stderr_total, 0);
+    }
+
+    #[test]
+    fn test_output_timeout() {
+        let out = Command::new("/bin/sh")
+            .args(&["-c", "echo hello; exit 3"])
+            .output_timeout(Duration::from_secs(10))
+            .unwrap();
+        assert_eq!(out.status, ExitStatus::Exited(3));
+        assert_eq!(out.stdout, b"hello\n");
+
+        let start = Instant::now();
+        let result = Command::new("/bin/sh")
+            .args(&["-c", "echo before; echo hang >&2; exec sleep 10"])
+            .output_timeout(Duration::from_millis(300));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match result {
+            Err(Error::Timeout(out)) => {
+                assert_eq!(out.status, ExitStatus::Signaled(Signal::SIGKILL, false));
+                assert_eq!(out.stdout, b"before\n");
+                assert_eq!(out.stderr, b"hang\n");
+                assert_eq!(
+                    Error::Timeout(out).to_string(),
+                    "command timed out: killed after writing 7 bytes to stdout \
+                     and 5 bytes to stderr"
+                );
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_output_timeout_continuous_output() {
+        let start = Instant::now();
+        let result = Command::new("/usr/bin/yes")
+            .max_output_bytes(100, 0)
+            .output_timeout(Duration::from_millis(200));
+        assert!(start.elapsed() < Duration::from_secs(5));
+        match result {
+            Err(Error::Timeout(out)) => {
+                assert_eq!(out.stdout.len(), 100);
+                assert!(out.stdout_truncated);
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
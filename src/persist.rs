@@ -0,0 +1,180 @@
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use libc::pid_t;
+use nix::mount::{mount, umount2, MntFlags, MsFlags};
+
+use crate::namespace::{to_clone_flag, to_ns_name};
+use crate::{Command, Error, Namespace};
+
+impl Command {
+    /// Keep the namespace of the child alive by bind-mounting it to `path`
+    ///
+    /// This is like `ip netns add`: the parent creates the file (if it
+    /// doesn't exist) and bind-mounts `/proc/<child>/ns/<ns>` onto it while
+    /// the child is not yet unfrozen. The namespace outlives the child and
+    /// can be joined by other processes, i.e. with `set_namespace` on the
+    /// opened `path`. Use `unpersist_namespace` to release it.
+    ///
+    /// The namespace must be unshared. If any of the mounts fails, the
+    /// ones already made for this spawn are unmounted, the files created
+    /// for them are removed, the child is killed and
+    /// `Error::PersistNamespace` is returned. Note that mounts are left
+    /// in place if spawn fails at a later step (i.e. `execve` fails).
+    ///
+    /// The file should be on a private mount (not shared with the mount
+    /// namespace of the child) otherwise kernel refuses to persist mount
+    /// namespaces.
+    ///
+    /// # Panics
+    ///
+    /// If path is not absolute
+    pub fn persist_namespace(&mut self, ns: Namespace, path: PathBuf) -> &mut Command {
+        if !path.is_absolute() {
+            panic!("Namespace path must be absolute");
+        }
+        self.config.persist_namespaces.push((ns, path));
+        self
+    }
+
+    pub(crate) fn check_persist_namespaces(&self) -> Result<(), Error> {
+        for &(ns, _) in &self.config.persist_namespaces {
+            if !self.config.namespaces.contains(to_clone_flag(ns)) {
+                return Err(Error::ConflictingOptions {
+                    message: "persist_namespace requires unsharing the namespace",
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Sets `created` if the file didn't exist, even if the mount fails
+fn persist_one(ns: Namespace, path: &Path, pid: pid_t, created: &mut bool) -> Result<(), i32> {
+    let errno = |e: io::Error| e.raw_os_error().unwrap_or(libc::EIO);
+    match OpenOptions::new().write(true).create_new(true).open(path) {
+        Ok(_) => *created = true,
+        Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+            OpenOptions::new().write(true).open(path).map_err(errno)?;
+        }
+        Err(e) => return Err(errno(e)),
+    }
+    let source = format!("/proc/{}/ns/{}", pid, to_ns_name(ns));
+    mount(
+        Some(&source[..]),
+        path,
+        None::<&str>,
+        MsFlags::MS_BIND,
+        None::<&str>,
+    )
+    .map_err(|e| e.as_errno().map_or(libc::EIO, |e| e as i32))
+}
+
+/// Bind-mounts namespaces of the process `pid` to the requested paths
+pub fn persist_namespaces(list: &[(Namespace, PathBuf)], pid: pid_t) -> Result<(), Error> {
+    let mut created_files = Vec::new();
+    for (idx, &(ns, ref path)) in list.iter().enumerate() {
+        let mut created = false;
+        let res = persist_one(ns, path, pid, &mut created);
+        if created {
+            created_files.push(path);
+        }
+        if let Err(errno) = res {
+            for (_, path) in &list[..idx] {
+                umount2(path, MntFlags::MNT_DETACH).ok();
+            }
+            for path in created_files {
+                fs::remove_file(path).ok();
+            }
+            return Err(Error::PersistNamespace {
+                path: path.clone(),
+                errno,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Release the namespace persisted by `Command::persist_namespace`
+///
+/// Lazily unmounts the namespace (like `umount -l`) and removes the file.
+/// The namespace is destroyed when the last process in it exits and nobody
+/// else keeps it open.
+pub fn unpersist_namespace<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    umount2(path, MntFlags::MNT_DETACH)
+        .map_err(|e| io::Error::from_raw_os_error(e.as_errno().map_or(libc::EIO, |e| e as i32)))?;
+    fs::remove_file(path)
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File};
+    use std::io::Read;
+
+    use super::unpersist_namespace;
+    use crate::{Command, Error, Namespace, Stdio};
+
+    #[test]
+    fn test_persist_net() {
+        let path = std::env::temp_dir().join(format!("unshare-netns-{}", std::process::id()));
+        let mut cmd = Command::new("/usr/sbin/ip");
+        cmd.args(&["link", "set", "lo", "up"]);
+        cmd.unshare(&[Namespace::Net]);
+        cmd.persist_namespace(Namespace::Net, path.clone());
+        assert!(cmd.status().unwrap().success());
+
+        // the child is dead, but the namespace is still there
+        let mut cmd = Command::new("/usr/sbin/ip");
+        cmd.args(&["-o", "link", "show", "lo"]);
+        cmd.set_namespace(&File::open(&path).unwrap(), Namespace::Net)
+            .unwrap();
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        assert!(output.contains("<LOOPBACK,UP,LOWER_UP>"), "{}", output);
+
+        unpersist_namespace(&path).unwrap();
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_persist_failure() {
+        let dir = std::env::temp_dir().join(format!("unshare-netns-fail-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let good = dir.join("good");
+        let existing = dir.join("existing");
+        fs::write(&existing, b"").unwrap();
+        let bad = dir.join("missing/bad");
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Net, Namespace::Uts, Namespace::Ipc]);
+        cmd.persist_namespace(Namespace::Uts, good.clone());
+        cmd.persist_namespace(Namespace::Ipc, existing.clone());
+        cmd.persist_namespace(Namespace::Net, bad.clone());
+        match cmd.spawn() {
+            Err(Error::PersistNamespace { path, errno }) => {
+                assert_eq!(path, bad);
+                assert_eq!(errno, libc::ENOENT);
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        // the mounts are undone, and only the created file is removed
+        assert!(!good.exists());
+        fs::remove_file(&existing).unwrap();
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    #[should_panic(expected = "must be absolute")]
+    fn test_relative_path() {
+        Command::new("/bin/true").persist_namespace(Namespace::Net, "netns".into());
+    }
+}
@@ -0,0 +1,71 @@
+use std::error::Error as StdError;
+use std::fmt;
+
+use libc::pid_t;
+
+/// A process id
+///
+/// Returned by `Child::pid()`, `reap_zombies()` and `child_events()`. Note
+/// that pids are reused after a process is reaped, see `Child::is_reaped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Pid(pid_t);
+
+impl Pid {
+    /// Creates `Pid` from a raw value
+    pub fn from_raw(pid: pid_t) -> Pid {
+        Pid(pid)
+    }
+
+    /// Returns the raw `pid_t` value
+    pub fn as_raw(self) -> pid_t {
+        self.0
+    }
+}
+
+impl fmt::Display for Pid {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(fmt)
+    }
+}
+
+impl From<nix::unistd::Pid> for Pid {
+    fn from(pid: nix::unistd::Pid) -> Pid {
+        Pid(pid.as_raw())
+    }
+}
+
+impl From<Pid> for nix::unistd::Pid {
+    fn from(pid: Pid) -> nix::unistd::Pid {
+        nix::unistd::Pid::from_raw(pid.0)
+    }
+}
+
+impl From<Pid> for pid_t {
+    fn from(pid: Pid) -> pid_t {
+        pid.0
+    }
+}
+
+/// The error of sending a signal to the child which is already reaped
+///
+/// It's wrapped into `io::Error` of `InvalidInput` kind, use
+/// `io::Error::get_ref()` and `downcast_ref` to check for it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReapedError {
+    /// The pid the child had, it may belong to another process now
+    pub pid: Pid,
+}
+
+impl fmt::Display for ReapedError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            fmt,
+            "invalid argument: can't signal process {} which is already \
+             reaped, the pid may be reused (use a pidfd to refer to the \
+             process after it's reaped)",
+            self.pid
+        )
+    }
+}
+
+impl StdError for ReapedError {}
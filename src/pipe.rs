@@ -1,10 +1,10 @@
 use std::io;
 use std::mem;
-use std::os::unix::io::RawFd;
+use std::os::unix::io::{AsRawFd, RawFd};
 
 use libc;
 use libc::{c_void, size_t};
-use nix::fcntl::OFlag;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
 use nix::unistd::pipe2;
 
 use crate::error::ErrorCode::CreatePipe;
@@ -50,7 +50,25 @@ impl Drop for Pipe {
     }
 }
 
+/// Duplicate the descriptor to the lowest free number not less than `min_fd`
+/// and close the original one
+pub(crate) fn move_fd(fd: RawFd, min_fd: RawFd) -> Result<RawFd, Error> {
+    if fd >= min_fd {
+        return Ok(fd);
+    }
+    let new_fd = result(CreatePipe, fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(min_fd)))?;
+    unsafe { libc::close(fd) };
+    Ok(new_fd)
+}
+
 impl PipeReader {
+    /// Move the descriptor so that its number is at least `min_fd`
+    pub fn move_above(self, min_fd: RawFd) -> Result<PipeReader, Error> {
+        // on error the original descriptor is closed by drop
+        let fd = move_fd(self.0, min_fd)?;
+        mem::forget(self);
+        Ok(PipeReader(fd))
+    }
     /// Extract file descriptor from pipe reader without closing
     // TODO(tailhook) implement IntoRawFd here
     pub fn into_fd(self) -> RawFd {
@@ -61,6 +79,12 @@ impl PipeReader {
 }
 
 impl PipeWriter {
+    /// Move the descriptor so that its number is at least `min_fd`
+    pub fn move_above(self, min_fd: RawFd) -> Result<PipeWriter, Error> {
+        let fd = move_fd(self.0, min_fd)?;
+        mem::forget(self);
+        Ok(PipeWriter(fd))
+    }
     /// Extract file descriptor from pipe reader without closing
     // TODO(tailhook) implement IntoRawFd here
     pub fn into_fd(self) -> RawFd {
@@ -82,6 +106,18 @@ impl Drop for PipeWriter {
     }
 }
 
+impl AsRawFd for PipeReader {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
+impl AsRawFd for PipeWriter {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
+    }
+}
+
 impl io::Read for PipeReader {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         let ret =
@@ -106,3 +142,48 @@ impl io::Write for PipeWriter {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::os::unix::io::AsRawFd;
+
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_pipes_not_inherited_by_next_spawn() {
+        let mut first = Command::new("/bin/echo")
+            .arg("first")
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut second = Command::new("/bin/sleep")
+            .arg("10")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        for fd in &[
+            first.stdout.as_ref().unwrap().as_raw_fd(),
+            second.stdin.as_ref().unwrap().as_raw_fd(),
+            second.stdout.as_ref().unwrap().as_raw_fd(),
+        ] {
+            let flags = unsafe { libc::fcntl(*fd, libc::F_GETFD) };
+            assert_eq!(flags & libc::FD_CLOEXEC, libc::FD_CLOEXEC);
+        }
+        // if the second child had a copy of the write end, EOF would only
+        // come when it exits
+        let mut output = String::new();
+        first
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output, "first\n");
+        assert!(second.peek_status().unwrap().is_none());
+        first.wait().unwrap();
+        second.kill().unwrap();
+        second.wait().unwrap();
+    }
+}
@@ -0,0 +1,141 @@
+use std::io;
+
+use crate::pipe::Pipe;
+use crate::stdio::Closing;
+use crate::{Child, Command, Error, ExitStatus, Stdio};
+
+/// A builder for running `cmd1 | cmd2 | cmd3`
+///
+/// Stdout of each command is connected to stdin of the next one. Stdin of
+/// the first command and stdout of the last one (as well as stderr of all
+/// the commands) are configured by the usual `Command::stdin` and
+/// `Command::stdout` methods. Stdout of all but the last command and stdin
+/// of all but the first command are overriden by the pipeline.
+pub struct Pipeline {
+    commands: Vec<Command>,
+}
+
+/// The running children of the `Pipeline`
+#[derive(Debug)]
+pub struct PipelineChildren {
+    /// The children in the same order as commands added to the pipeline
+    pub children: Vec<Child>,
+}
+
+impl Pipeline {
+    /// Start a pipeline with the first command
+    pub fn new(first: Command) -> Pipeline {
+        Pipeline {
+            commands: vec![first],
+        }
+    }
+
+    /// Add a command which reads output of the previous one
+    pub fn pipe(mut self, next: Command) -> Pipeline {
+        self.commands.push(next);
+        self
+    }
+
+    /// Spawn all the commands from left to right
+    ///
+    /// The ends of the pipes are closed in the parent as soon as the
+    /// respective child is spawned, and they are never inherited by other
+    /// children, so when a command exits the next one gets end of file
+    /// (and previous one gets `SIGPIPE`).
+    ///
+    /// If any command fails to spawn, the already started ones are killed
+    /// and waited for, and the error is returned.
+    pub fn spawn(&mut self) -> Result<PipelineChildren, Error> {
+        let mut children = Vec::with_capacity(self.commands.len());
+        let mut next_stdin = None;
+        let last = self.commands.len() - 1;
+        for (idx, cmd) in self.commands.iter_mut().enumerate() {
+            if let Some(rd) = next_stdin.take() {
+                cmd.stdin(Stdio::Fd(Closing::new(rd)));
+            }
+            if idx != last {
+                let (rd, wr) = match Pipe::new() {
+                    Ok(pipe) => pipe.split(),
+                    Err(e) => return Err(kill_all(children, e)),
+                };
+                cmd.stdout(Stdio::Fd(Closing::new(wr.into_fd())));
+                next_stdin = Some(rd.into_fd());
+            }
+            let result = cmd.spawn();
+            // Command keeps the file descriptors passed to it, so we must
+            // close pipe ends here for end of file to propagate
+            if idx != 0 {
+                cmd.stdin(Stdio::null());
+            }
+            if idx != last {
+                cmd.stdout(Stdio::null());
+            }
+            match result {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    // the read end of the next pipe is closed here
+                    drop(next_stdin.map(Closing::new));
+                    return Err(kill_all(children, e));
+                }
+            }
+        }
+        Ok(PipelineChildren { children })
+    }
+}
+
+fn kill_all(children: Vec<Child>, err: Error) -> Error {
+    for mut child in children {
+        child.kill().ok();
+        child.wait().ok();
+    }
+    err
+}
+
+impl PipelineChildren {
+    /// Wait for all the children and return their exit statuses
+    ///
+    /// Statuses are in the same order as commands in the pipeline
+    pub fn wait_all(&mut self) -> Result<Vec<ExitStatus>, io::Error> {
+        self.children.iter_mut().map(|c| c.wait()).collect()
+    }
+
+    /// Wait for all the children and return exit status of the last one
+    ///
+    /// This is what shell returns as an exit status of the pipeline
+    pub fn last_status(&mut self) -> Result<ExitStatus, io::Error> {
+        let statuses = self.wait_all()?;
+        Ok(*statuses.last().expect("pipeline is never empty"))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+
+    use crate::{Command, ExitStatus, Pipeline, Stdio};
+
+    #[test]
+    fn test_eof_propagates() {
+        let mut head = Command::new("/usr/bin/head");
+        head.args(&["-n", "3"]);
+        let mut wc = Command::new("/usr/bin/wc");
+        wc.arg("-l").stdout(Stdio::piped());
+        let mut pipeline = Pipeline::new(Command::new("/usr/bin/yes"))
+            .pipe(head)
+            .pipe(wc);
+        let mut children = pipeline.spawn().unwrap();
+        let mut output = String::new();
+        children.children[2]
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(output.trim(), "3");
+        let statuses = children.wait_all().unwrap();
+        assert_eq!(statuses.len(), 3);
+        assert!(!statuses[0].success()); // killed by SIGPIPE
+        assert_eq!(statuses[1], ExitStatus::Exited(0));
+        assert_eq!(children.last_status().unwrap(), ExitStatus::Exited(0));
+    }
+}
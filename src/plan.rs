@@ -0,0 +1,573 @@
+use std::ffi::OsStr;
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::AsRawFd;
+
+use nix::mount::MsFlags;
+use nix::sched::CloneFlags;
+
+use crate::idmap;
+use crate::mount::Mount;
+use crate::namespace::to_clone_flag;
+use crate::run::{check_arg_max, LateEnvValue};
+use crate::{Command, Error, Fd, SpawnPhase};
+
+/// A single step of `SpawnPlan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlanStep {
+    /// The phase the step is done in, as reported to `Command::trace`
+    pub phase: SpawnPhase,
+    /// What is done with its parameters, i.e. `setuid(1000)`
+    pub action: String,
+}
+
+/// What `spawn` would do, returned by `Command::plan`
+///
+/// Steps are listed in the order they are executed. Displayed as one
+/// numbered step per line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SpawnPlan {
+    steps: Vec<PlanStep>,
+}
+
+impl SpawnPlan {
+    /// The steps in the order of execution
+    pub fn steps(&self) -> &[PlanStep] {
+        &self.steps
+    }
+
+    fn add(&mut self, phase: SpawnPhase, action: String) {
+        self.steps.push(PlanStep { phase, action });
+    }
+}
+
+impl fmt::Display for SpawnPlan {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        for (index, step) in self.steps.iter().enumerate() {
+            writeln!(fmt, "{:>2}. {:?}: {}", index + 1, step.phase, step.action)?;
+        }
+        Ok(())
+    }
+}
+
+fn describe_fd(fd: &Fd) -> String {
+    match *fd {
+        Fd::ReadPipe => "read end of a new pipe".into(),
+        Fd::WritePipe => "write end of a new pipe".into(),
+        Fd::Inherit => "inherited".into(),
+        Fd::ReadNull => "/dev/null for reading".into(),
+        Fd::WriteNull => "/dev/null for writing".into(),
+        Fd::Fd(ref fd) => format!("descriptor {} of the parent", fd.as_raw_fd()),
+        Fd::Borrowed(fd) => format!("descriptor {} of the parent", fd),
+        Fd::Moved(ref fd) => format!("descriptor {} of the parent", fd.as_raw_fd()),
+        Fd::TeePipe(_) => "write end of a new pipe, copied by a thread".into(),
+        Fd::OpenInRoot(ref path, flags) => {
+            let mode = if flags & libc::O_ACCMODE == libc::O_RDONLY {
+                "reading"
+            } else {
+                "writing"
+            };
+            format!("{:?} opened for {} inside the new root", path, mode)
+        }
+    }
+}
+
+fn describe_late(value: &LateEnvValue) -> String {
+    match *value {
+        LateEnvValue::Pid => "<pid>".into(),
+        LateEnvValue::InnerPid => "<inner pid>".into(),
+        LateEnvValue::HostPid => "<host pid>".into(),
+        LateEnvValue::NamespaceId(ref path) => format!("<inode of {:?}>", path),
+    }
+}
+
+impl Command {
+    /// Returns what `spawn` would do, without doing anything
+    ///
+    /// The options are validated and the program and the environment are
+    /// resolved with the same code `spawn` uses, so the same errors are
+    /// returned. The environment is listed sorted by name and the values
+    /// which are only known in the child (see `env_var_with_metadata`)
+    /// are shown as placeholders. Callbacks are listed but not called.
+    ///
+    /// Phases of the steps match the ones reported to `Command::trace`.
+    /// Note that descriptors are planned by their configuration, the
+    /// numbers of the descriptors actually passed are chosen by `spawn`.
+    pub fn plan(&self) -> Result<SpawnPlan, Error> {
+        use crate::SpawnPhase::*;
+
+        self.check_options(false)?;
+        let filename = match self.config.program_fd {
+            Some(_) => None,
+            None => Some(self.resolve_program()?),
+        };
+        let ready_fd = self.ready_fd();
+        let (environ, late) = self.build_environ(ready_fd)?;
+        check_arg_max(&self.args, &environ)?;
+
+        let mut plan = SpawnPlan { steps: Vec::new() };
+        if let Some(ref filename) = filename {
+            plan.add(BuildEnv, format!("resolve program to {:?}", filename));
+        }
+        match self.config.set_tid {
+            Some(ref tids) => plan.add(
+                Clone,
+                format!("clone3({:?}, set_tid={:?})", self.config.namespaces, tids),
+            ),
+            None => plan.add(Clone, format!("clone({:?})", self.config.namespaces)),
+        }
+        if let Some(sig) = self.config.death_sig {
+            plan.add(Clone, format!("prctl(PR_SET_PDEATHSIG, {:?})", sig));
+        }
+        if let Some(ref name) = self.config.comm {
+            let len = name.iter().position(|&b| b == 0).unwrap_or(0);
+            let name = String::from_utf8_lossy(&name[..len]);
+            plan.add(Clone, format!("prctl(PR_SET_NAME, {:?})", name));
+        }
+
+        // parent-side steps are measured as part of the next phase reported
+        let unfreeze = if self.before_unfreeze.is_some() || self.config.unfreezer.is_some() {
+            BeforeUnfreeze
+        } else {
+            Unfreeze
+        };
+        let after_clone = if self.config.id_maps.is_some() {
+            WriteIdMaps
+        } else {
+            unfreeze
+        };
+        if let Some(pgid) = self.config.process_group {
+            plan.add(after_clone, format!("setpgid(<pid>, {})", pgid));
+        } else if self.config.make_group_leader && !self.config.make_session_leader {
+            plan.add(after_clone, "setpgid(<pid>, <pid>)".into());
+        }
+        if let Some((ref uids, ref gids)) = self.config.id_maps {
+            let uids = uids
+                .iter()
+                .map(|m| format!("{} {} {}", m.inside_uid, m.outside_uid, m.count))
+                .collect::<Vec<_>>();
+            let gids = gids
+                .iter()
+                .map(|m| format!("{} {} {}", m.inside_gid, m.outside_gid, m.count))
+                .collect::<Vec<_>>();
+            match self.id_map_commands {
+                Some((ref ucmd, ref gcmd)) => {
+                    plan.add(
+                        WriteIdMaps,
+                        format!("run {:?} for uid map {:?}", ucmd, uids),
+                    );
+                    plan.add(
+                        WriteIdMaps,
+                        format!("run {:?} for gid map {:?}", gcmd, gids),
+                    );
+                }
+                None => {
+                    plan.add(WriteIdMaps, format!("write /proc/<pid>/uid_map {:?}", uids));
+                    if !idmap::has_cap_setgid() {
+                        plan.add(WriteIdMaps, "write /proc/<pid>/setgroups \"deny\"".into());
+                    }
+                    plan.add(WriteIdMaps, format!("write /proc/<pid>/gid_map {:?}", gids));
+                }
+            }
+        }
+        for name in &self.config.move_interfaces {
+            plan.add(
+                unfreeze,
+                format!("move interface {:?} into the child", name),
+            );
+        }
+        for (ns, path) in &self.config.persist_namespaces {
+            plan.add(
+                unfreeze,
+                format!("bind-mount {:?} namespace to {:?}", ns, path),
+            );
+        }
+        if let Some((ref path, ref limits)) = self.config.cgroup {
+            for (name, value) in limits.files() {
+                plan.add(
+                    unfreeze,
+                    format!("write {:?} to {:?}", value, path.join(name)),
+                );
+            }
+            plan.add(unfreeze, format!("move <pid> to cgroup {:?}", path));
+        }
+        if self.before_unfreeze.is_some() {
+            plan.add(BeforeUnfreeze, "call before_unfreeze callback".into());
+        }
+        if self.config.unfreezer.is_some() {
+            plan.add(
+                BeforeUnfreeze,
+                "wait for the external unfreezer to proceed".into(),
+            );
+        }
+        plan.add(Unfreeze, "wake up the child".into());
+
+        if self.config.make_session_leader {
+            plan.add(ChildNamespaces, "setsid()".into());
+        }
+        if !self.config.make_session_leader && self.config.inherit_ctty == Some(false) {
+            plan.add(ChildNamespaces, "detach from controlling terminal".into());
+        }
+        if let Some(pgid) = self.config.process_group {
+            plan.add(ChildNamespaces, format!("setpgid(0, {})", pgid));
+        }
+        if self.config.foreground_tty.is_some() {
+            plan.add(ChildNamespaces, "tcsetpgrp(<tty>, <pgid>)".into());
+        }
+        for (ns, _) in self.setns_order() {
+            plan.add(ChildNamespaces, format!("setns({:?})", to_clone_flag(ns)));
+        }
+        let mut late_vars = late
+            .iter()
+            .map(|&(index, offset, ref value)| {
+                let name = OsStr::from_bytes(&environ[index][..offset - 1]);
+                (name, value)
+            })
+            .collect::<Vec<_>>();
+        late_vars.sort_by_key(|&(name, _)| name);
+        for (name, value) in late_vars {
+            plan.add(
+                ChildNamespaces,
+                format!("set {:?} to {}", name, describe_late(value)),
+            );
+        }
+        if let Some(uid) = self.config.loginuid {
+            plan.add(
+                ChildNamespaces,
+                format!("write {} to \"/proc/self/loginuid\"", uid),
+            );
+        }
+
+        if !self.config.mounts.is_empty() {
+            plan.add(
+                ChildChangeRoot,
+                "mount(NULL, \"/\", NULL, MS_REC | MS_SLAVE, NULL)".into(),
+            );
+        }
+        let early_fd = match self.config.work_dir_fd {
+            Some(ref fd) if self.config.work_dir_fd_before_root => Some(fd.as_raw_fd()),
+            _ => None,
+        };
+        if let Some(fd) = early_fd {
+            plan.add(
+                ChildChangeRoot,
+                format!("fchdir(descriptor {} of the parent)", fd),
+            );
+        }
+        match self.pivot_root {
+            Some((ref new, Some(ref old))) => {
+                plan.add(ChildChangeRoot, format!("pivot_root({:?}, {:?})", new, old));
+                if self.unmount_old_root {
+                    plan.add(ChildChangeRoot, format!("umount2({:?}, MNT_DETACH)", old));
+                }
+            }
+            Some((ref new, None)) => {
+                plan.add(
+                    ChildChangeRoot,
+                    format!("mkdir(\"{}/.pivot_old.<random>\", 0700)", new.display()),
+                );
+                plan.add(
+                    ChildChangeRoot,
+                    format!(
+                        "pivot_root({:?}, \"{}/.pivot_old.<random>\")",
+                        new,
+                        new.display()
+                    ),
+                );
+                if self.unmount_old_root {
+                    plan.add(
+                        ChildChangeRoot,
+                        "umount2(\"/.pivot_old.<random>\", MNT_DETACH)".into(),
+                    );
+                    plan.add(ChildChangeRoot, "rmdir(\"/.pivot_old.<random>\")".into());
+                }
+            }
+            None => {}
+        }
+        if let Some(ref dir) = self.chroot_dir {
+            plan.add(ChildChangeRoot, format!("chroot({:?})", dir));
+            if early_fd.is_none() {
+                plan.add(ChildChangeRoot, "chdir(\"/\")".into());
+            }
+        }
+        for mount in &self.config.mounts {
+            let secure = if self.config.secure_mounts {
+                mount.secure_flags()
+            } else {
+                MsFlags::empty()
+            };
+            match *mount {
+                Mount::Sysfs {
+                    ref target,
+                    readonly,
+                } => {
+                    let mode = if readonly { "read-only" } else { "read-write" };
+                    plan.add(
+                        ChildChangeRoot,
+                        format!("mount sysfs at {:?} {} ({:?})", target, mode, secure),
+                    );
+                }
+                Mount::Shm { ref data, .. } => {
+                    plan.add(
+                        ChildChangeRoot,
+                        format!("mount tmpfs at \"/dev/shm\" with {:?} ({:?})", data, secure),
+                    );
+                }
+                Mount::Raw {
+                    ref source,
+                    ref target,
+                    ref fstype,
+                    flags,
+                    ref data,
+                } => {
+                    plan.add(
+                        ChildChangeRoot,
+                        format!(
+                            "mount({:?}, {:?}, {:?}, {:?}, {:?})",
+                            source, target, fstype, flags, data
+                        ),
+                    );
+                }
+            }
+        }
+
+        if let Some(gid) = self.config.gid {
+            plan.add(ChildSetUser, format!("setgid({})", gid));
+        }
+        if let Some(ref groups) = self.supplementary_groups()? {
+            plan.add(ChildSetUser, format!("setgroups({:?})", groups));
+        }
+        if let Some(uid) = self.config.uid {
+            plan.add(ChildSetUser, format!("setuid({})", uid));
+        }
+        if let Some(caps) = self.keep_caps {
+            plan.add(
+                ChildSetUser,
+                format!("set capabilities {:#x} {:#x}", caps[0], caps[1]),
+            );
+        }
+
+        if let Some(ref dir) = self.config.work_dir {
+            plan.add(ChildFds, format!("chdir({:?})", dir));
+        }
+        if let (Some(ref fd), None) = (&self.config.work_dir_fd, early_fd) {
+            plan.add(
+                ChildFds,
+                format!("fchdir(descriptor {} of the parent)", fd.as_raw_fd()),
+            );
+        }
+        let mut fds = self.fds.iter().collect::<Vec<_>>();
+        fds.sort_by_key(|&(&fd, _)| fd);
+        for (fd, cfg) in fds {
+            if self.config.cloexec_fds.contains(fd) {
+                plan.add(
+                    ChildFds,
+                    format!("fd {}: {}, close-on-exec", fd, describe_fd(cfg)),
+                );
+            } else {
+                plan.add(ChildFds, format!("fd {}: {}", fd, describe_fd(cfg)));
+            }
+        }
+        if let Some(fd) = ready_fd {
+            plan.add(ChildFds, format!("fd {}: ready notification pipe", fd));
+        }
+        for &(start, end, _) in &self.close_fds {
+            if start < end {
+                plan.add(ChildFds, format!("close fds {}..{}", start, end));
+            }
+        }
+
+        let reset_handlers = !self
+            .config
+            .inherit_handlers
+            .unwrap_or(!self.config.restore_sigmask);
+        match (self.config.restore_sigmask, reset_handlers) {
+            (true, true) => plan.add(ChildPreExec, "reset signal mask and handlers".into()),
+            (true, false) => plan.add(ChildPreExec, "reset signal mask".into()),
+            (false, true) => plan.add(ChildPreExec, "reset signal handlers".into()),
+            (false, false) => {}
+        }
+        if let Some(secs) = self.config.cpu_time_limit {
+            plan.add(
+                ChildPreExec,
+                format!(
+                    "setrlimit(RLIMIT_CPU, {}, {})",
+                    secs,
+                    secs.saturating_add(1)
+                ),
+            );
+        }
+        if let Some(node) = self.config.numa_node {
+            plan.add(
+                ChildPreExec,
+                format!("set_mempolicy(MPOL_BIND, [{}])", node),
+            );
+        }
+        let mitigations = &self.config.mitigations;
+        if let Some(mode) = mitigations.store_bypass {
+            plan.add(
+                ChildPreExec,
+                format!("set store bypass speculation {:?}", mode),
+            );
+        }
+        if let Some(mode) = mitigations.indirect_branch {
+            plan.add(
+                ChildPreExec,
+                format!("set indirect branch speculation {:?}", mode),
+            );
+        }
+        if mitigations.disable_thp {
+            plan.add(ChildPreExec, "prctl(PR_SET_THP_DISABLE)".into());
+        }
+        if self.pre_exec.is_some() {
+            plan.add(ChildPreExec, "call pre_exec callback".into());
+        }
+        if self.config.audit_fds && (self.chroot_dir.is_some() || self.pivot_root.is_some()) {
+            plan.add(
+                ChildPreExec,
+                "check that no descriptor is outside of the root".into(),
+            );
+        }
+        if self.config.reap_orphans && self.config.namespaces.contains(CloneFlags::CLONE_NEWPID) {
+            plan.add(ChildPreExec, "fork, the parent runs as init".into());
+        }
+        if self.config.trace_me {
+            plan.add(ChildPreExec, "ptrace(PTRACE_TRACEME)".into());
+        }
+
+        let mut env = environ
+            .iter()
+            .enumerate()
+            .map(|(index, pair)| {
+                let pair = &pair[..pair.len() - 1];
+                match late.iter().find(|&&(i, _, _)| i == index) {
+                    Some(&(_, offset, ref value)) => format!(
+                        "{}{}",
+                        OsStr::from_bytes(&pair[..offset]).to_string_lossy(),
+                        describe_late(value)
+                    ),
+                    None => OsStr::from_bytes(pair).to_string_lossy().into_owned(),
+                }
+            })
+            .collect::<Vec<_>>();
+        env.sort();
+        match filename {
+            Some(filename) => plan.add(
+                Exec,
+                format!("execve({:?}, {:?}, {:?})", filename, self.args, env),
+            ),
+            None => plan.add(
+                Exec,
+                format!("fexecve(<sealed program>, {:?}, {:?})", self.args, env),
+            ),
+        }
+        if self.after_exec.is_some() {
+            plan.add(Exec, "call after_exec callback".into());
+        }
+        Ok(plan)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::{Arc, Mutex};
+
+    use crate::{Command, Error, MetadataVar, Namespace, ProgramBase, SpawnPhase, Stdio, UidMap};
+
+    const GOLDEN: &str = r#" 1. BuildEnv: resolve program to "/bin/echo"
+ 2. Clone: clone(CLONE_NEWNS | CLONE_NEWPID | CLONE_NEWNET)
+ 3. Clone: prctl(PR_SET_PDEATHSIG, SIGKILL)
+ 4. Unfreeze: setpgid(<pid>, <pid>)
+ 5. Unfreeze: wake up the child
+ 6. ChildNamespaces: set "CHILD_PID" to <pid>
+ 7. ChildChangeRoot: mount(NULL, "/", NULL, MS_REC | MS_SLAVE, NULL)
+ 8. ChildChangeRoot: mount sysfs at "/sys" read-only (MS_NOSUID | MS_NODEV | MS_NOEXEC)
+ 9. ChildSetUser: setgid(1000)
+10. ChildSetUser: setgroups([1000, 1001])
+11. ChildSetUser: setuid(1000)
+12. ChildFds: chdir("/tmp")
+13. ChildFds: fd 0: /dev/null for reading
+14. ChildFds: fd 1: write end of a new pipe
+15. ChildFds: fd 2: inherited
+16. ChildFds: close fds 3..100
+17. ChildPreExec: reset signal mask and handlers
+18. Exec: execve("/bin/echo", ["/bin/echo", "hello"], ["CHILD_PID=<pid>", "LANG=C"])
+"#;
+
+    #[test]
+    fn test_golden() {
+        let mut cmd = Command::new("/bin/echo");
+        cmd.arg("hello");
+        cmd.env_clear().env("LANG", "C");
+        cmd.env_var_with_metadata("CHILD_PID", MetadataVar::Pid);
+        cmd.unshare(&[Namespace::Mount, Namespace::Pid, Namespace::Net]);
+        cmd.mount_sysfs("/sys", true);
+        cmd.uid(1000).gid(1000).groups(vec![1000, 1001]);
+        cmd.make_group_leader(true);
+        cmd.current_dir("/tmp");
+        cmd.stdin(Stdio::null()).stdout(Stdio::piped());
+        cmd.close_fds(3..100);
+        assert_eq!(cmd.plan().unwrap().to_string(), GOLDEN);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut cmd = Command::new("nonexistent/program");
+        cmd.resolve_program_against(ProgramBase::ParentCwd);
+        cmd.unshare(&[Namespace::Mount]);
+        cmd.mount_sysfs("/sys", true);
+        assert!(matches!(cmd.plan(), Err(Error::ConflictingOptions { .. })));
+        cmd.unshare(&[Namespace::Net]);
+        assert!(matches!(cmd.plan(), Err(Error::Exec(libc::ENOENT))));
+    }
+
+    #[test]
+    fn test_order_matches_trace() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::User, Namespace::Mount, Namespace::Net]);
+        cmd.set_id_maps(
+            vec![UidMap {
+                inside_uid: 0,
+                outside_uid: 0,
+                count: 1,
+            }],
+            vec![],
+        );
+        cmd.mount_sysfs("/sys", true);
+        cmd.make_group_leader(true);
+        cmd.before_unfreeze(|_| Ok(()));
+        unsafe {
+            cmd.pre_exec(|| Ok(()));
+        }
+        cmd.uid(0);
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let traced = phases.clone();
+        cmd.trace(move |phase, _| traced.lock().unwrap().push(phase));
+
+        let plan = cmd.plan().unwrap();
+        cmd.status().unwrap();
+        let mut planned = Vec::new();
+        for step in plan.steps() {
+            if planned.last() != Some(&step.phase) {
+                planned.push(step.phase);
+            }
+        }
+        let traced = phases.lock().unwrap().clone();
+        // every phase with steps is reported once, in the same order
+        assert_eq!(
+            planned.iter().collect::<HashSet<_>>().len(),
+            planned.len(),
+            "{:?}",
+            planned
+        );
+        let reported = traced
+            .iter()
+            .filter(|phase| planned.contains(phase))
+            .collect::<Vec<_>>();
+        assert_eq!(reported, planned.iter().collect::<Vec<_>>());
+        assert!(planned.contains(&SpawnPhase::WriteIdMaps));
+        assert!(planned.contains(&SpawnPhase::BeforeUnfreeze));
+        assert!(planned.contains(&SpawnPhase::ChildChangeRoot));
+    }
+}
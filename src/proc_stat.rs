@@ -0,0 +1,11 @@
+//! Parsing of `/proc/<pid>/stat`
+
+/// Splits the fields which follow the command name, starting from the 3rd
+/// one (`state`), so field `n` of proc(5) is at index `n - 3`
+///
+/// The command name may contain spaces and parens, but it's always
+/// followed by the last paren. Returns `None` if there is no paren.
+pub(crate) fn stat_fields(stat: &str) -> Option<Vec<&str>> {
+    let pos = stat.rfind(')')?;
+    Some(stat[pos + 1..].split_whitespace().collect())
+}
@@ -0,0 +1,210 @@
+use std::ffi::{CString, OsStr};
+use std::fs::{self, File};
+use std::io::Write;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::path::{Path, PathBuf};
+
+use crate::ffi_util::ToCString;
+use crate::run::relative_to;
+use crate::{Command, Error};
+
+/// What a relative program path (i.e. `./bin/app`) is resolved against
+///
+/// Programs without a slash are looked up in `PATH` of the child either way.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProgramBase {
+    /// The working directory of the child, after changing root and
+    /// `current_dir`, which is what `execve` does (the default)
+    ChildCwd,
+    /// The working directory of the parent at the time of `spawn`
+    ///
+    /// The path is canonicalized on the host filesystem. If root is
+    /// changed by `chroot_dir` or `pivot_root`, the path is then made
+    /// relative to the new root, so the program must be inside of it.
+    ParentCwd,
+}
+
+impl Command {
+    /// Set what a relative program path (one with a slash, but not
+    /// starting with it) is resolved against
+    ///
+    /// By default it's the working directory of the child (`ChildCwd`),
+    /// which is often surprising when the program works without
+    /// `chroot_dir` or `current_dir` but fails with `ENOENT` with them.
+    /// With `ParentCwd` the path is resolved before spawning, so
+    /// `Command::new("./app")` runs the `app` in the current directory of
+    /// the parent. If the file can't be found, spawn fails with
+    /// `Error::Exec` (or `Error::ExecInRoot` if the file is outside of the
+    /// new root).
+    ///
+    /// Note: the `argv[0]` is not changed.
+    pub fn resolve_program_against(&mut self, base: ProgramBase) -> &mut Command {
+        self.config.program_base = base;
+        self
+    }
+
+    /// Run the program from memory: `bytes` are written to a sealed memfd
+    ///
+    /// The memfd is created right away, when writing is finished it's sealed
+    /// with `F_SEAL_WRITE`, `F_SEAL_SHRINK` and `F_SEAL_GROW`, so neither
+    /// the parent nor the child can change the program. The child runs it
+    /// with `fexecve`, the path passed to `Command::new` is only used as
+    /// `argv[0]`. The same memfd is used for all spawns of the command.
+    ///
+    /// The descriptor is passed to the program without CLOEXEC flag, as
+    /// scripts (`#!` interpreters) are run by opening `/dev/fd/<N>`.
+    ///
+    /// Returns `Error::WriteProgram` or `Error::SealProgram` if the memfd
+    /// can't be created and filled or sealed respectively.
+    pub fn sealed_program(&mut self, bytes: &[u8]) -> Result<&mut Command, Error> {
+        let fd = unsafe {
+            libc::memfd_create(
+                b"unshare-program\0".as_ptr() as *const libc::c_char,
+                libc::MFD_CLOEXEC | libc::MFD_ALLOW_SEALING,
+            )
+        };
+        if fd < 0 {
+            return Err(Error::WriteProgram(nix::errno::errno()));
+        }
+        let mut file = unsafe { File::from_raw_fd(fd) };
+        file.write_all(bytes)
+            .map_err(|e| Error::WriteProgram(e.raw_os_error().unwrap_or(libc::EIO)))?;
+        let seals = libc::F_SEAL_WRITE | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW;
+        if unsafe { libc::fcntl(file.as_raw_fd(), libc::F_ADD_SEALS, seals) } < 0 {
+            return Err(Error::SealProgram(nix::errno::errno()));
+        }
+        self.config.program_fd = Some(OwnedFd::from(file));
+        Ok(self)
+    }
+
+    /// The new root of the child as seen by the parent
+    pub(crate) fn new_root(&self) -> Option<PathBuf> {
+        match (&self.pivot_root, &self.chroot_dir) {
+            (None, None) => None,
+            (Some((new, _)), None) => Some(new.clone()),
+            (None, Some(dir)) => Some(dir.clone()),
+            (Some((new, _)), Some(dir)) => Some(new.join(relative_to(dir, "/", false).unwrap())),
+        }
+    }
+
+    /// Returns the path passed to `execve`
+    pub(crate) fn resolve_program(&self) -> Result<CString, Error> {
+        let program = Path::new(OsStr::from_bytes(self.filename.as_bytes()));
+        let relative = !program.is_absolute() && program.components().count() > 1;
+        if self.config.program_base == ProgramBase::ChildCwd || !relative {
+            return Ok(self.filename.clone());
+        }
+        let path = fs::canonicalize(program)
+            .map_err(|e| Error::Exec(e.raw_os_error().unwrap_or(libc::ENOENT)))?;
+        let root = match self.new_root() {
+            None => return Ok(path.to_cstring()),
+            Some(root) => root,
+        };
+        // compare canonical paths, as the root may contain symlinks too
+        let canonical_root = fs::canonicalize(&root).unwrap_or(root.clone());
+        match relative_to(&path, canonical_root, true) {
+            Some(inside) => Ok(inside.to_cstring()),
+            None => Err(Error::ExecInRoot {
+                errno: libc::ENOENT,
+                root,
+                path,
+                missing_interpreter: None,
+            }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::current_dir;
+    use std::fs;
+    use std::path::PathBuf;
+
+    use super::ProgramBase;
+    use crate::{Command, Error, ExitStatus};
+
+    /// Relative path from the current directory to the `dest`
+    fn relative(dest: &str) -> PathBuf {
+        // go through `src` so that the path doesn't work from `/`
+        let mut path = PathBuf::from("src");
+        for _ in current_dir().unwrap().components() {
+            path.push("..");
+        }
+        path.join(dest.trim_start_matches('/'))
+    }
+
+    #[test]
+    fn test_child_cwd() {
+        let mut cmd = Command::new(relative("/bin/true"));
+        cmd.current_dir("/");
+        match cmd.status().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parent_cwd() {
+        let mut cmd = Command::new(relative("/bin/true"));
+        cmd.current_dir("/");
+        cmd.resolve_program_against(ProgramBase::ParentCwd);
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_parent_cwd_in_root() {
+        let root = std::env::temp_dir().join(format!("unshare-program-{}", std::process::id()));
+        fs::create_dir_all(root.join("bin")).unwrap();
+        fs::write(root.join("bin/app"), "").unwrap();
+        let mut cmd = Command::new(relative(root.join("bin/app").to_str().unwrap()));
+        cmd.chroot_dir(&root);
+        cmd.resolve_program_against(ProgramBase::ParentCwd);
+        let inside = cmd.resolve_program();
+        let mut cmd = Command::new(relative("/bin/true"));
+        cmd.chroot_dir(&root);
+        cmd.resolve_program_against(ProgramBase::ParentCwd);
+        let outside = cmd.resolve_program();
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(inside.unwrap().as_bytes(), b"/bin/app");
+        match outside {
+            Err(Error::ExecInRoot {
+                errno: libc::ENOENT,
+                path,
+                ..
+            }) => assert_eq!(path, fs::canonicalize("/bin/true").unwrap()),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sealed_program() {
+        let mut cmd = Command::new("true");
+        cmd.sealed_program(&fs::read("/bin/true").unwrap()).unwrap();
+        cmd.current_dir("/");
+        assert!(cmd.status().unwrap().success());
+        // can be spawned multiple times
+        assert!(cmd.status().unwrap().success());
+
+        let mut cmd = Command::new("script");
+        cmd.sealed_program(b"#!/bin/sh\nexit 5\n").unwrap();
+        assert_eq!(cmd.status().unwrap(), ExitStatus::Exited(5));
+    }
+
+    #[test]
+    fn test_sealed_program_is_sealed() {
+        use std::os::unix::io::AsRawFd;
+
+        let mut cmd = Command::new("script");
+        cmd.sealed_program(b"#!/bin/sh\n").unwrap();
+        let fd = cmd.config.program_fd.as_ref().unwrap().as_raw_fd();
+        let seals = unsafe { libc::fcntl(fd, libc::F_GET_SEALS) };
+        assert_eq!(
+            seals,
+            libc::F_SEAL_WRITE | libc::F_SEAL_SHRINK | libc::F_SEAL_GROW
+        );
+        let written = unsafe { libc::write(fd, b"x".as_ptr() as *const libc::c_void, 1) };
+        assert_eq!(written, -1);
+        assert_eq!(nix::errno::errno(), libc::EPERM);
+    }
+}
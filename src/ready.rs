@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::ffi::OsStr;
+use std::io::{self, Read};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration, Instant};
+
+use nix::fcntl::{fcntl, FcntlArg};
+
+use crate::error::result;
+use crate::error::ErrorCode as Err;
+use crate::pipe::{Pipe, PipeReader};
+use crate::run::wait_readable;
+use crate::stdio::Closing;
+use crate::{Child, Command, Error, ExitStatus};
+
+/// The result of `Child::ready_wait`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Readiness {
+    /// The child has written to the notification descriptor
+    Ready,
+    /// Nothing happened until the timeout
+    TimedOut,
+    /// The child has closed the descriptor without writing anything
+    ///
+    /// Contains the exit status if this is because the child has exited.
+    Failed(Option<ExitStatus>),
+}
+
+/// The state of the notification pipe in the parent
+#[derive(Debug)]
+pub(crate) enum ReadyPipe {
+    Waiting(PipeReader),
+    Done(Readiness),
+}
+
+impl Command {
+    /// Pass a descriptor to the child to notify when it's ready
+    ///
+    /// The number of the descriptor is put into environment variable
+    /// `key` (i.e. `READY_FD=3`). It's the lowest number that is not
+    /// configured with `file_descriptor` (and stdio). The child writes
+    /// any byte to it when it finished initialization (or closes it when
+    /// it failed to), which is awaited by `Child::ready_wait`.
+    ///
+    /// As any other passed descriptor, it's inherited by processes the
+    /// child runs, unless the child closes it.
+    pub fn ready_notification<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+        self.config.ready_env = Some(key.as_ref().to_os_string());
+        self.prepared_env = None;
+        self
+    }
+
+    /// The number of the notification descriptor in the child
+    pub(crate) fn ready_fd(&self) -> Option<RawFd> {
+        self.config.ready_env.as_ref()?;
+        (3..).find(|fd| !self.fds.contains_key(fd))
+    }
+}
+
+/// Creates the notification pipe, the write end is passed to the child
+/// as `dest_fd`
+///
+/// Other descriptors passed to the child are moved if one of them has the
+/// number `dest_fd` in the parent, so `dup2` doesn't clobber it. Both ends
+/// of the pipe are numbered at least `min_fd`.
+pub(crate) fn ready_pipe(
+    dest_fd: RawFd,
+    min_fd: RawFd,
+    fds: &mut HashMap<RawFd, RawFd>,
+    guards: &mut Vec<Closing>,
+) -> Result<PipeReader, Error> {
+    let (rd, wr) = Pipe::new()?.split();
+    let (rd, wr) = (rd.move_above(min_fd)?, wr.move_above(min_fd)?);
+    let fd = wr.into_fd();
+    guards.push(Closing::new(fd));
+    for src in fds.values_mut() {
+        if *src == dest_fd {
+            *src = result(
+                Err::CreatePipe,
+                fcntl(*src, FcntlArg::F_DUPFD_CLOEXEC(min_fd.max(3))),
+            )?;
+            guards.push(Closing::new(*src));
+        }
+    }
+    fds.insert(dest_fd, fd);
+    Ok(rd)
+}
+
+impl Child {
+    /// Wait until the child notifies that it's ready
+    ///
+    /// See `Command::ready_notification`. Returns `Readiness::Failed` with
+    /// the exit status if the child has exited before writing to the
+    /// descriptor. The final result is remembered, so it's returned by
+    /// subsequent calls immediately.
+    ///
+    /// Returns `InvalidInput` error if `ready_notification` wasn't set.
+    pub fn ready_wait(&mut self, timeout: Duration) -> Result<Readiness, io::Error> {
+        let deadline = Instant::now() + timeout;
+        let pipe = match self.ready {
+            Some(ReadyPipe::Waiting(ref mut pipe)) => pipe,
+            Some(ReadyPipe::Done(state)) => return Ok(state),
+            None => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "invalid argument: ready notification is not enabled",
+                ))
+            }
+        };
+        match wait_readable(pipe.as_raw_fd(), deadline) {
+            Ok(()) => {}
+            Err(Error::SpawnTimeout) => return Ok(Readiness::TimedOut),
+            Err(e) => {
+                return Err(io::Error::from_raw_os_error(
+                    e.raw_os_error().unwrap_or(libc::EIO),
+                ))
+            }
+        }
+        let state = loop {
+            match pipe.read(&mut [0u8]) {
+                Ok(0) => {
+                    // the descriptor is closed on exit slightly before
+                    // the process becomes a zombie
+                    let left = deadline.saturating_duration_since(Instant::now());
+                    let status = self.wait_timeout(left.min(Duration::from_millis(100)))?;
+                    break Readiness::Failed(status);
+                }
+                Ok(_) => break Readiness::Ready,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        };
+        self.ready = Some(ReadyPipe::Done(state));
+        Ok(state)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::Readiness;
+    use crate::{Command, ExitStatus, Fd};
+
+    #[test]
+    fn test_ready() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "sleep 0.1; echo >&$READY_FD; exec sleep 10"]);
+        cmd.ready_notification("READY_FD");
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(
+            child.ready_wait(Duration::from_millis(10)).unwrap(),
+            Readiness::TimedOut
+        );
+        assert_eq!(
+            child.ready_wait(Duration::from_secs(5)).unwrap(),
+            Readiness::Ready
+        );
+        // remembered
+        assert_eq!(
+            child.ready_wait(Duration::from_secs(0)).unwrap(),
+            Readiness::Ready
+        );
+        child.kill().unwrap();
+        child.wait().unwrap();
+    }
+
+    #[test]
+    fn test_failed() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.ready_notification("READY_FD");
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(
+            child.ready_wait(Duration::from_secs(5)).unwrap(),
+            Readiness::Failed(Some(ExitStatus::Exited(3)))
+        );
+    }
+
+    #[test]
+    fn test_free_slot() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&[
+            "-c",
+            "[ $READY_FD = 5 ] && [ -e /proc/self/fd/3 ] && echo >&5",
+        ]);
+        cmd.file_descriptor(3, Fd::ReadNull);
+        cmd.file_descriptor(4, Fd::WriteNull);
+        cmd.ready_notification("READY_FD");
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(
+            child.ready_wait(Duration::from_secs(5)).unwrap(),
+            Readiness::Ready
+        );
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_not_enabled() {
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert!(child.ready_wait(Duration::from_secs(1)).is_err());
+        child.wait().unwrap();
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+use libc::{c_int, c_void, pid_t, siginfo_t};
+use nix::errno::Errno;
+
+use crate::stdio::Closing;
+use crate::wait::reap_exited;
+use crate::{Command, ExitStatus, Pid};
+
+type Filter = Box<dyn Fn(Pid, ExitStatus) -> bool + Send>;
+
+/// The write end of the self-pipe of the installed reaper, or `-1`
+static WAKE_FD: AtomicI32 = AtomicI32::new(-1);
+/// The `SIGCHLD` handler (and its flags) which was installed before
+static PREV_HANDLER: AtomicUsize = AtomicUsize::new(0);
+static PREV_FLAGS: AtomicI32 = AtomicI32::new(0);
+
+/// The state shared by the reaper thread and the commands
+pub(crate) struct Registry {
+    alive: Mutex<HashSet<pid_t>>,
+    statuses: Mutex<HashMap<pid_t, ExitStatus>>,
+    stop: AtomicBool,
+    // both ends are kept until the last command is dropped, so that
+    // writing never raises `SIGPIPE`
+    wake_read: Closing,
+    wake: Closing,
+}
+
+impl Registry {
+    /// Adds a child spawned by the command with `auto_reap`
+    ///
+    /// It might have exited already, so the thread is woken up to check.
+    pub(crate) fn register(&self, pid: pid_t) {
+        if self.stop.load(Ordering::SeqCst) {
+            return;
+        }
+        self.alive.lock().unwrap().insert(pid);
+        self.wake();
+    }
+
+    fn wake(&self) {
+        unsafe { libc::write(self.wake.as_raw_fd(), b"x".as_ptr() as *const c_void, 1) };
+    }
+
+    fn reap(&self, filter: &Filter) {
+        let pids = self
+            .alive
+            .lock()
+            .unwrap()
+            .iter()
+            .cloned()
+            .collect::<Vec<_>>();
+        for pid in pids {
+            let status = match reap_exited(pid) {
+                Ok(Some((_, status))) => Some(status),
+                Ok(None) => continue,
+                // reaped by someone else
+                Err(Errno::ECHILD) => None,
+                Err(_) => continue,
+            };
+            self.alive.lock().unwrap().remove(&pid);
+            if let Some(status) = status {
+                if filter(Pid::from_raw(pid), status) {
+                    self.statuses.lock().unwrap().insert(pid, status);
+                }
+            }
+        }
+    }
+}
+
+/// A guard which reaps fire-and-forget children in background
+///
+/// While the guard exists, a `SIGCHLD` handler is installed, which wakes up
+/// a thread that reaps the children spawned by commands with `auto_reap`.
+/// Other children of the process (i.e. spawned by `std::process` or by
+/// other libraries) are never waited for, so they can't be stolen. The
+/// handler which was installed before is called from ours, and is restored
+/// when the guard is dropped (which also stops the thread).
+///
+/// Only one reaper can be installed at a time.
+///
+/// Note that if `SIGCHLD` was ignored (which makes the kernel reap all the
+/// children of the process), it isn't ignored while the reaper is installed.
+pub struct AutoReaper {
+    registry: Arc<Registry>,
+    thread: Option<JoinHandle<()>>,
+    previous: libc::sigaction,
+}
+
+impl AutoReaper {
+    /// Installs the `SIGCHLD` handler and starts the reaper thread
+    ///
+    /// The `filter` is called in the thread for each reaped child, if it
+    /// returns `true` the exit status is kept to be retrieved by
+    /// `take_status` (return `false` for the children which nobody checks,
+    /// so that the statuses don't accumulate).
+    ///
+    /// Returns `AlreadyExists` error if another reaper is installed.
+    pub fn install<F>(filter: F) -> io::Result<AutoReaper>
+    where
+        F: Fn(Pid, ExitStatus) -> bool + Send + 'static,
+    {
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let (read, write) = (Closing::new(fds[0]), Closing::new(fds[1]));
+        // the handler must never block
+        if unsafe { libc::fcntl(write.as_raw_fd(), libc::F_SETFL, libc::O_NONBLOCK) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if WAKE_FD
+            .compare_exchange(-1, write.as_raw_fd(), Ordering::SeqCst, Ordering::SeqCst)
+            .is_err()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "auto reaper is already installed",
+            ));
+        }
+        let mut previous: libc::sigaction = unsafe { mem::zeroed() };
+        unsafe {
+            libc::sigaction(libc::SIGCHLD, ptr::null(), &mut previous);
+            PREV_HANDLER.store(previous.sa_sigaction, Ordering::SeqCst);
+            PREV_FLAGS.store(previous.sa_flags, Ordering::SeqCst);
+            let mut action: libc::sigaction = mem::zeroed();
+            action.sa_sigaction = handle_sigchld as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART;
+            libc::sigemptyset(&mut action.sa_mask);
+            if libc::sigaction(libc::SIGCHLD, &action, ptr::null_mut()) != 0 {
+                let err = io::Error::last_os_error();
+                WAKE_FD.store(-1, Ordering::SeqCst);
+                return Err(err);
+            }
+        }
+        let registry = Arc::new(Registry {
+            alive: Mutex::new(HashSet::new()),
+            statuses: Mutex::new(HashMap::new()),
+            stop: AtomicBool::new(false),
+            wake_read: read,
+            wake: write,
+        });
+        let filter: Filter = Box::new(filter);
+        let shared = registry.clone();
+        let thread = thread::Builder::new()
+            .name("unshare-reaper".into())
+            .spawn(move || {
+                let mut buf = [0u8; 64];
+                while !shared.stop.load(Ordering::SeqCst) {
+                    shared.reap(&filter);
+                    let rc = unsafe {
+                        libc::read(
+                            shared.wake_read.as_raw_fd(),
+                            buf.as_mut_ptr() as *mut c_void,
+                            buf.len(),
+                        )
+                    };
+                    if rc == 0 || rc < 0 && Errno::last() != Errno::EINTR {
+                        break;
+                    }
+                }
+            });
+        let thread = match thread {
+            Ok(thread) => thread,
+            Err(e) => {
+                unsafe { libc::sigaction(libc::SIGCHLD, &previous, ptr::null_mut()) };
+                WAKE_FD.store(-1, Ordering::SeqCst);
+                return Err(e);
+            }
+        };
+        Ok(AutoReaper {
+            registry,
+            thread: Some(thread),
+            previous,
+        })
+    }
+
+    /// Returns the exit status of the child if it's reaped (and accepted
+    /// by the filter), the status is forgotten after that
+    pub fn take_status(&self, pid: Pid) -> Option<ExitStatus> {
+        self.registry.statuses.lock().unwrap().remove(&pid.as_raw())
+    }
+}
+
+impl Drop for AutoReaper {
+    fn drop(&mut self) {
+        unsafe { libc::sigaction(libc::SIGCHLD, &self.previous, ptr::null_mut()) };
+        WAKE_FD.store(-1, Ordering::SeqCst);
+        self.registry.stop.store(true, Ordering::SeqCst);
+        self.registry.wake();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+extern "C" fn handle_sigchld(sig: c_int, info: *mut siginfo_t, context: *mut c_void) {
+    // the handler may interrupt code which checks errno
+    let errno = nix::errno::errno();
+    let fd = WAKE_FD.load(Ordering::SeqCst);
+    if fd >= 0 {
+        unsafe { libc::write(fd, b"x".as_ptr() as *const c_void, 1) };
+    }
+    let handler = PREV_HANDLER.load(Ordering::SeqCst);
+    if handler != libc::SIG_DFL && handler != libc::SIG_IGN {
+        unsafe {
+            if PREV_FLAGS.load(Ordering::SeqCst) & libc::SA_SIGINFO != 0 {
+                let handler: extern "C" fn(c_int, *mut siginfo_t, *mut c_void) =
+                    mem::transmute(handler);
+                handler(sig, info, context);
+            } else {
+                let handler: extern "C" fn(c_int) = mem::transmute(handler);
+                handler(sig);
+            }
+        }
+    }
+    unsafe { *libc::__errno_location() = errno };
+}
+
+impl Command {
+    /// Make spawned children reaped by `reaper` in background
+    ///
+    /// The exit status is available from `AutoReaper::take_status`. Don't
+    /// wait for the returned `Child`, it races with the reaper. Children
+    /// spawned after the reaper is dropped are not reaped.
+    pub fn auto_reap(&mut self, reaper: &AutoReaper) -> &mut Command {
+        self.config.auto_reap = Some(reaper.registry.clone());
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+    use std::process;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::AutoReaper;
+    use crate::{Command, ExitStatus};
+
+    #[test]
+    fn test_auto_reap() {
+        let reaper = AutoReaper::install(|_, status| status != ExitStatus::Exited(1)).unwrap();
+        assert!(AutoReaper::install(|_, _| true).is_err());
+        let mut std_child = process::Command::new("/bin/sh")
+            .args(&["-c", "sleep 0.2; exit 3"])
+            .spawn()
+            .unwrap();
+        let mut cmd = Command::new("/bin/true");
+        cmd.auto_reap(&reaper);
+        let pids = (0..5)
+            .map(|_| cmd.spawn().unwrap().pid())
+            .collect::<Vec<_>>();
+        let failed = Command::new("/bin/false")
+            .auto_reap(&reaper)
+            .spawn()
+            .unwrap()
+            .pid();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut statuses = Vec::new();
+        for &pid in &pids {
+            loop {
+                if let Some(status) = reaper.take_status(pid) {
+                    statuses.push(status);
+                    break;
+                }
+                assert!(Instant::now() < deadline);
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        assert_eq!(statuses, vec![ExitStatus::Exited(0); 5]);
+        for pid in pids {
+            assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+        }
+        while Path::new(&format!("/proc/{}", failed)).exists() {
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(reaper.take_status(failed), None);
+        assert_eq!(std_child.wait().unwrap().code(), Some(3));
+        drop(reaper);
+        AutoReaper::install(|_, _| true).unwrap();
+    }
+}
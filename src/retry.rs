@@ -0,0 +1,121 @@
+use std::time::Duration;
+
+use crate::Command;
+
+/// How to retry transient failures of `clone`, see `Command::retry_transient`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one
+    pub attempts: u32,
+    /// Delay before the second attempt, it's doubled on each next attempt
+    pub backoff: Duration,
+}
+
+impl RetryPolicy {
+    /// Returns the delay before the next attempt if it should be made
+    ///
+    /// The `attempt` is the number of attempts made so far.
+    pub(crate) fn next_delay(&self, errno: i32, attempt: u32) -> Option<Duration> {
+        if errno != libc::EAGAIN && errno != libc::ENOMEM {
+            return None;
+        }
+        if attempt >= self.attempts {
+            return None;
+        }
+        Some(self.backoff * 2u32.saturating_pow(attempt - 1))
+    }
+}
+
+impl Command {
+    /// Retry `clone` if it fails with `EAGAIN` or `ENOMEM`
+    ///
+    /// These errors are transient: they happen when process limits
+    /// (`RLIMIT_NPROC`, `pid_max`, `pids` cgroup) are reached or under
+    /// memory pressure. Only the `clone` itself is retried, the child process
+    /// is never restarted once created.
+    ///
+    /// If all attempts fail, `Error::ForkAttempts` is returned.
+    pub fn retry_transient(&mut self, policy: RetryPolicy) -> &mut Command {
+        self.config.retry = Some(policy);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+    use std::time::Duration;
+
+    use super::RetryPolicy;
+    use crate::test_util::inner_test_of;
+    use crate::{Command, Error};
+
+    #[test]
+    fn test_delays() {
+        let policy = RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(10),
+        };
+        assert_eq!(
+            policy.next_delay(libc::EAGAIN, 1),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(
+            policy.next_delay(libc::ENOMEM, 2),
+            Some(Duration::from_millis(20))
+        );
+        assert_eq!(policy.next_delay(libc::EAGAIN, 3), None);
+        assert_eq!(policy.next_delay(libc::EPERM, 1), None);
+    }
+
+    #[test]
+    fn test_nproc_limit() {
+        // root is not limited by `RLIMIT_NPROC`, so the test is run by
+        // `nobody`, which can't access the build directory
+        let dir = std::env::temp_dir().join(format!("unshare-retry-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let exe = dir.join("test");
+        fs::copy(std::env::current_exe().unwrap(), &exe).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o755)).unwrap();
+        fs::set_permissions(&exe, fs::Permissions::from_mode(0o755)).unwrap();
+        let mut cmd = inner_test_of(&exe, "retry::test::nproc_limit_inner", "UNSHARE_TEST_NPROC");
+        cmd.uid(65534).gid(65534).groups(Vec::new());
+        let status = cmd.status();
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(status.unwrap().success());
+    }
+
+    #[test]
+    #[ignore]
+    fn nproc_limit_inner() {
+        if std::env::var_os("UNSHARE_TEST_NPROC").is_none() {
+            return;
+        }
+        let limit = libc::rlimit {
+            rlim_cur: 1,
+            rlim_max: 1,
+        };
+        assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NPROC, &limit) }, 0);
+        match Command::new("/bin/true").spawn() {
+            Err(Error::Fork(libc::EAGAIN)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let mut cmd = Command::new("/bin/true");
+        cmd.retry_transient(RetryPolicy {
+            attempts: 3,
+            backoff: Duration::from_millis(1),
+        });
+        match cmd.spawn() {
+            Err(
+                e @ Error::ForkAttempts {
+                    errno: libc::EAGAIN,
+                    attempts: 3,
+                },
+            ) => {
+                assert!(e.to_string().ends_with("gave up after 3 attempts"));
+            }
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+}
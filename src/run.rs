@@ -1,15 +1,22 @@
+use std::cell::Cell;
 use std::collections::HashMap;
-use std::env::current_dir;
-use std::ffi::CString;
+use std::env::{self, current_dir};
+use std::ffi::{CStr, CString, OsStr};
 use std::fs::File;
 use std::io::{self, Read, Write};
-use std::iter::repeat;
+use std::mem;
 use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::FileExt;
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::panic::{self, AssertUnwindSafe};
 use std::path::{Path, PathBuf};
+use std::process;
 use std::ptr;
+use std::slice;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use libc::{c_char, close};
+use libc::{c_char, c_int, close, gid_t};
 use nix;
 use nix::errno::Errno::EINTR;
 use nix::fcntl::OFlag;
@@ -18,20 +25,47 @@ use nix::sched::{clone, CloneFlags};
 use nix::sys::signal::{kill, SIGCHLD, SIGKILL};
 use nix::sys::stat::Mode;
 use nix::sys::wait::waitpid;
-use nix::unistd::{setpgid, Pid};
+use nix::unistd::{getpgrp, setpgid, Pid};
 
+use crate::cgroup;
 use crate::child;
 use crate::chroot::{Chroot, Pivot};
-use crate::config::Config;
+use crate::clone3::clone_with_tids;
+use crate::config::{Config, NsFile};
+use crate::environ::EnvBase;
 use crate::error::ErrorCode as Err;
-use crate::error::{cmd_result, result, Error};
+use crate::error::{result, Error, IntoError, SetupStage};
 use crate::ffi_util::ToCString;
-use crate::namespace::to_clone_flag;
-use crate::pipe::{Pipe, PipeHolder, PipeReader, PipeWriter};
+use crate::idmap::{self, default_gid_args, default_uid_args};
+use crate::interpreter::{find_interpreter, INTERP_MAX};
+use crate::kill_reason::KillContext;
+use crate::namespace::{to_clone_flag, to_ns_name, Namespace};
+use crate::netlink;
+use crate::persist;
+use crate::pipe::{move_fd, Pipe, PipeHolder, PipeReader, PipeWriter};
+use crate::ready::{self, ReadyPipe};
+use crate::spawn_many;
 use crate::stdio::{Closing, Fd};
-use crate::{Child, Command, ExitStatus};
+use crate::tee::TeeSetup;
+use crate::trace::{PhaseTimer, SpawnPhase, TRACE_RECORD};
+use crate::{Child, Command, ExitStatus, GidMap, MetadataVar, UidMap};
 
 pub const MAX_PID_LEN: usize = 12;
+pub const MAX_INO_LEN: usize = 20;
+
+/// Environment variable value that is only known in the child
+pub enum LateEnvValue {
+    Pid,
+    /// Filled after the init forks the program
+    InnerPid,
+    HostPid,
+    /// Inode of the namespace file by path
+    NamespaceId(CString),
+}
+
+/// Positions of the late values in `environ`: entry index, offset of the
+/// value and the value
+pub type LateEnv = Vec<(usize, usize, LateEnvValue)>;
 
 pub struct ChildInfo<'a> {
     pub filename: *const c_char,
@@ -44,13 +78,24 @@ pub struct ChildInfo<'a> {
     pub wakeup_pipe: RawFd,
     pub error_pipe: RawFd,
     pub fds: &'a [(RawFd, RawFd)],
+    /// Descriptors opened by the child: the number, path and flags
+    pub open_fds: &'a [(RawFd, *const c_char, c_int)],
     /// This map may only be used for lookup but not for iteration!
     pub fd_lookup: &'a HashMap<RawFd, RawFd>,
     pub close_fds: &'a [(RawFd, RawFd)],
     pub setns_namespaces: &'a [(CloneFlags, RawFd)],
-    pub pid_env_vars: &'a [(usize, usize)],
+    pub metadata_env_vars: &'a [(usize, usize, LateEnvValue)],
     pub keep_caps: &'a Option<[u32; 2]>,
+    pub supplementary_gids: &'a Option<Vec<gid_t>>,
     pub pre_exec: &'a Option<Box<dyn Fn() -> Result<(), io::Error>>>,
+    /// Send trace records through the error pipe
+    pub trace: bool,
+    /// Descriptor set by `capture_child_errors` or `-1`
+    pub progress_fd: RawFd,
+    /// The closure of `spawn_fn` which is run instead of `execve`
+    pub payload: Option<&'a dyn Fn() -> i32>,
+    /// Descriptor set by `sealed_program` to run with `fexecve` or `-1`
+    pub program_fd: RawFd,
 }
 
 fn raw_with_null(arr: &Vec<CString>) -> Vec<*const c_char> {
@@ -71,7 +116,42 @@ fn raw_with_null_mut(arr: &mut Vec<Vec<u8>>) -> Vec<*mut c_char> {
     return vec;
 }
 
-fn relative_to<A: AsRef<Path>, B: AsRef<Path>>(dir: A, rel: B, absolute: bool) -> Option<PathBuf> {
+/// The name of the variable in the `name=value` entry of `environ`
+fn env_name(pair: &[u8]) -> &[u8] {
+    pair.split(|&b| b == b'=').next().unwrap_or(pair)
+}
+
+/// Checks that arguments and environment fit into the `ARG_MAX` limit
+///
+/// The size is calculated the same way the kernel does: the sum of lengths
+/// of all the strings (including nul terminator) and pointers to them.
+pub(crate) fn check_arg_max(args: &[CString], environ: &[Vec<u8>]) -> Result<(), Error> {
+    let limit = unsafe { libc::sysconf(libc::_SC_ARG_MAX) };
+    if limit <= 0 {
+        // no limit or unknown
+        return Ok(());
+    }
+    let pointer = mem::size_of::<*const c_char>();
+    let bytes = args
+        .iter()
+        .map(|a| a.as_bytes_with_nul().len() + pointer)
+        .chain(environ.iter().map(|e| e.len() + pointer))
+        .sum::<usize>()
+        + 2 * pointer;
+    if bytes > limit as usize {
+        return Err(Error::ArgumentListTooLong {
+            bytes,
+            limit: limit as usize,
+        });
+    }
+    Ok(())
+}
+
+pub(crate) fn relative_to<A: AsRef<Path>, B: AsRef<Path>>(
+    dir: A,
+    rel: B,
+    absolute: bool,
+) -> Option<PathBuf> {
     let dir = dir.as_ref();
     let rel = rel.as_ref();
     let mut dircmp = dir.components();
@@ -87,23 +167,109 @@ fn relative_to<A: AsRef<Path>, B: AsRef<Path>>(dir: A, rel: B, absolute: bool) -
     }
 }
 
+/// The stack size of the child if not set by `child_stack_size`
+///
+/// Unoptimized build needs more than 8k of stack in the child.
+pub(crate) const MIN_CHILD_STACK: usize = 16384;
+
+/// The stack size of the child for `spawn_fn` and the init of
+/// `reap_orphans_as_init`, same as for a new thread
+const MIN_PAYLOAD_STACK: usize = 2 << 20;
+
+/// The stack for `clone` with an inaccessible guard page below it
+///
+/// So overflowing the stack kills the child by `SIGSEGV` instead of
+/// silently overwriting the memory below it.
+struct ChildStack {
+    ptr: *mut libc::c_void,
+    len: usize,
+    page: usize,
+}
+
+impl ChildStack {
+    fn new(size: usize) -> Result<ChildStack, Error> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let len = size.div_ceil(page) * page + page;
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_STACK,
+                -1,
+                0,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(Error::Fork(nix::errno::errno()));
+        }
+        let stack = ChildStack { ptr, len, page };
+        // the stack grows down, so the guard is the lowest page
+        if unsafe { libc::mprotect(ptr, page, libc::PROT_NONE) } != 0 {
+            return Err(Error::Fork(nix::errno::errno()));
+        }
+        Ok(stack)
+    }
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe {
+            slice::from_raw_parts_mut((self.ptr as *mut u8).add(self.page), self.len - self.page)
+        }
+    }
+}
+
+impl Drop for ChildStack {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Waits until the pipe is readable, returns `SpawnTimeout` after deadline
+pub(crate) fn wait_readable(fd: RawFd, deadline: Instant) -> Result<(), Error> {
+    loop {
+        let now = Instant::now();
+        if now >= deadline {
+            return Err(Error::SpawnTimeout);
+        }
+        // round up, so we don't spin when less than a millisecond is left
+        let millis = (deadline - now).as_micros().div_ceil(1000);
+        let mut pollfd = libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let rc = unsafe { libc::poll(&mut pollfd, 1, millis.min(i32::MAX as u128) as i32) };
+        if rc > 0 {
+            return Ok(());
+        } else if rc < 0 {
+            let errno = nix::errno::errno();
+            if errno != libc::EINTR {
+                return Err(Error::PipeError(errno));
+            }
+        }
+    }
+}
+
 fn prepare_descriptors(
     fds: &HashMap<RawFd, Fd>,
+    min_fd: RawFd,
 ) -> Result<
     (
         HashMap<RawFd, RawFd>,
         HashMap<RawFd, PipeHolder>,
         Vec<Closing>,
+        Vec<TeeSetup>,
     ),
     Error,
 > {
     let mut inner = HashMap::new();
     let mut outer = HashMap::new();
     let mut guards = Vec::new();
+    let mut tees = Vec::new();
     for (&dest_fd, fdkind) in fds.iter() {
         let mut fd = match fdkind {
             &Fd::ReadPipe => {
                 let (rd, wr) = Pipe::new()?.split();
+                let (rd, wr) = (rd.move_above(min_fd)?, wr.move_above(min_fd)?);
                 let fd = rd.into_fd();
                 guards.push(Closing::new(fd));
                 outer.insert(dest_fd, PipeHolder::Writer(wr));
@@ -111,11 +277,34 @@ fn prepare_descriptors(
             }
             &Fd::WritePipe => {
                 let (rd, wr) = Pipe::new()?.split();
+                let (rd, wr) = (rd.move_above(min_fd)?, wr.move_above(min_fd)?);
                 let fd = wr.into_fd();
                 guards.push(Closing::new(fd));
                 outer.insert(dest_fd, PipeHolder::Reader(rd));
                 fd
             }
+            Fd::TeePipe(secondary) => {
+                let (rd, wr) = Pipe::new()?.split();
+                let (rd, wr) = (rd.move_above(min_fd)?, wr.move_above(min_fd)?);
+                let fd = wr.into_fd();
+                guards.push(Closing::new(fd));
+                let (capture_rd, capture_wr) = Pipe::new()?.split();
+                let capture_rd = capture_rd.move_above(min_fd)?;
+                let capture_wr = capture_wr.move_above(min_fd)?;
+                let secondary_fd = secondary.as_ref().map_or(dest_fd, |f| f.as_raw_fd());
+                let secondary = result(
+                    Err::StdioError,
+                    fcntl(secondary_fd, FcntlArg::F_DUPFD_CLOEXEC(min_fd.max(3))),
+                )?;
+                outer.insert(dest_fd, PipeHolder::Reader(capture_rd));
+                tees.push(TeeSetup {
+                    fd: dest_fd,
+                    source: rd,
+                    capture: capture_wr,
+                    secondary: Closing::new(secondary),
+                });
+                fd
+            }
             &Fd::ReadNull => {
                 // Need to keep fd with cloexec, until we are in child
                 let fd = result(
@@ -126,6 +315,7 @@ fn prepare_descriptors(
                         Mode::empty(),
                     ),
                 )?;
+                let fd = move_fd(fd, min_fd)?;
                 guards.push(Closing::new(fd));
                 fd
             }
@@ -139,23 +329,48 @@ fn prepare_descriptors(
                         Mode::empty(),
                     ),
                 )?;
+                let fd = move_fd(fd, min_fd)?;
                 guards.push(Closing::new(fd));
                 fd
             }
             &Fd::Inherit => dest_fd,
-            &Fd::Fd(ref x) => x.as_raw_fd(),
+            &Fd::Fd(ref x) | &Fd::Moved(ref x) => x.as_raw_fd(),
+            &Fd::Borrowed(x) => x,
+            // opened by the child itself
+            &Fd::OpenInRoot(..) => continue,
         };
         // The descriptor must not clobber the descriptors that are passed to
         // a child
         while fd != dest_fd && fds.contains_key(&fd) {
-            fd = result(Err::CreatePipe, fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(3)))?;
+            fd = result(
+                Err::CreatePipe,
+                fcntl(fd, FcntlArg::F_DUPFD_CLOEXEC(min_fd.max(3))),
+            )?;
             guards.push(Closing::new(fd));
         }
         inner.insert(dest_fd, fd);
     }
-    Ok((inner, outer, guards))
+    Ok((inner, outer, guards, tees))
 }
 
+/// The flags of `clone` set by the crate, can't be set by `raw_clone_flags`
+const MANAGED_CLONE_FLAGS: c_int = libc::CSIGNAL
+    | libc::CLONE_VM
+    | libc::CLONE_FS
+    | libc::CLONE_FILES
+    | libc::CLONE_SIGHAND
+    | libc::CLONE_PIDFD
+    | libc::CLONE_PTRACE
+    | libc::CLONE_VFORK
+    | libc::CLONE_PARENT
+    | libc::CLONE_THREAD
+    | libc::CLONE_SETTLS
+    | libc::CLONE_PARENT_SETTID
+    | libc::CLONE_CHILD_CLEARTID
+    | libc::CLONE_DETACHED
+    | libc::CLONE_UNTRACED
+    | libc::CLONE_CHILD_SETTID;
+
 impl Command {
     /// Run the command and return exit status
     pub fn status(&mut self) -> Result<ExitStatus, Error> {
@@ -164,66 +379,356 @@ impl Command {
             .wait()
             .map_err(|e| Error::WaitError(e.raw_os_error().unwrap_or(-1)))
     }
+    /// Limit the time `spawn` waits for the child to exec
+    ///
+    /// The time is counted from `clone` until `execve` succeeds in the
+    /// child, so it covers the whole setup of the child but not the runtime
+    /// of the program. If the timeout expires, the child is killed and
+    /// reaped and `Error::SpawnTimeout` is returned.
+    ///
+    /// Note: the timeout is checked only while waiting for the child, the
+    /// work done by the parent itself (`newuidmap` commands,
+    /// `before_unfreeze` callback) is not interrupted.
+    pub fn spawn_timeout(&mut self, timeout: Duration) -> &mut Command {
+        self.config.spawn_timeout = Some(timeout);
+        self
+    }
+    /// Set the size of the stack allocated for the child by `clone`
+    ///
+    /// The whole setup of the child, including the `pre_exec` callback,
+    /// runs on this stack, and overflowing it kills the child by `SIGSEGV`
+    /// before it's able to report anything. The default (16 KiB) is enough
+    /// for the setup itself, increase it if `pre_exec` needs more. Sizes
+    /// below the default are rounded up to it. With `reap_orphans_as_init`
+    /// the init keeps running on this stack, so it's at least 2 MiB then.
+    ///
+    /// This only matters for the `clone`-based path. With `set_tid` the
+    /// child is created by `clone3` and runs on a copy of the parent's
+    /// stack, so the setting is ignored.
+    pub fn child_stack_size(&mut self, bytes: usize) -> &mut Command {
+        self.config.child_stack_size = bytes.max(MIN_CHILD_STACK);
+        self
+    }
     /// Spawn the command and return a handle that can be waited for
+    ///
+    /// This method returns only after `execve` in the child succeeded (the
+    /// child reports errors through a close-on-exec pipe and the parent
+    /// reads it until it's closed). So if `Ok` is returned the program is
+    /// already running, there is no need for a separate "wait for exec"
+    /// step. Any error in the child before exec is returned from here.
     pub fn spawn(&mut self) -> Result<Child, Error> {
-        // TODO(tailhook) We need mutable self only for init_env_map. Probably
-        // we might do this internally and don't modify Command. That would
-        // be more clear and also allow to print Display command easily in
-        // error handler
-        self.init_env_map();
-        unsafe { self.spawn_inner() }
+        // TODO(tailhook) We need mutable self only for before_unfreeze
+        // callback. It would be more clear to not modify Command and also
+        // allow to print Display command easily in error handler
+        let result = unsafe { self.spawn_inner(None) };
+        // moved descriptors are closed whether spawn succeeded or not
+        self.fds.retain(|_, fd| !matches!(fd, Fd::Moved(_)));
+        result
     }
 
-    unsafe fn spawn_inner(&mut self) -> Result<Child, Error> {
-        // TODO(tailhook) add RAII for pipes
-        let (wakeup_rd, wakeup) = Pipe::new()?.split();
-        let (errpipe, errpipe_wr) = Pipe::new()?.split();
+    /// Spawn a child that runs the closure instead of executing a program
+    ///
+    /// The child is set up exactly as by `spawn` (namespaces, root, user,
+    /// descriptors, environment, `pre_exec` callback), but then the closure
+    /// is called instead of `execve`, and the child exits with the return
+    /// value of the closure as the exit code. A panic in the closure exits
+    /// the child with code 101, it's never unwound into the code of the
+    /// parent. The program and arguments of the `Command` are not used.
+    ///
+    /// As with `spawn`, errors of the setup are returned from here, and
+    /// `Ok` is returned when the closure is about to be called.
+    ///
+    /// The closure runs after the setup is done, so usual Rust code is fine
+    /// there, with the limitations below. The child exits by `_exit`, so
+    /// nothing is flushed (write to stdout with `println!` or flush it
+    /// explicitly).
+    ///
+    /// The closure runs on the stack allocated for `clone`, which is at
+    /// least 2 MiB for `spawn_fn` (like the stack of a new thread), use
+    /// `child_stack_size` for more. The stack has a guard page, so
+    /// overflowing it kills the child by `SIGSEGV`.
+    ///
+    /// # Safety
+    ///
+    /// The child is a copy of the parent taken at `clone` time with only
+    /// the calling thread, so a lock (including the one inside the memory
+    /// allocator, or of `stdout`) held by another thread at that moment is
+    /// never released in the child. So in a multithreaded parent, the
+    /// closure must avoid anything that might have been locked by other
+    /// threads, like the `pre_exec` callback.
+    pub unsafe fn spawn_fn<F: FnOnce() -> i32 + Send>(&mut self, f: F) -> Result<Child, Error> {
+        let f = Cell::new(Some(f));
+        let payload = || -> i32 {
+            let f = f.take().expect("closure is called once");
+            panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(101)
+        };
+        let result = self.spawn_inner(Some(&payload));
+        self.fds.retain(|_, fd| !matches!(fd, Fd::Moved(_)));
+        result
+    }
 
-        let c_args = raw_with_null(&self.args);
+    /// Validates the options which are checked by `spawn` (or `spawn_fn`
+    /// if `closure` is set)
+    pub(crate) fn check_options(&self, closure: bool) -> Result<(), Error> {
+        if let Some(place) = self.nul_byte {
+            return Err(place.to_error());
+        }
+        if closure && self.config.setns_namespaces.contains_key(&Namespace::Pid) {
+            return Err(Error::ConflictingOptions {
+                message: "set_namespace(Pid) can't be combined with spawn_fn, \
+                          the namespace only applies to processes forked \
+                          by the closure",
+            });
+        }
+        if self.config.make_session_leader
+            && (self.config.process_group.is_some() || self.config.foreground_tty.is_some())
+        {
+            return Err(Error::ConflictingOptions {
+                message: "make_session_leader can't be combined with \
+                          process_group or foreground_tty",
+            });
+        }
+        if self.config.reap_orphans && self.config.trace_me {
+            return Err(Error::ConflictingOptions {
+                message: "reap_orphans_as_init can't be combined with trace_me",
+            });
+        }
+        if self.config.namespaces.bits() & MANAGED_CLONE_FLAGS != 0 {
+            return Err(Error::ConflictingOptions {
+                message: "raw_clone_flags can't include flags managed by the crate",
+            });
+        }
+        if matches!(self.env_base, EnvBase::Raw(..)) && !self.environ.is_empty() {
+            return Err(Error::ConflictingOptions {
+                message: "raw_env can't be combined with env or env_remove",
+            });
+        }
+        self.fd_plan().map_err(Error::FdConflict)?;
+        self.check_mounts()?;
+        self.check_move_interfaces()?;
+        self.check_persist_namespaces()?;
+        if let Some((ref uids, ref gids)) = self.config.id_maps {
+            UidMap::validate_ranges(uids)?;
+            GidMap::validate_ranges(gids)?;
+        }
+        Ok(())
+    }
 
-        let mut environ: Vec<_> = self
-            .environ
-            .as_ref()
-            .unwrap()
-            .iter()
-            .map(|(k, v)| {
-                let mut pair = k[..].as_bytes().to_vec();
-                pair.push(b'=');
-                pair.extend(v.as_bytes());
-                pair.push(0);
-                pair
-            })
-            .collect();
-        let mut pid_env_vars = Vec::new();
-        for var_name in &self.pid_env_vars {
+    /// Builds the environment block ahead of time, so that repeated spawns
+    /// of the same command don't rebuild it
+    ///
+    /// Only the environment is cached: the environment of the parent is
+    /// read, `env_keep` and `env_filter` are applied and variables are
+    /// checked for nul bytes. The block is reused by the following spawns
+    /// until any environment setting of the command is changed (or
+    /// `prepare` is called again). So changes of the environment of the
+    /// parent process made after `prepare` are not seen by the child.
+    ///
+    /// Each spawn still copies the block to add the `ready_notification`
+    /// variable and the values of `env_var_with_metadata`, and converts the
+    /// arguments and descriptors as usual. The options are validated too,
+    /// so the errors `spawn` would return for them are reported early.
+    pub fn prepare(&mut self) -> Result<(), Error> {
+        self.check_options(false)?;
+        self.prepared_env = None;
+        self.prepared_env = Some(self.base_environ()?);
+        Ok(())
+    }
+
+    /// Builds the inherited and explicitly set part of `environ`
+    fn base_environ(&self) -> Result<Vec<Vec<u8>>, Error> {
+        if let EnvBase::Raw(ref entries) = self.env_base {
+            return Ok(entries.clone());
+        }
+        let mut environ = Vec::new();
+        for (k, v) in self.resolve_env(env::vars_os()) {
+            if self.config.ready_env.as_ref() == Some(&k) {
+                continue;
+            }
+            if k.as_bytes().contains(&0) || v.as_bytes().contains(&0) {
+                return Err(Error::NulByteInEnvironment { key: k });
+            }
+            let mut pair = k[..].as_bytes().to_vec();
+            pair.push(b'=');
+            pair.extend(v.as_bytes());
+            pair.push(0);
+            environ.push(pair);
+        }
+        Ok(environ)
+    }
+
+    /// Builds `environ` of the child, the values of `env_var_with_metadata`
+    /// which are only known in the child are filled with zeros, their
+    /// positions are returned too
+    pub(crate) fn build_environ(
+        &self,
+        ready_fd: Option<RawFd>,
+    ) -> Result<(Vec<Vec<u8>>, LateEnv), Error> {
+        let mut environ = match self.prepared_env {
+            Some(ref environ) => environ.clone(),
+            None => self.base_environ()?,
+        };
+        if let (Some(key), Some(fd)) = (&self.config.ready_env, ready_fd) {
+            if key.as_bytes().contains(&0) {
+                return Err(Error::NulByteInEnvironment { key: key.clone() });
+            }
+            let mut pair = key.as_bytes().to_vec();
+            pair.push(b'=');
+            pair.extend(fd.to_string().as_bytes());
+            pair.push(0);
+            environ.push(pair);
+        }
+        let mut metadata_env_vars = Vec::new();
+        for (var_name, value) in &self.metadata_env_vars {
+            if var_name.as_bytes().contains(&0) {
+                return Err(Error::NulByteInEnvironment {
+                    key: var_name.clone(),
+                });
+            }
             let mut pair = var_name[..].as_bytes().to_vec();
             pair.push(b'=');
             let (index, offset) = (environ.len(), pair.len());
-            pair.extend(repeat(0).take(MAX_PID_LEN + 1));
+            let late = match *value {
+                MetadataVar::Pid => LateEnvValue::Pid,
+                MetadataVar::InnerPid => LateEnvValue::InnerPid,
+                MetadataVar::HostPid => LateEnvValue::HostPid,
+                MetadataVar::NamespaceId(ns) => LateEnvValue::NamespaceId(
+                    format!("/proc/self/ns/{}", to_ns_name(ns)).to_cstring(),
+                ),
+                MetadataVar::ParentPid => {
+                    pair.extend(process::id().to_string().as_bytes());
+                    pair.push(0);
+                    environ.push(pair);
+                    continue;
+                }
+                MetadataVar::SpawnTimestamp => {
+                    let time = SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap_or_default();
+                    let value = format!("{}.{:06}", time.as_secs(), time.subsec_micros());
+                    pair.extend(value.as_bytes());
+                    pair.push(0);
+                    environ.push(pair);
+                    continue;
+                }
+            };
+            let width = match late {
+                LateEnvValue::NamespaceId(..) => MAX_INO_LEN,
+                _ => MAX_PID_LEN,
+            };
+            pair.resize(pair.len() + width + 1, 0);
             environ.push(pair);
-            pid_env_vars.push((index, offset));
+            metadata_env_vars.push((index, offset, late));
         }
+        if self.config.env_sorted {
+            let mut entries = environ.into_iter().enumerate().collect::<Vec<_>>();
+            entries.sort_by(|(_, a), (_, b)| env_name(a).cmp(env_name(b)));
+            // the late values are found by index in the child
+            let mut position = vec![0; entries.len()];
+            for (new, &(old, _)) in entries.iter().enumerate() {
+                position[old] = new;
+            }
+            environ = entries.into_iter().map(|(_, pair)| pair).collect();
+            for late in &mut metadata_env_vars {
+                late.0 = position[late.0];
+            }
+        }
+        Ok((environ, metadata_env_vars))
+    }
+
+    unsafe fn spawn_inner(&mut self, payload: Option<&dyn Fn() -> i32>) -> Result<Child, Error> {
+        let mut timer = PhaseTimer::start(self.tracer.is_some());
+        self.check_options(payload.is_some())?;
+        let filename = match payload {
+            Some(_) => self.filename.clone(),
+            None if self.config.program_fd.is_some() => self.filename.clone(),
+            None => self.resolve_program()?,
+        };
+
+        // TODO(tailhook) add RAII for pipes
+        // the child's ends are placed above the target descriptors so that
+        // they are not overwritten by `dup2` in the child
+        let ready_fd = self.ready_fd();
+        let min_fd = self
+            .fds
+            .keys()
+            .chain(ready_fd.as_ref())
+            .max()
+            .map_or(3, |&fd| (fd + 1).max(3))
+            .max(self.config.min_internal_fd.unwrap_or(0));
+        // the parent's ends are moved only if `min_internal_fd` is set
+        let parent_min_fd = self.config.min_internal_fd.unwrap_or(0);
+        let (wakeup_rd, wakeup) = Pipe::new()?.split();
+        let wakeup_rd = wakeup_rd.move_above(min_fd)?;
+        let wakeup = wakeup.move_above(parent_min_fd)?;
+        let (errpipe, errpipe_wr) = Pipe::new()?.split();
+        let errpipe = errpipe.move_above(parent_min_fd)?;
+        let errpipe_wr = errpipe_wr.move_above(min_fd)?;
+        self.reserved_fds = vec![wakeup_rd.as_raw_fd(), errpipe_wr.as_raw_fd()];
+        let progress = match self.progress {
+            Some(ref writer) => {
+                let fd = fcntl(writer.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min_fd));
+                let fd = Closing::new(result(Err::CreatePipe, fd)?);
+                self.reserved_fds.push(fd.as_raw_fd());
+                Some(fd)
+            }
+            None => None,
+        };
+        let progress_fd = progress.as_ref().map_or(-1, |fd| fd.as_raw_fd());
+        // CLOEXEC is cleared by the child right before `fexecve`
+        let program = match (payload, &self.config.program_fd) {
+            (None, Some(fd)) => {
+                let fd = fcntl(fd.as_raw_fd(), FcntlArg::F_DUPFD_CLOEXEC(min_fd));
+                Some(Closing::new(result(Err::CreatePipe, fd)?))
+            }
+            _ => None,
+        };
+        let program_fd = program.as_ref().map_or(-1, |fd| fd.as_raw_fd());
+
+        // TODO(tailhook) flatten argv and envp into one buffer and sort
+        // the descriptors once in `prepare`, so that a prepared spawn
+        // doesn't allocate before clone (`prepare` caches only environment)
+        let c_args = raw_with_null(&self.args);
+
+        let (mut environ, metadata_env_vars) = self.build_environ(ready_fd)?;
+        check_arg_max(&self.args, &environ)?;
         let c_environ: Vec<_> = raw_with_null_mut(&mut environ);
 
-        let (int_fds, ext_fds, _guards) = prepare_descriptors(&self.fds)?;
+        let (mut int_fds, ext_fds, mut guards, tees) =
+            prepare_descriptors(&self.fds, parent_min_fd)?;
+        let ready = match ready_fd {
+            Some(fd) => Some(ready::ready_pipe(
+                fd,
+                parent_min_fd,
+                &mut int_fds,
+                &mut guards,
+            )?),
+            None => None,
+        };
+        // the threads exit as soon as the write end of the pipe is closed,
+        // which also happens if spawn fails
+        let tees = tees
+            .into_iter()
+            .map(|tee| result(Err::StdioError, tee.start()))
+            .collect::<Result<HashMap<_, _>, _>>()?;
 
-        let pivot = self
-            .pivot_root
-            .as_ref()
-            .map(|&(ref new, ref old, unmnt)| Pivot {
-                new_root: new.to_cstring(),
-                put_old: old.to_cstring(),
-                old_inside: relative_to(old, new, true).unwrap().to_cstring(),
-                workdir: current_dir()
-                    .ok()
-                    .and_then(|cur| relative_to(cur, new, true))
-                    .unwrap_or(PathBuf::from("/"))
-                    .to_cstring(),
-                unmount_old_root: unmnt,
-            });
+        let supplementary_gids = self.supplementary_groups()?;
+        let pivot = self.pivot_root.as_ref().map(|(new, old)| Pivot {
+            new_root: new.to_cstring(),
+            put_old: old.as_ref().map(|old| {
+                let inside = relative_to(old, new, true).unwrap();
+                (old.to_cstring(), inside.to_cstring())
+            }),
+            workdir: current_dir()
+                .ok()
+                .and_then(|cur| relative_to(cur, new, true))
+                .unwrap_or(PathBuf::from("/"))
+                .to_cstring(),
+            unmount_old_root: self.unmount_old_root,
+        });
 
         let chroot = self.chroot_dir.as_ref().map(|dir| {
-            let wrk_rel = if let Some((ref piv, _, _)) = self.pivot_root {
+            let wrk_rel = if let Some((ref piv, _)) = self.pivot_root {
                 piv.join(relative_to(dir, "/", false).unwrap())
             } else {
                 dir.to_path_buf()
@@ -238,7 +743,13 @@ impl Command {
             }
         });
 
-        let mut nstack = [0u8; 4096];
+        // only the pages actually used are allocated by the kernel
+        let stack_size = if payload.is_some() || self.config.reap_orphans {
+            self.config.child_stack_size.max(MIN_PAYLOAD_STACK)
+        } else {
+            self.config.child_stack_size
+        };
+        let mut nstack = ChildStack::new(stack_size)?;
         let mut wakeup = Some(wakeup);
         let mut wakeup_rd = Some(wakeup_rd);
         let mut errpipe_wr = Some(errpipe_wr);
@@ -247,61 +758,131 @@ impl Command {
         // We transform all hashmaps into vectors, because iterating over
         // hash map involves closure which crashes in the child in unoptimized
         // build
-        let fds = int_fds.iter().map(|(&x, &y)| (x, y)).collect::<Vec<_>>();
-        let close_fds = self.close_fds.iter().cloned().collect::<Vec<_>>();
-        let setns_ns = self
-            .config
-            .setns_namespaces
+        let mut fds = int_fds.iter().map(|(&x, &y)| (x, y)).collect::<Vec<_>>();
+        // in the order of `fd_plan`
+        fds.sort();
+        let open_fds = self
+            .fds
+            .iter()
+            .filter_map(|(&dest, fd)| match *fd {
+                Fd::OpenInRoot(ref path, flags) => Some((dest, path.as_ptr(), flags)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let close_fds = self
+            .close_fds
             .iter()
-            .map(|(ns, fd)| (to_clone_flag(*ns), fd.as_raw_fd()))
+            .map(|&(start, end, _)| (start, end))
             .collect::<Vec<_>>();
-        let pid = result(
-            Err::Fork,
-            clone(
-                Box::new(|| -> isize {
-                    // Note: mo memory allocations/deallocations here
-                    close(wakeup.take().unwrap().into_fd());
-                    let child_info = ChildInfo {
-                        filename: self.filename.as_ptr(),
-                        args: args_slice,
-                        environ: environ_slice,
-                        cfg: &self.config,
-                        chroot: &chroot,
-                        pivot: &pivot,
-                        wakeup_pipe: wakeup_rd.take().unwrap().into_fd(),
-                        error_pipe: errpipe_wr.take().unwrap().into_fd(),
-                        fds: &fds,
-                        fd_lookup: &int_fds,
-                        close_fds: &close_fds,
-                        setns_namespaces: &setns_ns,
-                        pid_env_vars: &pid_env_vars,
-                        keep_caps: &self.keep_caps,
-                        pre_exec: &self.pre_exec,
-                    };
-                    child::child_after_clone(&child_info);
-                }),
-                &mut nstack[..],
-                self.config.namespaces,
-                Some(SIGCHLD as i32),
-            ),
-        )?;
+        let setns_ns = self
+            .setns_order()
+            .into_iter()
+            .map(|(ns, (fd, _))| (to_clone_flag(ns), fd.as_raw_fd()))
+            .collect::<Vec<_>>();
+        let trace = timer.enabled();
+        let kill_context = KillContext::new(
+            self.config.death_sig,
+            self.config.cgroup.as_ref().map(|(path, _)| path.as_path()),
+        );
+        timer.finish(&self.tracer, SpawnPhase::BuildEnv);
+        self.check_before_clone()?;
+        let pid = {
+            let mut child_fn = || -> isize {
+                // Note: mo memory allocations/deallocations here
+                close(wakeup.take().unwrap().into_fd());
+                let child_info = ChildInfo {
+                    filename: filename.as_ptr(),
+                    args: args_slice,
+                    environ: environ_slice,
+                    cfg: &self.config,
+                    chroot: &chroot,
+                    pivot: &pivot,
+                    wakeup_pipe: wakeup_rd.take().unwrap().into_fd(),
+                    error_pipe: errpipe_wr.take().unwrap().into_fd(),
+                    fds: &fds,
+                    open_fds: &open_fds,
+                    fd_lookup: &int_fds,
+                    close_fds: &close_fds,
+                    setns_namespaces: &setns_ns,
+                    metadata_env_vars: &metadata_env_vars,
+                    keep_caps: &self.keep_caps,
+                    supplementary_gids: &supplementary_gids,
+                    pre_exec: &self.pre_exec,
+                    trace,
+                    progress_fd,
+                    payload,
+                    program_fd,
+                };
+                child::child_after_clone(&child_info);
+            };
+            // the child function is not called if clone fails, so it's
+            // safe to retry
+            let mut attempt = 0;
+            loop {
+                attempt += 1;
+                let res = if let Some(ref tids) = self.config.set_tid {
+                    clone_with_tids(&mut child_fn, self.config.namespaces, tids)
+                } else {
+                    result(
+                        Err::Fork,
+                        clone(
+                            Box::new(&mut child_fn),
+                            nstack.as_mut_slice(),
+                            self.config.namespaces,
+                            Some(SIGCHLD as i32),
+                        ),
+                    )
+                };
+                match (res, self.config.retry) {
+                    (Err(Error::Fork(errno)), Some(policy)) => {
+                        match policy.next_delay(errno, attempt) {
+                            Some(delay) => thread::sleep(delay),
+                            None if attempt > 1 => {
+                                return Err(Error::ForkAttempts {
+                                    errno,
+                                    attempts: attempt,
+                                })
+                            }
+                            None => return Err(Error::Fork(errno)),
+                        }
+                    }
+                    (res, _) => break res?,
+                }
+            }
+        };
+        timer.finish(&self.tracer, SpawnPhase::Clone);
         drop(wakeup_rd);
         drop(errpipe_wr); // close pipe so we don't wait for ourself
 
-        if let Err(e) = self.after_start(pid, wakeup.unwrap(), errpipe) {
-            kill(pid, SIGKILL).ok();
-            loop {
-                match waitpid(pid, None) {
-                    Err(nix::Error::Sys(EINTR)) => continue,
-                    _ => break,
+        match self.after_start(pid, wakeup.unwrap(), errpipe, &filename, &mut timer) {
+            Ok(()) => {}
+            Err(e) => {
+                kill(pid, SIGKILL).ok();
+                loop {
+                    match waitpid(pid, None) {
+                        Err(nix::Error::Sys(EINTR)) => continue,
+                        _ => break,
+                    }
                 }
+                return Err(e);
             }
-            return Err(e);
         }
 
         let mut outer_fds = ext_fds;
+        let pgid = match self.config.process_group {
+            Some(0) => pid.as_raw(),
+            Some(pgid) => pgid,
+            None if self.config.make_group_leader || self.config.make_session_leader => {
+                pid.as_raw()
+            }
+            None => getpgrp().as_raw(),
+        };
+        if let Some(ref reaper) = self.config.auto_reap {
+            reaper.register(pid.as_raw());
+        }
         Ok(Child {
             pid: pid.into(),
+            pgid,
             status: None,
             stdin: outer_fds.remove(&0).map(|x| match x {
                 PipeHolder::Writer(x) => x,
@@ -316,6 +897,11 @@ impl Command {
                 _ => unreachable!(),
             }),
             fds: outer_fds,
+            tees,
+            output_limits: self.config.output_limits,
+            cgroup: self.config.cgroup.as_ref().map(|(path, _)| path.clone()),
+            ready: ready.map(ReadyPipe::Waiting),
+            kill_context,
         })
     }
 
@@ -324,80 +910,437 @@ impl Command {
         pid: Pid,
         mut wakeup: PipeWriter,
         mut errpipe: PipeReader,
+        filename: &CStr,
+        timer: &mut PhaseTimer,
     ) -> Result<(), Error> {
-        if self.config.make_group_leader {
+        let deadline = self.config.spawn_timeout.map(|t| Instant::now() + t);
+        // when making a session leader, child calls `setsid()` itself which
+        // would fail if it's already a group leader
+        if let Some(pgid) = self.config.process_group {
+            result(Err::SetPGid, setpgid(pid, Pid::from_raw(pgid)))?;
+        } else if self.config.make_group_leader && !self.config.make_session_leader {
             result(Err::SetPGid, setpgid(pid, pid))?;
         }
 
         if let Some(&(ref uids, ref gids)) = self.config.id_maps.as_ref() {
-            if let Some(&(ref ucmd, ref gcmd)) = self.id_map_commands.as_ref() {
-                let mut cmd = Command::new(ucmd);
-                cmd.arg(format!("{}", pid));
-                for map in uids {
-                    cmd.arg(format!("{}", map.inside_uid));
-                    cmd.arg(format!("{}", map.outside_uid));
-                    cmd.arg(format!("{}", map.count));
-                }
-                cmd_result(Err::SetIdMap, cmd.status())?;
-                let mut cmd = Command::new(gcmd);
-                cmd.arg(format!("{}", pid));
-                for map in gids {
-                    cmd.arg(format!("{}", map.inside_gid));
-                    cmd.arg(format!("{}", map.outside_gid));
-                    cmd.arg(format!("{}", map.count));
-                }
-                cmd_result(Err::SetIdMap, cmd.status())?;
-            } else {
-                let mut buf = Vec::new();
-                for map in uids {
-                    writeln!(
-                        &mut buf,
-                        "{} {} {}",
-                        map.inside_uid, map.outside_uid, map.count
-                    )
-                    .unwrap();
-                }
-                result(
-                    Err::SetIdMap,
-                    File::create(format!("/proc/{}/uid_map", pid))
-                        .and_then(|mut f| f.write_all(&buf[..])),
-                )?;
-                let mut buf = Vec::new();
-                for map in gids {
-                    writeln!(
-                        &mut buf,
-                        "{} {} {}",
-                        map.inside_gid, map.outside_gid, map.count
-                    )
-                    .unwrap();
-                }
-                result(
-                    Err::SetIdMap,
-                    File::create(format!("/proc/{}/gid_map", pid))
-                        .and_then(|mut f| f.write_all(&buf[..])),
-                )?;
-            }
+            self.write_id_maps(pid, uids, gids)
+                .map_err(|e| Error::AtStage(SetupStage::WriteIdMaps, Box::new(e)))?;
+            timer.finish(&self.tracer, SpawnPhase::WriteIdMaps);
+        }
+        netlink::move_interfaces(&self.config.move_interfaces, i32::from(pid))?;
+        persist::persist_namespaces(&self.config.persist_namespaces, i32::from(pid))?;
+        if let Some((ref path, ref limits)) = self.config.cgroup {
+            cgroup::apply_cgroup(path, limits, i32::from(pid))?;
+        }
+        if let Some(ref mut captured) = self.config.captured_namespaces {
+            *captured = spawn_many::capture_namespaces(self.config.namespaces, i32::from(pid))?;
         }
         if let Some(ref mut callback) = self.before_unfreeze {
             callback(i32::from(pid) as u32).map_err(Error::BeforeUnfreeze)?;
         }
+        self.run_unfreezer(i32::from(pid), deadline)?;
+        if self.before_unfreeze.is_some() || self.config.unfreezer.is_some() {
+            timer.finish(&self.tracer, SpawnPhase::BeforeUnfreeze);
+        }
 
-        result(Err::PipeError, wakeup.write_all(b"x"))?;
-        let mut err = [0u8; 6];
-        match result(Err::PipeError, errpipe.read(&mut err))? {
-            0 => {} // Process successfully execve'd or dead
-            5 => {
-                let code = err[0];
-                let errno = ((err[1] as i32) << 24)
-                    | ((err[2] as i32) << 16)
-                    | ((err[3] as i32) << 8)
-                    | (err[4] as i32);
-                return Err(Err::from_i32(code as i32, errno));
+        // the wakeup message also delivers the pid of the child as seen
+        // by the parent, which child can't find out itself in pid namespace
+        let pid_bytes = i32::from(pid).to_be_bytes();
+        let mut msg = [b'x', 0, 0, 0, 0];
+        msg[1..].copy_from_slice(&pid_bytes);
+        result(Err::PipeError, wakeup.write_all(&msg))?;
+        timer.finish(&self.tracer, SpawnPhase::Unfreeze);
+        // child sends 6-byte records: either error (code, stage and errno)
+        // or trace record (phase, stage and duration in microseconds)
+        let mut rec = [0u8; 6];
+        let mut outside = Vec::new();
+        let mut outside_stage = SetupStage::Exec;
+        loop {
+            let mut len = 0;
+            while len < rec.len() {
+                if let Some(deadline) = deadline {
+                    wait_readable(errpipe.as_raw_fd(), deadline)?;
+                }
+                match errpipe.read(&mut rec[len..]) {
+                    Ok(0) => break,
+                    Ok(n) => len += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e.into_error(Err::PipeError)),
+                }
+            }
+            match len {
+                0 => break, // Process successfully execve'd or dead
+                6 => {}
+                _ => return Err(Error::UnknownError),
             }
-            _ => {
-                return Err(Error::UnknownError);
+            let value = u32::from_be_bytes([rec[2], rec[3], rec[4], rec[5]]);
+            if rec[0] & TRACE_RECORD != 0 {
+                let phase =
+                    SpawnPhase::from_id(rec[0] & !TRACE_RECORD).ok_or(Error::UnknownError)?;
+                let time = Duration::from_micros(value as u64);
+                timer.report(&self.tracer, phase, time);
+                continue;
             }
+            let stage = SetupStage::from_id(rec[1]).ok_or(Error::UnknownError)?;
+            let code = rec[0] as i32;
+            if code == Err::EscapeFd as i32 {
+                // all of them are reported before the child exits
+                outside.push(value as RawFd);
+                outside_stage = stage;
+                continue;
+            }
+            let err = if code == Err::SetNs as i32 {
+                self.setns_error(value)
+            } else if code == Err::MountAt as i32 {
+                self.mount_error(value)
+            } else if code == Err::Exec as i32 || code == Err::ExecInterpreter as i32 {
+                let interpreter_missing = code == Err::ExecInterpreter as i32;
+                self.exec_error(value as i32, interpreter_missing, filename)
+            } else {
+                Err::from_i32(code, value as i32)
+            };
+            return Err(Error::AtStage(stage, Box::new(err)));
+        }
+        if !outside.is_empty() {
+            let err = Error::FdsOutsideRoot { fds: outside };
+            return Err(Error::AtStage(outside_stage, Box::new(err)));
+        }
+        timer.finish(&self.tracer, SpawnPhase::Exec);
+        if let Some(ref mut callback) = self.after_exec {
+            callback(i32::from(pid));
         }
         Ok(())
     }
+
+    /// Writes `uid_map` and `gid_map` of the child, by the helpers if set
+    fn write_id_maps(&self, pid: Pid, uids: &[UidMap], gids: &[GidMap]) -> Result<(), Error> {
+        let (ucmd, gcmd) = match self.id_map_commands {
+            Some((ref ucmd, ref gcmd)) => (ucmd, gcmd),
+            None => return idmap::write_maps(i32::from(pid), uids, gids),
+        };
+        let pid = i32::from(pid);
+        let (uid_args, gid_args) = match self.id_map_args {
+            Some((ref uargs, ref gargs)) => (uargs(pid, uids), gargs(pid, gids)),
+            None => (default_uid_args(pid, uids), default_gid_args(pid, gids)),
+        };
+        let timeout = self.config.id_map_timeout;
+        let uid_map = format!("/proc/{}/uid_map", pid);
+        idmap::run_helper(ucmd, &uid_args, &uid_map, timeout)?;
+        let gid_map = format!("/proc/{}/gid_map", pid);
+        idmap::run_helper(gcmd, &gid_args, &gid_map, timeout)
+    }
+
+    /// Namespaces set by `set_namespace` in the order they are joined
+    ///
+    /// Other namespaces may be owned by the user namespace, so it's joined
+    /// first to have capabilities there. Mount namespace is the last, like
+    /// in `nsenter`, the order of the rest doesn't matter but is fixed.
+    pub(crate) fn setns_order(&self) -> Vec<(Namespace, &NsFile)> {
+        let mut order = self
+            .config
+            .setns_namespaces
+            .iter()
+            .map(|(&ns, file)| (ns, file))
+            .collect::<Vec<_>>();
+        order.sort_by_key(|&(ns, _)| {
+            let rank = match ns {
+                Namespace::User => 0,
+                Namespace::Pid => 1,
+                Namespace::Mount => 3,
+                _ => 2,
+            };
+            (rank, to_clone_flag(ns).bits())
+        });
+        order
+    }
+
+    /// Builds the error of the `SetNs` record, which has the index in
+    /// `setns_order` in the high 16 bits and errno in the low ones
+    fn setns_error(&self, value: u32) -> Error {
+        let errno = (value & 0xffff) as i32;
+        match self.setns_order().get((value >> 16) as usize) {
+            Some(&(namespace, (_, source))) => Error::SetNs {
+                namespace,
+                source_description: source.clone(),
+                errno,
+            },
+            None => Error::UnknownError,
+        }
+    }
+
+    fn mount_error(&self, value: u32) -> Error {
+        match self.config.mounts.get((value >> 16) as usize) {
+            Some(mount) => Error::MountAt {
+                target: PathBuf::from(OsStr::from_bytes(mount.target().as_bytes())),
+                errno: (value & 0xffff) as i32,
+            },
+            None => Error::UnknownError,
+        }
+    }
+
+    /// Builds the exec error, telling which file was tried if root is changed
+    ///
+    /// This is only called on the error path, so it's fine to do some
+    /// filesystem access here.
+    fn exec_error(&self, errno: i32, interpreter_missing: bool, filename: &CStr) -> Error {
+        let root = match self.new_root() {
+            Some(_) if self.config.program_fd.is_some() => return Error::Exec(errno),
+            None => return Error::Exec(errno),
+            Some(root) => root,
+        };
+        // same as `workdir` of `Chroot` and `Pivot`
+        let mut cwd = Some(
+            current_dir()
+                .ok()
+                .and_then(|cur| relative_to(cur, &root, true))
+                .unwrap_or(PathBuf::from("/")),
+        );
+        if let Some(ref dir) = self.config.work_dir {
+            cwd = cwd.map(|cwd| cwd.join(OsStr::from_bytes(dir.as_bytes())));
+        } else if self.config.work_dir_fd.is_some() {
+            cwd = None;
+        }
+        let program = Path::new(OsStr::from_bytes(filename.to_bytes()));
+        let path = match cwd {
+            Some(cwd) if program.components().count() > 1 => {
+                cwd.join(program).components().collect()
+            }
+            _ => program.to_path_buf(),
+        };
+        let missing_interpreter = if interpreter_missing {
+            let host_path = root.join(relative_to(&path, "/", false).unwrap_or(path.clone()));
+            let mut buf = [0u8; INTERP_MAX];
+            let interp = File::open(host_path).ok().and_then(|file| {
+                let read = |off, dest: &mut [u8]| file.read_at(dest, off).unwrap_or(0);
+                find_interpreter(read, &mut buf)
+            });
+            Some(match interp {
+                Some(len) => PathBuf::from(OsStr::from_bytes(&buf[..len])),
+                None => PathBuf::new(),
+            })
+        } else {
+            None
+        };
+        Error::ExecInRoot {
+            errno,
+            root,
+            path,
+            missing_interpreter,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use crate::{Command, Error, ExitStatus, Namespace, Signal, Stdio};
+
+    #[test]
+    fn test_prepare() {
+        let env = |cmd: &mut Command| {
+            let output = cmd.stdout(Stdio::piped()).output().unwrap();
+            let mut vars = String::from_utf8(output.stdout)
+                .unwrap()
+                .lines()
+                .map(String::from)
+                .collect::<Vec<_>>();
+            vars.sort();
+            vars
+        };
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env_clear().env("A", "1").ready_notification("READY_FD");
+        let unprepared = env(&mut cmd);
+        cmd.prepare().unwrap();
+        assert_eq!(env(&mut cmd), unprepared);
+        assert_eq!(env(&mut cmd), unprepared);
+        assert_eq!(unprepared, ["A=1", "READY_FD=3"]);
+        cmd.env("B", "2");
+        assert_eq!(env(&mut cmd), ["A=1", "B=2", "READY_FD=3"]);
+
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env("BAD", "a\0b");
+        assert!(matches!(
+            cmd.prepare(),
+            Err(Error::NulByteInEnvironment { .. })
+        ));
+    }
+
+    #[test]
+    fn test_spawn_returns_after_exec() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let exe = fs::read_link(format!("/proc/{}/exe", child.pid())).unwrap();
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(exe, fs::canonicalize("/bin/sleep").unwrap());
+    }
+
+    #[test]
+    fn test_spawn_fn() {
+        let mut cmd = Command::new("payload");
+        cmd.unshare(&[Namespace::Uts]);
+        cmd.env_clear().env("GREETING", "hello");
+        cmd.stdout(Stdio::piped());
+        let mut child = unsafe {
+            cmd.spawn_fn(|| {
+                nix::unistd::sethostname("payload").unwrap();
+                let name = nix::unistd::gethostname(&mut [0u8; 64])
+                    .unwrap()
+                    .to_str()
+                    .unwrap()
+                    .to_string();
+                let greeting = std::env::var("GREETING").unwrap();
+                writeln!(std::io::stdout(), "{} {}", greeting, name).unwrap();
+                7
+            })
+        }
+        .unwrap();
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(7));
+        assert_eq!(output, "hello payload\n");
+    }
+
+    #[test]
+    fn test_spawn_fn_panic() {
+        let mut cmd = Command::new("payload");
+        cmd.stderr(Stdio::null());
+        let mut child = unsafe { cmd.spawn_fn(|| panic!("in the child")) }.unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(101));
+    }
+
+    #[test]
+    fn test_spawn_fn_stack_overflow() {
+        fn recurse(depth: u64) -> u64 {
+            let buf = std::hint::black_box([depth as u8; 4096]);
+            if depth == u64::MAX {
+                return 0;
+            }
+            recurse(depth + 1) + buf[100] as u64
+        }
+        let mut cmd = Command::new("payload");
+        cmd.stderr(Stdio::null());
+        let mut child = unsafe { cmd.spawn_fn(|| recurse(0) as i32) }.unwrap();
+        assert!(matches!(
+            child.wait().unwrap(),
+            ExitStatus::Signaled(Signal::SIGSEGV, _)
+        ));
+    }
+
+    #[test]
+    fn test_spawn_fn_setup_error() {
+        let mut cmd = Command::new("payload");
+        cmd.current_dir("/nonexistent");
+        match unsafe { cmd.spawn_fn(|| 0) }.map_err(Error::into_inner) {
+            Err(Error::Chdir(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_child_stack_size() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.child_stack_size(1 << 20);
+        unsafe {
+            cmd.pre_exec(|| {
+                let buf = [1u8; 256 << 10];
+                std::hint::black_box(&buf);
+                Ok(())
+            })
+        };
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_spawn_timeout() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.spawn_timeout(Duration::from_millis(100));
+        unsafe {
+            cmd.pre_exec(|| {
+                thread::sleep(Duration::from_secs(10));
+                Ok(())
+            })
+        };
+        let start = Instant::now();
+        match cmd.spawn() {
+            Err(Error::SpawnTimeout) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(start.elapsed() < Duration::from_secs(5));
+        // the timeout doesn't apply to the program itself
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("0.3").spawn_timeout(Duration::from_millis(100));
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_trace_me() {
+        use nix::sys::ptrace;
+        use nix::sys::signal::Signal;
+        use nix::sys::wait::{waitpid, WaitStatus};
+        use nix::unistd::Pid;
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.trace_me();
+        let mut child = cmd.spawn().unwrap();
+        let pid = Pid::from(child.pid());
+        assert_eq!(
+            waitpid(pid, None).unwrap(),
+            WaitStatus::Stopped(pid, Signal::SIGTRAP)
+        );
+        // stopped past `execve`
+        let exe = fs::read_link(format!("/proc/{}/exe", child.pid())).unwrap();
+        assert_eq!(exe, fs::canonicalize("/bin/sh").unwrap());
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+    }
+
+    #[test]
+    fn test_wait_traced() {
+        use nix::sys::ptrace::{self, Options};
+        use nix::sys::signal::Signal;
+        use nix::unistd::Pid;
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.trace_me();
+        let mut child = cmd.spawn().unwrap();
+        let pid = Pid::from(child.pid());
+        let status = child.wait().unwrap();
+        assert_eq!(status, ExitStatus::Stopped(Signal::SIGTRAP));
+        assert!(!status.terminated());
+        ptrace::setoptions(pid, Options::PTRACE_O_TRACEEXIT).unwrap();
+        ptrace::cont(pid, None).unwrap();
+        let status = child.wait_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(
+            status,
+            Some(ExitStatus::PtraceEvent(
+                Signal::SIGTRAP,
+                libc::PTRACE_EVENT_EXIT
+            ))
+        );
+        assert!(!child.is_reaped());
+        ptrace::cont(pid, None).unwrap();
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+    }
+
+    #[test]
+    fn test_spawn_reports_error_before_exec() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.current_dir("/nonexistent");
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Chdir(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
 }
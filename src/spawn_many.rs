@@ -0,0 +1,442 @@
+use std::error::Error as StdError;
+use std::ffi::{OsStr, OsString};
+use std::fmt;
+use std::fs::File;
+use std::mem;
+use std::os::unix::io::IntoRawFd;
+
+use libc::pid_t;
+use nix::sched::CloneFlags;
+
+use crate::config::NsFile;
+use crate::namespace::{to_clone_flag, to_ns_name};
+use crate::stdio::Closing;
+use crate::{Child, Command, Error, Namespace, Stdio};
+
+/// Namespaces which siblings join, pid namespace can't be joined by the
+/// process itself, so every sibling gets its own one
+const SHARED: &[Namespace] = &[
+    Namespace::User,
+    Namespace::Mount,
+    Namespace::Uts,
+    Namespace::Ipc,
+    Namespace::Net,
+    Namespace::Cgroup,
+];
+
+/// Settings of a single child of `Command::spawn_many`
+///
+/// They are applied on top of the command for this child only.
+#[derive(Default)]
+pub struct PerChildOverrides {
+    args: Vec<OsString>,
+    env: Vec<(OsString, OsString)>,
+    stdin: Option<Stdio>,
+    stdout: Option<Stdio>,
+    stderr: Option<Stdio>,
+}
+
+impl PerChildOverrides {
+    /// Add an argument after the arguments of the command
+    pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut PerChildOverrides {
+        self.args.push(arg.as_ref().to_os_string());
+        self
+    }
+
+    /// Add multiple arguments after the arguments of the command
+    pub fn args<I, S>(&mut self, args: I) -> &mut PerChildOverrides
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
+        }
+        self
+    }
+
+    /// Set an environment variable
+    pub fn env<K, V>(&mut self, key: K, val: V) -> &mut PerChildOverrides
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        self.env
+            .push((key.as_ref().to_os_string(), val.as_ref().to_os_string()));
+        self
+    }
+
+    /// Configure stdin of the child
+    pub fn stdin(&mut self, cfg: Stdio) -> &mut PerChildOverrides {
+        self.stdin = Some(cfg);
+        self
+    }
+
+    /// Configure stdout of the child
+    pub fn stdout(&mut self, cfg: Stdio) -> &mut PerChildOverrides {
+        self.stdout = Some(cfg);
+        self
+    }
+
+    /// Configure stderr of the child
+    pub fn stderr(&mut self, cfg: Stdio) -> &mut PerChildOverrides {
+        self.stderr = Some(cfg);
+        self
+    }
+}
+
+/// The error of `Command::spawn_many`
+#[derive(Debug)]
+pub struct SpawnManyError {
+    /// The index of the child which failed to spawn
+    pub index: usize,
+    /// The error of spawning the child
+    pub error: Error,
+    /// The children spawned before the failure, empty unless
+    /// `keep_siblings_on_failure` is set
+    pub spawned: Vec<Child>,
+}
+
+impl fmt::Display for SpawnManyError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "error spawning child {}: {}", self.index, self.error)
+    }
+}
+
+impl StdError for SpawnManyError {}
+
+/// The settings of the command which only the first child applies
+struct SharedSetup {
+    namespaces: CloneFlags,
+    mounts: Vec<crate::mount::Mount>,
+    id_maps: Option<(Vec<crate::UidMap>, Vec<crate::GidMap>)>,
+    move_interfaces: Vec<String>,
+    persist_namespaces: Vec<(Namespace, std::path::PathBuf)>,
+    /// The `setns` settings overridden by the namespaces of the first child
+    replaced: Vec<(Namespace, NsFile)>,
+}
+
+/// Opens namespaces of the child `pid` which the next children would join
+pub(crate) fn capture_namespaces(
+    namespaces: CloneFlags,
+    pid: pid_t,
+) -> Result<Vec<(Namespace, NsFile)>, Error> {
+    let mut fds = Vec::new();
+    for &ns in SHARED {
+        if namespaces.contains(to_clone_flag(ns)) {
+            let path = format!("/proc/{}/ns/{}", pid, to_ns_name(ns));
+            let file = File::open(&path).map_err(|e| Error::SetNs {
+                namespace: ns,
+                source_description: path.clone(),
+                errno: e.raw_os_error().unwrap_or(libc::ENOENT),
+            })?;
+            fds.push((ns, (Closing::new(file.into_raw_fd()), path)));
+        }
+    }
+    Ok(fds)
+}
+
+impl Command {
+    /// Keep the already spawned children if `spawn_many` fails
+    ///
+    /// By default they are killed and waited for. When set, they are
+    /// returned in `SpawnManyError::spawned`.
+    pub fn keep_siblings_on_failure(&mut self, keep: bool) -> &mut Command {
+        self.config.keep_siblings = keep;
+        self
+    }
+
+    /// Spawn `n` children sharing the namespaces and their setup
+    ///
+    /// The first child is spawned as usual: it creates the namespaces,
+    /// mounts filesystems, gets id maps written and interfaces moved. The
+    /// others join its namespaces with `setns` and skip all of that, which
+    /// makes spawning them much cheaper. The pid namespace can't be joined
+    /// this way, so each child gets a new one if `Namespace::Pid` is
+    /// unshared.
+    ///
+    /// `per_child` is called with the index of each child to set the
+    /// arguments (appended to the arguments of the command), environment
+    /// and stdio for this child only.
+    ///
+    /// The namespaces are opened while the first child is not yet
+    /// unfrozen, so they stay alive until all the children are spawned,
+    /// even if the first child exits early.
+    ///
+    /// Returns `SpawnManyError` with the index of the child which failed.
+    /// Children already spawned are killed unless
+    /// `keep_siblings_on_failure` is set.
+    ///
+    /// `pivot_root` and `set_tid` can't be used, as they can't be repeated
+    /// inside of the shared namespaces.
+    pub fn spawn_many<F>(&mut self, n: usize, per_child: F) -> Result<Vec<Child>, SpawnManyError>
+    where
+        F: Fn(usize, &mut PerChildOverrides),
+    {
+        let fail = |index, error| SpawnManyError {
+            index,
+            error,
+            spawned: Vec::new(),
+        };
+        if self.pivot_root.is_some() || self.config.set_tid.is_some() {
+            return Err(fail(
+                0,
+                Error::ConflictingOptions {
+                    message: "spawn_many can't be combined with pivot_root or set_tid",
+                },
+            ));
+        }
+        let mut children = Vec::with_capacity(n);
+        let mut shared = None;
+        let mut result = Ok(());
+        self.config.captured_namespaces = Some(Vec::new());
+        for index in 0..n {
+            if index == 1 {
+                shared = Some(self.join_namespaces());
+            }
+            let mut overrides = PerChildOverrides::default();
+            per_child(index, &mut overrides);
+            match self.spawn_with(overrides) {
+                Ok(child) => children.push(child),
+                Err(e) => {
+                    result = Err(fail(index, e));
+                    break;
+                }
+            }
+        }
+        self.config.captured_namespaces = None;
+        if let Some(setup) = shared {
+            self.restore_shared(setup);
+        }
+        match result {
+            Ok(()) => Ok(children),
+            Err(mut e) if self.config.keep_siblings => {
+                e.spawned = children;
+                Err(e)
+            }
+            Err(e) => {
+                for mut child in children {
+                    child.kill().ok();
+                    child.wait().ok();
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Makes the next children join the namespaces captured from the first
+    fn join_namespaces(&mut self) -> SharedSetup {
+        let joined = self.config.captured_namespaces.take().unwrap_or_default();
+        let pid_ns = self.config.namespaces & to_clone_flag(Namespace::Pid);
+        let mut setup = SharedSetup {
+            namespaces: mem::replace(&mut self.config.namespaces, pid_ns),
+            mounts: mem::take(&mut self.config.mounts),
+            id_maps: self.config.id_maps.take(),
+            move_interfaces: mem::take(&mut self.config.move_interfaces),
+            persist_namespaces: mem::take(&mut self.config.persist_namespaces),
+            replaced: Vec::new(),
+        };
+        for (ns, fd) in joined {
+            if let Some(old) = self.config.setns_namespaces.insert(ns, fd) {
+                setup.replaced.push((ns, old));
+            }
+        }
+        setup
+    }
+
+    fn restore_shared(&mut self, setup: SharedSetup) {
+        for &ns in SHARED {
+            if setup.namespaces.contains(to_clone_flag(ns)) {
+                self.config.setns_namespaces.remove(&ns);
+            }
+        }
+        self.config.setns_namespaces.extend(setup.replaced);
+        self.config.namespaces = setup.namespaces;
+        self.config.mounts = setup.mounts;
+        self.config.id_maps = setup.id_maps;
+        self.config.move_interfaces = setup.move_interfaces;
+        self.config.persist_namespaces = setup.persist_namespaces;
+    }
+
+    /// Spawns with the overrides applied, the command is restored after
+    fn spawn_with(&mut self, overrides: PerChildOverrides) -> Result<Child, Error> {
+        let args = self.args.clone();
+        let nul_byte = self.nul_byte;
+        let environ = self.environ.clone();
+        let mut stdio = Vec::new();
+        for (fd, cfg) in [
+            (0, overrides.stdin),
+            (1, overrides.stdout),
+            (2, overrides.stderr),
+        ] {
+            if let Some(cfg) = cfg {
+                stdio.push((fd, self.fds.remove(&fd)));
+                self.fds.insert(fd, cfg.to_fd(fd != 0));
+            }
+        }
+        self.args(overrides.args);
+        let env_changed = !overrides.env.is_empty();
+        for (key, val) in overrides.env {
+            self.env(key, val);
+        }
+        let result = self.spawn();
+        self.args = args;
+        self.nul_byte = nul_byte;
+        self.environ = environ;
+        if env_changed {
+            self.prepared_env = None;
+        }
+        for (fd, cfg) in stdio {
+            match cfg {
+                Some(cfg) => self.fds.insert(fd, cfg),
+                None => self.fds.remove(&fd),
+            };
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::io::Read;
+    use std::time::Instant;
+
+    use crate::{Command, Error, Namespace, Stdio};
+
+    fn ns_inode(pid: i32, ns: &str) -> String {
+        let link = fs::read_link(format!("/proc/{}/ns/{}", pid, ns)).unwrap();
+        link.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn test_shared_namespaces() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.unshare(&[Namespace::Mount, Namespace::Net, Namespace::Pid]);
+        cmd.mount_sysfs("/sys", true);
+        let mut children = cmd
+            .spawn_many(3, |idx, child| {
+                child.arg(format!("{}", 10 + idx));
+            })
+            .unwrap();
+        let first = children[0].pid().as_raw();
+        for (idx, child) in children.iter().enumerate().skip(1) {
+            let pid = child.pid().as_raw();
+            assert_eq!(ns_inode(pid, "mnt"), ns_inode(first, "mnt"));
+            assert_eq!(ns_inode(pid, "net"), ns_inode(first, "net"));
+            assert_ne!(ns_inode(pid, "pid"), ns_inode(first, "pid"));
+            let cmdline = fs::read_to_string(format!("/proc/{}/cmdline", pid)).unwrap();
+            assert_eq!(cmdline, format!("/bin/sleep\0{}\0", 10 + idx));
+        }
+        assert_ne!(
+            ns_inode(first, "net"),
+            ns_inode(std::process::id() as i32, "net")
+        );
+        let cmdline = fs::read_to_string(format!("/proc/{}/cmdline", first)).unwrap();
+        assert_eq!(cmdline, "/bin/sleep\x0010\0");
+        for child in &mut children {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+        // the command is not changed
+        assert!(cmd
+            .config
+            .namespaces
+            .contains(nix::sched::CloneFlags::CLONE_NEWNET));
+        assert_eq!(cmd.config.mounts.len(), 1);
+        assert!(cmd.config.setns_namespaces.is_empty());
+        assert_eq!(cmd.args.len(), 1);
+    }
+
+    #[test]
+    fn test_per_child_stdio() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("echo $IDX");
+        cmd.unshare(&[Namespace::Uts]);
+        let mut children = cmd
+            .spawn_many(2, |idx, child| {
+                child.env("IDX", idx.to_string());
+                child.stdout(Stdio::piped());
+            })
+            .unwrap();
+        for (idx, child) in children.iter_mut().enumerate() {
+            let mut output = String::new();
+            child
+                .stdout
+                .take()
+                .unwrap()
+                .read_to_string(&mut output)
+                .unwrap();
+            assert!(child.wait().unwrap().success());
+            assert_eq!(output, format!("{}\n", idx));
+        }
+        assert!(cmd.environ.is_empty());
+    }
+
+    #[test]
+    fn test_partial_failure() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10");
+        let result = cmd.spawn_many(3, |idx, child| {
+            if idx == 2 {
+                child.arg("bad\0arg");
+            }
+        });
+        match result {
+            Err(e) => {
+                assert_eq!(e.index, 2);
+                assert!(matches!(e.error, Error::NulByteInArgument { index: 2 }));
+                assert!(e.spawned.is_empty());
+            }
+            Ok(_) => panic!("spawn_many should fail"),
+        }
+
+        cmd.keep_siblings_on_failure(true);
+        let result = cmd.spawn_many(3, |idx, child| {
+            if idx == 2 {
+                child.arg("bad\0arg");
+            }
+        });
+        let mut e = result.err().unwrap();
+        assert_eq!(e.index, 2);
+        assert_eq!(e.spawned.len(), 2);
+        for child in &mut e.spawned {
+            child.kill().unwrap();
+            child.wait().unwrap();
+        }
+        // the argument of the override is forgotten
+        assert_eq!(cmd.args.len(), 2);
+    }
+
+    /// Compares the time of spawning workers one by one and
+    /// with `spawn_many`, run with `--ignored --nocapture`
+    #[test]
+    #[ignore]
+    fn bench_spawn_many() {
+        use std::io::Write;
+
+        let workers = 20;
+        let mut cmd = Command::new("/bin/true");
+        cmd.unshare(&[Namespace::Mount, Namespace::Net, Namespace::Ipc]);
+        cmd.mount_sysfs("/sys", true);
+        let start = Instant::now();
+        for _ in 0..workers {
+            cmd.status().unwrap();
+        }
+        let separate = start.elapsed();
+        let start = Instant::now();
+        let children = cmd.spawn_many(workers, |_, _| {}).unwrap();
+        let shared = start.elapsed();
+        for mut child in children {
+            child.wait().unwrap();
+        }
+        writeln!(
+            std::io::stdout(),
+            "{} workers: separately {:?}, spawn_many {:?}",
+            workers,
+            separate,
+            shared
+        )
+        .unwrap();
+    }
+}
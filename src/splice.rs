@@ -0,0 +1,372 @@
+use std::fs::File;
+use std::os::unix::io::{AsFd, AsRawFd, RawFd};
+use std::ptr;
+
+use libc::c_void;
+
+use crate::{Error, PipeReader, PipeWriter};
+
+/// Buffer size for the `read`/`write` fallback and chunk size for
+/// `copy_to_file`
+const CHUNK: usize = 65536;
+
+/// Moves up to `len` bytes from `src` to `dst` where one of them is a pipe
+///
+/// Returns zero on end of file. Falls back to `read` and `write` if `splice`
+/// isn't supported for the descriptor (i.e. file opened with `O_APPEND` or
+/// a filesystem without splice support). If any of the descriptors is
+/// non-blocking, waits until it is ready.
+fn splice(src: RawFd, dst: RawFd, len: usize) -> Result<usize, Error> {
+    loop {
+        let rc = unsafe {
+            libc::splice(
+                src,
+                ptr::null_mut(),
+                dst,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE,
+            )
+        };
+        if rc >= 0 {
+            return Ok(rc as usize);
+        }
+        match nix::errno::errno() {
+            libc::EINTR => {}
+            libc::EAGAIN => wait_both(src, dst)?,
+            libc::EINVAL => return copy(src, dst, len),
+            errno => return Err(Error::Splice(errno)),
+        }
+    }
+}
+
+/// Waits until `src` is readable and `dst` is writable, as `splice` doesn't
+/// tell which one returned `EAGAIN`
+///
+/// A descriptor which is ready isn't polled any more, so a file or a closed
+/// pipe (which are always ready) don't make us spin.
+fn wait_both(src: RawFd, dst: RawFd) -> Result<(), Error> {
+    let mut pollfds = [
+        libc::pollfd {
+            fd: src,
+            events: libc::POLLIN,
+            revents: 0,
+        },
+        libc::pollfd {
+            fd: dst,
+            events: libc::POLLOUT,
+            revents: 0,
+        },
+    ];
+    while pollfds.iter().any(|pollfd| pollfd.fd >= 0) {
+        let rc = unsafe { libc::poll(pollfds.as_mut_ptr(), 2, -1) };
+        if rc < 0 {
+            match nix::errno::errno() {
+                libc::EINTR => continue,
+                errno => return Err(Error::Splice(errno)),
+            }
+        }
+        for pollfd in &mut pollfds {
+            if pollfd.revents != 0 {
+                // negative descriptors are ignored by `poll`
+                pollfd.fd = -1;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Waits until `fd` is ready for `events`
+fn wait_ready(fd: RawFd, events: libc::c_short) -> Result<(), Error> {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events,
+        revents: 0,
+    };
+    loop {
+        let rc = unsafe { libc::poll(&mut pollfd, 1, -1) };
+        if rc >= 0 {
+            return Ok(());
+        }
+        match nix::errno::errno() {
+            libc::EINTR => {}
+            errno => return Err(Error::Splice(errno)),
+        }
+    }
+}
+
+/// The fallback of `splice`, all the data read is written before returning
+fn copy(src: RawFd, dst: RawFd, len: usize) -> Result<usize, Error> {
+    let mut buf = [0u8; CHUNK];
+    let len = len.min(CHUNK);
+    let n = loop {
+        let rc = unsafe { libc::read(src, buf.as_mut_ptr() as *mut c_void, len) };
+        if rc >= 0 {
+            break rc as usize;
+        }
+        match nix::errno::errno() {
+            libc::EINTR => {}
+            libc::EAGAIN => wait_ready(src, libc::POLLIN)?,
+            errno => return Err(Error::Splice(errno)),
+        }
+    };
+    let mut data = &buf[..n];
+    while !data.is_empty() {
+        let rc = unsafe { libc::write(dst, data.as_ptr() as *const c_void, data.len()) };
+        if rc >= 0 {
+            data = &data[rc as usize..];
+            continue;
+        }
+        match nix::errno::errno() {
+            libc::EINTR => {}
+            libc::EAGAIN => wait_ready(dst, libc::POLLOUT)?,
+            errno => return Err(Error::Splice(errno)),
+        }
+    }
+    Ok(n)
+}
+
+impl PipeReader {
+    /// Move up to `len` bytes from the pipe to `dst` without copying them
+    /// to userspace
+    ///
+    /// Returns the number of bytes moved, which may be less than `len`
+    /// even if more data is available later, and zero if the pipe is closed
+    /// by the writer. Uses `splice(2)`, and falls back to `read`/`write` if
+    /// `dst` doesn't support it. Data read by the fallback is always
+    /// written completely.
+    pub fn splice_to(&mut self, dst: &impl AsFd, len: usize) -> Result<usize, Error> {
+        splice(self.as_raw_fd(), dst.as_fd().as_raw_fd(), len)
+    }
+
+    /// Move all of the data from the pipe to the file until the pipe is
+    /// closed by the writer
+    ///
+    /// Returns the total number of bytes written, data is written at the
+    /// current position of the file. See `splice_to` for details.
+    pub fn copy_to_file(&mut self, file: &mut File) -> Result<u64, Error> {
+        let mut total = 0;
+        loop {
+            match self.splice_to(file, CHUNK)? {
+                0 => return Ok(total),
+                n => total += n as u64,
+            }
+        }
+    }
+}
+
+impl PipeWriter {
+    /// Move up to `len` bytes from `src` to the pipe without copying them
+    /// to userspace
+    ///
+    /// Returns the number of bytes moved, zero at the end of `src`. Same
+    /// as `PipeReader::splice_to` but in the other direction.
+    pub fn splice_from(&mut self, src: &impl AsFd, len: usize) -> Result<usize, Error> {
+        splice(src.as_fd().as_raw_fd(), self.as_raw_fd(), len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{ErrorKind, Read, Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    use super::{copy, splice};
+    use crate::pipe::Pipe;
+    use crate::{Command, Stdio};
+
+    fn temp_file(name: &str) -> (PathBuf, File) {
+        let path = std::env::temp_dir().join(format!("unshare-{}-{}", name, std::process::id()));
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)
+            .unwrap();
+        (path, file)
+    }
+
+    fn expected_seq() -> String {
+        (1..=100000).map(|i| format!("{}\n", i)).collect()
+    }
+
+    #[test]
+    fn test_copy_to_file() {
+        let (path, mut file) = temp_file("splice-copy");
+        let mut child = Command::new("/usr/bin/seq")
+            .args(&["1", "100000"])
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let copied = child
+            .stdout
+            .take()
+            .unwrap()
+            .copy_to_file(&mut file)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let expected = expected_seq();
+        assert_eq!(copied, expected.len() as u64);
+        assert!(data == expected);
+    }
+
+    #[test]
+    fn test_partial() {
+        let (path, mut file) = temp_file("splice-partial");
+        let (mut rd, mut wr) = Pipe::new().unwrap().split();
+        wr.write_all(b"hello world").unwrap();
+        assert_eq!(rd.splice_to(&file, 5).unwrap(), 5);
+        drop(wr);
+        assert_eq!(rd.splice_to(&file, 1024).unwrap(), 6);
+        assert_eq!(rd.splice_to(&file, 1024).unwrap(), 0);
+        let mut data = String::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_string(&mut data).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data, "hello world");
+    }
+
+    #[test]
+    fn test_append_fallback() {
+        // splice to `O_APPEND` files fails with `EINVAL`
+        let (path, file) = temp_file("splice-append");
+        drop(file);
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(b"start\n").unwrap();
+        let (mut rd, mut wr) = Pipe::new().unwrap().split();
+        wr.write_all(b"appended\n").unwrap();
+        drop(wr);
+        assert_eq!(rd.copy_to_file(&mut file).unwrap(), 9);
+        let data = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(data, "start\nappended\n");
+    }
+
+    #[test]
+    fn test_splice_from() {
+        let (path, mut file) = temp_file("splice-from");
+        file.write_all(expected_seq().as_bytes()).unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut child = Command::new("/usr/bin/wc")
+            .arg("-l")
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let mut stdin = child.stdin.take().unwrap();
+        while stdin.splice_from(&file, 4096).unwrap() > 0 {}
+        drop(stdin);
+        let mut output = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut output)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(output.trim(), "100000");
+    }
+
+    #[test]
+    fn test_nonblocking_full_destination() {
+        // the source stays open but idle, so waiting for it would hang
+        let (src_rd, mut src_wr) = Pipe::new().unwrap().split();
+        let (mut dst_rd, mut dst_wr) = Pipe::new().unwrap().split();
+        let fd = dst_wr.as_raw_fd();
+        assert_eq!(
+            unsafe { libc::fcntl(fd, libc::F_SETFL, libc::O_NONBLOCK) },
+            0
+        );
+        let mut filled = 0;
+        loop {
+            match dst_wr.write(&[0; 4096]) {
+                Ok(n) => filled += n,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+                Err(e) => panic!("write error {:?}", e),
+            }
+        }
+        let (src, dst) = (src_rd.as_raw_fd(), dst_wr.as_raw_fd());
+        let (tx, rx) = mpsc::channel();
+        let (refilled_tx, refilled) = mpsc::channel();
+        thread::spawn(move || {
+            src_wr.write_all(b"fallback").unwrap();
+            tx.send(copy(src, dst, 1024)).unwrap();
+            refilled.recv().unwrap();
+            src_wr.write_all(b"spliced").unwrap();
+            tx.send(splice(src, dst, 1024)).unwrap();
+            // keeps the writer open until the test is finished
+            tx.send(Ok(0)).unwrap();
+        });
+        thread::sleep(Duration::from_millis(100));
+        let mut buf = vec![0; filled];
+        dst_rd.read_exact(&mut buf).unwrap();
+        let timeout = Duration::from_secs(5);
+        assert_eq!(rx.recv_timeout(timeout).unwrap().unwrap(), 8);
+        dst_rd.read_exact(&mut buf[..8]).unwrap();
+        assert_eq!(&buf[..8], b"fallback");
+        // the destination is full again for the splice
+        let mut blocked = dst_wr.write(&vec![0; filled]).unwrap();
+        while let Ok(n) = dst_wr.write(&[0; 4096]) {
+            blocked += n;
+        }
+        refilled_tx.send(()).unwrap();
+        thread::sleep(Duration::from_millis(100));
+        let mut buf = vec![0; blocked];
+        dst_rd.read_exact(&mut buf).unwrap();
+        assert_eq!(rx.recv_timeout(timeout).unwrap().unwrap(), 7);
+        dst_rd.read_exact(&mut buf[..7]).unwrap();
+        assert_eq!(&buf[..7], b"spliced");
+        rx.recv_timeout(timeout).unwrap().unwrap();
+    }
+
+    #[test]
+    #[ignore]
+    fn throughput() {
+        // run with `--ignored --nocapture` to see the numbers
+        let size = "256M";
+        let spawn = || {
+            Command::new("/usr/bin/head")
+                .args(&["-c", size, "/dev/zero"])
+                .stdout(Stdio::piped())
+                .spawn()
+                .unwrap()
+        };
+        let (path, mut file) = temp_file("splice-speed");
+        let mut child = spawn();
+        let start = Instant::now();
+        let mut stdout = child.stdout.take().unwrap();
+        let mut buf = [0u8; 65536];
+        loop {
+            match stdout.read(&mut buf).unwrap() {
+                0 => break,
+                n => file.write_all(&buf[..n]).unwrap(),
+            }
+        }
+        let naive = start.elapsed();
+        child.wait().unwrap();
+
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut child = spawn();
+        let start = Instant::now();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .copy_to_file(&mut file)
+            .unwrap();
+        let spliced = start.elapsed();
+        child.wait().unwrap();
+        fs::remove_file(&path).unwrap();
+        println!("read/write: {:?}, splice: {:?}", naive, spliced);
+        assert!(spliced < naive * 2);
+    }
+}
@@ -1,35 +1,161 @@
 use crate::Signal;
+use std::convert::TryFrom;
 use std::fmt;
+use std::os::unix::process::ExitStatusExt;
+use std::process;
 
 /// The exit status of a process
 ///
 /// Returned either by `reap_zombies()` or by `child_events()`
 /// or by `Child::wait()`
+///
+/// More kinds of statuses may be added in future, so matches on it should
+/// have a wildcard arm.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
 pub enum ExitStatus {
     /// Process exited normally with some exit code
     Exited(i8),
     /// Process was killed by a signal (bool flag is true when core is dumped)
     Signaled(Signal, /* dore dumped */ bool),
+    /// Process was killed by a signal which is unknown to `Signal` type,
+    /// (i.e. realtime signals), the number is the signal number
+    SignaledRaw(i32, /* core dumped */ bool),
+    /// Process was stopped by a signal
+    ///
+    /// Only returned by `Child::wait_untraced()` and `from_raw()`, and by
+    /// the other wait methods for a traced child (see `Command::trace_me`)
+    Stopped(Signal),
+    /// Process was stopped by a signal which is unknown to `Signal` type,
+    /// the number is the signal number
+    ///
+    /// Only happens to a traced child, when a realtime signal is delivered
+    StoppedRaw(i32),
+    /// Traced process was stopped for a ptrace event, the number is the
+    /// `PTRACE_EVENT_*` value
+    ///
+    /// Only reported if the tracer enabled events with `PTRACE_SETOPTIONS`
+    PtraceEvent(Signal, i32),
+    /// Process was resumed by `SIGCONT`
+    ///
+    /// Only returned by `Child::wait_untraced()` and `from_raw()`
+    Continued,
 }
 
 impl ExitStatus {
+    /// Converts the raw status as returned by `waitpid` system call
+    ///
+    /// Returns `None` if status has unknown format.
+    pub fn from_raw(status: i32) -> Option<ExitStatus> {
+        use libc::{WCOREDUMP, WEXITSTATUS, WSTOPSIG, WTERMSIG};
+        use libc::{WIFCONTINUED, WIFEXITED, WIFSIGNALED, WIFSTOPPED};
+        if WIFEXITED(status) {
+            Some(ExitStatus::Exited(WEXITSTATUS(status) as i8))
+        } else if WIFSIGNALED(status) {
+            let core = WCOREDUMP(status);
+            Some(match Signal::try_from(WTERMSIG(status)) {
+                Ok(sig) => ExitStatus::Signaled(sig, core),
+                Err(_) => ExitStatus::SignaledRaw(WTERMSIG(status), core),
+            })
+        } else if WIFSTOPPED(status) {
+            let event = status >> 16;
+            Some(match Signal::try_from(WSTOPSIG(status)) {
+                Ok(sig) if event != 0 => ExitStatus::PtraceEvent(sig, event),
+                Ok(sig) => ExitStatus::Stopped(sig),
+                Err(_) => ExitStatus::StoppedRaw(WSTOPSIG(status)),
+            })
+        } else if WIFCONTINUED(status) {
+            Some(ExitStatus::Continued)
+        } else {
+            None
+        }
+    }
+    /// Converts status to the raw format as returned by `waitpid`
+    pub fn into_raw(self) -> i32 {
+        match self {
+            ExitStatus::Exited(code) => (code as u8 as i32) << 8,
+            ExitStatus::Signaled(sig, core) => sig as i32 | if core { 0x80 } else { 0 },
+            ExitStatus::SignaledRaw(sig, core) => sig | if core { 0x80 } else { 0 },
+            ExitStatus::Stopped(sig) => (sig as i32) << 8 | 0x7f,
+            ExitStatus::StoppedRaw(sig) => sig << 8 | 0x7f,
+            ExitStatus::PtraceEvent(sig, event) => event << 16 | (sig as i32) << 8 | 0x7f,
+            ExitStatus::Continued => 0xffff,
+        }
+    }
+    /// Returns `true` if the process has exited or was killed
+    ///
+    /// It's `false` for the stops and continues, the wait methods of
+    /// `Child` return them without remembering, so the process can be
+    /// waited for again.
+    pub fn terminated(&self) -> bool {
+        matches!(
+            self,
+            &ExitStatus::Exited(..) | &ExitStatus::Signaled(..) | &ExitStatus::SignaledRaw(..)
+        )
+    }
     /// Returns `true` if this exit status means successful exit
     pub fn success(&self) -> bool {
         self == &ExitStatus::Exited(0)
     }
     /// Returns exit code if the process has exited normally
+    ///
+    /// The code is signed like in `Exited`, so codes above 127 are returned
+    /// as negative numbers (unlike stdlib, which returns `0..=255`).
     pub fn code(&self) -> Option<i32> {
         match self {
             &ExitStatus::Exited(e) => Some(e as i32),
-            &ExitStatus::Signaled(_, _) => None,
+            _ => None,
         }
     }
     /// Returns signal number if he process was killed by signal
     pub fn signal(&self) -> Option<i32> {
         match self {
-            &ExitStatus::Exited(_) => None,
             &ExitStatus::Signaled(sig, _) => Some(sig as i32),
+            &ExitStatus::SignaledRaw(sig, _) => Some(sig),
+            _ => None,
+        }
+    }
+    /// Returns `true` if the process was killed by signal and dumped core
+    pub fn core_dumped(&self) -> bool {
+        matches!(
+            self,
+            &ExitStatus::Signaled(_, true) | &ExitStatus::SignaledRaw(_, true)
+        )
+    }
+    /// Returns signal if the process was stopped by signal (including
+    /// the stops for ptrace events)
+    pub fn stopped_signal(&self) -> Option<Signal> {
+        match *self {
+            ExitStatus::Stopped(sig) => Some(sig),
+            ExitStatus::PtraceEvent(sig, _) => Some(sig),
+            _ => None,
+        }
+    }
+    /// Returns the `PTRACE_EVENT_*` value if the process was stopped for
+    /// a ptrace event
+    pub fn ptrace_event(&self) -> Option<i32> {
+        match self {
+            &ExitStatus::PtraceEvent(_, event) => Some(event),
+            _ => None,
+        }
+    }
+    /// Returns `true` if the process was resumed by `SIGCONT`
+    pub fn continued(&self) -> bool {
+        self == &ExitStatus::Continued
+    }
+    /// Returns the status as `$?` is set by bash
+    ///
+    /// This is the exit code if the process has exited normally and
+    /// `128 + signal` if it was killed (no matter if core is dumped).
+    ///
+    /// Returns `None` for stops and continues, like `code()`, because the
+    /// process hasn't finished yet.
+    pub fn exit_code_like_shell(&self) -> Option<u8> {
+        match *self {
+            ExitStatus::Exited(code) => Some(code as u8),
+            ExitStatus::Signaled(sig, _) => Some(128u8.wrapping_add(sig as u8)),
+            ExitStatus::SignaledRaw(sig, _) => Some(128u8.wrapping_add(sig as u8)),
+            _ => None,
         }
     }
 }
@@ -49,6 +175,222 @@ impl fmt::Display for ExitStatus {
                     sig, sig as i32
                 )
             }
+            &SignaledRaw(sig, false) => write!(fmt, "killed by signal {}", sig),
+            &SignaledRaw(sig, true) => {
+                write!(fmt, "killed by signal {} (core dumped)", sig)
+            }
+            &Stopped(sig) => {
+                write!(fmt, "stopped by signal {:?}[{}]", sig, sig as i32)
+            }
+            &StoppedRaw(sig) => write!(fmt, "stopped by signal {}", sig),
+            &PtraceEvent(sig, event) => write!(
+                fmt,
+                "stopped by signal {:?}[{}] for ptrace event {}",
+                sig, sig as i32, event
+            ),
+            &Continued => write!(fmt, "continued"),
+        }
+    }
+}
+
+impl From<process::ExitStatus> for ExitStatus {
+    /// Converts the status from stdlib
+    ///
+    /// # Panics
+    ///
+    /// If the status has unknown format, which is never returned by
+    /// stdlib, but can be constructed with `ExitStatusExt::from_raw`.
+    fn from(status: process::ExitStatus) -> ExitStatus {
+        ExitStatus::from_raw(status.into_raw()).expect("unsupported wait status")
+    }
+}
+
+impl From<ExitStatus> for process::ExitStatus {
+    fn from(status: ExitStatus) -> process::ExitStatus {
+        process::ExitStatus::from_raw(status.into_raw())
+    }
+}
+
+impl From<ExitStatus> for process::ExitCode {
+    /// Converts the status with `ExitStatus::exit_code_like_shell`
+    ///
+    /// So `main` can exit the same way as the child did, like shell
+    /// scripts do. Stops and continues are converted to `ExitCode::FAILURE`.
+    fn from(status: ExitStatus) -> process::ExitCode {
+        status
+            .exit_code_like_shell()
+            .map_or(process::ExitCode::FAILURE, process::ExitCode::from)
+    }
+}
+
+impl PartialEq<process::ExitStatus> for ExitStatus {
+    fn eq(&self, other: &process::ExitStatus) -> bool {
+        self.into_raw() == other.into_raw()
+    }
+}
+
+impl PartialEq<ExitStatus> for process::ExitStatus {
+    fn eq(&self, other: &ExitStatus) -> bool {
+        self.into_raw() == other.into_raw()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process;
+
+    use super::ExitStatus;
+    use crate::Signal;
+
+    #[test]
+    fn test_exited() {
+        let status = ExitStatus::from_raw(0x0100).unwrap();
+        assert_eq!(status, ExitStatus::Exited(1));
+        assert_eq!(status.code(), Some(1));
+        assert!(!status.success());
+        assert!(ExitStatus::from_raw(0).unwrap().success());
+    }
+
+    #[test]
+    fn test_signaled() {
+        let status = ExitStatus::from_raw(Signal::SIGSEGV as i32 | 0x80).unwrap();
+        assert_eq!(status, ExitStatus::Signaled(Signal::SIGSEGV, true));
+        assert!(status.core_dumped());
+        assert_eq!(status.signal(), Some(Signal::SIGSEGV as i32));
+        assert_eq!(status.code(), None);
+        let status = ExitStatus::from_raw(Signal::SIGTERM as i32).unwrap();
+        assert!(!status.core_dumped());
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn test_stopped() {
+        let status = ExitStatus::from_raw((Signal::SIGTSTP as i32) << 8 | 0x7f).unwrap();
+        assert_eq!(status.stopped_signal(), Some(Signal::SIGTSTP));
+        assert_eq!(status.signal(), None);
+        assert!(!status.continued());
+        assert!(!status.success());
+    }
+
+    #[test]
+    fn test_ptrace_stops() {
+        let raw = libc::PTRACE_EVENT_EXEC << 16 | (Signal::SIGTRAP as i32) << 8 | 0x7f;
+        let status = ExitStatus::from_raw(raw).unwrap();
+        assert_eq!(
+            status,
+            ExitStatus::PtraceEvent(Signal::SIGTRAP, libc::PTRACE_EVENT_EXEC)
+        );
+        assert_eq!(status.stopped_signal(), Some(Signal::SIGTRAP));
+        assert_eq!(status.ptrace_event(), Some(libc::PTRACE_EVENT_EXEC));
+        assert_eq!(status.into_raw(), raw);
+        assert!(!status.terminated());
+
+        let raw = libc::SIGRTMIN() << 8 | 0x7f;
+        let status = ExitStatus::from_raw(raw).unwrap();
+        assert_eq!(status, ExitStatus::StoppedRaw(libc::SIGRTMIN()));
+        assert_eq!(status.into_raw(), raw);
+        assert_eq!(
+            status.to_string(),
+            format!("stopped by signal {}", libc::SIGRTMIN())
+        );
+        assert!(!status.terminated());
+        assert!(ExitStatus::Exited(0).terminated());
+        assert!(ExitStatus::SignaledRaw(libc::SIGRTMIN(), false).terminated());
+    }
+
+    #[test]
+    fn test_continued() {
+        let status = ExitStatus::from_raw(0xffff).unwrap();
+        assert!(status.continued());
+        assert_eq!(status.stopped_signal(), None);
+        assert_eq!(status.code(), None);
+    }
+
+    #[test]
+    fn test_exit_code_round_trip() {
+        for code in 0..=255 {
+            let std = process::ExitStatus::from_raw(code << 8);
+            let status = ExitStatus::from(std);
+            assert_eq!(status.code().map(|c| c as u8 as i32), std.code());
+            assert_eq!(status.into_raw(), code << 8);
+            assert_eq!(process::ExitStatus::from(status), std);
+            assert!(status == std && std == status);
+        }
+    }
+
+    #[test]
+    fn test_signal_round_trip() {
+        let signals = [
+            Signal::SIGHUP as i32,
+            Signal::SIGINT as i32,
+            Signal::SIGKILL as i32,
+            Signal::SIGSEGV as i32,
+            Signal::SIGTERM as i32,
+            libc::SIGRTMIN(),
+            libc::SIGRTMAX(),
+        ];
+        for &sig in &signals {
+            for &core in &[0, 0x80] {
+                let std = process::ExitStatus::from_raw(sig | core);
+                let status = ExitStatus::from(std);
+                assert_eq!(status.signal(), Some(sig));
+                assert_eq!(status.signal(), std.signal());
+                assert_eq!(status.core_dumped(), core != 0);
+                assert_eq!(status.into_raw(), sig | core);
+                assert!(status == std);
+            }
         }
+        assert_eq!(
+            ExitStatus::from_raw(libc::SIGRTMIN()),
+            Some(ExitStatus::SignaledRaw(libc::SIGRTMIN(), false))
+        );
+    }
+
+    #[test]
+    fn test_shell_code() {
+        assert_eq!(ExitStatus::Exited(0).exit_code_like_shell(), Some(0));
+        assert_eq!(ExitStatus::Exited(-1).exit_code_like_shell(), Some(255));
+        assert_eq!(
+            ExitStatus::Signaled(Signal::SIGTERM, false).exit_code_like_shell(),
+            Some(143)
+        );
+        assert_eq!(
+            ExitStatus::Signaled(Signal::SIGSEGV, true).exit_code_like_shell(),
+            Some(139)
+        );
+        assert_eq!(
+            ExitStatus::SignaledRaw(libc::SIGRTMIN(), false).exit_code_like_shell(),
+            Some((128 + libc::SIGRTMIN()) as u8)
+        );
+        assert_eq!(
+            ExitStatus::Stopped(Signal::SIGTSTP).exit_code_like_shell(),
+            None
+        );
+        assert_eq!(ExitStatus::Continued.exit_code_like_shell(), None);
+        assert_eq!(ExitStatus::Continued.code(), None);
+        assert_eq!(
+            process::ExitCode::from(ExitStatus::Exited(3)),
+            process::ExitCode::from(3)
+        );
+        assert_eq!(
+            process::ExitCode::from(ExitStatus::Continued),
+            process::ExitCode::FAILURE
+        );
+    }
+
+    #[test]
+    fn test_shell_code_matches_sh() {
+        let script = "kill -TERM $$";
+        let status = process::Command::new("/bin/sh")
+            .args(&["-c", &format!("/bin/sh -c '{}'; exit $?", script)])
+            .stderr(process::Stdio::null())
+            .status()
+            .unwrap();
+        let inner = crate::Command::new("/bin/sh")
+            .args(&["-c", script])
+            .status()
+            .unwrap();
+        assert_eq!(inner.exit_code_like_shell().map(i32::from), status.code());
     }
 }
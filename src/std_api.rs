@@ -5,15 +5,20 @@
 // file at the top-level directory of this distribution and at
 // http://rust-lang.org/COPYRIGHT.
 //
-use std::collections::HashMap;
-use std::collections::HashSet;
 use std::default::Default;
-use std::env;
-use std::ffi::OsStr;
-use std::path::Path;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::OwnedFd;
+use std::path::{Path, PathBuf};
 
-use crate::ffi_util::ToCString;
-use crate::{Command, Fd, Stdio};
+use nix::unistd::{getgrouplist, Group, User};
+
+use crate::environ::{EnvBase, VarMap};
+use crate::ffi_util::{NulByte, ToCString};
+use crate::run::relative_to;
+use crate::{Command, Error, Fd, Stdio};
 use libc::{gid_t, uid_t};
 
 impl Command {
@@ -28,58 +33,150 @@ impl Command {
     /// Builder methods are provided to change these defaults and
     /// otherwise configure the process.
     pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
-        Command {
-            filename: program.to_cstring(),
-            args: vec![program.to_cstring()],
-            environ: None,
+        let mut cmd = Command {
+            filename: CString::default(),
+            args: Vec::new(),
+            nul_byte: None,
+            env_base: EnvBase::Inherit,
+            env_filters: Vec::new(),
+            environ: VarMap::new(),
             config: Default::default(),
             chroot_dir: None,
             pivot_root: None,
+            unmount_old_root: false,
             fds: vec![(0, Fd::inherit()), (1, Fd::inherit()), (2, Fd::inherit())]
                 .into_iter()
                 .collect(),
             close_fds: Vec::new(),
             id_map_commands: None,
-            pid_env_vars: HashSet::new(),
+            id_map_args: None,
+            metadata_env_vars: VarMap::new(),
             keep_caps: None,
             before_unfreeze: None,
             pre_exec: None,
-        }
+            after_exec: None,
+            tracer: None,
+            reserved_fds: Vec::new(),
+            progress: None,
+            prepared_env: None,
+        };
+        cmd.filename = cmd.checked_cstring(program.as_ref(), NulByte::Program);
+        cmd.arg(program);
+        cmd
+    }
+
+    /// Constructs a new `Command` without the opinionated defaults of
+    /// `Command::new`
+    ///
+    /// This is for the applications which manage the lifetime and the
+    /// signal state of the children themselves. It differs from `new` only
+    /// in these:
+    ///
+    /// * No parent death signal (`new` sets `SIGKILL`, see
+    ///   `set_parent_death_signal`)
+    /// * The signal mask and the ignored signals are inherited from the
+    ///   parent (`new` resets them, see `keep_sigmask`)
+    ///
+    /// Everything else is the same, including the stdio and the
+    /// environment which are inherited by both.
+    pub fn without_defaults<S: AsRef<OsStr>>(program: S) -> Command {
+        let mut cmd = Command::new(program);
+        cmd.config.death_sig = None;
+        cmd.config.restore_sigmask = false;
+        cmd
+    }
+
+    /// Constructs a new `Command` from the whole argument vector, like
+    /// `execv` takes it: `argv[0]` is both the program and the argument
+    /// zero, the rest are the arguments
+    ///
+    /// This is convenient to re-execute a parsed command line. If the
+    /// argument zero is not the path of the program (i.e. it was found in
+    /// `PATH`), use `from_argv_with_program` instead.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `argv` is empty.
+    pub fn from_argv(argv: Vec<OsString>) -> Command {
+        let mut argv = argv.into_iter();
+        let program = argv.next().expect("argv must not be empty");
+        let mut cmd = Command::new(program);
+        cmd.args(argv);
+        cmd
+    }
+
+    /// Same as `from_argv`, but runs `program` and keeps `argv[0]` only as
+    /// the argument zero (see `arg0`)
+    ///
+    /// # Panics
+    ///
+    /// Panics if `argv` is empty.
+    pub fn from_argv_with_program<S: AsRef<OsStr>>(program: S, argv: Vec<OsString>) -> Command {
+        let mut argv = argv.into_iter();
+        let arg0 = argv.next().expect("argv must not be empty");
+        let mut cmd = Command::new(program);
+        cmd.arg0(arg0).args(argv);
+        cmd
+    }
+
+    /// Converts the value to C string. If the value contains nul byte
+    /// the error is recorded to be returned by `spawn()` and a placeholder
+    /// is returned instead (this is how stdlib works too).
+    pub(crate) fn checked_cstring(&mut self, value: &OsStr, place: NulByte) -> CString {
+        CString::new(value.as_bytes()).unwrap_or_else(|_| {
+            if self.nul_byte.is_none() {
+                self.nul_byte = Some(place);
+            }
+            CString::new("<string-with-nul>").unwrap()
+        })
     }
 
     /// Add an argument to pass to the program.
+    ///
+    /// If argument contains a nul byte, `spawn()` returns
+    /// `Error::NulByteInArgument`.
     pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
-        self.args.push(arg.to_cstring());
+        let arg = self.checked_cstring(arg.as_ref(), NulByte::Argument(self.args.len()));
+        self.args.push(arg);
         self
     }
 
     /// Add multiple arguments to pass to the program.
-    pub fn args<S: AsRef<OsStr>>(&mut self, args: &[S]) -> &mut Command {
-        self.args.extend(args.iter().map(ToCString::to_cstring));
-        self
-    }
-
-    // TODO(tailhook) It's only public for our run module any better way?
-    // TODO(tailhook) make it private
-    #[doc(hidden)]
-    pub fn init_env_map(&mut self) {
-        if self.environ.is_none() {
-            self.environ = Some(env::vars_os().collect());
+    ///
+    /// Accepts anything iterable (slices, vectors, arrays, iterator
+    /// adapters), arguments are appended in the iteration order. Nul bytes
+    /// are reported the same way as by `arg`.
+    pub fn args<I, S>(&mut self, args: I) -> &mut Command
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<OsStr>,
+    {
+        for arg in args {
+            self.arg(arg);
         }
+        self
     }
 
     /// Inserts or updates an environment variable mapping.
+    ///
+    /// Unless `env_clear` is called, the variable is layered over the
+    /// environment inherited from the parent, which is read when process is
+    /// spawned (i.e. there is no need to copy `std::env::vars_os()` to
+    /// just override a few variables).
+    ///
+    /// The variables are passed after the inherited ones, in the order they
+    /// were first set, see `env_sorted` for details.
     pub fn env<K, V>(&mut self, key: K, val: V) -> &mut Command
     where
         K: AsRef<OsStr>,
         V: AsRef<OsStr>,
     {
-        self.init_env_map();
-        self.environ
-            .as_mut()
-            .unwrap()
-            .insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
-        self.pid_env_vars.remove(key.as_ref());
+        self.environ.insert(
+            key.as_ref().to_os_string(),
+            Some(val.as_ref().to_os_string()),
+        );
+        self.metadata_env_vars.remove(key.as_ref());
+        self.prepared_env = None;
         self
     }
 
@@ -91,28 +188,28 @@ impl Command {
         V: AsRef<OsStr>,
     {
         for (ref key, ref val) in vars {
-            self.init_env_map();
-            self.environ
-                .as_mut()
-                .unwrap()
-                .insert(key.as_ref().to_os_string(), val.as_ref().to_os_string());
-            self.pid_env_vars.remove(key.as_ref());
+            self.env(key, val);
         }
         self
     }
 
     /// Removes an environment variable mapping.
     pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
-        self.init_env_map();
-        self.environ.as_mut().unwrap().remove(key.as_ref());
-        self.pid_env_vars.remove(key.as_ref());
+        self.environ.insert(key.as_ref().to_os_string(), None);
+        self.metadata_env_vars.remove(key.as_ref());
+        self.prepared_env = None;
         self
     }
 
     /// Clears the entire environment map for the child process.
+    ///
+    /// This also discards variables set with `env` and `env_keep` filters
+    /// configured before this call.
     pub fn env_clear(&mut self) -> &mut Command {
-        self.environ = Some(HashMap::new());
-        self.pid_env_vars = HashSet::new();
+        self.env_base = EnvBase::Clear;
+        self.environ = VarMap::new();
+        self.metadata_env_vars = VarMap::new();
+        self.prepared_env = None;
         self
     }
 
@@ -137,6 +234,44 @@ impl Command {
     /// not no-op if using chroot/pivot_root.
     pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
         self.config.work_dir = Some(dir.as_ref().to_cstring());
+        self.config.work_dir_fd = None;
+        self
+    }
+
+    /// Sets the working directory for the child process to the directory
+    /// referred by the file descriptor
+    ///
+    /// The child calls `fchdir` instead of `chdir`, so the directory may be
+    /// renamed (or even be unreachable by path) between configuring the
+    /// command and spawning it. Descriptors opened with `O_PATH` are fine.
+    ///
+    /// This is applied at the same point as `current_dir`, i.e. after
+    /// `chroot` and `pivot_root`, unless `current_dir_fd_before_root` is
+    /// set. Note that the descriptor keeps on referring to the directory it
+    /// was opened for, so with `chroot_dir` or `pivot_root` it must be
+    /// opened inside the new root, otherwise spawn fails with
+    /// `Error::ChangeRoot(EXDEV)`.
+    ///
+    /// The descriptor is never inherited by the child: it's closed right
+    /// after `fchdir`, whether it has `CLOEXEC` flag or not. It's also not
+    /// affected by `close_fds`. Overrides `current_dir` and vice versa.
+    pub fn current_dir_fd(&mut self, fd: OwnedFd) -> &mut Command {
+        self.config.work_dir_fd = Some(fd);
+        self.config.work_dir = None;
+        self
+    }
+
+    /// Call `fchdir` for `current_dir_fd` before changing the root
+    ///
+    /// By default (`false`) it's done after `chroot` and `pivot_root`, and
+    /// after the user is changed, like `chdir` for `current_dir`. When
+    /// enabled, it's done right before the root is changed, with the
+    /// privileges of the parent, and the root change keeps the working
+    /// directory instead of moving it to `/`. The directory must be inside
+    /// the new root in this case, otherwise spawn fails with
+    /// `Error::ChangeRoot(EXDEV)`.
+    pub fn current_dir_fd_before_root(&mut self, before: bool) -> &mut Command {
+        self.config.work_dir_fd_before_root = before;
         self
     }
 
@@ -176,6 +311,438 @@ impl Command {
     /// or if you also set up user namespace
     pub fn groups(&mut self, ids: Vec<gid_t>) -> &mut Command {
         self.config.supplementary_gids = Some(ids);
+        self.config.init_groups_user = None;
+        self
+    }
+
+    /// Set supplementary groups by name
+    ///
+    /// Names are resolved using the group database of the *current* (parent)
+    /// process right away, i.e. before any namespace or root directory
+    /// change is applied to the child. Resulting group ids replace ones set
+    /// by `groups()`.
+    ///
+    /// Returns an error containing the group name if any of the groups is
+    /// not found, and `InvalidInput` if it contains a nul byte.
+    pub fn groups_by_name(&mut self, names: &[&str]) -> io::Result<&mut Command> {
+        let mut ids = Vec::with_capacity(names.len());
+        for name in names {
+            if name.contains('\0') {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("group name {:?} contains a nul byte", name),
+                ));
+            }
+            match Group::from_name(name) {
+                Ok(Some(group)) => ids.push(group.gid.as_raw()),
+                Ok(None) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("unknown group {:?}", name),
+                    ));
+                }
+                Err(nix::Error::Sys(errno)) => return Err(errno.into()),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+            }
+        }
+        self.config.supplementary_gids = Some(ids);
+        self.config.init_groups_user = None;
+        Ok(self)
+    }
+
+    /// Set user id and primary group id of the new process by user name
+    ///
+    /// Works the same way as calling `uid()` and `gid()` with the values
+    /// from the user database. When `with_supplementary` is true,
+    /// supplementary groups are also set to all the groups the user is a
+    /// member of (like `initgroups` does), otherwise they are cleared (like
+    /// `groups(vec![])`), so the child never keeps the groups of the parent.
+    /// Either way this replaces groups set by `groups()`.
+    ///
+    /// Similarly to `groups_by_name` the name is resolved in the *current*
+    /// (parent) process right away, so the host's user database is used even
+    /// if the child enters a user namespace or changes root directory.
+    ///
+    /// Returns an error containing the user name if user is not found, and
+    /// `InvalidInput` if the name contains a nul byte.
+    pub fn user(&mut self, name: &str, with_supplementary: bool) -> io::Result<&mut Command> {
+        let c_name = CString::new(name).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("user name {:?} contains a nul byte", name),
+            )
+        })?;
+        let user = match User::from_name(name) {
+            Ok(Some(user)) => user,
+            Ok(None) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("unknown user {:?}", name),
+                ));
+            }
+            Err(nix::Error::Sys(errno)) => return Err(errno.into()),
+            Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+        };
+        let groups = if with_supplementary {
+            match getgrouplist(&c_name, user.gid) {
+                Ok(groups) => groups.iter().map(|g| g.as_raw()).collect(),
+                Err(nix::Error::Sys(errno)) => return Err(errno.into()),
+                Err(e) => return Err(io::Error::new(io::ErrorKind::InvalidInput, e)),
+            }
+        } else {
+            Vec::new()
+        };
+        self.config.supplementary_gids = Some(groups);
+        self.config.init_groups_user = None;
+        self.config.uid = Some(user.uid.as_raw());
+        self.config.gid = Some(user.gid.as_raw());
+        Ok(self)
+    }
+
+    /// Set supplementary groups of the user from the group database of the
+    /// new root
+    ///
+    /// The result is the same as of `initgroups(user, gid)` called in the
+    /// child after `chroot_dir`/`pivot_root`: the groups listing the user
+    /// as a member in `/etc/group` of the new root are set, along with the
+    /// primary group, which is the one set by `gid` or otherwise the group
+    /// of the user in `/etc/passwd` of the new root. This is what is needed
+    /// when the child enters a user namespace with its own root, where the
+    /// host's database (used by `user` and `groups_by_name`) doesn't apply.
+    ///
+    /// The files are read by `spawn` in the parent (through the host path of
+    /// the new root), because `initgroups` can't run in the child: it
+    /// allocates and may load NSS modules. So only the `files` database is
+    /// used, and the files are read before the child changes root. Then
+    /// the groups are set with the other credentials: after changing root,
+    /// after `setgid` and before `setuid`. A missing file is the same as
+    /// an empty one, an unknown user gets only the primary group (if `gid`
+    /// is set). Other errors of reading the files are returned from `spawn`
+    /// as `Error::SetUser`.
+    ///
+    /// This replaces groups set by `groups()` and vice versa.
+    pub fn init_supplementary_groups(&mut self, user: String) -> &mut Command {
+        self.config.init_groups_user = Some(user);
+        self.config.supplementary_gids = None;
         self
     }
+
+    /// The groups set by the child, read from the new root for
+    /// `init_supplementary_groups`
+    pub(crate) fn supplementary_groups(&self) -> Result<Option<Vec<gid_t>>, Error> {
+        let user = match self.config.init_groups_user {
+            Some(ref user) => user,
+            None => return Ok(self.config.supplementary_gids.clone()),
+        };
+        // `chroot_dir` is inside of the `pivot_root`, if both are set
+        let root = match (&self.pivot_root, &self.chroot_dir) {
+            (Some((new, _)), Some(dir)) => new.join(relative_to(dir, "/", false).unwrap()),
+            (Some((new, _)), None) => new.clone(),
+            (None, Some(dir)) => dir.clone(),
+            (None, None) => PathBuf::from("/"),
+        };
+        let primary = match self.config.gid {
+            Some(gid) => Some(gid),
+            None => read_user_db(&root.join("etc/passwd"))?
+                .lines()
+                .map(|line| line.split(':').collect::<Vec<_>>())
+                .find(|fields| fields.len() > 3 && fields[0] == user)
+                .and_then(|fields| fields[3].parse().ok()),
+        };
+        let mut groups = primary.into_iter().collect::<Vec<gid_t>>();
+        for line in read_user_db(&root.join("etc/group"))?.lines() {
+            let fields = line.split(':').collect::<Vec<_>>();
+            if fields.len() < 4 || !fields[3].split(',').any(|member| member == user) {
+                continue;
+            }
+            match fields[2].parse() {
+                Ok(gid) if !groups.contains(&gid) => groups.push(gid),
+                _ => {}
+            }
+        }
+        Ok(Some(groups))
+    }
+}
+
+/// Reads `/etc/passwd` or `/etc/group`, missing file is empty
+fn read_user_db(path: &Path) -> Result<String, Error> {
+    match fs::read(path) {
+        Ok(data) => Ok(String::from_utf8_lossy(&data).into_owned()),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(String::new()),
+        Err(e) => Err(Error::SetUser(e.raw_os_error().unwrap_or(libc::EIO))),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::io::{self, Read};
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::{FromRawFd, OwnedFd};
+
+    use nix::fcntl::{open, OFlag};
+    use nix::sys::stat::Mode;
+
+    use crate::test_util::inner_test;
+    use crate::{Command, Error, Stdio};
+
+    #[test]
+    fn test_env_layered_over_parent() {
+        let path = std::env::var("PATH").unwrap();
+        let mut cmd = Command::new("/usr/bin/env");
+        cmd.env("UNSHARE_TEST_OVERRIDE", "1");
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let lines = out.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&"UNSHARE_TEST_OVERRIDE=1"));
+        assert!(lines.contains(&&format!("PATH={}", path)[..]));
+    }
+
+    #[test]
+    fn test_current_dir_fd_renamed() {
+        let base = temp_dir().join(format!("unshare-cwd-fd-{}", std::process::id()));
+        let old = base.join("old");
+        let new = base.join("new");
+        fs::create_dir_all(&old).unwrap();
+        let fd = open(
+            &old,
+            OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+            Mode::empty(),
+        )
+        .unwrap();
+        fs::rename(&old, &new).unwrap();
+
+        let mut cmd = Command::new("/bin/pwd");
+        cmd.current_dir_fd(unsafe { OwnedFd::from_raw_fd(fd) });
+        cmd.stdout(Stdio::piped());
+        let mut child = cmd.spawn().unwrap();
+        let mut out = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut out)
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+        let expected = new.canonicalize().unwrap();
+        fs::remove_dir_all(&base).unwrap();
+        assert_eq!(out.trim_end(), expected.to_str().unwrap());
+    }
+
+    #[test]
+    fn test_current_dir_fd_not_inherited() {
+        // without `O_CLOEXEC`
+        let fd = open("/", OFlag::O_PATH | OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", &format!("[ ! -e /proc/self/fd/{} ]", fd)]);
+        cmd.current_dir_fd(unsafe { OwnedFd::from_raw_fd(fd) });
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    fn test_current_dir_fd_before_root() {
+        let dir = temp_dir().join(format!("unshare-cwd-early-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o700)).unwrap();
+        let spawn = |before| {
+            let fd = open(
+                &dir,
+                OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+                Mode::empty(),
+            )
+            .unwrap();
+            let mut cmd = Command::new("/bin/true");
+            cmd.current_dir_fd(unsafe { OwnedFd::from_raw_fd(fd) });
+            cmd.current_dir_fd_before_root(before).uid(65534);
+            cmd.status()
+        };
+        // `nobody` can't enter the directory itself
+        match spawn(false).map_err(Error::into_inner) {
+            Err(Error::Chdir(libc::EACCES)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        assert!(spawn(true).unwrap().success());
+        fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nul_in_argument() {
+        let mut cmd = Command::new("/bin/echo");
+        cmd.arg("ok").arg("bad\0arg");
+        match cmd.spawn() {
+            Err(Error::NulByteInArgument { index: 2 }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        let mut cmd = Command::new("/bin/echo");
+        cmd.arg0("echo\0");
+        match cmd.spawn() {
+            Err(Error::NulByteInArgument { index: 0 }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_nul_in_user() {
+        let mut cmd = Command::new("/bin/true");
+        let err = cmd.user("root\0", true).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = cmd.groups_by_name(&["root\0"]).map(|_| ()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn test_user_groups() {
+        // the parent needs supplementary groups to check they aren't kept
+        let mut cmd = inner_test(
+            "std_api::test::user_groups_inner",
+            "UNSHARE_TEST_USER_GROUPS",
+        );
+        cmd.groups(vec![4321]);
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    #[ignore]
+    fn user_groups_inner() {
+        if std::env::var_os("UNSHARE_TEST_USER_GROUPS").is_none() {
+            return;
+        }
+        let groups = |cmd: &mut Command| {
+            let output = cmd.output().unwrap();
+            assert!(output.status.success());
+            let status = String::from_utf8(output.stdout).unwrap();
+            let line = status.trim().strip_prefix("Groups:").unwrap().to_string();
+            line.split_whitespace()
+                .map(|g| g.parse().unwrap())
+                .collect::<Vec<u32>>()
+        };
+        let mut cmd = Command::new("/bin/grep");
+        cmd.args(&["^Groups:", "/proc/self/status"]);
+        assert_eq!(groups(&mut cmd), [4321]);
+        cmd.user("daemon", false).unwrap();
+        assert_eq!(groups(&mut cmd), []);
+        cmd.user("daemon", true).unwrap();
+        assert_eq!(groups(&mut cmd), [1]);
+    }
+
+    #[test]
+    fn test_from_argv() {
+        let argv = vec!["/bin/echo".into(), "a".into(), "b c".into()];
+        let mut cmd = Command::from_argv(argv);
+        let output = cmd.stdout(Stdio::piped()).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "a b c\n");
+        assert_eq!(format!("{:?}", cmd), r#"<Command "/bin/echo" "a" "b c">"#);
+    }
+
+    #[test]
+    #[should_panic(expected = "argv must not be empty")]
+    fn test_from_argv_empty() {
+        Command::from_argv(Vec::new());
+    }
+
+    #[test]
+    fn test_from_argv_with_program() {
+        let argv = vec!["fake-sh".into(), "-c".into(), "echo $0".into()];
+        let mut cmd = Command::from_argv_with_program("/bin/sh", argv);
+        let output = cmd.stdout(Stdio::piped()).output().unwrap();
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "fake-sh\n");
+    }
+
+    #[test]
+    fn test_args_iter() {
+        let mut cmd = Command::new("/bin/echo");
+        cmd.args(["a", "b"]);
+        cmd.args(vec![String::from("c")]);
+        cmd.args((1..3).map(|x| format!("n{}", x)));
+        cmd.args(&["bad\0arg"]);
+        let args = cmd.args.iter().map(|x| x.to_str().unwrap());
+        assert_eq!(
+            args.collect::<Vec<_>>(),
+            ["/bin/echo", "a", "b", "c", "n1", "n2", "<string-with-nul>"]
+        );
+        match cmd.spawn() {
+            Err(Error::NulByteInArgument { index: 6 }) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_nul_in_program() {
+        match Command::new("/bin/echo\0").spawn() {
+            Err(Error::NulByteInProgram) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_nul_in_environ() {
+        let mut cmd = Command::new("/bin/true");
+        cmd.env("GOOD", "1").env("BAD", "x\0y");
+        match cmd.spawn() {
+            Err(Error::NulByteInEnvironment { ref key }) if key == "BAD" => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_arg_max() {
+        let mut cmd = Command::new("/bin/true");
+        let arg = "x".repeat(100_000);
+        for _ in 0..400 {
+            cmd.arg(&arg);
+        }
+        match cmd.spawn() {
+            Err(Error::ArgumentListTooLong { bytes, limit }) => assert!(bytes > limit),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_init_supplementary_groups() {
+        let root = temp_dir().join(format!("unshare-initgroups-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/passwd"), "app:x:1000:500::/:/bin/sh\n").unwrap();
+        fs::write(
+            root.join("etc/group"),
+            "wheel:x:10:root,app\naudio:x:63:app\nother:x:70:bob\napp:x:500:\n",
+        )
+        .unwrap();
+        let mut cmd = Command::new("/bin/true");
+        cmd.chroot_dir(&root);
+        cmd.init_supplementary_groups("app".into());
+        assert!(cmd
+            .plan()
+            .unwrap()
+            .to_string()
+            .contains("setgroups([500, 10, 63])"));
+        let check = |expected: &'static [libc::gid_t]| {
+            move || {
+                let mut groups = [0; 16];
+                let n = unsafe { libc::getgroups(16, groups.as_mut_ptr()) };
+                // the kernel keeps them sorted
+                (groups[..n.max(0) as usize] != *expected) as i32
+            }
+        };
+        let status = unsafe { cmd.spawn_fn(check(&[10, 63, 500])) }
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+        cmd.gid(700);
+        let status = unsafe { cmd.spawn_fn(check(&[10, 63, 700])) }
+            .unwrap()
+            .wait()
+            .unwrap();
+        assert!(status.success());
+        fs::remove_dir_all(&root).unwrap();
+    }
 }
@@ -1,10 +1,14 @@
+use std::ffi::CString;
 use std::io;
-use std::os::unix::io::{AsRawFd, IntoRawFd, RawFd};
+use std::os::unix::io::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::Path;
 
 use libc;
 use nix;
 use nix::fcntl::{fcntl, FcntlArg};
 
+use crate::ffi_util::ToCString;
+
 /// An enumeration that is used to configure stdio file descritors
 ///
 /// The enumeration members might be non-stable, it's better to use
@@ -16,8 +20,14 @@ pub enum Stdio {
     Inherit,
     /// This fd will open /dev/null in read or write mode
     Null,
+    /// Same as `Null` but `/dev/null` is opened by the child inside the
+    /// new root
+    NullInRoot,
     /// This is fd passed by application (and closed by `unshare`)
     Fd(Closing),
+    /// This fd will use pipe to the application, and the output is also
+    /// copied to another descriptor (`None` means same fd of the parent)
+    Tee(Option<Closing>),
 }
 
 /// An enumeration that is used to configure non-stdio file descriptors. It
@@ -26,7 +36,20 @@ pub enum Stdio {
 ///
 /// The enumeration members might be non-stable, it's better to use
 /// one of the constructors to create an instance
+///
+/// Descriptors passed by the application are owned as follows:
+///
+/// | Constructor   | Closed in the parent by `unshare`               |
+/// |---------------|-------------------------------------------------|
+/// | `dup_of`      | never, application must keep it open for spawns |
+/// | `take`        | when `spawn` returns, whether it failed or not  |
+/// | `from_file`   | when the `Command` is dropped or fd reconfigured |
+/// | `dup_file`    | same as `from_file`, the original is untouched  |
+///
+/// In all cases the child gets a copy of the descriptor, so closing it in
+/// the parent doesn't affect the child.
 // TODO(tailhook) should this object be hidden?
+#[non_exhaustive]
 pub enum Fd {
     /// This fd is a reading end of a pipe
     ReadPipe,
@@ -40,6 +63,17 @@ pub enum Fd {
     WriteNull,
     /// This is fd passed by application (and closed by `unshare`)
     Fd(Closing),
+    /// This is fd borrowed from application (never closed by `unshare`)
+    Borrowed(RawFd),
+    /// This is fd moved from application (closed by `unshare` when `spawn`
+    /// returns)
+    Moved(Closing),
+    /// This fd is a writing end of a pipe, the output is also copied to
+    /// another descriptor (`None` means same fd of the parent)
+    TeePipe(Option<Closing>),
+    /// This fd is the path opened with the flags by the child, after
+    /// changing root
+    OpenInRoot(CString, libc::c_int),
 }
 
 pub struct Closing(RawFd);
@@ -74,16 +108,47 @@ impl Stdio {
     pub fn null() -> Stdio {
         Stdio::Null
     }
+    /// Stream is attached to `/dev/null` of the new root
+    ///
+    /// Unlike `null()`, the file is opened by the child after
+    /// `chroot_dir` or `pivot_root`, so the child keeps no reference to
+    /// the file system of the parent. If the new root has no `/dev/null`
+    /// (bind-mount or `mknod` it there), `spawn` returns
+    /// `Error::OpenInRoot`. Without changing root it's the same as
+    /// `null()`.
+    pub fn null_in_root() -> Stdio {
+        Stdio::NullInRoot
+    }
+    /// Pipe is created for child process and the output is also copied to
+    /// the `secondary` file, like `tee` command does
+    ///
+    /// The output is copied by a thread in the parent process, which
+    /// exits when the child (and its children) close the stream. If
+    /// the pipe is not read by the application, the child blocks, if the
+    /// secondary file can't be written, the copying to it is stopped. See
+    /// also `Child::detach_tee`. Only useful for `stdout` and `stderr`,
+    /// for `stdin` it's the same as `piped()`.
+    pub fn tee<F: AsRawFd>(secondary: &F) -> io::Result<Stdio> {
+        dup_file_cloexec(secondary).map(|f| Stdio::Tee(Some(f)))
+    }
+    /// Same as `tee()` but output is copied to the respective stream of
+    /// the parent process, so it's shown live and captured at the same time
+    pub fn piped_tee_to_inherit() -> Stdio {
+        Stdio::Tee(None)
+    }
     /// Converts stdio definition to file descriptor definition
     /// (mostly needed internally)
     pub fn to_fd(self, write: bool) -> Fd {
         match (self, write) {
             (Stdio::Fd(x), _) => Fd::Fd(x),
-            (Stdio::Pipe, false) => Fd::ReadPipe,
+            (Stdio::Pipe, false) | (Stdio::Tee(_), false) => Fd::ReadPipe,
+            (Stdio::Tee(x), true) => Fd::TeePipe(x),
             (Stdio::Pipe, true) => Fd::WritePipe,
             (Stdio::Inherit, _) => Fd::Inherit,
             (Stdio::Null, false) => Fd::ReadNull,
             (Stdio::Null, true) => Fd::WriteNull,
+            (Stdio::NullInRoot, false) => Fd::read_in_root("/dev/null"),
+            (Stdio::NullInRoot, true) => Fd::write_in_root("/dev/null"),
         }
     }
     /// A simpler helper method for `from_raw_fd`, that does dup of file
@@ -123,6 +188,21 @@ impl Fd {
     pub fn write_null() -> Fd {
         Fd::WriteNull
     }
+    /// Open the file for reading in the child, after changing root
+    ///
+    /// The path is resolved inside of `chroot_dir` or `pivot_root` (and
+    /// relative to the working directory of the child), by the user set
+    /// by `uid`. Errors are reported by `spawn` as `Error::OpenInRoot`.
+    pub fn read_in_root<P: AsRef<Path>>(path: P) -> Fd {
+        Fd::OpenInRoot(path.as_ref().to_cstring(), libc::O_RDONLY)
+    }
+    /// Open the file for writing in the child, after changing root
+    ///
+    /// Same as `read_in_root`, but the file is opened write-only. It's
+    /// neither created nor truncated, so it must exist.
+    pub fn write_in_root<P: AsRef<Path>>(path: P) -> Fd {
+        Fd::OpenInRoot(path.as_ref().to_cstring(), libc::O_WRONLY)
+    }
     /// A simpler helper method for `from_raw_fd`, that does dup of file
     /// descriptor, so is actually safe to use (but can fail)
     pub fn dup_file<F: AsRawFd>(file: &F) -> io::Result<Fd> {
@@ -132,6 +212,22 @@ impl Fd {
     pub fn from_file<F: IntoRawFd>(file: F) -> Fd {
         Fd::Fd(Closing(file.into_raw_fd()))
     }
+    /// Pass a copy of the descriptor which is still owned by application
+    ///
+    /// The descriptor is never closed by `unshare`, so it may be shared by
+    /// many children (e.g. a log file). It must stay open until the last
+    /// `spawn` of the command.
+    pub fn dup_of(fd: RawFd) -> Fd {
+        Fd::Borrowed(fd)
+    }
+    /// Move the descriptor to the child
+    ///
+    /// The descriptor is closed in the parent when `spawn` returns, even if
+    /// it fails. The next `spawn` of the same command doesn't get this
+    /// descriptor, as it's removed from the configuration.
+    pub fn take(fd: OwnedFd) -> Fd {
+        Fd::Moved(Closing(fd.into_raw_fd()))
+    }
 }
 
 impl Closing {
@@ -0,0 +1,166 @@
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+
+use libc::c_void;
+
+use crate::pipe::{PipeReader, PipeWriter};
+use crate::stdio::Closing;
+use crate::Child;
+
+/// The pipes of a teed descriptor before the forwarding thread is started
+pub struct TeeSetup {
+    pub fd: RawFd,
+    pub source: PipeReader,
+    pub capture: PipeWriter,
+    pub secondary: Closing,
+}
+
+impl TeeSetup {
+    /// Start a thread forwarding output of the child
+    ///
+    /// The thread exits when the child (and everyone else) closes the write
+    /// end of the pipe. Returned flag is cleared when the secondary sink is
+    /// detached or the thread is done.
+    pub fn start(self) -> io::Result<(RawFd, Arc<AtomicBool>)> {
+        let fd = self.fd;
+        let active = Arc::new(AtomicBool::new(true));
+        let flag = active.clone();
+        thread::Builder::new()
+            .name(format!("unshare-tee-{}", fd))
+            .spawn(move || self.run(&flag))?;
+        Ok((fd, active))
+    }
+
+    fn run(self, active: &AtomicBool) {
+        let TeeSetup {
+            mut source,
+            capture,
+            secondary,
+            ..
+        } = self;
+        let mut capture = Some(capture);
+        let mut buf = [0u8; 8192];
+        loop {
+            let n = match source.read(&mut buf) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Err(_) => break,
+            };
+            // blocking writes here slow down the child, like with `tee`
+            if active.load(Ordering::SeqCst) && write_all(secondary.as_raw_fd(), &buf[..n]).is_err()
+            {
+                active.store(false, Ordering::SeqCst);
+            }
+            if let Some(ref mut pipe) = capture {
+                if pipe.write_all(&buf[..n]).is_err() {
+                    // reader is closed, but keep forwarding to secondary
+                    capture = None;
+                }
+            }
+        }
+        active.store(false, Ordering::SeqCst);
+    }
+}
+
+fn write_all(fd: RawFd, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        let rc = unsafe { libc::write(fd, buf.as_ptr() as *const c_void, buf.len()) };
+        if rc < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() == io::ErrorKind::Interrupted {
+                continue;
+            }
+            return Err(err);
+        }
+        buf = &buf[rc as usize..];
+    }
+    Ok(())
+}
+
+impl Child {
+    /// Returns `true` if output of the descriptor is still copied to
+    /// the secondary sink of `Stdio::tee`
+    ///
+    /// Returns `false` when the descriptor is not teed, when the sink is
+    /// detached or fails to write, or when the child closed the descriptor
+    /// and all the data is forwarded.
+    pub fn is_teeing(&self, fd: RawFd) -> bool {
+        self.tees
+            .get(&fd)
+            .is_some_and(|active| active.load(Ordering::SeqCst))
+    }
+
+    /// Stop copying output of the descriptor to the secondary sink
+    ///
+    /// The output is still available through the pipe (i.e. `Child::stdout`).
+    /// Returns `true` if the sink was active.
+    pub fn detach_tee(&mut self, fd: RawFd) -> bool {
+        match self.tees.get(&fd) {
+            Some(active) => active.swap(false, Ordering::SeqCst),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+
+    use crate::pipe::Pipe;
+    use crate::{Command, Stdio};
+
+    #[test]
+    fn test_tee() {
+        let (mut rd, wr) = Pipe::new().unwrap().split();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "echo hello; echo world"]);
+        cmd.stdout(Stdio::tee(&wr).unwrap());
+        drop(wr);
+        let mut child = cmd.spawn().unwrap();
+        drop(cmd);
+        let mut captured = String::new();
+        child
+            .stdout
+            .take()
+            .unwrap()
+            .read_to_string(&mut captured)
+            .unwrap();
+        let mut copy = String::new();
+        rd.read_to_string(&mut copy).unwrap();
+        assert_eq!(captured, "hello\nworld\n");
+        assert_eq!(copy, captured);
+        assert!(child.wait().unwrap().success());
+        assert!(!child.is_teeing(1));
+    }
+
+    #[test]
+    fn test_detach_tee() {
+        let (mut rd, wr) = Pipe::new().unwrap().split();
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "echo before; read x; echo after"]);
+        cmd.stdin(Stdio::piped());
+        cmd.stdout(Stdio::tee(&wr).unwrap());
+        drop(wr);
+        let mut child = cmd.spawn().unwrap();
+        drop(cmd);
+        let mut stdout = child.stdout.take().unwrap();
+        let mut line = [0u8; 7];
+        stdout.read_exact(&mut line).unwrap();
+        assert_eq!(&line, b"before\n");
+        assert!(child.is_teeing(1));
+        assert!(child.detach_tee(1));
+        assert!(!child.is_teeing(1));
+        child.stdin.take().unwrap().write_all(b"\n").unwrap();
+        let mut captured = String::new();
+        stdout.read_to_string(&mut captured).unwrap();
+        let mut copy = String::new();
+        rd.read_to_string(&mut copy).unwrap();
+        assert_eq!(captured, "after\n");
+        assert_eq!(copy, "before\n");
+        assert!(child.wait().unwrap().success());
+    }
+}
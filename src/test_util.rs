@@ -0,0 +1,44 @@
+//! Helpers shared by the tests
+
+use std::env;
+use std::ffi::OsStr;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::{Command, Stdio};
+
+/// A command running the `#[ignore]`d test `name` of this test binary
+///
+/// Used for the tests which affect the whole process (i.e. waiting for any
+/// child), the inner test returns early unless `var` is set. Stdout is
+/// discarded, as the test harness prints there.
+pub fn inner_test(name: &str, var: &str) -> Command {
+    inner_test_of(env::current_exe().unwrap(), name, var)
+}
+
+/// Same as `inner_test`, but runs `exe`, i.e. a copy of the test binary
+pub fn inner_test_of<S: AsRef<OsStr>>(exe: S, name: &str, var: &str) -> Command {
+    let mut cmd = Command::new(exe);
+    cmd.args(&["--exact", name, "--ignored"]);
+    cmd.env(var, "1").stdout(Stdio::null());
+    cmd
+}
+
+/// Returns the cgroup v2 directory of the current process if `controller`
+/// can be used for children there
+pub fn cgroup_with_controller(controller: &str) -> Option<PathBuf> {
+    let mounts = fs::read_to_string("/proc/self/mounts").ok()?;
+    let mount = mounts
+        .lines()
+        .map(|line| line.split(' ').collect::<Vec<_>>())
+        .find(|fields| fields.len() > 2 && fields[2] == "cgroup2")?[1]
+        .to_string();
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let own = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+    let dir = PathBuf::from(mount).join(own.trim_start_matches('/'));
+    let control = fs::read_to_string(dir.join("cgroup.subtree_control")).ok()?;
+    if !control.split_whitespace().any(|c| c == controller) {
+        return None;
+    }
+    Some(dir)
+}
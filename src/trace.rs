@@ -0,0 +1,221 @@
+use std::time::{Duration, Instant};
+
+use crate::{Command, PipeWriter};
+
+pub(crate) type Tracer = Box<dyn Fn(SpawnPhase, Duration) + Send>;
+
+/// Marks trace records from the child in the error pipe, error codes never
+/// have this bit set
+pub const TRACE_RECORD: u8 = 0x80;
+
+/// A step of spawning the process, reported to the `Command::trace` callback
+///
+/// Phases are reported in the order of this enum. The ones starting with
+/// `Child` are measured in the child process and sent back to the parent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SpawnPhase {
+    /// Preparing arguments, environment, and file descriptors
+    BuildEnv,
+    /// The `clone` system call itself
+    Clone,
+    /// Writing uid/gid maps or running `newuidmap`/`newgidmap`, only
+    /// reported if `set_id_maps` is used
+    WriteIdMaps,
+    /// Running the `before_unfreeze` callback and waiting for the
+    /// `external_unfreezer`, only reported if either is set
+    BeforeUnfreeze,
+    /// Waking up the child
+    Unfreeze,
+    /// Joining namespaces, session and controlling terminal setup, and
+    /// filling in `env_var_with_metadata` variables
+    ChildNamespaces,
+    /// `pivot_root`, `chroot` and mounting filesystems
+    ChildChangeRoot,
+    /// Setting user, groups and capabilities
+    ChildSetUser,
+    /// Setting working directory and file descriptors
+    ChildFds,
+    /// Resetting signal mask and running `pre_exec` callback
+    ChildPreExec,
+    /// From the last child phase until `execve` succeeded, as observed by
+    /// the parent
+    Exec,
+}
+
+impl SpawnPhase {
+    pub(crate) fn from_id(id: u8) -> Option<SpawnPhase> {
+        use self::SpawnPhase::*;
+        Some(match id {
+            0 => ChildNamespaces,
+            1 => ChildChangeRoot,
+            2 => ChildSetUser,
+            3 => ChildFds,
+            4 => ChildPreExec,
+            _ => return None,
+        })
+    }
+}
+
+/// Identifiers of the child phases as sent through the pipe
+pub mod child_phase {
+    pub const NAMESPACES: u8 = 0;
+    pub const CHANGE_ROOT: u8 = 1;
+    pub const SET_USER: u8 = 2;
+    pub const FDS: u8 = 3;
+    pub const PRE_EXEC: u8 = 4;
+
+    /// Name of the phase as written by `capture_child_errors`
+    pub fn name(phase: u8) -> &'static [u8] {
+        match phase {
+            NAMESPACES => b"namespaces",
+            CHANGE_ROOT => b"change_root",
+            SET_USER => b"set_user",
+            FDS => b"fds",
+            PRE_EXEC => b"pre_exec",
+            _ => b"unknown",
+        }
+    }
+}
+
+/// Measures parent-side phases, does nothing if there is no tracer
+pub struct PhaseTimer {
+    last: Option<Instant>,
+}
+
+impl PhaseTimer {
+    pub fn start(enabled: bool) -> PhaseTimer {
+        PhaseTimer {
+            last: if enabled { Some(Instant::now()) } else { None },
+        }
+    }
+    pub fn enabled(&self) -> bool {
+        self.last.is_some()
+    }
+    /// Report the phase which lasted since the previous one
+    pub fn finish(&mut self, tracer: &Option<Tracer>, phase: SpawnPhase) {
+        if let (Some(last), Some(tracer)) = (self.last, tracer.as_ref()) {
+            let now = Instant::now();
+            tracer(phase, now - last);
+            self.last = Some(now);
+        }
+    }
+    /// Report the phase measured elsewhere (i.e. in the child)
+    pub fn report(&mut self, tracer: &Option<Tracer>, phase: SpawnPhase, time: Duration) {
+        if let (Some(_), Some(tracer)) = (self.last, tracer.as_ref()) {
+            tracer(phase, time);
+            self.last = Some(Instant::now());
+        }
+    }
+}
+
+impl Command {
+    /// Set a callback which receives time spent in each spawn phase
+    ///
+    /// This is a diagnostic tool to find out where a slow spawn spends its
+    /// time. The callback is called in the parent process during `spawn`,
+    /// including for the phases measured in the child (those are reported
+    /// back through the pipe used for errors). See `SpawnPhase` for the
+    /// list of phases. If no callback is set, no timestamps are taken.
+    pub fn trace(
+        &mut self,
+        tracer: impl Fn(SpawnPhase, Duration) + Send + 'static,
+    ) -> &mut Command {
+        self.tracer = Some(Box::new(tracer));
+        self
+    }
+
+    /// Also write the progress of the child setup to the `dst` pipe
+    ///
+    /// The child writes a line when each setup phase is done and a line
+    /// with an error, if there is one, right before exiting. This is
+    /// written by the child itself, so it's useful if the child dies
+    /// during the setup for some reason the parent can't report. The error
+    /// is still returned from `spawn` too. The lines are:
+    ///
+    /// ```text
+    /// started <pid>
+    /// <phase> done
+    /// error <code> <errno>
+    /// ```
+    ///
+    /// Phases are `namespaces`, `change_root`, `set_user`, `fds`,
+    /// `pre_exec` in this order (see `SpawnPhase` for their description).
+    /// The `pid` is as seen by the parent. Nothing is written after the
+    /// successful `execve`. Each line is written by a single write, so if
+    /// `dst` is shared between multiple commands, the lines from different
+    /// children are not mixed up.
+    pub fn capture_child_errors(&mut self, dst: PipeWriter) -> &mut Command {
+        self.progress = Some(dst);
+        self
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    use crate::pipe::Pipe;
+    use crate::{Command, Error, SpawnPhase};
+
+    #[test]
+    fn test_phases_in_order() {
+        use crate::SpawnPhase::*;
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let mut cmd = Command::new("/bin/true");
+        let sink = phases.clone();
+        cmd.trace(move |phase, _| sink.lock().unwrap().push(phase));
+        cmd.status().unwrap();
+        let expected = vec![
+            BuildEnv,
+            Clone,
+            Unfreeze,
+            ChildNamespaces,
+            ChildChangeRoot,
+            ChildSetUser,
+            ChildFds,
+            ChildPreExec,
+            Exec,
+        ];
+        assert_eq!(*phases.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_phases_before_error() {
+        let phases = Arc::new(Mutex::new(Vec::<SpawnPhase>::new()));
+        let mut cmd = Command::new("/nonexistent");
+        let sink = phases.clone();
+        cmd.trace(move |phase, _| sink.lock().unwrap().push(phase));
+        assert!(cmd.spawn().is_err());
+        let phases = phases.lock().unwrap();
+        assert_eq!(phases.last(), Some(&SpawnPhase::ChildPreExec));
+    }
+
+    #[test]
+    fn test_capture_child_errors() {
+        let (mut rd, wr) = Pipe::new().unwrap().split();
+        let mut cmd = Command::new("/nonexistent");
+        cmd.capture_child_errors(wr);
+        match cmd.spawn().map_err(Error::into_inner) {
+            Err(Error::Exec(libc::ENOENT)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        // the writer is closed with the command
+        drop(cmd);
+        let mut output = String::new();
+        rd.read_to_string(&mut output).unwrap();
+        let lines = output.lines().collect::<Vec<_>>();
+        assert!(lines[0].starts_with("started "), "{:?}", lines);
+        assert_eq!(
+            &lines[1..],
+            &[
+                "namespaces done",
+                "change_root done",
+                "set_user done",
+                "fds done",
+                "pre_exec done",
+                "error exec 2",
+            ]
+        );
+    }
+}
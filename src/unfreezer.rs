@@ -0,0 +1,219 @@
+use std::os::unix::io::{AsRawFd, OwnedFd, RawFd};
+use std::time::Instant;
+
+use libc::c_void;
+
+use crate::run::wait_readable;
+use crate::{Command, Error};
+
+/// The version of the external unfreezer protocol spoken by this crate
+///
+/// See `Command::external_unfreezer` for the description of the protocol.
+pub const UNFREEZE_PROTOCOL_VERSION: u8 = 1;
+
+/// The length of every message, in both directions
+const MESSAGE_LEN: usize = 6;
+const READY: u8 = b'R';
+const PROCEED: u8 = b'P';
+const ABORT: u8 = b'A';
+
+impl Command {
+    /// Hand the unfreezing of the child to another process
+    ///
+    /// When the child is cloned and frozen, after the built-in setup and
+    /// the `before_unfreeze` callback, `spawn` sends a message to the
+    /// socket `fd` and waits for the reply. The child is woken up only if
+    /// the other party replies "proceed", otherwise it's killed and
+    /// `spawn` fails. This is meant for privileged helpers which do part
+    /// of the setup, i.e. write the uid maps.
+    ///
+    /// The protocol is stable within a version, every message is 6 bytes:
+    /// the version, the kind of the message, and a big-endian 32-bit
+    /// value:
+    ///
+    /// * `[version, b'R', pid]` is sent by `spawn`, the version is the
+    ///   highest one it speaks (`UNFREEZE_PROTOCOL_VERSION`), `pid` is the
+    ///   pid of the frozen child
+    /// * `[version, b'P', 0]` is the reply to proceed
+    /// * `[version, b'A', errno]` is the reply to abort, `spawn` fails
+    ///   with `Error::UnfreezeAborted(errno)`
+    ///
+    /// The version of the reply is chosen by the other party and must not
+    /// be higher than the one offered. Unsupported versions, other
+    /// messages and closing the socket without a reply give
+    /// `Error::UnfreezerProtocol`. The exchange repeats on every spawn of
+    /// the command. With `spawn_timeout` the reply is awaited only until the
+    /// deadline.
+    ///
+    /// The socket should be a stream or `SOCK_SEQPACKET` one, i.e. one end
+    /// of a `socketpair` whose other end is passed to the helper. Don't
+    /// keep the other end open in this process: the frozen child has a copy
+    /// of it, so the helper exiting without a reply would not be noticed.
+    pub fn external_unfreezer(&mut self, fd: OwnedFd) -> &mut Command {
+        self.config.unfreezer = Some(fd);
+        self
+    }
+
+    /// Runs `handshake` if `external_unfreezer` is set
+    pub(crate) fn run_unfreezer(&self, pid: i32, deadline: Option<Instant>) -> Result<(), Error> {
+        match self.config.unfreezer {
+            Some(ref fd) => handshake(fd.as_raw_fd(), pid, deadline),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Runs the exchange with the external unfreezer on `fd` for child `pid`
+pub(crate) fn handshake(fd: RawFd, pid: i32, deadline: Option<Instant>) -> Result<(), Error> {
+    let mut msg = [UNFREEZE_PROTOCOL_VERSION, READY, 0, 0, 0, 0];
+    msg[2..].copy_from_slice(&pid.to_be_bytes());
+    let mut written = 0;
+    while written < msg.len() {
+        let rc = unsafe {
+            libc::send(
+                fd,
+                msg[written..].as_ptr() as *const c_void,
+                msg.len() - written,
+                libc::MSG_NOSIGNAL,
+            )
+        };
+        if rc < 0 {
+            let errno = nix::errno::errno();
+            if errno == libc::EINTR {
+                continue;
+            }
+            return Err(Error::PipeError(errno));
+        }
+        written += rc as usize;
+    }
+
+    let mut reply = [0u8; MESSAGE_LEN];
+    let mut len = 0;
+    while len < reply.len() {
+        if let Some(deadline) = deadline {
+            wait_readable(fd, deadline)?;
+        }
+        let rc = unsafe {
+            libc::recv(
+                fd,
+                reply[len..].as_mut_ptr() as *mut c_void,
+                reply.len() - len,
+                0,
+            )
+        };
+        if rc < 0 {
+            let errno = nix::errno::errno();
+            if errno == libc::EINTR {
+                continue;
+            }
+            return Err(Error::PipeError(errno));
+        }
+        if rc == 0 {
+            return Err(Error::UnfreezerProtocol {
+                message: "connection closed without a reply",
+            });
+        }
+        len += rc as usize;
+    }
+    if reply[0] == 0 || reply[0] > UNFREEZE_PROTOCOL_VERSION {
+        return Err(Error::UnfreezerProtocol {
+            message: "unsupported protocol version",
+        });
+    }
+    let value = i32::from_be_bytes([reply[2], reply[3], reply[4], reply[5]]);
+    match reply[1] {
+        PROCEED => Ok(()),
+        ABORT => Err(Error::UnfreezeAborted(value)),
+        _ => Err(Error::UnfreezerProtocol {
+            message: "unexpected message",
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::{Read, Write};
+    use std::os::unix::io::OwnedFd;
+    use std::os::unix::net::UnixStream;
+    use std::path::Path;
+    use std::process;
+    use std::thread;
+
+    use super::UNFREEZE_PROTOCOL_VERSION;
+    use crate::{Command, Error};
+
+    /// Runs the external side: replies with `reply` to the ready message
+    /// and returns the pid it got
+    fn helper(reply: Vec<u8>) -> (UnixStream, thread::JoinHandle<i32>) {
+        let (ours, mut theirs) = UnixStream::pair().unwrap();
+        let thread = thread::spawn(move || {
+            let mut msg = [0u8; 6];
+            theirs.read_exact(&mut msg).unwrap();
+            assert_eq!(msg[..2], [UNFREEZE_PROTOCOL_VERSION, b'R']);
+            let pid = i32::from_be_bytes([msg[2], msg[3], msg[4], msg[5]]);
+            // the child is frozen, so it hasn't exec'd yet
+            assert!(Path::new(&format!("/proc/{}", pid)).exists());
+            theirs.write_all(&reply).unwrap();
+            pid
+        });
+        (ours, thread)
+    }
+
+    #[test]
+    fn test_proceed() {
+        let (socket, thread) = helper(vec![1, b'P', 0, 0, 0, 0]);
+        let mut cmd = Command::new("/bin/true");
+        cmd.external_unfreezer(socket.into());
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(thread.join().unwrap(), child.pid().as_raw());
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn test_abort() {
+        let mut reply = vec![1, b'A'];
+        reply.extend(libc::EPERM.to_be_bytes());
+        let (socket, thread) = helper(reply);
+        let mut cmd = Command::new("/bin/true");
+        cmd.external_unfreezer(socket.into());
+        match cmd.spawn() {
+            Err(Error::UnfreezeAborted(libc::EPERM)) => {}
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        // the child is killed and reaped
+        let pid = thread.join().unwrap();
+        assert!(!Path::new(&format!("/proc/{}", pid)).exists());
+    }
+
+    #[test]
+    fn test_version_negotiation() {
+        let (socket, thread) = helper(vec![2, b'P', 0, 0, 0, 0]);
+        let mut cmd = Command::new("/bin/true");
+        cmd.external_unfreezer(socket.into());
+        match cmd.spawn() {
+            Err(err @ Error::UnfreezerProtocol { .. }) => assert_eq!(
+                err.to_string(),
+                "external unfreezer protocol error: unsupported protocol version"
+            ),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        thread.join().unwrap();
+
+        // the helper is another process which exits without replying
+        let (socket, theirs) = UnixStream::pair().unwrap();
+        let mut reader = process::Command::new("/bin/sh")
+            .args(&["-c", "head -c 6 >/dev/null"])
+            .stdin(OwnedFd::from(theirs))
+            .spawn()
+            .unwrap();
+        cmd.external_unfreezer(socket.into());
+        match cmd.spawn() {
+            Err(err @ Error::UnfreezerProtocol { .. }) => assert_eq!(
+                err.to_string(),
+                "external unfreezer protocol error: connection closed without a reply"
+            ),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(reader.wait().unwrap().success());
+    }
+}
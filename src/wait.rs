@@ -1,15 +1,192 @@
+use std::fs;
 use std::io;
+use std::os::unix::fs::MetadataExt;
 use std::os::unix::io::RawFd;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicPtr, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use libc::pid_t;
-use nix::errno::Errno::EINTR;
+use libc::{c_int, pid_t};
+use nix::errno::Errno;
 use nix::sys::signal::{kill, Signal, SIGKILL};
-use nix::sys::wait::waitpid;
 use nix::unistd::Pid;
 use nix::Error;
 
+use crate::namespace::to_ns_name;
 use crate::pipe::PipeHolder;
-use crate::{Child, ExitStatus, PipeReader, PipeWriter};
+use crate::proc_stat::stat_fields;
+use crate::{Child, ExitStatus, Namespace, PipeReader, PipeWriter};
+
+/// The flag set by `set_wait_interrupt_flag`
+static INTERRUPT_FLAG: AtomicPtr<AtomicBool> = AtomicPtr::new(ptr::null_mut());
+
+/// Set the flag which makes `Child::wait_interruptible` and
+/// `Child::wait_until` return `WaitOutcome::Interrupted`
+///
+/// The flag is usually set by a signal handler (i.e. on `SIGTERM`), which
+/// must be installed without `SA_RESTART`, so the `waitpid` call fails
+/// with `EINTR`. The flag is only checked, never reset by this crate.
+pub fn set_wait_interrupt_flag(flag: &'static AtomicBool) {
+    INTERRUPT_FLAG.store(
+        flag as *const AtomicBool as *mut AtomicBool,
+        Ordering::SeqCst,
+    );
+}
+
+fn interrupted() -> bool {
+    let flag = INTERRUPT_FLAG.load(Ordering::SeqCst);
+    !flag.is_null() && unsafe { (*flag).load(Ordering::SeqCst) }
+}
+
+/// The result of `Child::wait_interruptible`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// The child has exited with the status
+    Exited(ExitStatus),
+    /// A traced child has stopped (or has reported a ptrace event), the
+    /// status is not remembered, so wait again after resuming the child
+    Stopped(ExitStatus),
+    /// The wait was interrupted by a signal and the flag set by
+    /// `set_wait_interrupt_flag` is set, the child may be still running
+    Interrupted,
+}
+
+/// Calls `waitpid` until it returns something we can represent
+///
+/// Unlike `nix::sys::wait::waitpid` this one doesn't fail for processes
+/// killed by realtime signals. The stops (and continues) are returned too,
+/// they are reported for traced children even without `WUNTRACED`. Returns
+/// `None` if there are no status changes and `WNOHANG` is passed.
+pub(crate) fn waitpid_raw(pid: pid_t, flags: c_int) -> Result<Option<(pid_t, ExitStatus)>, Errno> {
+    loop {
+        let mut status = 0;
+        let rc = unsafe { libc::waitpid(pid, &mut status, flags) };
+        if rc < 0 {
+            match Errno::last() {
+                Errno::EINTR => continue,
+                errno => return Err(errno),
+            }
+        }
+        if rc == 0 {
+            return Ok(None);
+        }
+        if let Some(status) = ExitStatus::from_raw(status) {
+            return Ok(Some((rc, status)));
+        }
+    }
+}
+
+/// What `waitid` with `WNOWAIT` has seen, the child is left as is
+enum Peeked {
+    Nothing,
+    Stopped,
+    Exited(pid_t, ExitStatus),
+}
+
+/// Peeks at the status change of `pid` (or any child if `-1`)
+fn peek_raw(pid: pid_t) -> Result<Peeked, Errno> {
+    let (idtype, id) = if pid == -1 {
+        (libc::P_ALL, 0)
+    } else {
+        (libc::P_PID, pid as libc::id_t)
+    };
+    loop {
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let flags = libc::WEXITED | libc::WNOWAIT | libc::WNOHANG;
+        if unsafe { libc::waitid(idtype, id, &mut info, flags) } < 0 {
+            match Errno::last() {
+                Errno::EINTR => continue,
+                errno => return Err(errno),
+            }
+        }
+        // `si_pid` is left zero if no child has exited yet
+        let child = unsafe { info.si_pid() };
+        if child == 0 {
+            return Ok(Peeked::Nothing);
+        }
+        let value = unsafe { info.si_status() };
+        let raw = match info.si_code {
+            libc::CLD_EXITED => (value & 0xff) << 8,
+            libc::CLD_KILLED => value,
+            libc::CLD_DUMPED => value | 0x80,
+            // the stops of traced children are reported even without
+            // `WUNTRACED`, and can't be skipped over
+            _ => return Ok(Peeked::Stopped),
+        };
+        return match ExitStatus::from_raw(raw) {
+            Some(status) => Ok(Peeked::Exited(child, status)),
+            None => Ok(Peeked::Nothing),
+        };
+    }
+}
+
+/// Lists the children of all threads of this process
+fn children_pids() -> Vec<pid_t> {
+    let mut pids = Vec::new();
+    let tasks = match fs::read_dir("/proc/self/task") {
+        Ok(tasks) => tasks,
+        Err(_) => return pids,
+    };
+    for task in tasks.flatten() {
+        if let Ok(data) = fs::read_to_string(task.path().join("children")) {
+            pids.extend(
+                data.split_whitespace()
+                    .filter_map(|x| x.parse::<pid_t>().ok()),
+            );
+        }
+    }
+    pids
+}
+
+/// Reaps `pid` (or any child if `-1`) only if it has exited
+///
+/// Unlike `waitpid_raw` with `WNOHANG`, the stops of traced children are
+/// never consumed, so reaping in background doesn't steal them from the
+/// tracer (which waits for them with `Child::wait`). Returns `None` if
+/// nothing has exited. When waiting for any child, and a stopped child
+/// hides the others, the pids of the children are peeked one by one.
+pub(crate) fn reap_exited(pid: pid_t) -> Result<Option<(pid_t, ExitStatus)>, Errno> {
+    loop {
+        match peek_raw(pid)? {
+            Peeked::Nothing => return Ok(None),
+            Peeked::Exited(child, _) => match waitpid_raw(child, libc::WNOHANG) {
+                // reaped by somebody else in between
+                Err(Errno::ECHILD) | Ok(None) if pid == -1 => continue,
+                result => return result,
+            },
+            Peeked::Stopped if pid == -1 => {
+                for child in children_pids() {
+                    match reap_exited(child) {
+                        Ok(Some(x)) => return Ok(Some(x)),
+                        Ok(None) | Err(Errno::ECHILD) => {}
+                        Err(errno) => return Err(errno),
+                    }
+                }
+                return Ok(None);
+            }
+            Peeked::Stopped => return Ok(None),
+        }
+    }
+}
+
+/// Parses the `NSpid` line of `/proc/<pid>/status`, returns errno on error
+fn read_ns_pids(pid: pid_t) -> Result<Vec<pid_t>, i32> {
+    let status = fs::read_to_string(format!("/proc/{}/status", pid))
+        .map_err(|e| e.raw_os_error().unwrap_or(libc::EIO))?;
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("NSpid:"))
+        .ok_or(libc::ENOTSUP)?;
+    let pids = line
+        .split_whitespace()
+        .map(|pid| pid.parse().map_err(|_| libc::EINVAL))
+        .collect::<Result<Vec<_>, _>>()?;
+    if pids.is_empty() {
+        return Err(libc::EINVAL);
+    }
+    Ok(pids)
+}
 
 impl Child {
     /// Returns pid of the process (a mirror of std method)
@@ -17,60 +194,288 @@ impl Child {
         self.pid as u32
     }
 
-    /// Returns pid of process with correct pid_t type
-    pub fn pid(&self) -> pid_t {
+    /// Returns pid of the process
+    ///
+    /// The value is set at `spawn` and doesn't change afterwards, it's
+    /// returned even after the child has exited. Note that the pid may belong
+    /// to another process after the child is reaped (see `is_reaped`).
+    pub fn pid(&self) -> crate::Pid {
+        crate::Pid::from_raw(self.pid)
+    }
+
+    /// Returns pid of process with raw pid_t type
+    #[deprecated(note = "use `pid()`, which returns typed `Pid`")]
+    pub fn raw_pid(&self) -> pid_t {
         self.pid
     }
 
+    /// Returns `true` if the exit status of the child is collected
+    ///
+    /// After that the pid can be reused by the system for another process,
+    /// so methods sending signals to the child return an error.
+    pub fn is_reaped(&self) -> bool {
+        self.status.is_some()
+    }
+
+    /// Returns the time the process started after system boot, in clock
+    /// ticks (the 22nd field of `/proc/<pid>/stat`)
+    ///
+    /// Together with the pid this identifies the process even if the pid
+    /// is reused. Returns `Error::ProcessInfo(ESRCH)` if the child has
+    /// exited (including being a zombie not waited for yet).
+    pub fn start_time(&self) -> Result<u64, crate::Error> {
+        let exited = crate::Error::ProcessInfo(libc::ESRCH);
+        if self.status.is_some() {
+            return Err(exited);
+        }
+        let stat = fs::read_to_string(format!("/proc/{}/stat", self.pid)).map_err(|e| {
+            match e.raw_os_error() {
+                Some(libc::ENOENT) | None => crate::Error::ProcessInfo(libc::ESRCH),
+                Some(errno) => crate::Error::ProcessInfo(errno),
+            }
+        })?;
+        let fields = stat_fields(&stat).ok_or(crate::Error::ProcessInfo(libc::EINVAL))?;
+        if fields.first() == Some(&"Z") {
+            return Err(exited);
+        }
+        fields
+            .get(22 - 3)
+            .and_then(|value| value.parse().ok())
+            .ok_or(crate::Error::ProcessInfo(libc::EINVAL))
+    }
+
+    /// Returns `true` if the child is in a different namespace of this
+    /// type than the current process
+    ///
+    /// Compares the namespace files in `/proc/<pid>/ns` and `/proc/self/ns`,
+    /// so it tells whether the isolation actually happened, whatever way the
+    /// namespace was set up (`false` for a namespace joined by
+    /// `set_namespace` if the current process is in it too). Returns
+    /// `Error::ProcessInfo(ESRCH)` if the child has exited (including being
+    /// a zombie not waited for yet).
+    pub fn in_new_namespace(&self, ns: Namespace) -> Result<bool, crate::Error> {
+        if self.status.is_some() {
+            return Err(crate::Error::ProcessInfo(libc::ESRCH));
+        }
+        let stat = |pid: &dyn std::fmt::Display| {
+            fs::metadata(format!("/proc/{}/ns/{}", pid, to_ns_name(ns))).map_err(|e| {
+                match e.raw_os_error() {
+                    // the namespaces of a zombie are released already
+                    Some(libc::ENOENT) | None => crate::Error::ProcessInfo(libc::ESRCH),
+                    Some(errno) => crate::Error::ProcessInfo(errno),
+                }
+            })
+        };
+        let child = stat(&self.pid)?;
+        let own = stat(&"self")?;
+        Ok((child.dev(), child.ino()) != (own.dev(), own.ino()))
+    }
+
+    /// Returns the pids of the child in all the pid namespaces it's in,
+    /// from the one of the current process to the innermost one
+    ///
+    /// The pids are read from the `NSpid` line of `/proc/<pid>/status` on
+    /// each call, so it only works until the child is reaped, afterwards
+    /// `Error::ProcessInfo(ESRCH)` is returned. The last value is `1` if
+    /// the child is the first process of a new pid namespace. Returns
+    /// `Error::ProcessInfo(ENOTSUP)` if the kernel doesn't show the line
+    /// (it's there since Linux 4.1), or another `ProcessInfo` error if the
+    /// file couldn't be read.
+    pub fn ns_pids(&self) -> Result<Vec<pid_t>, crate::Error> {
+        if self.status.is_some() {
+            return Err(crate::Error::ProcessInfo(libc::ESRCH));
+        }
+        read_ns_pids(self.pid).map_err(crate::Error::ProcessInfo)
+    }
+
     /// Synchronously wait for child to complete and return exit status
+    ///
+    /// If the child is traced (see `Command::trace_me`), its stops are
+    /// returned too. Those are not remembered, so call `wait` again after
+    /// resuming the child, `ExitStatus::terminated` tells them apart. The
+    /// same applies to the other wait methods (`wait_interruptible` and
+    /// `wait_until` return them as `WaitOutcome::Stopped`).
     pub fn wait(&mut self) -> Result<ExitStatus, io::Error> {
         if let Some(x) = self.status {
             return Ok(x);
         }
-        let status = self._wait()?;
-        self.status = Some(status);
+        let status = self._wait(0)?;
+        self.remember(status);
         Ok(status)
     }
 
-    fn _wait(&mut self) -> Result<ExitStatus, io::Error> {
-        use nix::sys::wait::WaitStatus::*;
+    /// Keeps the status unless it's a stop of a traced child
+    fn remember(&mut self, status: ExitStatus) {
+        if status.terminated() {
+            self.status = Some(status);
+        }
+    }
+
+    /// Wait for child to complete unless interrupted by a signal
+    ///
+    /// Unlike `wait`, which restarts `waitpid` when it's interrupted,
+    /// returns `WaitOutcome::Interrupted` if the flag set by
+    /// `set_wait_interrupt_flag` is set on `EINTR`. So a supervisor can
+    /// start the graceful shutdown from the thread waiting for the child.
+    /// The flag is also checked before each `waitpid`, so a signal received
+    /// right before the call is not lost. Interruptions when the flag is
+    /// not set (or there is no flag) are ignored.
+    pub fn wait_interruptible(&mut self) -> Result<WaitOutcome, io::Error> {
         loop {
-            match waitpid(Some(Pid::from_raw(self.pid)), None) {
-                Ok(PtraceEvent(..)) => {}
-                Ok(PtraceSyscall(..)) => {}
-                Ok(Exited(x, status)) => {
-                    assert!(i32::from(x) == self.pid);
-                    return Ok(ExitStatus::Exited(status as i8));
+            if let Some(x) = self.status {
+                return Ok(WaitOutcome::Exited(x));
+            }
+            if interrupted() {
+                return Ok(WaitOutcome::Interrupted);
+            }
+            let mut status = 0;
+            if unsafe { libc::waitpid(self.pid, &mut status, 0) } < 0 {
+                match Errno::last() {
+                    Errno::EINTR if interrupted() => return Ok(WaitOutcome::Interrupted),
+                    Errno::EINTR => continue,
+                    errno => return Err(io::Error::from_raw_os_error(errno as i32)),
                 }
-                Ok(Signaled(x, sig, core)) => {
-                    assert!(i32::from(x) == self.pid);
-                    return Ok(ExitStatus::Signaled(sig, core));
+            }
+            match ExitStatus::from_raw(status) {
+                Some(status) if !status.terminated() => {
+                    return Ok(WaitOutcome::Stopped(status));
                 }
-                Ok(Stopped(_, _)) => unreachable!(),
-                Ok(Continued(_)) => unreachable!(),
-                Ok(StillAlive) => unreachable!(),
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::InvalidPath) => unreachable!(),
-                Err(Error::InvalidUtf8) => unreachable!(),
-                Err(Error::UnsupportedOperation) => {
-                    return Err(io::Error::new(
-                        io::ErrorKind::Other,
-                        "nix error: unsupported operation",
-                    ));
+                status => self.status = status,
+            }
+        }
+    }
+
+    /// Wait for child to complete until the `deadline`
+    ///
+    /// Returns `None` if the child is still running at the `deadline`,
+    /// otherwise same as `wait_interruptible`. Like `wait_timeout`, the
+    /// child is polled with increasing intervals (up to 50 ms).
+    pub fn wait_until(&mut self, deadline: Instant) -> Result<Option<WaitOutcome>, io::Error> {
+        let mut interval = Duration::from_millis(1);
+        loop {
+            if let Some(x) = self.status {
+                return Ok(Some(WaitOutcome::Exited(x)));
+            }
+            if let Some((_, status)) = waitpid_raw(self.pid, libc::WNOHANG)? {
+                if !status.terminated() {
+                    return Ok(Some(WaitOutcome::Stopped(status)));
                 }
-                Err(Error::Sys(x)) => return Err(io::Error::from_raw_os_error(x as i32)),
+                self.status = Some(status);
+                continue;
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            let delay = interval.min(deadline - now);
+            let time = libc::timespec {
+                tv_sec: delay.as_secs() as libc::time_t,
+                tv_nsec: delay.subsec_nanos() as libc::c_long,
+            };
+            // unlike `thread::sleep`, doesn't continue sleeping on `EINTR`
+            if unsafe { libc::nanosleep(&time, ptr::null_mut()) } < 0 && interrupted() {
+                return Ok(Some(WaitOutcome::Interrupted));
             }
+            interval = (interval * 2).min(Duration::from_millis(50));
+        }
+    }
+
+    /// Synchronously wait for child to complete, stop or continue
+    ///
+    /// Similar to `wait` but also returns `ExitStatus::Stopped` when child
+    /// is stopped by a signal and `ExitStatus::Continued` when it's resumed
+    /// by `SIGCONT` (i.e. uses `WUNTRACED | WCONTINUED` flags).
+    pub fn wait_untraced(&mut self) -> Result<ExitStatus, io::Error> {
+        if let Some(x) = self.status {
+            return Ok(x);
+        }
+        let status = self._wait(libc::WUNTRACED | libc::WCONTINUED)?;
+        self.remember(status);
+        Ok(status)
+    }
+
+    /// Returns the exit status if the child has exited, without reaping it
+    ///
+    /// Uses `waitid` with `WNOWAIT`, so the zombie is left for the owner of
+    /// the `Child` to wait for, and the status is not remembered. Unlike
+    /// `wait_timeout` with zero timeout (which reaps the child) this may be
+    /// used by a component observing the child that somebody else owns.
+    /// Returns `None` if the child is still running (or a traced child is
+    /// stopped).
+    pub fn peek_status(&self) -> Result<Option<ExitStatus>, io::Error> {
+        if let Some(x) = self.status {
+            return Ok(Some(x));
+        }
+        match peek_raw(self.pid) {
+            Ok(Peeked::Exited(_, status)) => Ok(Some(status)),
+            Ok(_) => Ok(None),
+            Err(errno) => Err(io::Error::from_raw_os_error(errno as i32)),
+        }
+    }
+
+    /// Wait for child to complete for at most `timeout`
+    ///
+    /// Returns `None` if the child is still running after the timeout. The
+    /// child is polled with increasing intervals (up to 50 ms), so the
+    /// method may return a little bit later than the child exits.
+    pub fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>, io::Error> {
+        if let Some(x) = self.status {
+            return Ok(Some(x));
+        }
+        let deadline = Instant::now() + timeout;
+        let mut interval = Duration::from_millis(1);
+        loop {
+            if let Some((_, status)) = waitpid_raw(self.pid, libc::WNOHANG)? {
+                self.remember(status);
+                return Ok(Some(status));
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None);
+            }
+            thread::sleep(interval.min(deadline - now));
+            interval = (interval * 2).min(Duration::from_millis(50));
+        }
+    }
+
+    /// Send `SIGTERM`, then `SIGKILL` if child is still alive after `grace`
+    ///
+    /// Returns the final status of the (reaped) child. If the child has
+    /// already exited, returns its status without sending any signals, so
+    /// it's fine to call this method multiple times.
+    pub fn terminate(&mut self, grace: Duration) -> Result<ExitStatus, io::Error> {
+        if let Some(x) = self.status {
+            return Ok(x);
+        }
+        self.signal(Signal::SIGTERM)?;
+        if let Some(status) = self.wait_timeout(grace)? {
+            return Ok(status);
+        }
+        self.kill()?;
+        self.wait()
+    }
+
+    fn _wait(&mut self, flags: c_int) -> Result<ExitStatus, io::Error> {
+        match waitpid_raw(self.pid, flags)? {
+            Some((pid, status)) => {
+                assert!(pid == self.pid);
+                Ok(status)
+            }
+            None => unreachable!(),
         }
     }
 
     /// Send arbitrary unix signal to the process
+    ///
+    /// Returns `InvalidInput` error wrapping `ReapedError` if the child is
+    /// already reaped.
     pub fn signal(&self, signal: Signal) -> Result<(), io::Error> {
         // This prevents (somewhat not-reliable) killing some other process
         // with same pid
         if self.status.is_some() {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "invalid argument: can't kill an exited process",
+                crate::ReapedError { pid: self.pid() },
             ));
         }
         kill(Pid::from_raw(self.pid), signal).map_err(|e| match e {
@@ -110,3 +515,259 @@ impl Child {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+    use std::process;
+
+    use std::time::{Duration, Instant};
+
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::mpsc;
+    use std::thread;
+
+    use super::{set_wait_interrupt_flag, WaitOutcome};
+    use crate::proc_stat::stat_fields;
+    use crate::{Command, Error, ExitStatus, Namespace, ReapedError, Signal};
+
+    #[test]
+    fn test_peek_status() {
+        let mut child = Command::new("/bin/sh")
+            .args(&["-c", "exit 7"])
+            .spawn()
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let status = loop {
+            if let Some(status) = child.peek_status().unwrap() {
+                break status;
+            }
+            assert!(Instant::now() < deadline);
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(status, ExitStatus::Exited(7));
+        // still a zombie which is not reaped
+        let stat = fs::read_to_string(format!("/proc/{}/stat", child.pid())).unwrap();
+        assert!(stat.contains(") Z "), "{}", stat);
+        assert!(!child.is_reaped());
+        assert_eq!(child.peek_status().unwrap(), Some(status));
+        assert_eq!(child.wait().unwrap(), status);
+
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        assert_eq!(child.peek_status().unwrap(), None);
+        child.kill().unwrap();
+        let status = loop {
+            if let Some(status) = child.peek_status().unwrap() {
+                break status;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(status, ExitStatus::Signaled(Signal::SIGKILL, false));
+        assert_eq!(child.wait().unwrap(), status);
+    }
+
+    #[test]
+    fn test_wait_timeout() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let result = child.wait_timeout(Duration::from_millis(50)).unwrap();
+        assert_eq!(result, None);
+        child.kill().unwrap();
+        let status = child.wait_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(status, Some(ExitStatus::Signaled(Signal::SIGKILL, false)));
+    }
+
+    #[test]
+    fn test_terminate() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let status = child.terminate(Duration::from_secs(5)).unwrap();
+        assert_eq!(status, ExitStatus::Signaled(Signal::SIGTERM, false));
+        // idempotent
+        assert_eq!(child.terminate(Duration::from_secs(5)).unwrap(), status);
+    }
+
+    #[test]
+    fn test_terminate_escalates() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "trap '' TERM; exec sleep 10"]);
+        let mut child = cmd.spawn().unwrap();
+        // give shell some time to set up the trap
+        std::thread::sleep(Duration::from_millis(100));
+        let start = Instant::now();
+        let status = child.terminate(Duration::from_millis(200)).unwrap();
+        assert_eq!(status, ExitStatus::Signaled(Signal::SIGKILL, false));
+        assert!(start.elapsed() >= Duration::from_millis(200));
+    }
+
+    #[test]
+    fn test_core_dumped() {
+        let dir = std::env::temp_dir().join(format!("unshare-core-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").current_dir(&dir);
+        unsafe {
+            cmd.pre_exec(|| {
+                let limit = libc::rlimit {
+                    rlim_cur: libc::RLIM_INFINITY,
+                    rlim_max: libc::RLIM_INFINITY,
+                };
+                if libc::setrlimit(libc::RLIMIT_CORE, &limit) != 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+        let mut child = cmd.spawn().unwrap();
+        child.signal(Signal::SIGQUIT).unwrap();
+        let status = child.wait().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(status.signal(), Some(Signal::SIGQUIT as i32));
+        assert!(status.core_dumped(), "{}", status);
+    }
+
+    #[test]
+    fn test_wait_untraced() {
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        child.signal(Signal::SIGSTOP).unwrap();
+        let status = child.wait_untraced().unwrap();
+        assert_eq!(status.stopped_signal(), Some(Signal::SIGSTOP));
+        child.signal(Signal::SIGCONT).unwrap();
+        assert!(child.wait_untraced().unwrap().continued());
+        child.kill().unwrap();
+        assert_eq!(
+            child.wait_untraced().unwrap(),
+            ExitStatus::Signaled(Signal::SIGKILL, false)
+        );
+        // terminal status is remembered
+        assert!(child.wait().unwrap().signal().is_some());
+    }
+
+    #[test]
+    fn test_start_time() {
+        let stat = fs::read_to_string("/proc/self/stat").unwrap();
+        let own: u64 = stat_fields(&stat).unwrap()[22 - 3].parse().unwrap();
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let start = child.start_time().unwrap();
+        assert!(start >= own);
+        assert_eq!(child.start_time().unwrap(), start);
+        child.kill().unwrap();
+        child.wait().unwrap();
+        match child.start_time() {
+            Err(Error::ProcessInfo(libc::ESRCH)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_in_new_namespace() {
+        let mut cmd = Command::new("/bin/sleep");
+        cmd.arg("10").unshare(&[Namespace::Net, Namespace::Uts]);
+        let mut child = cmd.spawn().unwrap();
+        assert!(child.in_new_namespace(Namespace::Net).unwrap());
+        assert!(child.in_new_namespace(Namespace::Uts).unwrap());
+        assert!(!child.in_new_namespace(Namespace::Ipc).unwrap());
+        child.kill().unwrap();
+        // a zombie
+        while fs::read_to_string(format!("/proc/{}/stat", child.pid()))
+            .map_or(false, |stat| !stat.contains(") Z "))
+        {
+            thread::sleep(Duration::from_millis(1));
+        }
+        match child.in_new_namespace(Namespace::Net) {
+            Err(Error::ProcessInfo(libc::ESRCH)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+        child.wait().unwrap();
+        match child.in_new_namespace(Namespace::Net) {
+            Err(Error::ProcessInfo(libc::ESRCH)) => {}
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_signal_after_reap() {
+        let mut child = Command::new("/bin/true").spawn().unwrap();
+        assert!(!child.is_reaped());
+        let pid = child.pid();
+        assert_eq!(nix::unistd::Pid::from(pid).as_raw(), pid.as_raw());
+        child.wait().unwrap();
+        assert!(child.is_reaped());
+        for err in [
+            child.signal(Signal::SIGTERM).unwrap_err(),
+            child.kill().unwrap_err(),
+        ] {
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+            let inner = err.get_ref().unwrap().downcast_ref::<ReapedError>();
+            assert_eq!(inner, Some(&ReapedError { pid }));
+        }
+    }
+
+    static INTERRUPT: AtomicBool = AtomicBool::new(false);
+
+    extern "C" fn on_usr1(_: libc::c_int) {
+        INTERRUPT.store(true, Ordering::SeqCst);
+    }
+
+    #[test]
+    fn test_wait_interruptible() {
+        use nix::sys::signal::{sigaction, SaFlags, SigAction, SigHandler, SigSet};
+
+        set_wait_interrupt_flag(&INTERRUPT);
+        // no `SA_RESTART`, so `waitpid` fails with `EINTR`
+        let action = SigAction::new(
+            SigHandler::Handler(on_usr1),
+            SaFlags::empty(),
+            SigSet::empty(),
+        );
+        unsafe { sigaction(Signal::SIGUSR1, &action).unwrap() };
+
+        let mut child = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiter = thread::spawn(move || {
+            tx.send(unsafe { libc::pthread_self() }).unwrap();
+            let outcome = child.wait_interruptible().unwrap();
+            (child, outcome)
+        });
+        let thread_id = rx.recv().unwrap();
+        thread::sleep(Duration::from_millis(50));
+        unsafe { libc::pthread_kill(thread_id, libc::SIGUSR1) };
+        let (mut child, outcome) = waiter.join().unwrap();
+        assert_eq!(outcome, WaitOutcome::Interrupted);
+        // still running
+        assert!(!child.is_reaped());
+        assert_eq!(child.wait_timeout(Duration::from_millis(0)).unwrap(), None);
+        INTERRUPT.store(false, Ordering::SeqCst);
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(child.wait_until(deadline).unwrap(), None);
+        child.kill().unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(
+            child.wait_until(deadline).unwrap(),
+            Some(WaitOutcome::Exited(ExitStatus::Signaled(
+                Signal::SIGKILL,
+                false
+            )))
+        );
+        assert_eq!(
+            child.wait_interruptible().unwrap(),
+            WaitOutcome::Exited(ExitStatus::Signaled(Signal::SIGKILL, false))
+        );
+
+        // the interrupt flag is global, so the stops are checked here
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.trace_me();
+        let mut child = cmd.spawn().unwrap();
+        assert_eq!(
+            child.wait_interruptible().unwrap(),
+            WaitOutcome::Stopped(ExitStatus::Stopped(Signal::SIGTRAP))
+        );
+        assert!(!child.is_reaped());
+        nix::sys::ptrace::cont(child.pid().into(), None).unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        assert_eq!(
+            child.wait_until(deadline).unwrap(),
+            Some(WaitOutcome::Exited(ExitStatus::Exited(3)))
+        );
+    }
+}
@@ -1,12 +1,9 @@
 use std::marker::PhantomData;
 
-use libc::pid_t;
-use nix::errno::Errno::{ECHILD, EINTR};
-use nix::sys::wait::waitpid;
-use nix::sys::wait::WaitPidFlag;
-use nix::Error;
+use nix::errno::Errno::ECHILD;
 
-use crate::{ExitStatus, Signal};
+use crate::wait::{reap_exited, waitpid_raw};
+use crate::{Child, Error, ExitStatus, Pid, Signal};
 
 /// A non-blocking iteration over zombie processes
 ///
@@ -14,29 +11,16 @@ use crate::{ExitStatus, Signal};
 pub struct ZombieIterator(PhantomData<u8>);
 
 impl Iterator for ZombieIterator {
-    type Item = (pid_t, ExitStatus);
-
-    fn next(&mut self) -> Option<(pid_t, ExitStatus)> {
-        use nix::sys::wait::WaitStatus::*;
-        loop {
-            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
-                Ok(PtraceEvent(..)) => {}
-                Ok(PtraceSyscall(..)) => {}
-                Ok(Exited(pid, status)) => {
-                    return Some((pid.into(), ExitStatus::Exited(status as i8)));
-                }
-                Ok(Signaled(pid, sig, core)) => {
-                    return Some((pid.into(), ExitStatus::Signaled(sig, core)));
-                }
-                Ok(Stopped(_, _)) => continue,
-                Ok(Continued(_)) => continue,
-                Ok(StillAlive) => return None,
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::Sys(ECHILD)) => return None,
-                Err(e) => {
-                    panic!("Unexpected waitpid error: {:?}", e);
-                }
-            }
+    type Item = (Pid, ExitStatus);
+
+    fn next(&mut self) -> Option<(Pid, ExitStatus)> {
+        // stops of traced children are not zombies, and are left for the
+        // tracer
+        match reap_exited(-1) {
+            Ok(Some((pid, status))) => Some((Pid::from_raw(pid), status)),
+            Ok(None) => None,
+            Err(ECHILD) => None,
+            Err(e) => panic!("Unexpected waitpid error: {:?}", e),
         }
     }
 }
@@ -76,11 +60,14 @@ pub fn reap_zombies() -> ZombieIterator {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ChildEvent {
     /// Child is dead, similar to what returned by `reap_zombies()`
-    Death(pid_t, ExitStatus),
+    Death(Pid, ExitStatus),
     /// Child is stopped on a signal Signal
-    Stop(pid_t, Signal),
+    Stop(Pid, Signal),
+    /// Child is stopped on a signal unknown to `Signal` type, which only
+    /// happens to traced children, the number is the signal number
+    StopRaw(Pid, i32),
     /// Child is continued (SIGCONT sent)
-    Continue(pid_t),
+    Continue(Pid),
 }
 
 /// A non-blocking iteration over zombies and child stops
@@ -93,27 +80,16 @@ impl Iterator for ChildEventsIterator {
 
     fn next(&mut self) -> Option<ChildEvent> {
         use self::ChildEvent::*;
-        use nix::sys::wait::WaitStatus::*;
-        let flags = WaitPidFlag::WNOHANG | WaitPidFlag::WUNTRACED | WaitPidFlag::WCONTINUED;
-        loop {
-            match waitpid(None, Some(flags)) {
-                Ok(PtraceEvent(..)) => {}
-                Ok(PtraceSyscall(..)) => {}
-                Ok(Exited(pid, status)) => {
-                    return Some(Death(pid.into(), ExitStatus::Exited(status as i8)));
-                }
-                Ok(Signaled(pid, sig, core)) => {
-                    return Some(Death(pid.into(), ExitStatus::Signaled(sig, core)));
-                }
-                Ok(Stopped(pid, sig)) => return Some(Stop(pid.into(), sig)),
-                Ok(Continued(pid)) => return Some(Continue(pid.into())),
-                Ok(StillAlive) => return None,
-                Err(Error::Sys(EINTR)) => continue,
-                Err(Error::Sys(ECHILD)) => return None,
-                Err(e) => {
-                    panic!("Unexpected waitpid error: {:?}", e);
-                }
-            }
+        let flags = libc::WNOHANG | libc::WUNTRACED | libc::WCONTINUED;
+        match waitpid_raw(-1, flags) {
+            Ok(Some((pid, ExitStatus::Stopped(sig)))) => Some(Stop(Pid::from_raw(pid), sig)),
+            Ok(Some((pid, ExitStatus::PtraceEvent(sig, _)))) => Some(Stop(Pid::from_raw(pid), sig)),
+            Ok(Some((pid, ExitStatus::StoppedRaw(sig)))) => Some(StopRaw(Pid::from_raw(pid), sig)),
+            Ok(Some((pid, ExitStatus::Continued))) => Some(Continue(Pid::from_raw(pid))),
+            Ok(Some((pid, status))) => Some(Death(Pid::from_raw(pid), status)),
+            Ok(None) => None,
+            Err(ECHILD) => None,
+            Err(e) => panic!("Unexpected waitpid error: {:?}", e),
         }
     }
 }
@@ -138,7 +114,7 @@ impl Iterator for ChildEventsIterator {
 ///         for event in zombies() {
 ///             match event {
 ///                 Death(pid, _) => alive.remove(pid),
-///                 Stop(..) => {}
+///                 Stop(..) | StopRaw(..) => {}
 ///                 Continue(..) => {}
 ///         }
 ///     }
@@ -153,3 +129,123 @@ impl Iterator for ChildEventsIterator {
 pub fn child_events() -> ChildEventsIterator {
     ChildEventsIterator(PhantomData)
 }
+
+/// Reaps the exited ones of `children` and sets their status
+///
+/// Each child which has no status yet is checked with non-blocking
+/// `waitpid`, the status of the exited ones is returned by `Child::wait`
+/// afterwards (and `Child::is_reaped` is `true`). Returns the number of
+/// children updated.
+///
+/// Unlike `reap_zombies`, only the pids of `children` are waited for, so
+/// other children of the process are left for their owners (and nothing
+/// needs to map pids back to `Child` objects). So the usual loop is to
+/// call this function on `SIGCHLD`.
+pub fn reap_into(children: &mut [&mut Child]) -> Result<usize, Error> {
+    let mut updated = 0;
+    for child in children.iter_mut() {
+        if child.status.is_some() {
+            continue;
+        }
+        // a stop of a traced child is left for `Child::wait`
+        match reap_exited(child.pid) {
+            Ok(Some((_, status))) => {
+                child.status = Some(status);
+                updated += 1;
+            }
+            Ok(None) => {}
+            Err(errno) => return Err(Error::WaitError(errno as i32)),
+        }
+    }
+    Ok(updated)
+}
+
+#[cfg(test)]
+mod test {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{child_events, reap_into, reap_zombies, ChildEvent};
+    use crate::test_util::inner_test;
+    use crate::{Command, ExitStatus};
+
+    #[test]
+    fn test_pids() {
+        // waiting for any child would steal children of other tests
+        let mut cmd = inner_test("zombies::test::pids_inner", "UNSHARE_TEST_ZOMBIE_PIDS");
+        assert!(cmd.status().unwrap().success());
+    }
+
+    #[test]
+    #[ignore]
+    fn pids_inner() {
+        if std::env::var_os("UNSHARE_TEST_ZOMBIE_PIDS").is_none() {
+            return;
+        }
+        let child = Command::new("/bin/sh")
+            .args(&["-c", "exit 4"])
+            .spawn()
+            .unwrap();
+        let event = loop {
+            if let Some(event) = child_events().next() {
+                break event;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(event, ChildEvent::Death(child.pid(), ExitStatus::Exited(4)));
+
+        let child = Command::new("/bin/true").spawn().unwrap();
+        let zombie = loop {
+            if let Some(zombie) = reap_zombies().next() {
+                break zombie;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(zombie, (child.pid(), ExitStatus::Exited(0)));
+    }
+
+    #[test]
+    fn test_reap_into() {
+        let mut quick = Command::new("/bin/sh")
+            .args(&["-c", "exit 5"])
+            .spawn()
+            .unwrap();
+        let mut slow = Command::new("/bin/sleep").arg("10").spawn().unwrap();
+        let updated = loop {
+            let updated = reap_into(&mut [&mut quick, &mut slow]).unwrap();
+            if updated > 0 {
+                break updated;
+            }
+            thread::sleep(Duration::from_millis(5));
+        };
+        assert_eq!(updated, 1);
+        assert!(quick.is_reaped());
+        assert!(!slow.is_reaped());
+        assert_eq!(reap_into(&mut [&mut quick, &mut slow]).unwrap(), 0);
+        slow.kill().unwrap();
+        assert_eq!(quick.wait().unwrap(), ExitStatus::Exited(5));
+        slow.wait().unwrap();
+    }
+
+    #[test]
+    fn test_reap_into_keeps_stops() {
+        use nix::sys::ptrace;
+        use nix::sys::signal::Signal;
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.args(&["-c", "exit 3"]);
+        cmd.trace_me();
+        let mut child = cmd.spawn().unwrap();
+        // the child stops at `execve` meanwhile
+        for _ in 0..20 {
+            assert_eq!(reap_into(&mut [&mut child]).unwrap(), 0);
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(child.wait().unwrap(), ExitStatus::Stopped(Signal::SIGTRAP));
+        ptrace::cont(child.pid().into(), None).unwrap();
+        while reap_into(&mut [&mut child]).unwrap() == 0 {
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert_eq!(child.wait().unwrap(), ExitStatus::Exited(3));
+    }
+}